@@ -1,6 +1,8 @@
 extern crate server_side;
 use server_side::*;
 
+use std::env;
+
 use std::sync::{Arc, Mutex};
 
 use tflitec::interpreter::{Interpreter, Options};
@@ -21,6 +23,30 @@ fn main() {
     //      and the remote server
     delay(0);
 
+    // FITTING FRAMES TO THE MODEL INPUT
+    //      Stretch (default), Letterbox or CenterCrop; keypoints
+    //      are mapped back onto the frame either way
+    resize(preprocess::ResizeMode::Stretch);
+
+    // CROPPING AROUND THE BODY FOUND IN THE PREVIOUS FRAME
+    //      (off by default; falls back to the whole frame when
+    //      nobody is visible)
+    crop(false);
+
+    // SMOOTHING KEYPOINTS OVER TIME
+    //      None (default) to draw every frame's raw output, or e.g.
+    //      Some(SmoothingConfig::default()) or
+    //      Some(SmoothingConfig { filter: Filter::KALMAN, ..Default::default() })
+    smoothing(None);
+
+    // POSE DRAWING STYLE
+    //      skeleton colours, confidence shading, joint names, ...
+    //      e.g. PoseStyle { labels: true, ..Default::default() }
+    style(PoseStyle::default());
+
+    // STOP AFTER A NUMBER OF FRAMES (0 = NO LIMIT)
+    frames(env::args().nth(3).map_or(0, |n| n.parse().expect("Frame count [FAILED]")));
+
     println!("SETTING UP INTERPRETER ... \n");
 
     // LOADING THE MODEL/INTERPRETER
@@ -32,6 +58,29 @@ fn main() {
     let interpreter = Arc::new(Mutex::new(interpreter)); // CREATE A MUTEXED ATOMIC REFERENCE TO THE INTERPRETER
     let interpreter = Arc::clone(&interpreter);
 
+    // PICK WHERE FRAMES COME FROM
+    //      v4l2[:<device>], file:<path>, dir:<path> or pattern[:<W>x<H>]
+    //      (defaults to the camera at /dev/video0)
+    let spec = env::args().nth(1).unwrap_or("v4l2".to_string());
+    let source = frame_source::from_spec(&spec).expect("Open frame source [FAILED]");
+
+    // PICK WHERE ANNOTATED FRAMES GO
+    //      window, file:<path>, images:<dir> or http:<addr:port>
+    //      (^C stops the loop cleanly so recordings are finalized)
+    let spec = env::args().nth(2).unwrap_or("window".to_string());
+    let sink = output::from_spec(&spec).expect("Open output [FAILED]");
+    output::install_stop_handler().expect("Install signal handler [FAILED]");
+
+    // WRITE KEYPOINTS TO A .jsonl OR .csv FILE (OPTIONAL)
+    if let Some(path) = env::args().nth(4) {
+        results(&path).expect("Open results file [FAILED]");
+    }
+
+    // KEEP MODEL OUTPUTS THAT FAIL VALIDATION IN A DIRECTORY (OPTIONAL)
+    if let Some(dir) = env::args().nth(5) {
+        dump_invalid(&dir);
+    }
+
     // DISPLAY THE FEED
-    display(interpreter);
+    display(interpreter, source, sink);
 }
@@ -1,7 +1,8 @@
 /// Setting up the server to receive connections/stream data from user (using ThreadPool)
 
+use std::cell::RefCell;
 use std::net::{TcpListener, TcpStream};
-use std::io::{prelude::*};
+use std::io::{self, prelude::*, ErrorKind};
 use std::sync::{Arc, Mutex};
 
 use byteorder::{ByteOrder, LittleEndian};
@@ -9,11 +10,21 @@ use byteorder::{ByteOrder, LittleEndian};
 use tflitec::interpreter::{Interpreter, Options};
 
 use remote_server::ThreadPool; // IMPORT THREADPOOL CAPABILITY
-
-const BUFFER1_SIZE: usize = 589824; // BUFFER1 : READING IN
-const BUFFER2_SIZE: usize = 147456; // BUFFER2 : BYTES TO FLOAT CONVERSION
-const BUFFER3_SIZE: usize = 4; // BUFFER3 : STORE THE BYTES OF A FLOAT
-const BUFFER4_SIZE: usize = 204; // BUFFER4 : OUTPUT TENSOR
+use server_side::boundary::{accept, read_frame, BoundarySpec, DType}; // PARTITION TENSOR PROTOCOL
+use server_side::boundary_spec;
+
+// BUFFER1 (READING IN) IS SIZED FROM THE MODEL'S INPUT TENSOR (FLOAT32, OR
+// int8/uint8 WHEN THE SPLITTER KEPT THE QUANTIZED BOUNDARY), BUFFER2
+// (OUTPUT) FROM WHATEVER THE MODEL RETURNS
+//      SinglePose :   51 floats (17 keypoints)
+//      MultiPose  :  336 floats (6 people x 56)
+// BOTH ARE KEPT PER WORKER THREAD AND REUSED ACROSS FRAMES AND CONNECTIONS, AND THE
+// INPUT IS DECODED STRAIGHT INTO THE INTERPRETER'S INPUT TENSOR
+
+thread_local! {
+    static BUFFER1: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+    static BUFFER2: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+}
 
 fn main() {
     // POSSIBLE CODES
@@ -28,90 +39,83 @@ fn main() {
 	
     let interpreter = Interpreter::with_model_path(&path, Some(Options::default())).expect("Load model [FAILED]");
 	interpreter.allocate_tensors().expect("Allocate tensors [FAILED]");
+    let boundary = boundary_spec(&interpreter.input(0).expect("Input tensor [FAILED]"))
+        .expect("Boundary tensor [FAILED]");
+    println!("PARTITION BOUNDARY: {}", boundary);
     let interpreter = Arc::new(Mutex::new(interpreter)); // CREATE A MUTEXED ATOMIC REFERENCE TO THE INTERPRETER
 
     // ACCEPT CONNECTIONS USING TCPSTREAM
     for stream in listener.incoming() {
         let stream: TcpStream = stream.expect("Finding connection [FAILED]");
         let interpreter = Arc::clone(&interpreter);
+        let boundary = boundary.clone();
 
         pool.execute(move || {
-            handle_connection(stream, interpreter);
+            handle_connection(stream, interpreter, boundary);
         });
     }
 }
 
 // HELPER FUNCTIONS
 
-fn handle_connection(mut stream: TcpStream, interpreter: Arc<Mutex<Interpreter>>) {
-    // READ INFORMATION IN FROM THE STREAM
-    let mut buffer1: [u8; BUFFER1_SIZE] = [0; BUFFER1_SIZE]; // CREATE BUFFER
-    stream.read(&mut buffer1).expect("Reading stream [FAILED]"); // READ IN THE DATA
-
-    // CONVERT BACK TO FLOATING POINT
-    let mut buffer2: [f32; BUFFER2_SIZE] = [0.0; BUFFER2_SIZE];
-    let mut buffer3: [u8; BUFFER3_SIZE] = [0; BUFFER3_SIZE];
-    for i in 0..BUFFER2_SIZE {
-        buffer3 = buffer1[i * 4..i * 4 + 4].try_into().expect("Taking slice [FAILED]");
-        buffer2[i] = LittleEndian::read_f32(&mut buffer3)
+// SERVES FRAMES UNTIL THE CLIENT HANGS UP (OR SENDS SOMETHING ELSE)
+fn handle_connection(mut stream: TcpStream, interpreter: Arc<Mutex<Interpreter>>, boundary: BoundarySpec) {
+    // CHECK THE CLIENT SPLIT THE MODEL THE SAME WAY (dtype, shape,
+    // scale/zero point), THE CLIENT IS TOLD WHY IF NOT
+    if let Err(e) = accept(&mut stream, &boundary) {
+        println!("Boundary handshake [FAILED]: {}", e);
+        return;
     }
 
-    // SET THE INPUT TO THE INTERPRETER
-    let interpreter = interpreter.lock().expect("Unlocking interpreter [FAILED]");
-    interpreter.copy(&buffer2, 0).expect("Copying data into interpreter [FAILED]");
-    
-    interpreter.invoke().expect("Invoke [FAILED]"); // RUN THE INTERPRETER
-
-    // GET THE OUTPUT FROM THE INTERPRETER
-    let output_tensor = interpreter.output(0).expect(" [FAILED]");
-    let output_tensor = output_tensor.data::<f32>();
-
-    // CONVERT TO OUTPUT DATA TO BYTES
-    let mut buffer4: [u8; BUFFER4_SIZE] = [0; BUFFER4_SIZE];
-    
-    for i in 0..51 {
-        LittleEndian::write_f32(&mut buffer3, output_tensor[i]);
-        for j in 0..4 {
-            buffer4[i * 4 + j] = buffer3[j];
+    loop {
+        match handle_frame(&mut stream, &interpreter, &boundary) {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                println!("Handling frame [FAILED]: {}", e);
+                return;
+            }
         }
     }
-
-    // WRITE BACK TO THE CALLER
-    stream.write_all(&buffer4[..]).expect("Writing back to caller [FAILED]");
-    stream.flush().expect("Flushing the stream [FAILED]");
 }
 
-/// Implemented for when dealing with YUV422
-fn _buff_yuv422to_rgb888(yuv422: &[u8]) -> Vec<u8> {
-    let mut rgb888 = Vec::new(); // CREATE RESULTING VECTOR
-    
-    for i in 0..yuv422.len() / 4 {
-        // DECOMPOSING THE YUV422 ENTRIES
-        let (y1, u, y2, v) = (
-            yuv422[i * 4] as f64,
-            yuv422[i * 4 + 1] as f64,
-            yuv422[i * 4 + 2] as f64,
-            yuv422[i * 4 + 3] as f64,
-        );
-
-        // CALCULATING THE RGB VALUES
-        let (rgb1, rgb2) = (
-            _yuv422to_rgb888(y1, u, v), 
-            _yuv422to_rgb888(y2, u, v),
-        );
-
-        // PUSHING THE RESULTS
-        rgb888.extend(rgb1);
-        rgb888.extend(rgb2);
-    }
+// ONE FRAME IN, ONE MODEL OUTPUT BACK. Ok(false) ONCE THE CLIENT CLOSED THE
+// CONNECTION BETWEEN FRAMES
+fn handle_frame(stream: &mut TcpStream, interpreter: &Mutex<Interpreter>, boundary: &BoundarySpec) -> io::Result<bool> {
+    BUFFER1.with(|buffer1| BUFFER2.with(|buffer2| {
+        let (mut buffer1, mut buffer2) = (buffer1.borrow_mut(), buffer2.borrow_mut());
+
+        // READ INFORMATION IN FROM THE STREAM
+        match read_frame(stream, boundary, &mut buffer1) { // READ IN THE DATA
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
 
-    return rgb888; // RETURN RESULTING VECTOR
-}
+        // INTO THE INPUT OF THE INTERPRETER (FLOATS CONVERTED BACK,
+        // int8/uint8 COPIED AS THEY ARE)
+        let interpreter = interpreter.lock().expect("Unlocking interpreter [FAILED]");
+        let mut input = interpreter.input(0).expect("Input tensor [FAILED]");
+        match boundary.dtype {
+            DType::Float32 => LittleEndian::read_f32_into(&buffer1, input.data_mut::<f32>()),
+            DType::Int8 | DType::Uint8 => input.data_mut::<u8>().copy_from_slice(&buffer1),
+        }
+
+        interpreter.invoke().expect("Invoke [FAILED]"); // RUN THE INTERPRETER
+
+        // GET THE OUTPUT FROM THE INTERPRETER
+        let output_tensor = interpreter.output(0).expect(" [FAILED]");
+        let output_tensor = output_tensor.data::<f32>();
+
+        // CONVERT OUTPUT DATA TO BYTES
+        //      (u64 LENGTH IN BYTES FIRST, SO ANY OUTPUT SIZE CAN BE SENT)
+        buffer2.resize(8 + output_tensor.len() * 4, 0);
+        LittleEndian::write_u64(&mut buffer2[..8], (output_tensor.len() * 4) as u64);
+        LittleEndian::write_f32_into(output_tensor, &mut buffer2[8..]);
 
-fn _yuv422to_rgb888(y: f64, u: f64, v: f64) -> [u8; 3] {
-    return [
-        (y + 1.4075 * (v - 128.0)) as u8,
-        (y - 0.3455 * (u - 128.0) - (0.7169 * (v - 128.0))) as u8,
-        (y + 1.7790 * (u - 128.0)) as u8,
-    ]; // CONVERT AND RETURN AN ARRAY OF [R, G, B] VALUES
+        // WRITE BACK TO THE CALLER
+        stream.write_all(&buffer2[..])?;
+        stream.flush()?;
+        Ok(true)
+    }))
 }
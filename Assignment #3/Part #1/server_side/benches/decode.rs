@@ -0,0 +1,76 @@
+//! MJPEG decode time per decoder, at full size and at the reduced scale the pipeline asks for.
+//!
+//!   cargo bench --bench decode [--features turbojpeg] [-- <directory of .jpg frames>]
+//!
+//! Frames can be recorded straight off the camera without re-encoding, e.g.
+//!   ffmpeg -f v4l2 -input_format mjpeg -video_size 800x448 -i /dev/video0 -c copy -frames 300 frames/%04d.jpg
+//! Without a directory, synthetic 800x448 frames are used.
+
+use std::env;
+use std::fs;
+use std::time::{Duration, Instant};
+
+use image::codecs::jpeg::JpegEncoder;
+use image::{ColorType, Rgb, RgbImage};
+
+use server_side::jpeg::{decoders, JpegDecoder};
+use server_side::preprocess::MODEL_INPUT;
+
+const PASSES: usize = 5;
+
+fn recorded(dir: &str) -> Vec<Vec<u8>> {
+    let mut paths: Vec<_> = fs::read_dir(dir).expect("reading frame directory")
+        .map(|entry| entry.expect("reading frame directory").path())
+        .filter(|path| matches!(path.extension().and_then(|e| e.to_str()), Some("jpg" | "jpeg" | "JPG")))
+        .collect();
+    paths.sort();
+    paths.iter().map(|path| fs::read(path).expect("reading frame")).collect()
+}
+
+fn synthetic() -> Vec<Vec<u8>> {
+    (0..30u32)
+        .map(|i| {
+            let image = RgbImage::from_fn(800, 448, |x, y| Rgb([(x + i * 8) as u8, y as u8, ((x ^ y) + i) as u8]));
+            let mut bytes = vec![];
+            JpegEncoder::new_with_quality(&mut bytes, 85)
+                .encode(image.as_raw(), 800, 448, ColorType::Rgb8)
+                .expect("encoding frame");
+            bytes
+        })
+        .collect()
+}
+
+// Mean time per frame, best of PASSES.
+fn time(decoder: &mut dyn JpegDecoder, frames: &[Vec<u8>], at_least: Option<(u32, u32)>) -> (Duration, (u32, u32)) {
+    let mut rgb = vec![];
+    let mut size = (0, 0);
+    let mut best = Duration::MAX;
+    for _ in 0..PASSES {
+        let start = Instant::now();
+        for frame in frames {
+            let full = decoder.size(frame).expect("reading header");
+            size = decoder.decode(frame, at_least.unwrap_or(full), &mut rgb).expect("decoding frame");
+        }
+        best = best.min(start.elapsed() / frames.len() as u32);
+    }
+    (best, size)
+}
+
+fn main() {
+    // cargo passes --bench; anything else is the frame directory.
+    let frames = match env::args().skip(1).find(|arg| !arg.starts_with("--")) {
+        Some(dir) => recorded(&dir),
+        None => synthetic(),
+    };
+    assert!(!frames.is_empty(), "no .jpg frames found");
+    println!("{} frames, best of {} passes\n", frames.len(), PASSES);
+
+    println!("{:<14} {:>12} {:>12} {:>12}", "decoder", "scale", "decoded", "ms/frame");
+    for mut decoder in decoders() {
+        for (label, at_least) in [("full", None), ("model input", Some(MODEL_INPUT))] {
+            let (per_frame, (w, h)) = time(decoder.as_mut(), &frames, at_least);
+            println!("{:<14} {:>12} {:>12} {:>12.3}", decoder.name(), label, format!("{}x{}", w, h),
+                     per_frame.as_secs_f64() * 1000.0);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! The partition-boundary tensor between model_local.tflite and model_remote.tflite, and the wire
+//! protocol carrying it from the client to the remote server.
+//!
+//! splitter.py can cut the model either after its injected DEQUANTIZE (FLOAT32 boundary) or right
+//! at the native int8 tensor (`--boundary int8`), which is 4x smaller and saves the remote half a
+//! QUANTIZE. Both halves must agree on which, so every connection starts with a handshake:
+//!
+//!   client -> server   hello:  "DNNB", version u8, BoundarySpec
+//!   server -> client   reply:  status u8 (0 = accepted), u32 message length, message (UTF-8)
+//!
+//! and then carries any number of frames, until the client closes the connection:
+//!
+//!   client -> server   header: dtype u8, scale f32, zero point i32, payload length u64, payload
+//!   server -> client   u64 length in bytes, then the model output as f32s
+//!
+//! A BoundarySpec is dtype u8, rank u8, rank x u32 dims, has_quantization u8, scale f32, zero
+//! point i32. Multi-byte values are little endian, like everything else we send.
+
+use std::fmt;
+use std::io::{self, Error, ErrorKind, Read, Write};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+pub const MAGIC: [u8; 4] = *b"DNNB";
+pub const VERSION: u8 = 1;
+
+const ACCEPTED: u8 = 0;
+const REJECTED: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DType {
+    Float32,
+    Int8,
+    Uint8,
+}
+
+impl DType {
+    pub fn size(&self) -> usize {
+        match self {
+            DType::Float32 => 4,
+            DType::Int8 | DType::Uint8 => 1,
+        }
+    }
+
+    fn code(&self) -> u8 {
+        match self {
+            DType::Float32 => 0,
+            DType::Int8 => 1,
+            DType::Uint8 => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> io::Result<DType> {
+        match code {
+            0 => Ok(DType::Float32),
+            1 => Ok(DType::Int8),
+            2 => Ok(DType::Uint8),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("unknown boundary dtype {}", code))),
+        }
+    }
+}
+
+// real = scale * (quantized - zero_point)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quantization {
+    pub scale: f32,
+    pub zero_point: i32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BoundarySpec {
+    pub dtype: DType,
+    pub shape: Vec<u32>,
+    // None for float tensors.
+    pub quantization: Option<Quantization>,
+}
+
+impl fmt::Display for BoundarySpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} {:?}", self.dtype, self.shape)?;
+        match self.quantization {
+            Some(q) => write!(f, " (scale {}, zero point {})", q.scale, q.zero_point),
+            None => Ok(()),
+        }
+    }
+}
+
+impl BoundarySpec {
+    pub fn elements(&self) -> usize {
+        self.shape.iter().map(|d| *d as usize).product()
+    }
+
+    pub fn byte_len(&self) -> usize {
+        self.elements() * self.dtype.size()
+    }
+
+    // Err describing every difference from `other`.
+    pub fn check(&self, other: &BoundarySpec) -> io::Result<()> {
+        let mut problems = vec![];
+        if self.dtype != other.dtype {
+            problems.push(format!("dtype {:?} vs {:?}", self.dtype, other.dtype));
+        }
+        if self.shape != other.shape {
+            problems.push(format!("shape {:?} vs {:?}", self.shape, other.shape));
+        }
+        match (self.quantization, other.quantization) {
+            (Some(a), Some(b)) if !same_quantization(a, b) =>
+                problems.push(format!("quantization {:?} vs {:?}", a, b)),
+            (Some(_), None) | (None, Some(_)) =>
+                problems.push(format!("quantization {:?} vs {:?}", self.quantization, other.quantization)),
+            _ => {}
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::InvalidData, format!("boundary mismatch: {}", problems.join(", "))))
+        }
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.push(self.dtype.code());
+        out.push(self.shape.len() as u8);
+        for d in &self.shape {
+            out.extend_from_slice(&d.to_le_bytes());
+        }
+        let q = self.quantization.unwrap_or(Quantization { scale: 0.0, zero_point: 0 });
+        out.push(self.quantization.is_some() as u8);
+        out.extend_from_slice(&q.scale.to_le_bytes());
+        out.extend_from_slice(&q.zero_point.to_le_bytes());
+    }
+
+    fn read_from(stream: &mut impl Read) -> io::Result<BoundarySpec> {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head)?;
+        let dtype = DType::from_code(head[0])?;
+        let mut dims = vec![0; head[1] as usize * 4];
+        stream.read_exact(&mut dims)?;
+        let shape = dims.chunks_exact(4).map(LittleEndian::read_u32).collect();
+
+        let mut q = [0; 9];
+        stream.read_exact(&mut q)?;
+        let quantization = (q[0] != 0).then(|| Quantization {
+            scale: LittleEndian::read_f32(&q[1..5]),
+            zero_point: LittleEndian::read_i32(&q[5..9]),
+        });
+        Ok(BoundarySpec { dtype, shape, quantization })
+    }
+}
+
+// Scales come out of the .tflite as f32 on both sides, but allow for rounding in the JSON round
+// trip splitter.py does.
+fn same_quantization(a: Quantization, b: Quantization) -> bool {
+    a.zero_point == b.zero_point && (a.scale - b.scale).abs() <= a.scale.abs().max(b.scale.abs()) * 1e-5
+}
+
+// CLIENT: offers `spec`; Err if the server turned it down (with the server's reason).
+pub fn handshake<S: Read + Write>(stream: &mut S, spec: &BoundarySpec) -> io::Result<()> {
+    let mut hello = MAGIC.to_vec();
+    hello.push(VERSION);
+    spec.write_to(&mut hello);
+    stream.write_all(&hello)?;
+
+    let mut head = [0; 5];
+    stream.read_exact(&mut head)?;
+    let mut message = vec![0; LittleEndian::read_u32(&head[1..]) as usize];
+    stream.read_exact(&mut message)?;
+    match head[0] {
+        ACCEPTED => Ok(()),
+        _ => Err(Error::new(ErrorKind::ConnectionRefused,
+                            format!("server rejected boundary {}: {}", spec, String::from_utf8_lossy(&message)))),
+    }
+}
+
+// SERVER: reads the client's hello and accepts it if it matches `expected` (what the remote model
+// takes as input). Either way the client hears back; Err after rejecting.
+pub fn accept<S: Read + Write>(stream: &mut S, expected: &BoundarySpec) -> io::Result<BoundarySpec> {
+    let mut head = [0; 5];
+    stream.read_exact(&mut head)?;
+    let offered = if head[..4] != MAGIC {
+        Err(Error::new(ErrorKind::InvalidData, "not a boundary handshake (old client?)"))
+    } else if head[4] != VERSION {
+        Err(Error::new(ErrorKind::InvalidData, format!("protocol version {}, expected {}", head[4], VERSION)))
+    } else {
+        BoundarySpec::read_from(stream)
+    };
+    let result = offered.and_then(|offered| {
+        offered.check(expected).map_err(|e| Error::new(e.kind(), format!("{} (client sends {}, server expects {})", e, offered, expected)))?;
+        Ok(offered)
+    });
+
+    let (status, message) = match &result {
+        Ok(_) => (ACCEPTED, String::new()),
+        Err(e) => (REJECTED, e.to_string()),
+    };
+    let mut reply = vec![status];
+    reply.extend_from_slice(&(message.len() as u32).to_le_bytes());
+    reply.extend_from_slice(message.as_bytes());
+    stream.write_all(&reply)?;
+    result
+}
+
+// CLIENT: one boundary tensor, raw (f32s little endian, int8/uint8 as bytes).
+pub fn write_frame(stream: &mut impl Write, spec: &BoundarySpec, data: &[u8]) -> io::Result<()> {
+    if data.len() != spec.byte_len() {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("boundary {} is {} bytes, got {}", spec, spec.byte_len(), data.len())));
+    }
+    let q = spec.quantization.unwrap_or(Quantization { scale: 0.0, zero_point: 0 });
+    let mut header = [0; 17];
+    header[0] = spec.dtype.code();
+    LittleEndian::write_f32(&mut header[1..5], q.scale);
+    LittleEndian::write_i32(&mut header[5..9], q.zero_point);
+    LittleEndian::write_u64(&mut header[9..], data.len() as u64);
+    stream.write_all(&header)?;
+    stream.write_all(data)
+}
+
+// SERVER: reads one frame into `data` (reusing its storage), checking its header against the
+// handshake.
+pub fn read_frame(stream: &mut impl Read, spec: &BoundarySpec, data: &mut Vec<u8>) -> io::Result<()> {
+    let mut header = [0; 17];
+    stream.read_exact(&mut header)?;
+    let dtype = DType::from_code(header[0])?;
+    let quantization = Quantization { scale: LittleEndian::read_f32(&header[1..5]), zero_point: LittleEndian::read_i32(&header[5..9]) };
+    let len = LittleEndian::read_u64(&header[9..]) as usize;
+
+    let frame = BoundarySpec {
+        dtype,
+        shape: spec.shape.clone(),
+        quantization: spec.quantization.map(|_| quantization),
+    };
+    spec.check(&frame)?;
+    if len != spec.byte_len() {
+        return Err(Error::new(ErrorKind::InvalidData,
+                              format!("boundary frame of {} bytes, expected {}", len, spec.byte_len())));
+    }
+
+    data.resize(len, 0);
+    stream.read_exact(data)
+}
+
+// int8/uint8 boundary values back to real numbers, e.g. for checking a quantized split against
+// the float one.
+pub fn dequantize(spec: &BoundarySpec, data: &[u8]) -> Vec<f32> {
+    let q = spec.quantization.unwrap_or(Quantization { scale: 1.0, zero_point: 0 });
+    match spec.dtype {
+        DType::Float32 => data.chunks_exact(4).map(LittleEndian::read_f32).collect(),
+        DType::Int8 => data.iter().map(|v| q.scale * (*v as i8 as i32 - q.zero_point) as f32).collect(),
+        DType::Uint8 => data.iter().map(|v| q.scale * (*v as i32 - q.zero_point) as f32).collect(),
+    }
+}
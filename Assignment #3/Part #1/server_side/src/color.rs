@@ -0,0 +1,213 @@
+//! Camera frame -> packed RGB24. Covers the formats our capture paths produce: YUYV/UYVY (4:2:2
+//! from webcams), NV12/I420 (4:2:0; the kernel module ships I420 400x712 frames) and RGB24/BGR24.
+//!
+//! The YUV kernels work in 16.16 fixed point with coefficients precomputed per ColorSpace, clamp
+//! instead of wrapping, and process a row at a time over plain slices so the compiler can vectorize
+//! the inner loops. Odd sizes round the chroma planes up, like V4L2 and libyuv do.
+
+use std::io::{self, Error, ErrorKind};
+
+const SHIFT: u32 = 16;
+const HALF: i32 = 1 << (SHIFT - 1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    // Packed 4:2:2, Y0 U Y1 V.
+    Yuyv,
+    // Packed 4:2:2, U Y0 V Y1.
+    Uyvy,
+    // Y plane, then interleaved U V at half width and height.
+    Nv12,
+    // Y plane, then U and V planes at half width and height (YUV420 / YU12).
+    I420,
+    Rgb24,
+    Bgr24,
+}
+
+const fn fourcc(code: &[u8; 4]) -> u32 {
+    (code[0] as u32) | (code[1] as u32) << 8 | (code[2] as u32) << 16 | (code[3] as u32) << 24
+}
+
+impl PixelFormat {
+    // From a V4L2 pixelformat code.
+    pub fn from_fourcc(code: u32) -> Option<PixelFormat> {
+        match code {
+            c if c == fourcc(b"YUYV") => Some(PixelFormat::Yuyv),
+            c if c == fourcc(b"UYVY") => Some(PixelFormat::Uyvy),
+            c if c == fourcc(b"NV12") => Some(PixelFormat::Nv12),
+            c if c == fourcc(b"YU12") => Some(PixelFormat::I420),
+            c if c == fourcc(b"RGB3") => Some(PixelFormat::Rgb24),
+            c if c == fourcc(b"BGR3") => Some(PixelFormat::Bgr24),
+            _ => None,
+        }
+    }
+
+    // Bytes in a width x height frame.
+    pub fn frame_size(&self, width: usize, height: usize) -> usize {
+        let (chroma_w, chroma_h) = (width.div_ceil(2), height.div_ceil(2));
+        match self {
+            PixelFormat::Yuyv | PixelFormat::Uyvy => chroma_w * 4 * height,
+            PixelFormat::Nv12 | PixelFormat::I420 => width * height + 2 * chroma_w * chroma_h,
+            PixelFormat::Rgb24 | PixelFormat::Bgr24 => width * height * 3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Matrix {
+    // SD video and most webcams.
+    Bt601,
+    // HD video.
+    Bt709,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Range {
+    // Y in 16..=235, U/V in 16..=240 (studio swing).
+    Limited,
+    // Everything in 0..=255 (JPEG/MJPEG).
+    Full,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColorSpace {
+    pub matrix: Matrix,
+    pub range: Range,
+}
+
+impl Default for ColorSpace {
+    // What V4L2 assumes for YUV formats when the driver does not say.
+    fn default() -> ColorSpace {
+        ColorSpace { matrix: Matrix::Bt601, range: Range::Limited }
+    }
+}
+
+// Fixed point conversion factors, see ColorSpace::coefficients.
+#[derive(Clone, Copy, Debug)]
+struct Coefficients {
+    y_offset: i32,
+    y: i32,
+    v_r: i32,
+    u_g: i32,
+    v_g: i32,
+    u_b: i32,
+}
+
+impl ColorSpace {
+    fn coefficients(&self) -> Coefficients {
+        let (kr, kb) = match self.matrix {
+            Matrix::Bt601 => (0.299, 0.114),
+            Matrix::Bt709 => (0.2126, 0.0722),
+        };
+        let kg = 1.0 - kr - kb;
+        let (y_offset, y_scale, c_scale) = match self.range {
+            Range::Limited => (16, 255.0 / 219.0, 255.0 / 224.0),
+            Range::Full => (0, 1.0, 1.0),
+        };
+        let fixed = |x: f64| (x * (1 << SHIFT) as f64).round() as i32;
+
+        Coefficients {
+            y_offset,
+            y: fixed(y_scale),
+            v_r: fixed(2.0 * (1.0 - kr) * c_scale),
+            u_g: fixed(-2.0 * kb * (1.0 - kb) / kg * c_scale),
+            v_g: fixed(-2.0 * kr * (1.0 - kr) / kg * c_scale),
+            u_b: fixed(2.0 * (1.0 - kb) * c_scale),
+        }
+    }
+}
+
+#[inline(always)]
+fn clamp(x: i32) -> u8 {
+    (x >> SHIFT).clamp(0, 255) as u8
+}
+
+#[inline(always)]
+fn pixel(c: &Coefficients, y: u8, u: u8, v: u8, out: &mut [u8]) {
+    let y = (y as i32 - c.y_offset) * c.y + HALF;
+    let (u, v) = (u as i32 - 128, v as i32 - 128);
+    out[0] = clamp(y + c.v_r * v);
+    out[1] = clamp(y + c.u_g * u + c.v_g * v);
+    out[2] = clamp(y + c.u_b * u);
+}
+
+// One row of luma against one row of chroma; U/V samples sit `step` bytes apart (1 for planar,
+// 2 for interleaved).
+#[inline(always)]
+fn row_420(c: &Coefficients, y: &[u8], u: &[u8], v: &[u8], step: usize, out: &mut [u8]) {
+    for (i, (y, out)) in y.iter().zip(out.chunks_exact_mut(3)).enumerate() {
+        pixel(c, *y, u[i / 2 * step], v[i / 2 * step], out);
+    }
+}
+
+// One packed 4:2:2 row; `order` gives the byte offsets of (Y0, U, Y1, V) in a macropixel.
+#[inline(always)]
+fn row_422(c: &Coefficients, packed: &[u8], order: [usize; 4], out: &mut [u8]) {
+    for (m, out) in packed.chunks_exact(4).zip(out.chunks_mut(6)) {
+        let (y0, u, y1, v) = (m[order[0]], m[order[1]], m[order[2]], m[order[3]]);
+        pixel(c, y0, u, v, &mut out[..3]);
+        if out.len() == 6 {
+            pixel(c, y1, u, v, &mut out[3..]);
+        }
+    }
+}
+
+// Converts a width x height frame in `format` into `rgb` (width * height * 3 bytes, packed RGB).
+// Allocation free, so it can run into a reused buffer every frame.
+pub fn convert_into(src: &[u8], format: PixelFormat, width: usize, height: usize, space: ColorSpace,
+                    rgb: &mut [u8]) -> io::Result<()> {
+    let expected = format.frame_size(width, height);
+    if src.len() < expected {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("{:?} {}x{} needs {} bytes, got {}", format, width, height, expected, src.len())));
+    }
+    if rgb.len() != width * height * 3 {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("RGB buffer for {}x{} needs {} bytes, got {}", width, height, width * height * 3, rgb.len())));
+    }
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+
+    let c = space.coefficients();
+    let (chroma_w, chroma_h) = (width.div_ceil(2), height.div_ceil(2));
+    let rows = rgb.chunks_exact_mut(width * 3);
+
+    match format {
+        PixelFormat::Yuyv | PixelFormat::Uyvy => {
+            let order = if format == PixelFormat::Yuyv { [0, 1, 2, 3] } else { [1, 0, 3, 2] };
+            for (packed, out) in src.chunks_exact(chroma_w * 4).zip(rows) {
+                row_422(&c, packed, order, out);
+            }
+        }
+        PixelFormat::I420 => {
+            let (luma, chroma) = src.split_at(width * height);
+            let (u, v) = chroma.split_at(chroma_w * chroma_h);
+            for (r, (y, out)) in luma.chunks_exact(width).zip(rows).enumerate() {
+                let at = (r / 2) * chroma_w;
+                row_420(&c, y, &u[at..at + chroma_w], &v[at..at + chroma_w], 1, out);
+            }
+        }
+        PixelFormat::Nv12 => {
+            let (luma, chroma) = src.split_at(width * height);
+            for (r, (y, out)) in luma.chunks_exact(width).zip(rows).enumerate() {
+                let uv = &chroma[(r / 2) * chroma_w * 2..(r / 2 + 1) * chroma_w * 2];
+                row_420(&c, y, uv, &uv[1..], 2, out);
+            }
+        }
+        PixelFormat::Rgb24 => rgb.copy_from_slice(&src[..expected]),
+        PixelFormat::Bgr24 => {
+            for (bgr, out) in src.chunks_exact(3).zip(rgb.chunks_exact_mut(3)) {
+                out.copy_from_slice(&[bgr[2], bgr[1], bgr[0]]);
+            }
+        }
+    }
+    Ok(())
+}
+
+// convert_into() into a new buffer.
+pub fn convert(src: &[u8], format: PixelFormat, width: usize, height: usize, space: ColorSpace) -> io::Result<Vec<u8>> {
+    let mut rgb = vec![0; width * height * 3];
+    convert_into(src, format, width, height, space, &mut rgb)?;
+    Ok(rgb)
+}
@@ -0,0 +1,168 @@
+//! MoveNet's cropping algorithm: instead of feeding the whole frame, feed a square around the body
+//! found in the previous frame, so the person fills more of the model input. This follows the
+//! reference implementation in the TensorFlow MoveNet tutorial (init_crop_region /
+//! determine_crop_region). Whenever the torso is not confidently visible the crop falls back to the
+//! whole frame.
+//!
+//! The crop is a Transform like any other preprocessing, so keypoints are mapped back through it.
+
+use image::imageops::{overlay, Nearest};
+use image::{DynamicImage, RgbImage};
+
+use crate::preprocess::Transform;
+
+// Keypoints scoring above this count as visible when placing the crop.
+pub const MIN_CROP_KEYPOINT_SCORE: f32 = 0.2;
+
+// KEYPOINT_NAMES indices.
+const LEFT_SHOULDER: usize = 5;
+const RIGHT_SHOULDER: usize = 6;
+const LEFT_HIP: usize = 11;
+const RIGHT_HIP: usize = 12;
+const TORSO: [usize; 4] = [LEFT_SHOULDER, RIGHT_SHOULDER, LEFT_HIP, RIGHT_HIP];
+
+// Region of the frame, as fractions of its height/width. May extend past the frame edges; the
+// outside is padded with black.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CropRegion {
+    pub y_min: f32,
+    pub x_min: f32,
+    pub y_max: f32,
+    pub x_max: f32,
+}
+
+impl CropRegion {
+    // The whole frame, padded to a square around its center.
+    pub fn full(width: u32, height: u32) -> CropRegion {
+        let (w, h) = (width.max(1) as f32, height.max(1) as f32);
+        if w > h {
+            let box_height = w / h;
+            let y_min = (h / 2.0 - w / 2.0) / h;
+            CropRegion { y_min, x_min: 0.0, y_max: y_min + box_height, x_max: 1.0 }
+        } else {
+            let box_width = h / w;
+            let x_min = (w / 2.0 - h / 2.0) / w;
+            CropRegion { y_min: 0.0, x_min, y_max: 1.0, x_max: x_min + box_width }
+        }
+    }
+
+    // Square around the body in `keypoints` ([y, x, score, ...] in frame pixels), sized from the
+    // torso and the spread of all visible joints. Falls back to full() when the torso is not
+    // visible or the square would be bigger than the frame.
+    pub fn from_keypoints(keypoints: &[f32], width: u32, height: u32) -> CropRegion {
+        let (w, h) = (width.max(1) as f32, height.max(1) as f32);
+        if !torso_visible(keypoints) {
+            return CropRegion::full(width, height);
+        }
+        let y = |i: usize| keypoints[i * 3];
+        let x = |i: usize| keypoints[i * 3 + 1];
+
+        let center_y = (y(LEFT_HIP) + y(RIGHT_HIP)) / 2.0;
+        let center_x = (x(LEFT_HIP) + x(RIGHT_HIP)) / 2.0;
+
+        // Largest distance from the center, over the torso and over every visible joint.
+        let (mut torso_y, mut torso_x, mut body_y, mut body_x) = (0.0f32, 0.0f32, 0.0f32, 0.0f32);
+        for i in TORSO {
+            torso_y = torso_y.max((center_y - y(i)).abs());
+            torso_x = torso_x.max((center_x - x(i)).abs());
+        }
+        for (i, k) in keypoints.chunks_exact(3).enumerate() {
+            if k[2] > MIN_CROP_KEYPOINT_SCORE {
+                body_y = body_y.max((center_y - y(i)).abs());
+                body_x = body_x.max((center_x - x(i)).abs());
+            }
+        }
+
+        let half = (torso_x * 1.9).max(torso_y * 1.9).max(body_y * 1.2).max(body_x * 1.2);
+        // Do not reach further than the farthest frame edge.
+        let half = half.min(center_x.max(w - center_x).max(center_y).max(h - center_y));
+        if half > w.max(h) / 2.0 {
+            return CropRegion::full(width, height);
+        }
+
+        let (y0, x0) = (center_y - half, center_x - half);
+        CropRegion {
+            y_min: y0 / h,
+            x_min: x0 / w,
+            y_max: (y0 + 2.0 * half) / h,
+            x_max: (x0 + 2.0 * half) / w,
+        }
+    }
+
+    // (x, y, width, height) in frame pixels.
+    pub fn to_pixels(&self, width: u32, height: u32) -> (f32, f32, f32, f32) {
+        let (w, h) = (width as f32, height as f32);
+        (self.x_min * w, self.y_min * h, (self.x_max - self.x_min) * w, (self.y_max - self.y_min) * h)
+    }
+
+    // Stretches this region of a `source`-sized frame to `input`.
+    pub fn transform(&self, source: (u32, u32), input: (u32, u32)) -> Transform {
+        let (x, y, w, h) = self.to_pixels(source.0, source.1);
+        Transform {
+            source,
+            input,
+            scale: (input.0 as f32 / w.max(1.0), input.1 as f32 / h.max(1.0)),
+            pad: (0.0, 0.0),
+            crop: (x, y),
+        }
+    }
+
+    // Cuts this region out of `image` (padding what lies outside) and resizes it to `input`.
+    pub fn preprocess(&self, image: &DynamicImage, input: (u32, u32)) -> (RgbImage, Transform) {
+        let source = (image.width(), image.height());
+        let transform = self.transform(source, input);
+        let (x, y, w, h) = self.to_pixels(source.0, source.1);
+
+        let mut canvas = RgbImage::new((w.round() as u32).max(1), (h.round() as u32).max(1));
+        overlay(&mut canvas, &image.to_rgb8(), -x.round() as i64, -y.round() as i64);
+        let rgb = DynamicImage::ImageRgb8(canvas).resize_exact(input.0, input.1, Nearest).to_rgb8();
+        (rgb, transform)
+    }
+}
+
+fn torso_visible(keypoints: &[f32]) -> bool {
+    let visible = |i: usize| keypoints.get(i * 3 + 2).is_some_and(|s| *s > MIN_CROP_KEYPOINT_SCORE);
+    (visible(LEFT_HIP) || visible(RIGHT_HIP)) && (visible(LEFT_SHOULDER) || visible(RIGHT_SHOULDER))
+}
+
+// Carries the crop from one frame to the next.
+pub struct CropTracker {
+    source: (u32, u32),
+    region: CropRegion,
+}
+
+impl Default for CropTracker {
+    fn default() -> CropTracker {
+        CropTracker::new()
+    }
+}
+
+impl CropTracker {
+    pub fn new() -> CropTracker {
+        CropTracker { source: (0, 0), region: CropRegion::full(1, 1) }
+    }
+
+    // The crop for the next `width` x `height` frame. A frame of a new size starts from full().
+    pub fn region(&mut self, width: u32, height: u32) -> CropRegion {
+        if self.source != (width, height) {
+            self.source = (width, height);
+            self.region = CropRegion::full(width, height);
+        }
+        self.region
+    }
+
+    pub fn preprocess(&mut self, image: &DynamicImage, input: (u32, u32)) -> (RgbImage, Transform) {
+        self.region(image.width(), image.height()).preprocess(image, input)
+    }
+
+    // Places the next crop from the model output for the frame cropped with `transform`.
+    pub fn update(&mut self, keypoints: &[f32], transform: &Transform) {
+        let source = transform.keypoints_to_source(keypoints);
+        self.source = transform.source;
+        self.region = CropRegion::from_keypoints(&source, transform.source.0, transform.source.1);
+    }
+
+    pub fn reset(&mut self) {
+        self.source = (0, 0);
+    }
+}
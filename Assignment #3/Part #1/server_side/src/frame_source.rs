@@ -0,0 +1,328 @@
+use std::fs;
+use std::io::{self, Cursor, Error, ErrorKind};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use image::io::Reader;
+use image::{DynamicImage, RgbImage};
+
+use opencv::core::{Mat, Rect, Scalar, Vector, CV_8UC3};
+use opencv::imgcodecs::{imdecode, imread, IMREAD_COLOR};
+use opencv::imgproc::{cvt_color, rectangle, COLOR_BGR2RGB, FILLED, LINE_8};
+use opencv::prelude::*;
+use opencv::videoio::{VideoCapture, CAP_ANY, CAP_PROP_FRAME_HEIGHT, CAP_PROP_FRAME_WIDTH};
+
+use crate::pipeline::Source;
+use crate::v4l2::{Backend, Device, VideoConfig, VideoHandler, V4L2_PIX_FMT_MJPG};
+
+/**
+ * Where frames come from. The clients only need "give me the next frame", so the camera can be
+ * swapped for a recorded clip, a folder of stills or a generated pattern when no /dev/video0
+ * exists (CI machines, containers).
+ */
+
+// Default camera format, same as the one the clients always used.
+pub const V4L2_PIX_WIDTH: u32 = 800;
+pub const V4L2_PIX_HEIGHT: u32 = 448;
+
+pub enum FrameData {
+    // Compressed frame exactly as the camera handed it over.
+    Mjpeg(Vec<u8>),
+    // Decoded 8-bit BGR frame, as opencv produces.
+    Bgr(Mat),
+}
+
+pub struct Frame {
+    // Position of the frame within its source, starting at 0.
+    pub seq: u64,
+    // Wall-clock time at which the frame was captured/read.
+    pub timestamp: SystemTime,
+    pub data: FrameData,
+}
+
+impl Frame {
+    fn new(seq: u64, data: FrameData) -> Frame {
+        Frame { seq, timestamp: SystemTime::now(), data }
+    }
+
+    // Frame as an opencv BGR matrix, for drawing and display.
+    pub fn to_mat(&self) -> io::Result<Mat> {
+        match &self.data {
+            FrameData::Mjpeg(bytes) => {
+                let mat = imdecode(&Vector::<u8>::from_slice(bytes), IMREAD_COLOR).map_err(cv_error)?;
+                if mat.empty() {
+                    return Err(Error::new(ErrorKind::InvalidData, "could not decode MJPEG frame"));
+                }
+                Ok(mat)
+            }
+            FrameData::Bgr(mat) => Ok(mat.clone()),
+        }
+    }
+
+    // Frame as pipeline input, still compressed / without copying.
+    pub fn source(&self) -> io::Result<Source<'_>> {
+        match &self.data {
+            FrameData::Mjpeg(bytes) => Ok(Source::Jpeg(bytes)),
+            FrameData::Bgr(mat) => Ok(Source::Bgr(mat.data_bytes().map_err(cv_error)?, mat.cols() as u32, mat.rows() as u32)),
+        }
+    }
+
+    // Frame as an RGB image, for feeding the interpreter.
+    pub fn to_image(&self) -> io::Result<DynamicImage> {
+        match &self.data {
+            FrameData::Mjpeg(bytes) => Reader::new(Cursor::new(bytes))
+                .with_guessed_format()?
+                .decode()
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            FrameData::Bgr(mat) => {
+                let mut rgb = Mat::default();
+                cvt_color(mat, &mut rgb, COLOR_BGR2RGB, 0).map_err(cv_error)?;
+                let raw = rgb.data_bytes().map_err(cv_error)?.to_vec();
+                RgbImage::from_raw(rgb.cols() as u32, rgb.rows() as u32, raw)
+                    .map(DynamicImage::ImageRgb8)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "frame is not continuous RGB"))
+            }
+        }
+    }
+}
+
+pub trait FrameSource {
+    // Next frame, or Ok(None) once the source is exhausted.
+    fn next_frame(&mut self) -> io::Result<Option<Frame>>;
+
+    // (width, height) of the frames produced.
+    fn size(&self) -> (u32, u32);
+}
+
+fn cv_error(e: opencv::Error) -> Error {
+    Error::new(ErrorKind::Other, e.to_string())
+}
+
+/// Builds a source from a command line spec:
+///
+///   v4l2[:<device>]     camera, MJPG at 800x448 (default /dev/video0)
+///   file:<path>         video file, anything opencv's VideoCapture can open
+///   dir:<path>          directory of images, played in file name order
+///   pattern[:<W>x<H>]   generated test pattern (default 800x448)
+pub fn from_spec(spec: &str) -> io::Result<Box<dyn FrameSource>> {
+    let (kind, arg) = match spec.split_once(':') {
+        Some((kind, arg)) => (kind, Some(arg)),
+        None => (spec, None),
+    };
+
+    match (kind, arg) {
+        ("v4l2", arg) => {
+            let config = VideoConfig {
+                path: arg.unwrap_or("/dev/video0").to_string(),
+                width: Some(V4L2_PIX_WIDTH),
+                height: Some(V4L2_PIX_HEIGHT),
+                pixelformat: Some(V4L2_PIX_FMT_MJPG),
+            };
+            Ok(Box::new(V4l2Source::new(&config)?))
+        }
+        ("file", Some(path)) => Ok(Box::new(VideoFileSource::new(path)?)),
+        ("dir", Some(path)) => Ok(Box::new(ImageDirSource::new(path)?)),
+        ("pattern", None) => Ok(Box::new(PatternSource::new(V4L2_PIX_WIDTH, V4L2_PIX_HEIGHT))),
+        ("pattern", Some(size)) => {
+            let parsed = size.split_once('x')
+                .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)));
+            match parsed {
+                Some((w, h)) => Ok(Box::new(PatternSource::new(w, h))),
+                None => Err(Error::new(ErrorKind::InvalidInput, format!("bad pattern size: {}", size))),
+            }
+        }
+        _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown frame source: {}", spec))),
+    }
+}
+
+// CAMERA
+
+pub struct V4l2Source<B: Backend = Device> {
+    handler: VideoHandler<B>,
+    seq: u64,
+}
+
+impl V4l2Source {
+    pub fn new(config: &VideoConfig) -> io::Result<V4l2Source> {
+        V4l2Source::from_handler(VideoHandler::with_config(config)?)
+    }
+}
+
+impl<B: Backend> V4l2Source<B> {
+    pub fn from_handler(handler: VideoHandler<B>) -> io::Result<V4l2Source<B>> {
+        let (_, _, pixelformat) = handler.format();
+        if pixelformat != V4L2_PIX_FMT_MJPG {
+            return Err(Error::new(ErrorKind::Unsupported,
+                format!("capture device does not deliver MJPG (got 0x{:x})", pixelformat)));
+        }
+
+        Ok(V4l2Source { handler, seq: 0 })
+    }
+
+    pub fn handler(&mut self) -> &mut VideoHandler<B> {
+        &mut self.handler
+    }
+}
+
+impl<B: Backend> FrameSource for V4l2Source<B> {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        let bytes = self.handler.frame()?;
+        let frame = Frame::new(self.seq, FrameData::Mjpeg(bytes));
+        self.seq += 1;
+        Ok(Some(frame))
+    }
+
+    fn size(&self) -> (u32, u32) {
+        let (width, height, _) = self.handler.format();
+        (width, height)
+    }
+}
+
+// VIDEO FILE
+
+pub struct VideoFileSource {
+    capture: VideoCapture,
+    size: (u32, u32),
+    seq: u64,
+}
+
+impl VideoFileSource {
+    pub fn new(path: &str) -> io::Result<VideoFileSource> {
+        let capture = VideoCapture::from_file(path, CAP_ANY).map_err(cv_error)?;
+        if !capture.is_opened().map_err(cv_error)? {
+            return Err(Error::new(ErrorKind::NotFound, format!("cannot open video {}", path)));
+        }
+
+        let width = capture.get(CAP_PROP_FRAME_WIDTH).map_err(cv_error)? as u32;
+        let height = capture.get(CAP_PROP_FRAME_HEIGHT).map_err(cv_error)? as u32;
+
+        Ok(VideoFileSource { capture, size: (width, height), seq: 0 })
+    }
+}
+
+impl FrameSource for VideoFileSource {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        let mut mat = Mat::default();
+        if !self.capture.read(&mut mat).map_err(cv_error)? || mat.empty() {
+            return Ok(None);
+        }
+
+        let frame = Frame::new(self.seq, FrameData::Bgr(mat));
+        self.seq += 1;
+        Ok(Some(frame))
+    }
+
+    fn size(&self) -> (u32, u32) {
+        self.size
+    }
+}
+
+// DIRECTORY OF IMAGES
+
+pub struct ImageDirSource {
+    paths: Vec<PathBuf>,
+    size: (u32, u32),
+    seq: u64,
+}
+
+impl ImageDirSource {
+    pub fn new(dir: &str) -> io::Result<ImageDirSource> {
+        let mut paths = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let is_image = path.extension()
+                .and_then(|e| e.to_str())
+                .map_or(false, |e| ["jpg", "jpeg", "png", "bmp"].contains(&e.to_lowercase().as_str()));
+            if is_image {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        if paths.is_empty() {
+            return Err(Error::new(ErrorKind::NotFound, format!("no images in {}", dir)));
+        }
+
+        // Size is taken from the first image; later ones are not resized.
+        let first = read_image(&paths[0])?;
+        let size = (first.cols() as u32, first.rows() as u32);
+
+        Ok(ImageDirSource { paths, size, seq: 0 })
+    }
+}
+
+fn read_image(path: &PathBuf) -> io::Result<Mat> {
+    let mat = imread(&path.to_string_lossy(), IMREAD_COLOR).map_err(cv_error)?;
+    if mat.empty() {
+        return Err(Error::new(ErrorKind::InvalidData, format!("cannot read image {}", path.display())));
+    }
+    Ok(mat)
+}
+
+impl FrameSource for ImageDirSource {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        let path = match self.paths.get(self.seq as usize) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let frame = Frame::new(self.seq, FrameData::Bgr(read_image(path)?));
+        self.seq += 1;
+        Ok(Some(frame))
+    }
+
+    fn size(&self) -> (u32, u32) {
+        self.size
+    }
+}
+
+// SYNTHETIC PATTERN
+
+// Gray background with a coloured block sweeping across it, so consecutive frames differ.
+pub struct PatternSource {
+    width: u32,
+    height: u32,
+    seq: u64,
+    limit: Option<u64>,
+}
+
+impl PatternSource {
+    pub fn new(width: u32, height: u32) -> PatternSource {
+        PatternSource { width, height, seq: 0, limit: None }
+    }
+
+    // Stop after `limit` frames instead of running forever.
+    pub fn with_limit(mut self, limit: u64) -> PatternSource {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+impl FrameSource for PatternSource {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        if self.limit.map_or(false, |limit| self.seq >= limit) {
+            return Ok(None);
+        }
+
+        let (w, h) = (self.width as i32, self.height as i32);
+        let mut mat = Mat::new_rows_cols_with_default(h, w, CV_8UC3, Scalar::all(128.0))
+            .map_err(cv_error)?;
+
+        let side = (w.min(h) / 4).max(1);
+        let x = (self.seq as i32 * 8) % (w - side).max(1);
+        let y = (h - side) / 2;
+        let shade = (self.seq % 256) as f64;
+        rectangle(&mut mat,
+            Rect::new(x, y, side, side),
+            Scalar::new(shade, 255.0 - shade, 255.0, 0.0),
+            FILLED, LINE_8, 0).map_err(cv_error)?;
+
+        let frame = Frame::new(self.seq, FrameData::Bgr(mat));
+        self.seq += 1;
+        Ok(Some(frame))
+    }
+
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+}
@@ -0,0 +1,123 @@
+//! MJPEG frame decoders behind one trait, so the preprocessing pipeline does not care which one
+//! runs. The pure-Rust jpeg-decoder (what the image crate uses) is always there; building with
+//! `--features turbojpeg` adds libjpeg-turbo, which is usually the faster of the two, and
+//! becomes the default. Both decode at a reduced DCT scale when the model needs fewer pixels.
+//!
+//! benches/decode.rs compares them on recorded frames.
+
+use std::io::{self, Cursor, Error, ErrorKind};
+
+pub trait JpegDecoder {
+    fn name(&self) -> &'static str;
+
+    // (width, height) from the frame header.
+    fn size(&mut self, jpeg: &[u8]) -> io::Result<(u32, u32)>;
+
+    // Decodes to packed RGB in `rgb` at the smallest supported scale that is still at least
+    // `at_least` in both axes, and returns the decoded size.
+    fn decode(&mut self, jpeg: &[u8], at_least: (u32, u32), rgb: &mut Vec<u8>) -> io::Result<(u32, u32)>;
+}
+
+// turbojpeg when built with it, jpeg-decoder otherwise.
+pub fn default_decoder() -> Box<dyn JpegDecoder + Send> {
+    #[cfg(feature = "turbojpeg")]
+    if let Ok(decoder) = TurboJpeg::new() {
+        return Box::new(decoder);
+    }
+    Box::new(RustJpeg)
+}
+
+// Every decoder available in this build, default first.
+pub fn decoders() -> Vec<Box<dyn JpegDecoder + Send>> {
+    #[cfg_attr(not(feature = "turbojpeg"), allow(unused_mut))]
+    let mut decoders: Vec<Box<dyn JpegDecoder + Send>> = vec![Box::new(RustJpeg)];
+    #[cfg(feature = "turbojpeg")]
+    if let Ok(decoder) = TurboJpeg::new() {
+        decoders.insert(0, Box::new(decoder));
+    }
+    decoders
+}
+
+fn invalid(e: impl std::error::Error + Send + Sync + 'static) -> Error {
+    Error::new(ErrorKind::InvalidData, e)
+}
+
+// jpeg-decoder, scaling by 1/8, 1/4, 1/2 or 1.
+pub struct RustJpeg;
+
+impl JpegDecoder for RustJpeg {
+    fn name(&self) -> &'static str {
+        "jpeg-decoder"
+    }
+
+    fn size(&mut self, jpeg: &[u8]) -> io::Result<(u32, u32)> {
+        let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(jpeg));
+        decoder.read_info().map_err(invalid)?;
+        let info = decoder.info().ok_or_else(|| Error::new(ErrorKind::InvalidData, "JPEG without a frame header"))?;
+        Ok((info.width as u32, info.height as u32))
+    }
+
+    fn decode(&mut self, jpeg: &[u8], at_least: (u32, u32), rgb: &mut Vec<u8>) -> io::Result<(u32, u32)> {
+        let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(jpeg));
+        let clamp = |v: u32| v.clamp(1, u16::MAX as u32) as u16;
+        let (w, h) = decoder.scale(clamp(at_least.0), clamp(at_least.1)).map_err(invalid)?;
+        let pixels = decoder.decode().map_err(invalid)?;
+
+        // The decoder allocates its own output; keep it as the frame buffer.
+        match decoder.info().map(|info| info.pixel_format) {
+            Some(jpeg_decoder::PixelFormat::RGB24) => *rgb = pixels,
+            Some(jpeg_decoder::PixelFormat::L8) => {
+                rgb.clear();
+                rgb.extend(pixels.iter().flat_map(|l| [*l, *l, *l]));
+            }
+            format => return Err(Error::new(ErrorKind::InvalidData, format!("unsupported JPEG pixel format {:?}", format))),
+        }
+        Ok((w as u32, h as u32))
+    }
+}
+
+// libjpeg-turbo, scaling by 1/8, 1/4, 1/2 or 1, straight into the caller's buffer.
+#[cfg(feature = "turbojpeg")]
+pub struct TurboJpeg {
+    decompressor: turbojpeg::Decompressor,
+}
+
+#[cfg(feature = "turbojpeg")]
+impl TurboJpeg {
+    pub fn new() -> io::Result<TurboJpeg> {
+        Ok(TurboJpeg { decompressor: turbojpeg::Decompressor::new().map_err(invalid)? })
+    }
+}
+
+#[cfg(feature = "turbojpeg")]
+impl JpegDecoder for TurboJpeg {
+    fn name(&self) -> &'static str {
+        "turbojpeg"
+    }
+
+    fn size(&mut self, jpeg: &[u8]) -> io::Result<(u32, u32)> {
+        let header = self.decompressor.read_header(jpeg).map_err(invalid)?;
+        Ok((header.width as u32, header.height as u32))
+    }
+
+    fn decode(&mut self, jpeg: &[u8], at_least: (u32, u32), rgb: &mut Vec<u8>) -> io::Result<(u32, u32)> {
+        let header = self.decompressor.read_header(jpeg).map_err(invalid)?;
+        // Same rounding as libjpeg's TJSCALED().
+        let scaled = |size: usize, eighths: usize| (size * eighths + 7) / 8;
+        let eighths = [1, 2, 4, 8].into_iter()
+            .find(|n| scaled(header.width, *n) >= at_least.0 as usize && scaled(header.height, *n) >= at_least.1 as usize)
+            .unwrap_or(8);
+        self.decompressor.set_scaling_factor(turbojpeg::ScalingFactor::new(eighths, 8)).map_err(invalid)?;
+
+        let (w, h) = (scaled(header.width, eighths), scaled(header.height, eighths));
+        rgb.resize(w * h * 3, 0);
+        self.decompressor.decompress(jpeg, turbojpeg::Image {
+            pixels: rgb.as_mut_slice(),
+            width: w,
+            pitch: w * 3,
+            height: h,
+            format: turbojpeg::PixelFormat::RGB,
+        }).map_err(invalid)?;
+        Ok((w as u32, h as u32))
+    }
+}
@@ -1,198 +1,76 @@
-use std::{fs::File, os::unix::prelude::AsRawFd, ptr::null_mut};
 use std::net::TcpStream; // NETWORKING
-use std::io::{prelude::*, Cursor}; // READ/WRITE CAPABILITY
+use std::io::{prelude::*, BufWriter}; // READ/WRITE CAPABILITY
+use std::fs::File;
 use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
 use std::{thread, time};
 use std::sync::{Arc, Mutex};
 
-use libc::{mmap, PROT_READ, PROT_WRITE, MAP_SHARED, MAP_FAILED}; // MMAP FUNCTIONALITY
-use nix::{ioctl_read, ioctl_write_int, ioctl_readwrite}; // IOCTL SYSTEM CALLS
 use byteorder::{ByteOrder, LittleEndian};
 
 use tflitec::interpreter::{Interpreter};
-
-use image::imageops::Nearest;
-use image;
-use image::io::Reader;
-
-use opencv::core::CV_8UC3;
-use opencv::{
-    prelude::*,
-    highgui::*
-}; // CAMERA TOOLS
-
-mod utils; // UTILITY FUNCTIONS
+use tflitec::tensor::{DataType, Tensor as TfTensor};
+
+pub mod boundary; // PARTITION TENSOR HANDSHAKE AND FRAMING
+pub mod color; // CAMERA FRAME -> RGB24 CONVERSION
+pub mod crop; // MOVENET CROP REGION FROM THE PREVIOUS FRAME
+pub mod frame_source; // CAMERA, VIDEO FILE, IMAGE DIRECTORY AND PATTERN INPUTS
+pub mod jpeg; // MJPEG DECODERS (jpeg-decoder, turbojpeg WITH --features turbojpeg)
+pub mod output; // WINDOW, VIDEO FILE, IMAGE SEQUENCE AND HTTP OUTPUTS
+pub mod pipeline; // DECODE -> RESIZE -> QUANTIZE INTO THE INPUT TENSOR
+pub mod pose; // SINGLEPOSE / MULTIPOSE OUTPUT PARSING AND TRACKING
+pub mod preprocess; // MODEL INPUT RESIZING AND KEYPOINT MAPPING
+pub mod results; // JSON LINES / CSV KEYPOINT EXPORT
+pub mod smoothing; // TEMPORAL KEYPOINT FILTERS
+pub mod utils; // UTILITY FUNCTIONS
+pub mod v4l2; // V4L2 CAPTURE
+#[cfg(feature = "fake-v4l2")]
+pub mod v4l2_fake; // IN-PROCESS V4L2 DRIVER FOR TESTS (--features fake-v4l2)
+use boundary::{handshake, write_frame, BoundarySpec, DType, Quantization};
+use crop::CropTracker;
+use frame_source::FrameSource;
+use output::{FrameSink, stop_requested};
+use pipeline::{Pipeline, ResizeFilter, Tensor};
+use pose::{OutputMonitor, PersonTracker, MIN_PERSON_SCORE, MULTIPOSE_VALUES};
+use preprocess::{ResizeMode, Transform};
+use results::{PoseRecord, ResultsWriter};
+use smoothing::{PersonSmoothers, SmoothingConfig};
 use utils::*;
 
 // BUFFER SIZES
+//      the partition tensor sent to the server and the keypoints
+//      coming back are sized by the models (SinglePose : 51 floats,
+//      MultiPose : 6 x 56 floats, with a u64 length in front)
 
-const BUFFER1_SIZE: usize = 589824;
-const BUFFER2_SIZE: usize = 4;
-const BUFFER3_SIZE: usize = 204;
-const BUFFER4_SIZE: usize = 51;
+const LENGTH_SIZE: usize = 8;
+// LARGEST REPLY THE SERVER CAN SEND: A MULTIPOSE OUTPUT AS f32s
+const MAX_OUTPUT_SIZE: usize = MULTIPOSE_VALUES * 4;
 
 // STATIC VARIABLES
 
 static ANNOTATE: AtomicBool = AtomicBool::new(false);
+static CROP: AtomicBool = AtomicBool::new(false);
 static KEY: AtomicI32 = AtomicI32::new(97);
 static DELAY: AtomicU64 = AtomicU64::new(40);
+static FRAMES: AtomicU64 = AtomicU64::new(0);
+static RESULTS: Mutex<Option<ResultsWriter<BufWriter<File>>>> = Mutex::new(None);
+static STYLE: Mutex<Option<PoseStyle>> = Mutex::new(None);
+static RESIZE: Mutex<ResizeMode> = Mutex::new(ResizeMode::Stretch);
+static FILTER: Mutex<ResizeFilter> = Mutex::new(ResizeFilter::Area);
+static SMOOTHING: Mutex<Option<SmoothingConfig>> = Mutex::new(None);
+static DUMP: Mutex<Option<String>> = Mutex::new(None);
 
-// CAPABILITY CONSTANTS
-
-// #define VIDIOC_QUERYCAP          _IOR('V',  0, struct v4l2_capability)
-
-const VIDIOC_QUERYCAP_MAGIC: u8 = 'V' as u8;
-const VIDIOC_QUERYCAP_TYPE_MODE: u8 = 0;
-
-const V4L2_CAP_VIDIO_CAPTURE: u32 = 0x00000001;
-const V4L2_CAP_STREAMING: u32 = 0x04000000;
-
-// FORMAT CONSTANTS/MACROS
-
-// #define VIDIOC_G_FMT             _IOWR('V',  4, struct v4l2_format)
-// #define VIDIOC_S_FMT             _IOWR('V',  5, struct v4l2_format)
-
-const VIDIOC_G_FMT_MAGIC: u8 = 'V' as u8;
-const VIDIOC_G_FMT_TYPE_MODE: u8 = 4;
-const VIDIOC_S_FMT_MAGIC: u8 = 'V' as u8;
-const VIDIOC_S_FMT_TYPE_MODE: u8 = 5;
-
-// #define v4l2_fourcc(a, b, c, d) \
-//      ((__u32)(a) | ((__u32)(b) << 8) | ((__u32)(c) << 16) | ((__u32)(d) << 24))
-
-macro_rules! v4l2_fourcc {
-    ($a:expr, $b:expr, $c:expr, $d:expr, $typ:ty) => {
-        ($a as $typ) | ($b as $typ) << 8 | ($c as $typ) << 16 | ($d as $typ) << 24
-    }
-}
-
-const V4L2_PIX_FMT_MJPG: u32 = v4l2_fourcc!(b'M', b'J', b'P', b'G', u32);
-
-// CUSTOM FORMAT SIZING CONSTANTS
-
-const V4L2_PIX_WIDTH: u32 = 800;
-const V4L2_PIX_HEIGHT: u32 = 448;
-
-// FORMAT TO USE
-//      size : 800x448
-//      rate : 30.000 fps
-
-const V4L2_BUF_TYPE_VIDEO_CAPTURE: u32 = 1;
-
-// REQUESTBUFFERS CONSTANTS
+// REMOTE SERVER
+//      between two VMs     :   <ipv4> :8000 of remote server
+//      within the same VM  : 127.0.0.1:8000
 
-// #define VIDIOC_REQBUFS           _IOWR('V',  8, struct v4l2_requestbuffers)
+const REMOTE_ADDR: &str = "127.0.0.1:8000"; // 192.168.25.130
 
-const VIDIOC_REQBUFS_MAGIC: u8 = 'V' as u8;
-const VIDIOC_REQBUFS_TYPE_MODE: u8 = 8;
+// STREAM TIMEOUT
+//      bounds connecting, writing and reading so a hung server
+//      fails the frame instead of blocking the client (and ^C)
+//      for good
 
-const V4L2_MEMORY_MMAP: u32 = 1;
-
-// BUFFER CONSTANTS
-
-// #define VIDIOC_QUERYBUF          _IOWR('V',  9, struct v4l2_buffer)
-
-const VIDIOC_QUERYBUF_MAGIC: u8 = 'V' as u8;
-const VIDIOC_QUERYBUF_TYPE_MODE: u8 = 9;
-
-// QUEUE BUFFER CONSTANTS
-
-// #define VIDIOC_QBUF              _IOWR('V', 15, struct v4l2_buffer)
-
-const VIDIOC_QBUF_MAGIC: u8 = 'V' as u8;
-const VIDIOC_QBUF_TYPE_MODE: u8 = 15;
-
-// DEQUEUE BUFFER CONSTANTS
-
-// #define VIDIOC_DQBUF             _IOWR('V', 17, struct v4l2_buffer)
-
-const VIDIOC_DQBUF_MAGIC: u8 = 'V' as u8;
-const VIDIOC_DQBUF_TYPE_MODE: u8 = 17;
-
-// STREAM ON CONSTANTS
-
-// #define VIDIOC_STREAMON          _IOW('V', 18, int)
-
-const VIDIOC_STREAMON_MAGIC: u8 = 'V' as u8;
-const VIDIOC_STREAMON_TYPE_MODE: u8 = 18;
-
-// STREAM OFF CONSTANTS
-
-// #define VIDIOC_STREAMOFF         _IOW('V', 19, int)
-
-const VIDIOC_STREAMOFF_MAGIC: u8 = 'V' as u8;
-const VIDIOC_STREAMOFF_TYPE_MODE: u8 = 19;
-
-// IOCTL STRUCTS
-
-#[repr(C)]
-#[derive(Default, Debug)]
-pub struct v4l2_capability {
-    pub driver: [u8; 16],
-    pub card: [u8; 32],
-    pub bus_info: [u8; 32],
-    pub version: u32,
-    pub capabilities: u32,
-    pub device_caps: u32,
-    pub reserved: [u32; 3]
-}
-
-#[repr(C)]
-#[derive(Debug)]
-pub struct v4l2_format {
-    pub r#type: u32,
-    pub align1: u32,
-    pub width: u32,
-    pub height: u32,
-    pub pixelformat: u32,
-    pub others1: [u8; 208 - 5 * 4]
-}
-
-impl Default for v4l2_format {
-    fn default() -> v4l2_format {
-        v4l2_format {
-            r#type: 0,
-            align1: 0,
-            width: 0,
-            height: 0,
-            pixelformat: 0,
-            others1: [0; 208 - 5 * 4]
-        }
-    }
-}
-
-#[repr(C)]
-#[derive(Debug)]
-pub struct v4l2_requestbuffers {
-    pub count: u32,
-    pub r#type: u32,
-    pub memory: u32,
-    pub reserved: [u32; 2]
-}
-
-impl Default for v4l2_requestbuffers {
-    fn default() -> v4l2_requestbuffers {
-        v4l2_requestbuffers {
-            count: 0,
-            r#type: 0,
-            memory: 0,
-            reserved: [0, 0]
-        }
-    }
-}
-
-#[repr(C)]
-#[derive(Default)]
-pub struct v4l2_buffer {
-    pub index: u32,
-    pub r#type: u32,
-    pub others1: [u32; 13],
-    pub memory: u32,
-    pub offset: u32,
-    pub others2: u32,
-    pub length: u32,
-    pub others3: [u32; 3]
-}
+const STREAM_TIMEOUT: time::Duration = time::Duration::from_secs(10);
 
 // STRING FORMATTING CONSTANTS
 static OK: &'static str = "[OK]";
@@ -202,11 +80,11 @@ static OFFSET: usize = 35;
 // PRIVATE HELPER FUNCTIONS
 
 fn connect() -> TcpStream {
-	// POSSIBLE CODES
-	//      between two VMs     :   <ipv4> :8000 of remote server
-	//      within the same VM  : 127.0.0.1:8000
-
-    return TcpStream::connect("127.0.0.1:8000").expect("Connection [FAILED]"); // 192.168.25.130
+    let addr = REMOTE_ADDR.parse().expect("Remote address [FAILED]");
+    let stream = TcpStream::connect_timeout(&addr, STREAM_TIMEOUT).expect("Connection [FAILED]");
+    stream.set_read_timeout(Some(STREAM_TIMEOUT)).expect("Stream timeout [FAILED]");
+    stream.set_write_timeout(Some(STREAM_TIMEOUT)).expect("Stream timeout [FAILED]");
+    return stream;
 }
 
 // pfcode : print formatted code
@@ -216,158 +94,39 @@ fn pfcode(message: &str, code: &str) {
 
 // PUBLIC/PUBLISHED FUNCTIONS
 
-pub fn display(interpreter: Arc<Mutex<Interpreter>>) {
-	println!("SETTING UP CAMERA ...\n");
-
-	// OPEN DEVICE FILE /dev/video0 AND GET FILE DESCRIPTOR
-
-	let file = File::options().write(true).read(true).open("/dev/video0").unwrap();
-	let fd = file.as_raw_fd();
-
-	// GATHER INFORMATION ABOUT VIDEO FILE
-
-	let mut capability: v4l2_capability = Default::default();
-	ioctl_read!(vidioc_querycap, VIDIOC_QUERYCAP_MAGIC, VIDIOC_QUERYCAP_TYPE_MODE, v4l2_capability);
-
-	match unsafe { vidioc_querycap(fd, &mut capability as *mut v4l2_capability) } {
-		Ok(_) => {
-			pfcode("Get Information", OK);
-
-			if &capability.capabilities & V4L2_CAP_VIDIO_CAPTURE > 0 {
-				pfcode("Single-Planar Video Capture", OK);
-			} else {
-				pfcode("Single-Planar Video Capture", FAIL);
-			}
-
-			if &capability.capabilities & V4L2_CAP_STREAMING > 0 {
-				pfcode("Streaming", OK);
-			} else {
-				pfcode("Streaming", FAIL);
-			}
-		}, Err(e) => {
-			pfcode("Get Information", &(FAIL.to_owned() + &format!(": {:?}", e).to_owned()));
-		}
-	}
-
-	// GET/SET THE FORMAT OF THE DEVICE
-
-	let mut format: v4l2_format = Default::default();
-	format.r#type = V4L2_BUF_TYPE_VIDEO_CAPTURE;
-
-	ioctl_readwrite!(vidioc_g_fmt, VIDIOC_G_FMT_MAGIC, VIDIOC_G_FMT_TYPE_MODE, v4l2_format);
-	match unsafe { vidioc_g_fmt(fd, &mut format as *mut v4l2_format) } {
-		Ok(_) => {
-			pfcode("Get Formatting", OK);
-		} Err(e) => {
-			pfcode("Get Formatting", &(FAIL.to_owned() + &format!(": {:?}", e).to_owned()));
-		}
-	}
-
-	format.width = V4L2_PIX_WIDTH;
-	format.height = V4L2_PIX_HEIGHT;
-	format.pixelformat = V4L2_PIX_FMT_MJPG;
-
-	ioctl_readwrite!(vidioc_s_fmt, VIDIOC_S_FMT_MAGIC, VIDIOC_S_FMT_TYPE_MODE, v4l2_format);
-	match unsafe { vidioc_s_fmt(fd, &mut format as *mut v4l2_format) } {
-		Ok(_) => {
-			pfcode("Set Formatting", OK);
-		}, Err(e) => {
-			pfcode("Set Formatting", &(FAIL.to_owned() + &format!(": {:?}", e).to_owned()));
-		}
-	}
-
-	// PRINT INFORMATION TO ENSURE SET CORRECTLY
-	// println!("width: {}", format.width);
-	// println!("height: {}", format.height);
-	// println!("pixelformat: {}", format.pixelformat);
-
-	// REQUEST BUFFERS FROM THE DEVICE
-
-	let mut requestbuffers: v4l2_requestbuffers = Default::default();
-	requestbuffers.count = 1;
-	requestbuffers.r#type = V4L2_BUF_TYPE_VIDEO_CAPTURE;
-	requestbuffers.memory = V4L2_MEMORY_MMAP;
-
-	ioctl_readwrite!(vidioc_reqbufs, VIDIOC_REQBUFS_MAGIC, VIDIOC_REQBUFS_TYPE_MODE, v4l2_requestbuffers);
-	match unsafe { vidioc_reqbufs(fd, &mut requestbuffers as *mut v4l2_requestbuffers) } {
-		Ok(_) => {
-			pfcode("Request Buffers", OK);
-		}, Err(e) => {
-			pfcode("Request Buffers", &(FAIL.to_owned() + &format!(": {:?}", e).to_owned()));
-		}
-	}
-
-	// QUERYING BUFFERS FROM DEVICE
-
-	let mut buffer: v4l2_buffer = Default::default();
-	buffer.index = 0;
-	buffer.r#type = V4L2_BUF_TYPE_VIDEO_CAPTURE;
-	buffer.memory = V4L2_MEMORY_MMAP;
-
-	ioctl_readwrite!(vidioc_querybuf, VIDIOC_QUERYBUF_MAGIC, VIDIOC_QUERYBUF_TYPE_MODE, v4l2_buffer);
-	match unsafe { vidioc_querybuf(fd, &mut buffer as *mut v4l2_buffer) } {
-		Ok(_) => {
-			pfcode("Query Buffers", OK);
-		} Err(e) => {
-			pfcode("Query Buffers", &(FAIL.to_owned() + &format!(": {:?}", e).to_owned()));
-		}
-	}
-
-	// MEMORY MAPPING FOR THE BUFFERS
-
-	let data;
-	unsafe {
-		data = mmap(
-			/* addr */      null_mut(),
-			/* len */       buffer.length.try_into().unwrap(),
-			/* prot */      PROT_READ | PROT_WRITE,
-			/* Make the mapping *public* so that it is written into the file */
-			/* flags */     MAP_SHARED,
-			/* fd */        fd,
-			/* offset */    buffer.offset.try_into().unwrap()
-		);
-
-		if data == MAP_FAILED {
-			panic!("Can't create mmapped file!");
-		} else {
-			pfcode("MMAP Created", OK);
-		}
-	}
-
-	// QUEUE BUFFER
-
-	buffer.index = 0;
-	buffer.r#type = V4L2_BUF_TYPE_VIDEO_CAPTURE;
-	buffer.memory = V4L2_MEMORY_MMAP;
-	ioctl_readwrite!(vidioc_qbuf, VIDIOC_QBUF_MAGIC, VIDIOC_QBUF_TYPE_MODE, v4l2_buffer);
-	match unsafe { vidioc_qbuf(fd, &mut buffer as *mut v4l2_buffer) } {
-		Ok(_) => {
-			pfcode("Queueing Buffer", OK);
-		} Err(e) => {
-			pfcode("Queueing Buffer", &(FAIL.to_owned() + &format!(": {:?}", e).to_owned()));
-		}
-	}
-
-	// ACTIVATE STREAMING
-
-	buffer.r#type = V4L2_BUF_TYPE_VIDEO_CAPTURE;
-	ioctl_write_int!(vidioc_streamon, VIDIOC_STREAMON_MAGIC, VIDIOC_STREAMON_TYPE_MODE);
-	match unsafe { vidioc_streamon(fd, std::mem::transmute::<&u32, u64>(&buffer.r#type)) } {
-		Ok(_) => {
-			pfcode("Turning Stream On", OK);
-		} Err(e) => {
-			pfcode("Turning Stream On", &(FAIL.to_owned() + &format!(": {:?}", e).to_owned()));
-		}
-	}
+// BOUNDARY SPEC OF A TENSOR
+//      the local model's output on the client, the remote model's
+//      input on the server (see boundary.rs)
+
+pub fn boundary_spec(tensor: &TfTensor) -> std::io::Result<BoundarySpec> {
+	let dtype = match tensor.data_type() {
+		DataType::Float32 => DType::Float32,
+		DataType::Int8 => DType::Int8,
+		DataType::Uint8 => DType::Uint8,
+		other => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+			format!("unsupported boundary tensor type {:?}", other))),
+	};
+	let quantization = match dtype {
+		DType::Float32 => None,
+		_ => tensor.quantization_parameters().map(|q| Quantization { scale: q.scale, zero_point: q.zero_point }),
+	};
+	Ok(BoundarySpec {
+		dtype,
+		shape: tensor.shape().dimensions().iter().map(|d| *d as u32).collect(),
+		quantization,
+	})
+}
 
-	let length = buffer.length.to_string();
-	println!("");
-	pfcode("Buffer Length", &length);
+pub fn display(interpreter: Arc<Mutex<Interpreter>>, mut source: Box<dyn FrameSource>,
+               mut sink: Box<dyn FrameSink>) {
+	let (width, height) = source.size();
+	pfcode("Frame Source", &format!("{} {}x{}", OK, width, height));
 
 	// RUNNING LOOPS
-	//      one which runs for as long as you want
-	//      and capture frames (shoots the video)
-	//      and one that iterates over the buffers (optional)
+	//      runs until the source runs dry, the terminating
+	//      key is pressed, SIGINT/SIGTERM arrives (once
+	//      output::install_stop_handler has been called)
+	//      or the frame limit is reached
 
 	if ANNOTATE.load(Ordering::Relaxed) == true {
 		println!("\nDISPLAYING VIDEO FEED w/ ANNOTATION\n");
@@ -376,115 +135,192 @@ pub fn display(interpreter: Arc<Mutex<Interpreter>>) {
 	}
 
 	println!("PRESS [SET KEY] or ^C TO EXIT THE FEED\n");
+	let style = STYLE.lock().unwrap().clone().unwrap_or_default();
+	let resize = *RESIZE.lock().unwrap();
+	let mut smoothers = SMOOTHING.lock().unwrap().clone().map(PersonSmoothers::new);
+	let mut tracker = PersonTracker::new();
+	let (model_input, boundary) = {
+		let interpreter = interpreter.lock().expect("Unlocking interpreter [FAILED]");
+		let input = interpreter.input(0).expect("Input tensor [FAILED]");
+		let dimensions = input.shape().dimensions(); // [1, HEIGHT, WIDTH, 3]
+		let output = interpreter.output(0).expect("Output tensor [FAILED]");
+		((dimensions[2] as u32, dimensions[1] as u32),
+		 boundary_spec(&output).expect("Boundary tensor [FAILED]"))
+	};
+	pfcode("Partition Boundary", &format!("{} {}", OK, boundary));
+	let mut cropper = CROP.load(Ordering::Relaxed).then(CropTracker::new);
+	let mut pipeline = Pipeline::new(*FILTER.lock().unwrap());
+	let mut monitor = OutputMonitor::new(DUMP.lock().unwrap().clone().map(Into::into))
+		.expect("Output dump directory [FAILED]");
+	pfcode("JPEG Decoder", &format!("{} {}", OK, pipeline.decoder()));
+	let mut rate = FrameRate::new();
+	let mut count: u64 = 0;
 	loop {
 
-		// DEQUEUE BUFFER
+		// GET THE NEXT FRAME
 
-		ioctl_readwrite!(vidioc_dqbuf, VIDIOC_DQBUF_MAGIC, VIDIOC_DQBUF_TYPE_MODE, v4l2_buffer);
-		match unsafe { vidioc_dqbuf(fd, &mut buffer as *mut v4l2_buffer) } {
-			Ok(_) => { } Err(e) => {
-				panic!("Dequeueing Buffer [FAILED]: {}", e);
+		let frame = match source.next_frame() {
+			Ok(Some(frame)) => frame,
+			Ok(None) => {
+				pfcode("End Of Source", OK);
+				break;
 			}
-		}
-
-		// GET RAW DATA STORED IN MMAP
-
-		let raw: &[u8];
-		unsafe {
-			raw = std::slice::from_raw_parts(
-				data as *const u8,
-				(V4L2_PIX_WIDTH * V4L2_PIX_HEIGHT * 4) as usize
-			);
-		}
-
-		// CREATE EMPTY IMAGE MATRIX
+			Err(e) => {
+				pfcode("Reading Frame", &format!("{}: {}", FAIL, e));
+				break;
+			}
+		};
 
-		let mut image = Mat::zeros(
-			V4L2_PIX_HEIGHT as i32, V4L2_PIX_WIDTH as i32, CV_8UC3
-		).unwrap().to_mat().unwrap();
+		let mut image = frame.to_mat().expect("Decoding frame [FAILED]");
+		let mut overlay = Overlay::default();
 
 		if ANNOTATE.load(Ordering::Relaxed) == true {
-			// READ IN THE IMAGE, CONVERT TO RGB, AND GET RAW DATA
-			let figure = Reader::new(Cursor::new(&raw)).with_guessed_format().unwrap().decode().unwrap();
-			let figure = figure.resize_exact(192, 192, Nearest);
-			let figure = figure.to_rgb8();
-			let figure = figure.into_raw();
-
-			// RUN LOCAL COMPONENT OF MODEL
+			// DECODE, FIT TO THE MODEL INPUT, AND WRITE STRAIGHT INTO
+			// THE INPUT TENSOR (no per-frame images; see pipeline.rs)
+			//      (the transform maps the keypoints back onto the frame;
+			//      with cropping the input is the region around the
+			//      body in the previous frame)
 			let interpreter = interpreter.lock().expect("Unlocking interpreter [FAILED]");
-			interpreter.copy(&figure, 0).expect("Copying data into interpreter [FAILED]");
+			let mut input = interpreter.input(0).expect("Input tensor [FAILED]");
+			let tensor = match input.data_type() {
+				DataType::Float32 => Tensor::F32(input.data_mut::<f32>()),
+				_ => Tensor::U8(input.data_mut::<u8>()),
+			};
+			let input_transform = pipeline.run(
+				frame.source().expect("Reading frame [FAILED]"),
+				|(width, height)| match cropper.as_mut() {
+					Some(cropper) => cropper.region(width, height).transform((width, height), model_input),
+					None => Transform::new((width, height), model_input, resize),
+				},
+				tensor,
+			).expect("Preprocessing frame [FAILED]");
+			let transform = Transform::new(input_transform.source, model_input, resize);
 
+			// RUN LOCAL COMPONENT OF MODEL
 			interpreter.invoke().expect("Invoke [FAILED]"); // RUN THE INTERPRETER
 
 			// GET THE OUTPUT FROM THE INTERPRETER
 			let output_tensor = interpreter.output(0).expect(" [FAILED]");
-			let output_tensor = output_tensor.data::<f32>();
-
-			// CONVERT OUTPUT DATA TO BYTES
-			let mut buffer1: [u8; BUFFER1_SIZE] = [0; BUFFER1_SIZE];
-			let mut buffer2: [u8; BUFFER2_SIZE] = [0; BUFFER2_SIZE];
-
-			for i in 0..147456 {
-				LittleEndian::write_f32(&mut buffer2, output_tensor[i]);
-				for j in 0..4 {
-					buffer1[i * 4 + j] = buffer2[j];
-				}
-			}
 
 			// WRITE DATA TO THE STREAM
+			//      (HANDSHAKE FIRST SO THE SERVER CAN CHECK THE BOUNDARY
+			//      MATCHES ITS MODEL, THEN FLOATS AS LITTLE ENDIAN BYTES OR
+			//      int8/uint8 VALUES AS THEY ARE)
 			let mut stream = connect();
-			stream.write(&buffer1).expect("Write to stream [FAILED]");
+			handshake(&mut stream, &boundary).expect("Boundary handshake [FAILED]");
+			match boundary.dtype {
+				DType::Float32 => {
+					let output_tensor = output_tensor.data::<f32>();
+					let mut buffer1: Vec<u8> = vec![0; output_tensor.len() * 4];
+					LittleEndian::write_f32_into(output_tensor, &mut buffer1);
+					write_frame(&mut stream, &boundary, &buffer1)
+				}
+				DType::Int8 | DType::Uint8 => write_frame(&mut stream, &boundary, output_tensor.data::<u8>()),
+			}.expect("Write to stream [FAILED]");
 
 			// ADD DELAY WHEN CONNECTION IS FURTHER AWAY (e.g. BETWEEN TWO VMs)
 			thread::sleep(
 				time::Duration::from_millis(DELAY.load(Ordering::Relaxed))
 			); // rather arbitrary for now
 
-			// READ DATA FROM THE STREAM
-			let mut buffer3: [u8; BUFFER3_SIZE] = [0; BUFFER3_SIZE];
+			// READ DATA FROM THE STREAM (LENGTH, THEN THE KEYPOINTS)
+			//      (A LENGTH NO MODEL OUTPUT HAS IS NOT ALLOCATED, THE
+			//      FRAME'S POSES ARE DROPPED INSTEAD)
+			let mut length: [u8; LENGTH_SIZE] = [0; LENGTH_SIZE];
+			stream.read_exact(&mut length).expect("Reading from stream [FAILED]");
+			let length = LittleEndian::read_u64(&length);
+
+			// CONVERT BACK TO FLOATING POINT, CHECK AND SPLIT INTO PEOPLE
+			//      (ONE FOR SINGLEPOSE, UP TO SIX FOR MULTIPOSE; A BAD
+			//      LENGTH, NaN OR OUT OF RANGE COORDINATE DROPS THE FRAME'S
+			//      POSES AND IS COUNTED/DUMPED)
+			let mut persons = if length > MAX_OUTPUT_SIZE as u64 {
+				pfcode("Parsing Output", &format!("{}: reply of {} bytes, more than the {} of any model output",
+					FAIL, length, MAX_OUTPUT_SIZE));
+				vec![]
+			} else {
+				let mut buffer3: Vec<u8> = vec![0; length as usize];
+				stream.read_exact(&mut buffer3).expect("Reading from stream [FAILED]");
+				match monitor.check(&buffer3, MIN_PERSON_SCORE) {
+					Ok(pose) => pose.persons,
+					Err(e) => {
+						pfcode("Parsing Output", &format!("{}: {}", FAIL, e));
+						vec![]
+					}
+				}
+			};
+
+			// PLACE THE NEXT CROP AROUND THE MOST CONFIDENT PERSON, AND EXPRESS
+			// THE KEYPOINTS AS IF THE WHOLE FRAME HAD BEEN FED (STEADY COORDINATES)
+			if let Some(cropper) = cropper.as_mut() {
+				match persons.iter().max_by(|a, b| a.score.total_cmp(&b.score)) {
+					Some(person) => cropper.update(&person.keypoints, &input_transform),
+					None => cropper.reset(),
+				}
+				for person in persons.iter_mut() {
+					person.reproject(&input_transform, &transform);
+				}
+			}
 
-			stream.read(&mut buffer3).expect("Reading from stream [FAILED]");
+			// KEEP IDS ACROSS FRAMES, THEN SMOOTH OVER TIME (OPTIONAL)
+			tracker.assign(&mut persons);
+			if let Some(smoothers) = smoothers.as_mut() {
+				smoothers.apply(&mut persons, frame.timestamp);
+			}
 
-			// CONVERT BACK TO FLOATING POINT
-			let mut buffer4: [f32; BUFFER4_SIZE] = [0.0; BUFFER4_SIZE];
-			for i in 0..BUFFER4_SIZE {
-				buffer2 = buffer3[i * 4..i * 4 + 4].try_into().expect("Taking slice [FAILED]");
-				buffer4[i] = LittleEndian::read_f32(&mut buffer2)
+			// RECORD THE RESULT
+			//      (ONE RECORD PER PERSON, OR A NULL ONE WHEN NOBODY
+			//      WAS DETECTED SO EVERY FRAME IS IN THE RESULTS)
+			if let Some(writer) = RESULTS.lock().unwrap().as_mut() {
+				for person in persons.iter().map(Some).chain(persons.is_empty().then_some(None)) {
+					writer.write(&PoseRecord {
+						seq: frame.seq,
+						timestamp: frame.timestamp,
+						latency: frame.timestamp.elapsed().unwrap_or_default(),
+						person: person.and_then(|person| person.id),
+						keypoints: person.map(|person| &person.keypoints[..]),
+						transform: &transform,
+						producer: &format!("split:{}", REMOTE_ADDR),
+					}).expect("Writing results [FAILED]");
+				}
 			}
 
-			draw_keypoints(&mut image, &buffer4, 0.25);
+			for person in &persons {
+				draw_person(&mut image, person, &transform, &style).expect("Draw pose [FAILED]");
+			}
+			overlay.latency = Some(frame.timestamp.elapsed().unwrap_or_default());
+			overlay.mode = Some(InferenceMode::Server);
 		}
 
-		// DISPLAY RESULT
+		overlay.fps = Some(rate.tick());
+		draw_overlay(&mut image, &overlay, &style).expect("Draw overlay [FAILED]");
 
-		imshow("MoveNet", &image).expect("imshow [ERROR]");
+		// DISPLAY/RECORD RESULT
 
-		// QUEUE BUFFER
-
-		ioctl_readwrite!(vidioc_qbuf, VIDIOC_QBUF_MAGIC, VIDIOC_QBUF_TYPE_MODE, v4l2_buffer);
-		match unsafe { vidioc_qbuf(fd, &mut buffer as *mut v4l2_buffer) } {
-			Ok(_) => { } Err(e) => {
-				panic!("Queueing Buffer [FAILED]: {}", e);
+		let quit = match sink.present(&image) {
+			Ok(quit) => quit,
+			Err(e) => {
+				pfcode("Presenting Frame", &format!("{}: {}", FAIL, e));
+				break;
 			}
-		}
+		};
 
-		// CHECK FOR A KEYPRESS TO TERMINATE PROGRAM
+		// CHECK FOR A KEYPRESS, SIGNAL OR FRAME LIMIT TO TERMINATE PROGRAM
 
-		let key = wait_key(1).expect("Wait key [FAILED]");
-		if key == KEY.load(Ordering::Relaxed) {
+		count += 1;
+		let limit = FRAMES.load(Ordering::Relaxed);
+		if quit || stop_requested() || (limit > 0 && count >= limit) {
 			break;
 		}
 	}
+	pfcode("Frames Processed", &count.to_string());
+	pfcode("Model Outputs", &monitor.to_string());
+	if pipeline.frames > 0 {
+		pfcode("Preprocessing (average)", &pipeline.average().to_string());
+	}
 
-	// DEACTIVATE STREAMING
-
-	ioctl_write_int!(vidioc_streamoff, VIDIOC_STREAMOFF_MAGIC, VIDIOC_STREAMOFF_TYPE_MODE);
-	match unsafe { vidioc_streamoff(fd, std::mem::transmute::<&u32, u64>(&buffer.r#type)) } {
-		Ok(_) => {
-			pfcode("Turning Stream Off\n", OK);
-		} Err(e) => {
-			pfcode("Turning Stream Off\n", &(FAIL.to_owned() + &format!(": {:?}", e).to_owned()));
-		}
+	if let Some(writer) = RESULTS.lock().unwrap().as_mut() {
+		writer.flush().expect("Flushing results [FAILED]");
 	}
 }
 
@@ -495,6 +331,34 @@ pub fn annotate(annotate: bool) {
 	ANNOTATE.store(annotate, Ordering::Relaxed);
 }
 
+pub fn crop(crop: bool) {
+	println!("SETTING CROPPING TO {}\n", crop.to_string().to_uppercase());
+	CROP.store(crop, Ordering::Relaxed);
+}
+
+pub fn resize(mode: ResizeMode) {
+	println!("SETTING MODEL INPUT RESIZING TO {:?}\n", mode);
+	*RESIZE.lock().unwrap() = mode;
+}
+
+pub fn filter(filter: ResizeFilter) {
+	println!("SETTING MODEL INPUT RESIZE FILTER TO {:?}\n", filter);
+	*FILTER.lock().unwrap() = filter;
+}
+
+pub fn smoothing(config: Option<SmoothingConfig>) {
+	match &config {
+		Some(config) => println!("SETTING KEYPOINT SMOOTHING TO {:?}\n", config.filter),
+		None => println!("SETTING KEYPOINT SMOOTHING TO OFF\n"),
+	}
+	*SMOOTHING.lock().unwrap() = config;
+}
+
+pub fn style(style: PoseStyle) {
+	println!("SETTING POSE STYLE (labels: {}, skeleton: {})\n", style.labels, style.skeleton);
+	*STYLE.lock().unwrap() = Some(style);
+}
+
 pub fn terminate(key: i32) {
 	println!("SETTING TERMINATING KEY TO {}\n", key);
 	KEY.store(key, Ordering::Relaxed);
@@ -504,3 +368,29 @@ pub fn delay(delay: u64) {
 	println!("SETTING THE READ DELAY TO {} MILLISECONDS\n", delay);
 	DELAY.store(delay, Ordering::Relaxed);
 }
+
+// FRAME LIMIT FUNCTION
+//      0 runs until the source ends or the user stops it
+
+pub fn frames(frames: u64) {
+	println!("SETTING THE FRAME LIMIT TO {}\n", frames);
+	FRAMES.store(frames, Ordering::Relaxed);
+}
+
+// DUMP FUNCTION
+//      keeps every refused model output (raw bytes and the
+//      reason) in the directory, for debugging the server
+
+pub fn dump_invalid(dir: &str) {
+	println!("DUMPING INVALID MODEL OUTPUTS TO {}\n", dir);
+	*DUMP.lock().unwrap() = Some(dir.to_string());
+}
+
+// RESULTS FUNCTION
+//      .csv writes CSV, anything else JSON Lines
+
+pub fn results(path: &str) -> std::io::Result<()> {
+	println!("WRITING KEYPOINTS TO {}\n", path);
+	*RESULTS.lock().unwrap() = Some(ResultsWriter::create(path)?);
+	Ok(())
+}
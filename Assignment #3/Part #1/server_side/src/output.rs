@@ -0,0 +1,236 @@
+//! Where annotated frames go. The window needs a display; the other sinks let the clients run over
+//! SSH, in containers or on CI, where the run ends on SIGINT/SIGTERM or after a frame count instead
+//! of a key press.
+
+use std::ffi::c_int;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+use opencv::core::{Mat, Size, Vector};
+use opencv::highgui::{imshow, wait_key};
+use opencv::imgcodecs::{imencode, imwrite};
+use opencv::prelude::*;
+use opencv::videoio::VideoWriter;
+
+// Frame rate written into recorded videos.
+pub const RECORD_FPS: f64 = 30.0;
+
+pub trait FrameSink {
+    // Shows/stores one frame. Returns true when the user asked to stop (window key press).
+    fn present(&mut self, frame: &Mat) -> io::Result<bool>;
+
+    // Called while waiting for the next frame, so a window stays responsive. Returns true when
+    // the user asked to stop.
+    fn idle(&mut self) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+fn cv_error(e: opencv::Error) -> Error {
+    Error::other(e.to_string())
+}
+
+/// Builds a sink from a command line spec:
+///
+///   window              opencv window, quit with the terminating key (default)
+///   file:<path>         video file; .mp4 is written as MPEG-4, anything else as MJPEG
+///   images:<dir>        numbered JPEG files, frame_000000.jpg, ...
+///   http:<addr:port>    MJPEG stream, open http://<addr:port>/ in a browser
+pub fn from_spec(spec: &str) -> io::Result<Box<dyn FrameSink>> {
+    match spec.split_once(':') {
+        None if spec == "window" => Ok(Box::new(WindowSink)),
+        Some(("file", path)) => Ok(Box::new(VideoFileSink::new(path))),
+        Some(("images", dir)) => Ok(Box::new(ImageSequenceSink::new(dir)?)),
+        Some(("http", addr)) => Ok(Box::new(MjpegHttpSink::new(addr)?)),
+        _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown output: {}", spec))),
+    }
+}
+
+// STOPPING WITHOUT A KEYBOARD
+
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_stop_signal(_: c_int) {
+    STOP.store(true, Ordering::Relaxed);
+}
+
+// After this, the first SIGINT/SIGTERM ends the frame loop cleanly (so video files get finalized)
+// instead of killing the process. The handler is one-shot: a second ^C kills a client stuck in a
+// blocking call the loop never gets back from.
+pub fn install_stop_handler() -> nix::Result<()> {
+    let action = SigAction::new(SigHandler::Handler(on_stop_signal), SaFlags::SA_RESETHAND, SigSet::empty());
+    unsafe {
+        sigaction(Signal::SIGINT, &action)?;
+        sigaction(Signal::SIGTERM, &action)?;
+    }
+    Ok(())
+}
+
+pub fn stop_requested() -> bool {
+    STOP.load(Ordering::Relaxed)
+}
+
+// WINDOW
+
+pub struct WindowSink;
+
+impl FrameSink for WindowSink {
+    fn present(&mut self, frame: &Mat) -> io::Result<bool> {
+        imshow("MoveNet", frame).map_err(cv_error)?;
+
+        self.idle()
+    }
+
+    fn idle(&mut self) -> io::Result<bool> {
+        let key = wait_key(1).map_err(cv_error)?;
+        Ok(key == crate::KEY.load(Ordering::Relaxed))
+    }
+}
+
+// VIDEO FILE
+
+// The writer is opened on the first frame, once the frame size is known.
+pub struct VideoFileSink {
+    path: String,
+    writer: Option<VideoWriter>,
+}
+
+impl VideoFileSink {
+    pub fn new(path: &str) -> VideoFileSink {
+        VideoFileSink { path: path.to_string(), writer: None }
+    }
+}
+
+impl FrameSink for VideoFileSink {
+    fn present(&mut self, frame: &Mat) -> io::Result<bool> {
+        if self.writer.is_none() {
+            let fourcc = if self.path.ends_with(".mp4") {
+                VideoWriter::fourcc('m', 'p', '4', 'v')
+            } else {
+                VideoWriter::fourcc('M', 'J', 'P', 'G')
+            }.map_err(cv_error)?;
+            let size = Size::new(frame.cols(), frame.rows());
+
+            let writer = VideoWriter::new(&self.path, fourcc, RECORD_FPS, size, true).map_err(cv_error)?;
+            if !writer.is_opened().map_err(cv_error)? {
+                return Err(Error::other(format!("cannot write video {}", self.path)));
+            }
+            self.writer = Some(writer);
+        }
+
+        self.writer.as_mut().unwrap().write(frame).map_err(cv_error)?;
+        Ok(false)
+    }
+}
+
+// IMAGE SEQUENCE
+
+pub struct ImageSequenceSink {
+    dir: PathBuf,
+    seq: u64,
+}
+
+impl ImageSequenceSink {
+    pub fn new(dir: &str) -> io::Result<ImageSequenceSink> {
+        fs::create_dir_all(dir)?;
+        Ok(ImageSequenceSink { dir: PathBuf::from(dir), seq: 0 })
+    }
+}
+
+impl FrameSink for ImageSequenceSink {
+    fn present(&mut self, frame: &Mat) -> io::Result<bool> {
+        let path = self.dir.join(format!("frame_{:06}.jpg", self.seq));
+        if !imwrite(&path.to_string_lossy(), frame, &Vector::new()).map_err(cv_error)? {
+            return Err(Error::other(format!("cannot write {}", path.display())));
+        }
+        self.seq += 1;
+        Ok(false)
+    }
+}
+
+// MJPEG OVER HTTP
+
+const BOUNDARY: &str = "frame";
+
+// Serves multipart/x-mixed-replace to any number of viewers. Clients are accepted on a
+// background thread and each one's request read on a thread of its own, so a client that never
+// finishes its request holds up nobody else; the ones that stop reading are dropped.
+pub struct MjpegHttpSink {
+    clients: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl MjpegHttpSink {
+    pub fn new(addr: &str) -> io::Result<MjpegHttpSink> {
+        let listener = TcpListener::bind(addr)?;
+        let clients = Arc::new(Mutex::new(vec![]));
+
+        let accepted = Arc::clone(&clients);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let accepted = Arc::clone(&accepted);
+                thread::spawn(move || {
+                    if let Ok(stream) = start_stream(stream) {
+                        accepted.lock().unwrap().push(stream);
+                    }
+                });
+            }
+        });
+
+        Ok(MjpegHttpSink { clients })
+    }
+
+    pub fn clients(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+}
+
+// Reads the request headers (whatever the path) and answers with the multipart header. Gives up
+// on a client that goes quiet for a while before the end of its request.
+fn start_stream(mut stream: TcpStream) -> io::Result<TcpStream> {
+    stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+
+    write!(stream, "HTTP/1.0 200 OK\r\n\
+                    Cache-Control: no-cache\r\n\
+                    Content-Type: multipart/x-mixed-replace; boundary={}\r\n\r\n", BOUNDARY)?;
+    Ok(stream)
+}
+
+impl FrameSink for MjpegHttpSink {
+    fn present(&mut self, frame: &Mat) -> io::Result<bool> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.is_empty() {
+            return Ok(false);
+        }
+
+        let mut jpeg = Vector::<u8>::new();
+        imencode(".jpg", frame, &mut jpeg, &Vector::new()).map_err(cv_error)?;
+        let jpeg = jpeg.to_vec();
+
+        clients.retain_mut(|client| {
+            write!(client, "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                   BOUNDARY, jpeg.len())
+                .and_then(|_| client.write_all(&jpeg))
+                .and_then(|_| client.write_all(b"\r\n"))
+                .is_ok()
+        });
+        Ok(false)
+    }
+}
@@ -0,0 +1,310 @@
+use std::fmt;
+use std::io::{self, Error, ErrorKind};
+use std::time::{Duration, Instant};
+
+use crate::jpeg::{default_decoder, JpegDecoder};
+use crate::preprocess::Transform;
+
+/**
+ * Frame -> model input tensor without the per-frame image allocations of preprocess():
+ *
+ *   decode    MJPEG straight to (about) the size the model needs, using the JPEG decoder's DCT
+ *             scaling (1/2, 1/4, 1/8), into a reused buffer (see jpeg.rs for the decoders)
+ *   resize    through the frame's Transform with a nearest/bilinear/area filter; sampling tables
+ *             are rebuilt only when the geometry changes and reuse their storage
+ *   quantize  into the interpreter's input tensor memory (u8 as is, f32 as 0..255)
+ *
+ * Each stage is timed. Only jpeg-decoder hands back a fresh Vec per frame (it is kept as the next
+ * frame's buffer); turbojpeg and everything else write into storage owned by the Pipeline.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResizeFilter {
+    // One source pixel per input pixel; cheapest, aliases when shrinking.
+    Nearest,
+    // Linear between the 2x2 nearest source pixels.
+    Bilinear,
+    // Mean over the source pixels an input pixel covers; best when shrinking.
+    Area,
+}
+
+impl ResizeFilter {
+    pub fn from_name(name: &str) -> io::Result<ResizeFilter> {
+        match name {
+            "nearest" => Ok(ResizeFilter::Nearest),
+            "bilinear" => Ok(ResizeFilter::Bilinear),
+            "area" => Ok(ResizeFilter::Area),
+            _ => Err(Error::new(ErrorKind::InvalidInput,
+                                format!("unknown resize filter '{}' (nearest, bilinear, area)", name))),
+        }
+    }
+}
+
+// Frame pixels as they arrive.
+pub enum Source<'a> {
+    Jpeg(&'a [u8]),
+    // Packed 8-bit RGB/BGR, width x height.
+    Rgb(&'a [u8], u32, u32),
+    Bgr(&'a [u8], u32, u32),
+}
+
+// Input tensor memory to write into.
+pub enum Tensor<'a> {
+    U8(&'a mut [u8]),
+    F32(&'a mut [f32]),
+}
+
+impl Tensor<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Tensor::U8(data) => data.len(),
+            Tensor::F32(data) => data.len(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StageTimes {
+    pub decode: Duration,
+    pub resize: Duration,
+    pub quantize: Duration,
+}
+
+impl StageTimes {
+    pub fn total(&self) -> Duration {
+        self.decode + self.resize + self.quantize
+    }
+}
+
+impl fmt::Display for StageTimes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        write!(f, "decode {:.2}ms resize {:.2}ms quantize {:.2}ms (total {:.2}ms)",
+               ms(self.decode), ms(self.resize), ms(self.quantize), ms(self.total()))
+    }
+}
+
+// Per output coordinate along one axis: taps[offsets[i]..offsets[i + 1]] as (source index, weight).
+// No taps means the output pixel lies outside the frame (letterbox padding) and stays black.
+#[derive(Default)]
+struct Axis {
+    offsets: Vec<usize>,
+    taps: Vec<(usize, f32)>,
+}
+
+impl Axis {
+    // `center(i)` is output pixel i's center in decoded pixels, `footprint` how many decoded pixels
+    // one output pixel spans.
+    fn build(&mut self, filter: ResizeFilter, outputs: u32, sources: u32, footprint: f32,
+             center: impl Fn(u32) -> f32) {
+        self.offsets.clear();
+        self.taps.clear();
+        let last = sources as i64 - 1;
+
+        for i in 0..outputs {
+            self.offsets.push(self.taps.len());
+            let c = center(i);
+            if sources == 0 || c < 0.0 || c >= sources as f32 {
+                continue;
+            }
+            match filter {
+                ResizeFilter::Nearest => self.taps.push((c as usize, 1.0)),
+                ResizeFilter::Area if footprint > 1.0 => {
+                    let (lo, hi) = (c - footprint / 2.0, c + footprint / 2.0);
+                    for s in (lo.floor().max(0.0) as i64)..=(hi.ceil() as i64).min(last) {
+                        let overlap = (hi.min(s as f32 + 1.0) - lo.max(s as f32)).max(0.0);
+                        if overlap > 0.0 {
+                            self.taps.push((s as usize, overlap / footprint));
+                        }
+                    }
+                }
+                // Bilinear, and Area when enlarging.
+                _ => {
+                    let p = c - 0.5;
+                    let s = p.floor();
+                    let t = p - s;
+                    let clamp = |s: i64| s.clamp(0, last) as usize;
+                    self.taps.push((clamp(s as i64), 1.0 - t));
+                    self.taps.push((clamp(s as i64 + 1), t));
+                }
+            }
+        }
+        self.offsets.push(self.taps.len());
+    }
+
+    fn taps(&self, i: usize) -> &[(usize, f32)] {
+        &self.taps[self.offsets[i]..self.offsets[i + 1]]
+    }
+}
+
+pub struct Pipeline {
+    filter: ResizeFilter,
+    decoder: Box<dyn JpegDecoder + Send>,
+    // Decoded RGB frame and its size.
+    frame: Vec<u8>,
+    frame_size: (u32, u32),
+    // Resized RGB, before quantizing to a non-u8 tensor.
+    resized: Vec<u8>,
+    // What the sampling tables were built for.
+    plan: Option<((u32, u32), Transform)>,
+    rows: Axis,
+    cols: Axis,
+    pub last: StageTimes,
+    pub total: StageTimes,
+    pub frames: u64,
+}
+
+impl Pipeline {
+    pub fn new(filter: ResizeFilter) -> Pipeline {
+        Pipeline::with_decoder(filter, default_decoder())
+    }
+
+    pub fn with_decoder(filter: ResizeFilter, decoder: Box<dyn JpegDecoder + Send>) -> Pipeline {
+        Pipeline {
+            filter,
+            decoder,
+            frame: vec![],
+            frame_size: (0, 0),
+            resized: vec![],
+            plan: None,
+            rows: Axis::default(),
+            cols: Axis::default(),
+            last: StageTimes::default(),
+            total: StageTimes::default(),
+            frames: 0,
+        }
+    }
+
+    pub fn decoder(&self) -> &'static str {
+        self.decoder.name()
+    }
+
+    // (width, height) of the last decoded frame; below the frame size when DCT scaling kicked in.
+    pub fn decoded_size(&self) -> (u32, u32) {
+        self.frame_size
+    }
+
+    // Mean stage times over all frames so far.
+    pub fn average(&self) -> StageTimes {
+        let n = self.frames.max(1) as u32;
+        StageTimes { decode: self.total.decode / n, resize: self.total.resize / n, quantize: self.total.quantize / n }
+    }
+
+    // Runs one frame into `tensor`. `place` gets the frame's full (width, height) and returns how it
+    // maps onto the model input (a ResizeMode's or a crop's Transform), which is handed back.
+    pub fn run(&mut self, source: Source, place: impl FnOnce((u32, u32)) -> Transform,
+               tensor: Tensor) -> io::Result<Transform> {
+        let start = Instant::now();
+        let transform = self.decode(source, place)?;
+        let decoded = Instant::now();
+
+        let (w, h) = transform.input;
+        let len = (w * h * 3) as usize;
+        if tensor.len() != len {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("input tensor holds {} values, {}x{} RGB needs {}", tensor.len(), w, h, len)));
+        }
+        self.plan(&transform);
+
+        let quantize;
+        match tensor {
+            Tensor::U8(data) => {
+                self.resize(data);
+                quantize = Instant::now();
+            }
+            Tensor::F32(data) => {
+                let mut resized = std::mem::take(&mut self.resized);
+                resized.resize(len, 0);
+                self.resize(&mut resized);
+                quantize = Instant::now();
+                for (out, v) in data.iter_mut().zip(&resized) {
+                    *out = *v as f32;
+                }
+                self.resized = resized;
+            }
+        }
+
+        self.last = StageTimes {
+            decode: decoded - start,
+            resize: quantize - decoded,
+            quantize: quantize.elapsed(),
+        };
+        self.total.decode += self.last.decode;
+        self.total.resize += self.last.resize;
+        self.total.quantize += self.last.quantize;
+        self.frames += 1;
+        Ok(transform)
+    }
+
+    // Fills self.frame with RGB, as small as the transform allows.
+    fn decode(&mut self, source: Source, place: impl FnOnce((u32, u32)) -> Transform) -> io::Result<Transform> {
+        match source {
+            Source::Jpeg(bytes) => {
+                let size = self.decoder.size(bytes)?;
+                let transform = place(size);
+
+                // Smallest DCT scale that still gives at least one frame pixel per input pixel.
+                let scale = transform.scale.0.max(transform.scale.1);
+                let wanted = |size: u32| ((size as f32 * scale).ceil() as u32).clamp(1, size.max(1));
+                self.frame_size = self.decoder.decode(bytes, (wanted(size.0), wanted(size.1)), &mut self.frame)?;
+                Ok(transform)
+            }
+            Source::Rgb(pixels, w, h) | Source::Bgr(pixels, w, h) => {
+                if pixels.len() != (w * h * 3) as usize {
+                    return Err(Error::new(ErrorKind::InvalidInput,
+                                          format!("{}x{} frame needs {} bytes, got {}", w, h, w * h * 3, pixels.len())));
+                }
+                let bgr = matches!(source, Source::Bgr(..));
+                self.frame.clear();
+                if bgr {
+                    self.frame.extend(pixels.chunks_exact(3).flat_map(|p| [p[2], p[1], p[0]]));
+                } else {
+                    self.frame.extend_from_slice(pixels);
+                }
+                self.frame_size = (w, h);
+                Ok(place((w, h)))
+            }
+        }
+    }
+
+    // Sampling tables for the decoded frame through `transform`, kept while neither changes.
+    fn plan(&mut self, transform: &Transform) {
+        if self.plan == Some((self.frame_size, *transform)) {
+            return;
+        }
+        self.plan = Some((self.frame_size, *transform));
+
+        // Decoded pixels per frame pixel (below 1 after DCT scaling).
+        let ratio = (self.frame_size.0 as f32 / transform.source.0.max(1) as f32,
+                     self.frame_size.1 as f32 / transform.source.1.max(1) as f32);
+        let t = *transform;
+        self.cols.build(self.filter, t.input.0, self.frame_size.0, ratio.0 / t.scale.0,
+                        |u| ((u as f32 + 0.5 - t.pad.0) / t.scale.0 + t.crop.0) * ratio.0);
+        self.rows.build(self.filter, t.input.1, self.frame_size.1, ratio.1 / t.scale.1,
+                        |v| ((v as f32 + 0.5 - t.pad.1) / t.scale.1 + t.crop.1) * ratio.1);
+    }
+
+    fn resize(&self, out: &mut [u8]) {
+        let stride = self.frame_size.0 as usize * 3;
+        let width = self.cols.offsets.len() - 1;
+
+        for (v, row) in out.chunks_exact_mut(width * 3).enumerate() {
+            let row_taps = self.rows.taps(v);
+            for (u, pixel) in row.chunks_exact_mut(3).enumerate() {
+                let mut sum = [0.0f32; 3];
+                for &(y, wy) in row_taps {
+                    let line = &self.frame[y * stride..];
+                    for &(x, wx) in self.cols.taps(u) {
+                        let w = wy * wx;
+                        sum[0] += w * line[x * 3] as f32;
+                        sum[1] += w * line[x * 3 + 1] as f32;
+                        sum[2] += w * line[x * 3 + 2] as f32;
+                    }
+                }
+                for c in 0..3 {
+                    pixel[c] = (sum[c] + 0.5).clamp(0.0, 255.0) as u8;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Model output -> people. MoveNet SinglePose gives 17 keypoints ([1, 1, 17, 3], 51 values);
+//! MultiPose gives up to 6 people with a bounding box each ([1, 6, 56]: 17 keypoints then
+//! [y_min, x_min, y_max, x_max, score]). Both parse into a Vec<Person>, so the rest of the clients
+//! do not care which model the server runs; PersonTracker keeps IDs stable across frames.
+//!
+//! Output comes back over the network or from the kernel module, so it is validated before anyone
+//! draws it: whole f32s, a known length, finite values and coordinates in [0, 1]. OutputMonitor
+//! counts what gets refused and can keep the raw bytes for a closer look.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::path::PathBuf;
+
+use crate::preprocess::Transform;
+use crate::results::KEYPOINT_NAMES;
+
+pub const KEYPOINT_VALUES: usize = 17 * 3;
+pub const SINGLEPOSE_VALUES: usize = KEYPOINT_VALUES;
+pub const MULTIPOSE_PERSONS: usize = 6;
+pub const MULTIPOSE_VALUES_PER_PERSON: usize = KEYPOINT_VALUES + 5;
+pub const MULTIPOSE_VALUES: usize = MULTIPOSE_PERSONS * MULTIPOSE_VALUES_PER_PERSON;
+
+// MultiPose detections scoring below this are dropped.
+pub const MIN_PERSON_SCORE: f32 = 0.2;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Person {
+    // Assigned by PersonTracker; None until tracked.
+    pub id: Option<u32>,
+    // [y, x, score] per keypoint in KEYPOINT_NAMES order, in model coordinates.
+    pub keypoints: Vec<f32>,
+    // [y_min, x_min, y_max, x_max] in model coordinates, from the model (MultiPose) or the
+    // keypoints (SinglePose).
+    pub bbox: [f32; 4],
+    pub score: f32,
+}
+
+impl Person {
+    // A SinglePose result: boxed around its confident keypoints, scored by their mean score.
+    pub fn from_keypoints(keypoints: &[f32], threshold: f32) -> Person {
+        let keypoints = keypoints[..KEYPOINT_VALUES].to_vec();
+        let score = keypoints.chunks_exact(3).map(|k| k[2]).sum::<f32>() / KEYPOINT_NAMES.len() as f32;
+        Person { id: None, bbox: keypoint_bbox(&keypoints, threshold), keypoints, score }
+    }
+
+    // Moves the keypoints and box from `from`'s model coordinates to `to`'s.
+    pub fn reproject(&mut self, from: &Transform, to: &Transform) {
+        self.keypoints = from.reproject(&self.keypoints, to);
+        let corners = from.reproject(&[self.bbox[0], self.bbox[1], 0.0, self.bbox[2], self.bbox[3], 0.0], to);
+        self.bbox = [corners[0], corners[1], corners[3], corners[4]];
+    }
+}
+
+// Box around keypoints scoring above `threshold` (all of them if none does).
+pub fn keypoint_bbox(keypoints: &[f32], threshold: f32) -> [f32; 4] {
+    let confident: Vec<&[f32]> = keypoints.chunks_exact(3).filter(|k| k[2] > threshold).collect();
+    let points = if confident.is_empty() { keypoints.chunks_exact(3).collect() } else { confident };
+
+    let mut bbox = [f32::MAX, f32::MAX, f32::MIN, f32::MIN];
+    for k in points {
+        bbox = [bbox[0].min(k[0]), bbox[1].min(k[1]), bbox[2].max(k[0]), bbox[3].max(k[1])];
+    }
+    if bbox[0] > bbox[2] { [0.0; 4] } else { bbox }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoseModel {
+    SinglePose,
+    MultiPose,
+}
+
+// A validated model output.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pose {
+    pub model: PoseModel,
+    // Above the minimum score only (MultiPose); SinglePose always has its one person.
+    pub persons: Vec<Person>,
+}
+
+// Why a model output was refused.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputError {
+    // Byte count that is not a whole number of f32s.
+    Bytes(usize),
+    // Neither 51 (SinglePose) nor a multiple of 56 (MultiPose) values.
+    Length(usize),
+    // NaN or infinity at this value index.
+    NotFinite { index: usize, value: f32 },
+    // Keypoint or box coordinate outside [0, 1] at this value index.
+    OutOfRange { index: usize, value: f32 },
+}
+
+impl OutputError {
+    // Short name to count errors by.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            OutputError::Bytes(_) => "bytes",
+            OutputError::Length(_) => "length",
+            OutputError::NotFinite { .. } => "not finite",
+            OutputError::OutOfRange { .. } => "out of range",
+        }
+    }
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputError::Bytes(n) => write!(f, "model output of {} bytes is not a whole number of f32s", n),
+            OutputError::Length(n) => write!(f, "unexpected model output of {} values", n),
+            OutputError::NotFinite { index, value } => write!(f, "model output value {} is {}", index, value),
+            OutputError::OutOfRange { index, value } =>
+                write!(f, "model output coordinate {} is {}, outside [0, 1]", index, value),
+        }
+    }
+}
+
+impl std::error::Error for OutputError {}
+
+impl From<OutputError> for io::Error {
+    fn from(e: OutputError) -> io::Error {
+        Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+// Little endian f32s, as the servers and the kernel module send them.
+pub fn decode_output(bytes: &[u8]) -> Result<Vec<f32>, OutputError> {
+    if !bytes.len().is_multiple_of(4) {
+        return Err(OutputError::Bytes(bytes.len()));
+    }
+    Ok(bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect())
+}
+
+// Checks raw output before parsing: a known length, every value finite, and keypoint (y, x) and
+// MultiPose box coordinates within [0, 1]. MultiPose always sends six rows and fills the ones
+// without a person with whatever the model left there, so only rows scoring at least `min_score`
+// (the ones parse_pose() keeps) are checked.
+pub fn validate_output(values: &[f32], min_score: f32) -> Result<PoseModel, OutputError> {
+    let (model, row) = match values.len() {
+        SINGLEPOSE_VALUES => (PoseModel::SinglePose, SINGLEPOSE_VALUES),
+        n if n > 0 && n.is_multiple_of(MULTIPOSE_VALUES_PER_PERSON) => (PoseModel::MultiPose, MULTIPOSE_VALUES_PER_PERSON),
+        n => return Err(OutputError::Length(n)),
+    };
+
+    for (r, values) in values.chunks_exact(row).enumerate() {
+        let detected = model == PoseModel::SinglePose || values[KEYPOINT_VALUES + 4] >= min_score;
+        if !detected {
+            continue;
+        }
+        for (i, value) in values.iter().copied().enumerate() {
+            let index = r * row + i;
+            if !value.is_finite() {
+                return Err(OutputError::NotFinite { index, value });
+            }
+            // Every value but the keypoint scores and the person score is a coordinate.
+            let coordinate = if i < KEYPOINT_VALUES { i % 3 != 2 } else { i < KEYPOINT_VALUES + 4 };
+            if coordinate && !(0.0..=1.0).contains(&value) {
+                return Err(OutputError::OutOfRange { index, value });
+            }
+        }
+    }
+    Ok(model)
+}
+
+// Validates and parses raw model output by its length: 51 values are SinglePose, multiples of 56
+// MultiPose.
+pub fn parse_pose(values: &[f32], min_score: f32) -> Result<Pose, OutputError> {
+    let model = validate_output(values, min_score)?;
+    if model == PoseModel::SinglePose {
+        return Ok(Pose { model, persons: vec![Person::from_keypoints(values, min_score)] });
+    }
+
+    let persons = values.chunks_exact(MULTIPOSE_VALUES_PER_PERSON)
+        .filter(|row| row[KEYPOINT_VALUES + 4] >= min_score)
+        .map(|row| Person {
+            id: None,
+            keypoints: row[..KEYPOINT_VALUES].to_vec(),
+            bbox: [row[KEYPOINT_VALUES], row[KEYPOINT_VALUES + 1], row[KEYPOINT_VALUES + 2], row[KEYPOINT_VALUES + 3]],
+            score: row[KEYPOINT_VALUES + 4],
+        })
+        .collect();
+    Ok(Pose { model, persons })
+}
+
+// parse_pose() for callers that only want the people.
+pub fn parse_output(values: &[f32], min_score: f32) -> Result<Vec<Person>, OutputError> {
+    parse_pose(values, min_score).map(|pose| pose.persons)
+}
+
+// Counts valid and refused outputs, the latter by OutputError::kind. With a dump directory every
+// refused output is also written there as invalid-<n>.bin (the bytes as received) and
+// invalid-<n>.txt (why it was refused).
+#[derive(Default)]
+pub struct OutputMonitor {
+    pub valid: u64,
+    pub invalid: BTreeMap<&'static str, u64>,
+    dump: Option<PathBuf>,
+}
+
+impl OutputMonitor {
+    pub fn new(dump: Option<PathBuf>) -> io::Result<OutputMonitor> {
+        if let Some(dir) = &dump {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(OutputMonitor { dump, ..Default::default() })
+    }
+
+    pub fn invalid_total(&self) -> u64 {
+        self.invalid.values().sum()
+    }
+
+    // Decodes, validates and parses one output as received, counting the result.
+    pub fn check(&mut self, bytes: &[u8], min_score: f32) -> Result<Pose, OutputError> {
+        let result = decode_output(bytes).and_then(|values| parse_pose(&values, min_score));
+        match &result {
+            Ok(_) => self.valid += 1,
+            Err(e) => {
+                let n = self.invalid_total();
+                *self.invalid.entry(e.kind()).or_insert(0) += 1;
+                if let Some(dir) = &self.dump {
+                    let written = fs::write(dir.join(format!("invalid-{:06}.bin", n)), bytes)
+                        .and_then(|_| fs::write(dir.join(format!("invalid-{:06}.txt", n)), format!("{}\n", e)));
+                    if let Err(dump_error) = written {
+                        eprintln!("dumping invalid output to {}: {}", dir.display(), dump_error);
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+impl fmt::Display for OutputMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} valid, {} invalid", self.valid, self.invalid_total())?;
+        if !self.invalid.is_empty() {
+            let kinds: Vec<String> = self.invalid.iter().map(|(kind, n)| format!("{} {}", kind, n)).collect();
+            write!(f, " ({})", kinds.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn iou(a: &[f32; 4], b: &[f32; 4]) -> f32 {
+    let h = (a[2].min(b[2]) - a[0].max(b[0])).max(0.0);
+    let w = (a[3].min(b[3]) - a[1].max(b[1])).max(0.0);
+    let intersection = h * w;
+    let union = (a[2] - a[0]) * (a[3] - a[1]) + (b[2] - b[0]) * (b[3] - b[1]) - intersection;
+    if union > 0.0 { intersection / union } else { 0.0 }
+}
+
+struct Track {
+    id: u32,
+    bbox: [f32; 4],
+    // Frames since last matched.
+    age: u32,
+}
+
+// Gives each person the ID of the track whose last box overlaps it most, best overlaps first.
+// People matching no track start a new one; tracks unmatched for max_age frames are dropped.
+pub struct PersonTracker {
+    pub min_iou: f32,
+    pub max_age: u32,
+    tracks: Vec<Track>,
+    next_id: u32,
+}
+
+impl Default for PersonTracker {
+    fn default() -> PersonTracker {
+        PersonTracker::new()
+    }
+}
+
+impl PersonTracker {
+    pub fn new() -> PersonTracker {
+        PersonTracker { min_iou: 0.3, max_age: 10, tracks: vec![], next_id: 1 }
+    }
+
+    pub fn assign(&mut self, persons: &mut [Person]) {
+        let mut pairs = vec![];
+        for (p, person) in persons.iter().enumerate() {
+            for (t, track) in self.tracks.iter().enumerate() {
+                let overlap = iou(&person.bbox, &track.bbox);
+                if overlap >= self.min_iou {
+                    pairs.push((overlap, p, t));
+                }
+            }
+        }
+        pairs.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let mut person_done = vec![false; persons.len()];
+        let mut track_done = vec![false; self.tracks.len()];
+        for (_, p, t) in pairs {
+            if !person_done[p] && !track_done[t] {
+                person_done[p] = true;
+                track_done[t] = true;
+                persons[p].id = Some(self.tracks[t].id);
+                self.tracks[t].bbox = persons[p].bbox;
+                self.tracks[t].age = 0;
+            }
+        }
+
+        for (t, track) in self.tracks.iter_mut().enumerate() {
+            if !track_done[t] {
+                track.age += 1;
+            }
+        }
+        let max_age = self.max_age;
+        self.tracks.retain(|track| track.age <= max_age);
+
+        for (p, person) in persons.iter_mut().enumerate() {
+            if !person_done[p] {
+                person.id = Some(self.next_id);
+                self.tracks.push(Track { id: self.next_id, bbox: person.bbox, age: 0 });
+                self.next_id += 1;
+            }
+        }
+    }
+
+    // IDs currently tracked.
+    pub fn ids(&self) -> Vec<u32> {
+        self.tracks.iter().map(|track| track.id).collect()
+    }
+}
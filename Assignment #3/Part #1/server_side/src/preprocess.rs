@@ -0,0 +1,152 @@
+//! Fitting frames to the model input, and mapping the model's keypoints back onto the frame.
+//!
+//! MoveNet outputs [y, x, score] with y/x as fractions of its input tensor. How that tensor was cut
+//! from the frame (stretched, letterboxed or cropped) decides where a keypoint lands on the frame,
+//! so preprocess() hands back the Transform it applied and everything drawing or exporting
+//! keypoints goes through its inverse.
+
+use image::imageops::{overlay, Nearest};
+use image::{DynamicImage, RgbImage};
+
+// MoveNet Lightning input size.
+pub const MODEL_INPUT: (u32, u32) = (192, 192);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResizeMode {
+    // Scale each axis independently to fill the input; distorts the aspect ratio.
+    Stretch,
+    // Scale to fit inside the input and pad the rest with black, keeping the aspect ratio.
+    Letterbox,
+    // Scale to cover the input and cut off what sticks out, keeping the aspect ratio.
+    CenterCrop,
+}
+
+// source pixel -> input pixel: (p - crop) * scale + pad, per axis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    // Frame (width, height).
+    pub source: (u32, u32),
+    // Model input (width, height).
+    pub input: (u32, u32),
+    pub scale: (f32, f32),
+    // Input pixels of padding on the left/top.
+    pub pad: (f32, f32),
+    // Source pixels cut off on the left/top.
+    pub crop: (f32, f32),
+}
+
+impl Transform {
+    // The transform preprocess() applies for this mode. Sizes are rounded the same way the resize
+    // itself is, so the mapping matches the pixels the model actually saw.
+    pub fn new(source: (u32, u32), input: (u32, u32), mode: ResizeMode) -> Transform {
+        let (src_w, src_h) = (source.0.max(1) as f32, source.1.max(1) as f32);
+        let (in_w, in_h) = (input.0 as f32, input.1 as f32);
+
+        match mode {
+            ResizeMode::Stretch => Transform {
+                source,
+                input,
+                scale: (in_w / src_w, in_h / src_h),
+                pad: (0.0, 0.0),
+                crop: (0.0, 0.0),
+            },
+            ResizeMode::Letterbox => {
+                let (w, h) = letterbox_size(source, input);
+                Transform {
+                    source,
+                    input,
+                    scale: (w as f32 / src_w, h as f32 / src_h),
+                    pad: (((input.0 - w) / 2) as f32, ((input.1 - h) / 2) as f32),
+                    crop: (0.0, 0.0),
+                }
+            }
+            ResizeMode::CenterCrop => {
+                let (w, h) = crop_size(source, input);
+                Transform {
+                    source,
+                    input,
+                    scale: (in_w / w as f32, in_h / h as f32),
+                    pad: (0.0, 0.0),
+                    crop: (((source.0 - w) / 2) as f32, ((source.1 - h) / 2) as f32),
+                }
+            }
+        }
+    }
+
+    // Frame pixel (x, y) -> model coordinates (y, x) as fractions of the input.
+    pub fn to_model(&self, x: f32, y: f32) -> (f32, f32) {
+        let u = (x - self.crop.0) * self.scale.0 + self.pad.0;
+        let v = (y - self.crop.1) * self.scale.1 + self.pad.1;
+        (v / self.input.1 as f32, u / self.input.0 as f32)
+    }
+
+    // Model coordinates (y, x) -> frame pixel (x, y). Points in the letterbox padding or outside
+    // a crop land outside the frame.
+    pub fn to_source(&self, y_ratio: f32, x_ratio: f32) -> (f32, f32) {
+        let u = x_ratio * self.input.0 as f32;
+        let v = y_ratio * self.input.1 as f32;
+        ((u - self.pad.0) / self.scale.0 + self.crop.0, (v - self.pad.1) / self.scale.1 + self.crop.1)
+    }
+
+    // Raw model output [y, x, score, ...] -> [y, x, score, ...] in frame pixels.
+    pub fn keypoints_to_source(&self, keypoints: &[f32]) -> Vec<f32> {
+        keypoints.chunks_exact(3)
+            .flat_map(|k| {
+                let (x, y) = self.to_source(k[0], k[1]);
+                [y, x, k[2]]
+            })
+            .collect()
+    }
+
+    // Raw model output under this transform -> the output a model fed through `to` would have
+    // given, for a frame of the same size. Lets a per-frame crop hand steady coordinates to
+    // smoothing and drawing.
+    pub fn reproject(&self, keypoints: &[f32], to: &Transform) -> Vec<f32> {
+        keypoints.chunks_exact(3)
+            .flat_map(|k| {
+                let (x, y) = self.to_source(k[0], k[1]);
+                let (y, x) = to.to_model(x, y);
+                [y, x, k[2]]
+            })
+            .collect()
+    }
+}
+
+// Largest size with the source aspect ratio that fits in the input.
+fn letterbox_size(source: (u32, u32), input: (u32, u32)) -> (u32, u32) {
+    let (src_w, src_h) = (source.0.max(1) as f64, source.1.max(1) as f64);
+    let scale = (input.0 as f64 / src_w).min(input.1 as f64 / src_h);
+    (((src_w * scale).round() as u32).clamp(1, input.0), ((src_h * scale).round() as u32).clamp(1, input.1))
+}
+
+// Largest region of the source with the input aspect ratio.
+fn crop_size(source: (u32, u32), input: (u32, u32)) -> (u32, u32) {
+    let (src_w, src_h) = (source.0.max(1) as f64, source.1.max(1) as f64);
+    let scale = (input.0 as f64 / src_w).max(input.1 as f64 / src_h);
+    (((input.0 as f64 / scale).round() as u32).clamp(1, source.0.max(1)),
+     ((input.1 as f64 / scale).round() as u32).clamp(1, source.1.max(1)))
+}
+
+// Fits `image` to an RGB model input of `input` size, returning the pixels and how they map back.
+pub fn preprocess(image: &DynamicImage, input: (u32, u32), mode: ResizeMode) -> (RgbImage, Transform) {
+    let source = (image.width(), image.height());
+    let transform = Transform::new(source, input, mode);
+
+    let rgb = match mode {
+        ResizeMode::Stretch => image.resize_exact(input.0, input.1, Nearest).to_rgb8(),
+        ResizeMode::Letterbox => {
+            let (w, h) = letterbox_size(source, input);
+            let mut canvas = RgbImage::new(input.0, input.1);
+            overlay(&mut canvas, &image.resize_exact(w, h, Nearest).to_rgb8(),
+                    transform.pad.0 as i64, transform.pad.1 as i64);
+            canvas
+        }
+        ResizeMode::CenterCrop => {
+            let (w, h) = crop_size(source, input);
+            image.crop_imm(transform.crop.0 as u32, transform.crop.1 as u32, w, h)
+                .resize_exact(input.0, input.1, Nearest)
+                .to_rgb8()
+        }
+    };
+    (rgb, transform)
+}
@@ -0,0 +1,193 @@
+//! Per-frame pose records for offline analysis and regression comparison, as JSON Lines or CSV.
+//! Keypoint positions are written in pixels of the source frame, whatever the model input was.
+//! A frame without anyone in it still gets a record, with a null person and null keypoints, so
+//! every frame shows up in the results. Written by hand like the rest of our serialization; the
+//! records are flat enough not to need serde.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::preprocess::Transform;
+
+// MoveNet SinglePose output order.
+pub const KEYPOINT_NAMES: [&str; 17] = [
+    "nose",
+    "left_eye", "right_eye",
+    "left_ear", "right_ear",
+    "left_shoulder", "right_shoulder",
+    "left_elbow", "right_elbow",
+    "left_wrist", "right_wrist",
+    "left_hip", "right_hip",
+    "left_knee", "right_knee",
+    "left_ankle", "right_ankle",
+];
+
+pub struct PoseRecord<'a> {
+    // Frame sequence number within its source.
+    pub seq: u64,
+    // When the frame was captured.
+    pub timestamp: SystemTime,
+    // Capture to result available.
+    pub latency: Duration,
+    // Tracking ID of the person, one record per person and frame; None when untracked or when
+    // nobody was detected.
+    pub person: Option<u32>,
+    // Raw model output, [y, x, score] per keypoint in KEYPOINT_NAMES order; None when nobody was
+    // detected in the frame.
+    pub keypoints: Option<&'a [f32]>,
+    // How the frame was fitted to the model input; maps the keypoints back onto the frame.
+    pub transform: &'a Transform,
+    // Which partition/server produced the result, e.g. "split:127.0.0.1:8000" or "kernel".
+    pub producer: &'a str,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResultFormat {
+    JsonLines,
+    Csv,
+}
+
+impl ResultFormat {
+    // .csv is CSV, anything else (.jsonl, .json, ...) is JSON Lines.
+    pub fn from_path(path: &str) -> ResultFormat {
+        if path.to_lowercase().ends_with(".csv") {
+            ResultFormat::Csv
+        } else {
+            ResultFormat::JsonLines
+        }
+    }
+}
+
+pub struct ResultsWriter<W: Write> {
+    out: W,
+    format: ResultFormat,
+    wrote_header: bool,
+}
+
+impl ResultsWriter<BufWriter<File>> {
+    pub fn create(path: &str) -> io::Result<ResultsWriter<BufWriter<File>>> {
+        Ok(ResultsWriter::new(BufWriter::new(File::create(path)?), ResultFormat::from_path(path)))
+    }
+}
+
+impl<W: Write> ResultsWriter<W> {
+    pub fn new(out: W, format: ResultFormat) -> ResultsWriter<W> {
+        ResultsWriter { out, format, wrote_header: false }
+    }
+
+    pub fn write(&mut self, record: &PoseRecord) -> io::Result<()> {
+        let line = match self.format {
+            ResultFormat::JsonLines => json_line(record),
+            ResultFormat::Csv => {
+                if !self.wrote_header {
+                    writeln!(self.out, "{}", csv_header())?;
+                    self.wrote_header = true;
+                }
+                csv_line(record)
+            }
+        };
+        writeln!(self.out, "{}", line)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+fn seconds(timestamp: SystemTime) -> f64 {
+    timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+}
+
+// Value i of the keypoints in frame pixels, or None if the output was short.
+fn value(keypoints: &[f32], i: usize) -> Option<f32> {
+    keypoints.get(i).copied()
+}
+
+// JSON has no NaN/inf, and missing values are null too.
+fn json_number(x: Option<f32>) -> String {
+    match x {
+        Some(x) if x.is_finite() => format!("{}", x),
+        _ => "null".to_string(),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_line(record: &PoseRecord) -> String {
+    let mut line = format!("{{\"seq\":{},\"timestamp\":{:.6},\"latency_ms\":{:.3},\"producer\":{},\
+                            \"person\":{},\"width\":{},\"height\":{},\"keypoints\":",
+                           record.seq, seconds(record.timestamp),
+                           record.latency.as_secs_f64() * 1000.0, json_string(record.producer),
+                           record.person.map_or("null".to_string(), |id| id.to_string()),
+                           record.transform.source.0, record.transform.source.1);
+    let Some(keypoints) = record.keypoints else {
+        line.push_str("null}");
+        return line;
+    };
+    let keypoints = record.transform.keypoints_to_source(keypoints);
+    line.push('{');
+    for (i, name) in KEYPOINT_NAMES.iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        let _ = write!(line, "\"{}\":{{\"y\":{},\"x\":{},\"score\":{}}}", name,
+                       json_number(value(&keypoints, i * 3)),
+                       json_number(value(&keypoints, i * 3 + 1)),
+                       json_number(value(&keypoints, i * 3 + 2)));
+    }
+    line.push_str("}}");
+    line
+}
+
+pub fn csv_header() -> String {
+    let mut header = String::from("seq,timestamp,latency_ms,producer,person,width,height");
+    for name in KEYPOINT_NAMES {
+        let _ = write!(header, ",{0}_y,{0}_x,{0}_score", name);
+    }
+    header
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+// Missing values are left empty; NaN/inf are written as Rust prints them.
+fn csv_number(x: Option<f32>) -> String {
+    x.map_or(String::new(), |x| format!("{}", x))
+}
+
+// Nobody detected leaves all the keypoint fields empty.
+fn csv_line(record: &PoseRecord) -> String {
+    let keypoints = record.keypoints.map(|k| record.transform.keypoints_to_source(k)).unwrap_or_default();
+    let mut line = format!("{},{:.6},{:.3},{},{},{},{}", record.seq, seconds(record.timestamp),
+                           record.latency.as_secs_f64() * 1000.0, csv_field(record.producer),
+                           record.person.map_or(String::new(), |id| id.to_string()),
+                           record.transform.source.0, record.transform.source.1);
+    for i in 0..KEYPOINT_NAMES.len() * 3 {
+        line.push(',');
+        line.push_str(&csv_number(value(&keypoints, i)));
+    }
+    line
+}
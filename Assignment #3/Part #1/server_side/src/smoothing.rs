@@ -0,0 +1,358 @@
+//! Temporal smoothing of the keypoints. Every frame is inferred on its own, so joints jitter from one
+//! frame to the next; PoseSmoother runs the deserialized model output through a per-keypoint filter
+//! before it is drawn or recorded.
+//!
+//! Positions are filtered in model coordinates (fractions of the input), so filter parameters do not
+//! depend on the frame size. Low-confidence keypoints can hold their last good position for a few
+//! frames, and all filters start over when the scene changes.
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::io::{self, Error, ErrorKind};
+use std::time::{Duration, SystemTime};
+
+use crate::pose::Person;
+
+// Used for the first frame and whenever timestamps do not move forward.
+const DEFAULT_DT: f32 = 1.0 / 30.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    None,
+    // Exponential smoothing, alpha is the weight of the new value.
+    Ema { alpha: f32 },
+    // One-Euro filter: cutoffs in Hz, beta in Hz per (model unit / second) of speed.
+    OneEuro { min_cutoff: f32, beta: f32, d_cutoff: f32 },
+    // Constant-velocity Kalman filter: acceleration and measurement variances in model units.
+    Kalman { process_noise: f32, measurement_noise: f32 },
+}
+
+impl Filter {
+    pub const EMA: Filter = Filter::Ema { alpha: 0.5 };
+    pub const ONE_EURO: Filter = Filter::OneEuro { min_cutoff: 1.0, beta: 5.0, d_cutoff: 1.0 };
+    pub const KALMAN: Filter = Filter::Kalman { process_noise: 0.5, measurement_noise: 1e-4 };
+
+    /// Builds a filter from a command line spec, parameters being optional:
+    ///
+    ///   none
+    ///   ema[:<alpha>]
+    ///   one-euro[:<min_cutoff>,<beta>[,<d_cutoff>]]
+    ///   kalman[:<process_noise>,<measurement_noise>]
+    pub fn from_spec(spec: &str) -> io::Result<Filter> {
+        let (name, params) = match spec.split_once(':') {
+            Some((name, params)) => (name, Some(params)),
+            None => (spec, None),
+        };
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("bad smoothing filter: {}", spec));
+        let values = match params {
+            Some(params) => params.split(',')
+                .map(|v| v.trim().parse::<f32>().map_err(|_| invalid()))
+                .collect::<io::Result<Vec<f32>>>()?,
+            None => vec![],
+        };
+
+        match (name, values.as_slice()) {
+            ("none", []) => Ok(Filter::None),
+            ("ema", []) => Ok(Filter::EMA),
+            ("ema", [alpha]) if *alpha > 0.0 && *alpha <= 1.0 => Ok(Filter::Ema { alpha: *alpha }),
+            ("one-euro", []) => Ok(Filter::ONE_EURO),
+            ("one-euro", [min_cutoff, beta]) => Ok(Filter::OneEuro { min_cutoff: *min_cutoff, beta: *beta, d_cutoff: 1.0 }),
+            ("one-euro", [min_cutoff, beta, d_cutoff]) =>
+                Ok(Filter::OneEuro { min_cutoff: *min_cutoff, beta: *beta, d_cutoff: *d_cutoff }),
+            ("kalman", []) => Ok(Filter::KALMAN),
+            ("kalman", [process_noise, measurement_noise]) =>
+                Ok(Filter::Kalman { process_noise: *process_noise, measurement_noise: *measurement_noise }),
+            _ => Err(invalid()),
+        }
+    }
+
+    pub fn make(&self) -> Box<dyn AxisFilter + Send> {
+        match *self {
+            Filter::None => Box::new(Passthrough),
+            Filter::Ema { alpha } => Box::new(Ema::new(alpha)),
+            Filter::OneEuro { min_cutoff, beta, d_cutoff } => Box::new(OneEuro::new(min_cutoff, beta, d_cutoff)),
+            Filter::Kalman { process_noise, measurement_noise } => Box::new(Kalman::new(process_noise, measurement_noise)),
+        }
+    }
+}
+
+// One coordinate of one keypoint over time.
+pub trait AxisFilter {
+    // Filters the next measurement, taken `dt` seconds after the previous one.
+    fn update(&mut self, value: f32, dt: f32) -> f32;
+}
+
+pub struct Passthrough;
+
+impl AxisFilter for Passthrough {
+    fn update(&mut self, value: f32, _dt: f32) -> f32 {
+        value
+    }
+}
+
+pub struct Ema {
+    alpha: f32,
+    value: Option<f32>,
+}
+
+impl Ema {
+    pub fn new(alpha: f32) -> Ema {
+        Ema { alpha, value: None }
+    }
+}
+
+impl AxisFilter for Ema {
+    fn update(&mut self, value: f32, _dt: f32) -> f32 {
+        let smoothed = match self.value {
+            Some(previous) => previous + self.alpha * (value - previous),
+            None => value,
+        };
+        self.value = Some(smoothed);
+        smoothed
+    }
+}
+
+// Casiez et al., "1€ Filter", CHI 2012: a low-pass filter whose cutoff rises with speed, so slow
+// movement is smoothed hard and fast movement is followed with little lag.
+pub struct OneEuro {
+    min_cutoff: f32,
+    beta: f32,
+    d_cutoff: f32,
+    value: Option<f32>,
+    derivative: f32,
+}
+
+impl OneEuro {
+    pub fn new(min_cutoff: f32, beta: f32, d_cutoff: f32) -> OneEuro {
+        OneEuro { min_cutoff, beta, d_cutoff, value: None, derivative: 0.0 }
+    }
+}
+
+fn smoothing_factor(cutoff: f32, dt: f32) -> f32 {
+    let tau = 1.0 / (2.0 * PI * cutoff);
+    1.0 / (1.0 + tau / dt)
+}
+
+impl AxisFilter for OneEuro {
+    fn update(&mut self, value: f32, dt: f32) -> f32 {
+        let previous = match self.value {
+            Some(previous) => previous,
+            None => {
+                self.value = Some(value);
+                return value;
+            }
+        };
+
+        let derivative = (value - previous) / dt;
+        self.derivative += smoothing_factor(self.d_cutoff, dt) * (derivative - self.derivative);
+
+        let cutoff = self.min_cutoff + self.beta * self.derivative.abs();
+        let smoothed = previous + smoothing_factor(cutoff, dt) * (value - previous);
+        self.value = Some(smoothed);
+        smoothed
+    }
+}
+
+// State [position, velocity] with covariance p.
+pub struct Kalman {
+    process_noise: f32,
+    measurement_noise: f32,
+    state: Option<[f32; 2]>,
+    p: [[f32; 2]; 2],
+}
+
+impl Kalman {
+    pub fn new(process_noise: f32, measurement_noise: f32) -> Kalman {
+        Kalman { process_noise, measurement_noise, state: None, p: [[0.0; 2]; 2] }
+    }
+
+    // Current velocity estimate, in units per second.
+    pub fn velocity(&self) -> f32 {
+        self.state.map_or(0.0, |s| s[1])
+    }
+}
+
+impl AxisFilter for Kalman {
+    fn update(&mut self, value: f32, dt: f32) -> f32 {
+        let r = self.measurement_noise;
+        let [mut x, mut v] = match self.state {
+            Some(state) => state,
+            None => {
+                self.state = Some([value, 0.0]);
+                self.p = [[r, 0.0], [0.0, 1.0]];
+                return value;
+            }
+        };
+        let p = self.p;
+        let q = self.process_noise;
+
+        // PREDICT
+        x += v * dt;
+        let p00 = p[0][0] + dt * (p[1][0] + p[0][1]) + dt * dt * p[1][1] + q * dt.powi(4) / 4.0;
+        let p01 = p[0][1] + dt * p[1][1] + q * dt.powi(3) / 2.0;
+        let p10 = p[1][0] + dt * p[1][1] + q * dt.powi(3) / 2.0;
+        let p11 = p[1][1] + q * dt * dt;
+
+        // UPDATE
+        let s = p00 + r;
+        let (k0, k1) = (p00 / s, p10 / s);
+        let residual = value - x;
+        x += k0 * residual;
+        v += k1 * residual;
+        self.p = [[(1.0 - k0) * p00, (1.0 - k0) * p01], [p10 - k1 * p00, p11 - k1 * p01]];
+
+        self.state = Some([x, v]);
+        x
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SmoothingConfig {
+    pub filter: Filter,
+    // Keypoints scoring below this keep their last good position (and score)...
+    pub hold_threshold: f32,
+    // ...for at most this many frames; after that the raw output goes through and the keypoint's
+    // filter starts over.
+    pub max_hold: u32,
+    // Start all filters over when the confident keypoints move further than this on average
+    // (model units, 1.0 = the whole input) from one frame to the next...
+    pub reset_distance: Option<f32>,
+    // ...or when there is a gap this long between frames.
+    pub reset_gap: Option<Duration>,
+}
+
+impl Default for SmoothingConfig {
+    fn default() -> SmoothingConfig {
+        SmoothingConfig {
+            filter: Filter::ONE_EURO,
+            hold_threshold: 0.2,
+            max_hold: 5,
+            reset_distance: Some(0.25),
+            reset_gap: Some(Duration::from_secs(1)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct KeypointState {
+    axes: Option<(Box<dyn AxisFilter + Send>, Box<dyn AxisFilter + Send>)>,
+    // Last output [y, x, score] from a confident measurement.
+    last: Option<[f32; 3]>,
+    held: u32,
+}
+
+pub struct PoseSmoother {
+    config: SmoothingConfig,
+    keypoints: Vec<KeypointState>,
+    last_time: Option<SystemTime>,
+    resets: u64,
+}
+
+impl PoseSmoother {
+    pub fn new(config: SmoothingConfig) -> PoseSmoother {
+        PoseSmoother { config, keypoints: vec![], last_time: None, resets: 0 }
+    }
+
+    pub fn config(&self) -> &SmoothingConfig {
+        &self.config
+    }
+
+    // Forgets all history, e.g. when switching sources.
+    pub fn reset(&mut self) {
+        self.keypoints.clear();
+        self.last_time = None;
+    }
+
+    // Number of scene changes detected so far.
+    pub fn resets(&self) -> u64 {
+        self.resets
+    }
+
+    // Smooths one frame of raw model output [y, x, score, ...] captured at `timestamp`.
+    pub fn apply(&mut self, keypoints: &[f32], timestamp: SystemTime) -> Vec<f32> {
+        let gap = self.last_time.and_then(|t| timestamp.duration_since(t).ok());
+        if self.last_time.is_some() && self.scene_changed(keypoints, gap) {
+            self.reset();
+            self.resets += 1;
+        }
+        self.last_time = Some(timestamp);
+
+        let dt = gap.map(|d| d.as_secs_f32()).filter(|dt| *dt > 0.0).unwrap_or(DEFAULT_DT);
+        let count = keypoints.len() / 3;
+        self.keypoints.resize_with(count, Default::default);
+
+        let mut out = Vec::with_capacity(count * 3);
+        for (state, k) in self.keypoints.iter_mut().zip(keypoints.chunks_exact(3)) {
+            let score = k[2];
+
+            // CONFIDENCE-GATED HOLD
+            if score < self.config.hold_threshold {
+                match state.last {
+                    Some(last) if state.held < self.config.max_hold => {
+                        state.held += 1;
+                        out.extend(last);
+                    }
+                    _ => {
+                        *state = KeypointState::default();
+                        out.extend_from_slice(k);
+                    }
+                }
+                continue;
+            }
+
+            // FILTER
+            let filter = self.config.filter;
+            let (fy, fx) = state.axes.get_or_insert_with(|| (filter.make(), filter.make()));
+            let smoothed = [fy.update(k[0], dt), fx.update(k[1], dt), score];
+            state.last = Some(smoothed);
+            state.held = 0;
+            out.extend(smoothed);
+        }
+        out
+    }
+
+    fn scene_changed(&self, keypoints: &[f32], gap: Option<Duration>) -> bool {
+        if let (Some(limit), Some(gap)) = (self.config.reset_gap, gap) {
+            if gap > limit {
+                return true;
+            }
+        }
+
+        let limit = match self.config.reset_distance {
+            Some(limit) => limit,
+            None => return false,
+        };
+        let (mut total, mut count) = (0.0, 0);
+        for (state, k) in self.keypoints.iter().zip(keypoints.chunks_exact(3)) {
+            if let Some(last) = state.last.filter(|_| k[2] >= self.config.hold_threshold) {
+                total += ((k[0] - last[0]).powi(2) + (k[1] - last[1]).powi(2)).sqrt();
+                count += 1;
+            }
+        }
+        count > 0 && total / count as f32 > limit
+    }
+}
+
+// One PoseSmoother per tracked person. Smoothers of people no longer present are dropped.
+pub struct PersonSmoothers {
+    config: SmoothingConfig,
+    smoothers: HashMap<u32, PoseSmoother>,
+}
+
+impl PersonSmoothers {
+    pub fn new(config: SmoothingConfig) -> PersonSmoothers {
+        PersonSmoothers { config, smoothers: HashMap::new() }
+    }
+
+    // Smooths the keypoints of every person with an ID; untracked people go through as they are.
+    pub fn apply(&mut self, persons: &mut [Person], timestamp: SystemTime) {
+        self.smoothers.retain(|id, _| persons.iter().any(|p| p.id == Some(*id)));
+        for person in persons.iter_mut() {
+            if let Some(id) = person.id {
+                let config = &self.config;
+                let smoother = self.smoothers.entry(id).or_insert_with(|| PoseSmoother::new(config.clone()));
+                person.keypoints = smoother.apply(&person.keypoints, timestamp);
+            }
+        }
+    }
+}
@@ -1,9 +1,15 @@
+use std::time::{Duration, Instant};
+
 use opencv::{
 	prelude::*,
 	imgproc::*,
 	core::*,
 };
 
+use crate::pose::Person;
+use crate::preprocess::{ResizeMode, Transform};
+use crate::results::KEYPOINT_NAMES;
+
 pub fn _resize_with_padding(img: &Mat, new_shape: [i32;2]) -> Mat {
 	let img_shape = [img.cols(), img.rows()];
 	let width: i32;
@@ -41,31 +47,261 @@ pub fn _resize_with_padding(img: &Mat, new_shape: [i32;2]) -> Mat {
 	rslt
 }
 
-pub fn draw_keypoints(img: &mut Mat, keypoints: &[f32], threshold: f32) {
+// POSE RENDERING
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+	Left,
+	Right,
+	Center,
+}
+
+// MoveNet's skeleton, as pairs of KEYPOINT_NAMES indices.
+pub const SKELETON: [(usize, usize); 18] = [
+	(0, 1), (0, 2), (1, 3), (2, 4),         // face
+	(0, 5), (0, 6), (5, 6),                 // neck and shoulders
+	(5, 7), (7, 9), (6, 8), (8, 10),        // arms
+	(5, 11), (6, 12), (11, 12),             // torso
+	(11, 13), (13, 15), (12, 14), (14, 16), // legs
+];
+
+// Odd keypoints are on the left of the body, even ones (but the nose) on the right.
+pub fn keypoint_side(index: usize) -> Side {
+	match index {
+		0 => Side::Center,
+		i if i % 2 == 1 => Side::Left,
+		_ => Side::Right,
+	}
+}
+
+// An edge belongs to a side when it does not cross over to the other one.
+pub fn edge_side(edge: (usize, usize)) -> Side {
+	match (keypoint_side(edge.0), keypoint_side(edge.1)) {
+		(Side::Left, Side::Right) | (Side::Right, Side::Left) | (Side::Center, Side::Center) => Side::Center,
+		(Side::Left, _) | (_, Side::Left) => Side::Left,
+		_ => Side::Right,
+	}
+}
+
+// Colours are BGR.
+#[derive(Clone, Debug)]
+pub struct PoseStyle {
+	// Keypoints (and the edges touching them) at or below this score are not drawn.
+	pub threshold: f32,
+	pub skeleton: bool,
+	pub left: Scalar,
+	pub right: Scalar,
+	pub center: Scalar,
+	pub line_thickness: i32,
+	pub radius: i32,
+	// Keypoints fade from low_confidence at the threshold to high_confidence at 1.0; without
+	// shading they are all high_confidence.
+	pub shade_confidence: bool,
+	pub low_confidence: Scalar,
+	pub high_confidence: Scalar,
+	pub labels: bool,
+	// Bounding box and tracking ID around each person.
+	pub boxes: bool,
+	pub font_scale: f64,
+	pub text: Scalar,
+}
+
+impl Default for PoseStyle {
+	fn default() -> PoseStyle {
+		PoseStyle {
+			threshold: 0.25,
+			skeleton: true,
+			left: Scalar::new(255.0, 0.0, 255.0, 0.0),
+			right: Scalar::new(255.0, 255.0, 0.0, 0.0),
+			center: Scalar::new(0.0, 255.0, 255.0, 0.0),
+			line_thickness: 2,
+			radius: 4,
+			shade_confidence: true,
+			low_confidence: Scalar::new(0.0, 0.0, 255.0, 0.0),
+			high_confidence: Scalar::new(0.0, 255.0, 0.0, 0.0),
+			labels: false,
+			boxes: true,
+			font_scale: 0.4,
+			text: Scalar::new(255.0, 255.0, 255.0, 0.0),
+		}
+	}
+}
+
+impl PoseStyle {
+	pub fn side_color(&self, side: Side) -> Scalar {
+		match side {
+			Side::Left => self.left,
+			Side::Right => self.right,
+			Side::Center => self.center,
+		}
+	}
+
+	pub fn confidence_color(&self, score: f32) -> Scalar {
+		if !self.shade_confidence {
+			return self.high_confidence;
+		}
+		let t = ((score - self.threshold) / (1.0 - self.threshold)).clamp(0.0, 1.0) as f64;
+		let mut color = Scalar::default();
+		for c in 0..4 {
+			color.0[c] = self.low_confidence.0[c] * (1.0 - t) + self.high_confidence.0[c] * t;
+		}
+		color
+	}
+}
+
+// Draws raw model output onto the frame `transform` was made for.
+pub fn draw_pose(img: &mut Mat, keypoints: &[f32], transform: &Transform, style: &PoseStyle) -> opencv::Result<()> {
 	// keypoints: [1, 17, 3]
-	let base: f32;
-	let pad_x: i32;
-	let pad_y: i32;
-	if img.rows() > img.cols() {
-		base = img.rows() as f32;
-		pad_x = (img.rows() - img.cols()) / 2;
-		pad_y = 0;
-	} else {
-		base = img.cols() as f32;
-		pad_x = 0;
-		pad_y = (img.cols() - img.rows()) / 2;
-	}
-
-	for index in 0..17 {
-		let y_ratio = keypoints[index * 3];
-		let x_ratio = keypoints[index * 3 + 1];
-		let confidence = keypoints[index * 3 + 2];
-		if confidence > threshold {
-			circle(img,
-				Point { x: (x_ratio * base) as i32 - pad_x, y: (y_ratio * base) as i32 - pad_y},
-				0,
-				Scalar::new(0.0, 255.0, 0.0, 0.0),
-				5, LINE_AA, 0).expect("Draw circle [FAILED]");
+	if keypoints.len() < KEYPOINT_NAMES.len() * 3 {
+		return Ok(());
+	}
+	let score = |index: usize| keypoints[index * 3 + 2];
+	let position = |index: usize| {
+		let (x, y) = transform.to_source(keypoints[index * 3], keypoints[index * 3 + 1]);
+		Point { x: x.round() as i32, y: y.round() as i32 }
+	};
+
+	// EDGES FIRST SO THE JOINTS STAY VISIBLE
+	if style.skeleton {
+		for edge in SKELETON {
+			if score(edge.0) > style.threshold && score(edge.1) > style.threshold {
+				let (from, to) = (position(edge.0), position(edge.1));
+				line(img, from, to, style.side_color(edge_side(edge)), style.line_thickness, LINE_AA, 0)?;
+			}
+		}
+	}
+
+	for index in 0..KEYPOINT_NAMES.len() {
+		if score(index) <= style.threshold {
+			continue;
+		}
+		let center = position(index);
+		circle(img, center, style.radius, style.confidence_color(score(index)), FILLED, LINE_AA, 0)?;
+
+		if style.labels {
+			let origin = Point { x: center.x + style.radius + 2, y: center.y - style.radius - 2 };
+			put_text(img, KEYPOINT_NAMES[index], origin, FONT_HERSHEY_SIMPLEX, style.font_scale,
+				style.text, 1, LINE_AA, false)?;
+		}
+	}
+	Ok(())
+}
+
+// Distinct colour per tracking ID.
+pub fn id_color(id: u32) -> Scalar {
+	const PALETTE: [(f64, f64, f64); 6] = [
+		(255.0, 128.0, 0.0), (0.0, 128.0, 255.0), (128.0, 255.0, 0.0),
+		(255.0, 0.0, 128.0), (0.0, 255.0, 128.0), (128.0, 0.0, 255.0),
+	];
+	let (b, g, r) = PALETTE[id as usize % PALETTE.len()];
+	Scalar::new(b, g, r, 0.0)
+}
+
+// One person: box and ID (when tracked and style.boxes), then the skeleton.
+pub fn draw_person(img: &mut Mat, person: &Person, transform: &Transform, style: &PoseStyle) -> opencv::Result<()> {
+	if style.boxes {
+		if let Some(id) = person.id {
+			let (x0, y0) = transform.to_source(person.bbox[0], person.bbox[1]);
+			let (x1, y1) = transform.to_source(person.bbox[2], person.bbox[3]);
+			let bbox = Rect::new(x0.round() as i32, y0.round() as i32,
+				(x1 - x0).round() as i32, (y1 - y0).round() as i32);
+			rectangle(img, bbox, id_color(id), 1, LINE_8, 0)?;
+			put_text(img, &format!("#{} {:.2}", id, person.score), Point { x: bbox.x, y: bbox.y - 4 },
+				FONT_HERSHEY_SIMPLEX, style.font_scale, id_color(id), 1, LINE_AA, false)?;
+		}
+	}
+	draw_pose(img, &person.keypoints, transform, style)
+}
+
+// For output of a model that saw `img` padded to a square.
+pub fn draw_keypoints(img: &mut Mat, keypoints: &[f32], threshold: f32) {
+	let source = (img.cols() as u32, img.rows() as u32);
+	let side = source.0.max(source.1);
+	let transform = Transform::new(source, (side, side), ResizeMode::Letterbox);
+
+	let style = PoseStyle { threshold, ..Default::default() };
+	draw_pose(img, keypoints, &transform, &style).expect("Draw pose [FAILED]");
+}
+
+// OVERLAY
+
+// Where the keypoints were computed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InferenceMode {
+	Local,
+	Server,
+}
+
+impl InferenceMode {
+	pub fn label(&self) -> &'static str {
+		match self {
+			InferenceMode::Local => "LOCAL",
+			InferenceMode::Server => "SERVER",
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Overlay {
+	pub fps: Option<f64>,
+	pub latency: Option<Duration>,
+	pub mode: Option<InferenceMode>,
+}
+
+// Frame rate and latency at the top left, the mode badge at the top right.
+pub fn draw_overlay(img: &mut Mat, overlay: &Overlay, style: &PoseStyle) -> opencv::Result<()> {
+	let mut baseline = 0;
+	let line_height = get_text_size("0", FONT_HERSHEY_SIMPLEX, style.font_scale, 1, &mut baseline)?.height + 6;
+	let mut y = line_height;
+
+	let mut lines = vec![];
+	if let Some(fps) = overlay.fps {
+		lines.push(format!("FPS {:.1}", fps));
+	}
+	if let Some(latency) = overlay.latency {
+		lines.push(format!("LATENCY {:.1} ms", latency.as_secs_f64() * 1000.0));
+	}
+	for text in lines {
+		put_text(img, &text, Point { x: 6, y }, FONT_HERSHEY_SIMPLEX, style.font_scale,
+			style.text, 1, LINE_AA, false)?;
+		y += line_height;
+	}
+
+	if let Some(mode) = overlay.mode {
+		let color = match mode {
+			InferenceMode::Local => style.low_confidence,
+			InferenceMode::Server => style.high_confidence,
+		};
+		let size = get_text_size(mode.label(), FONT_HERSHEY_SIMPLEX, style.font_scale, 1, &mut baseline)?;
+		let badge = Rect::new(img.cols() - size.width - 12, 0, size.width + 12, size.height + baseline + 8);
+		rectangle(img, badge, color, FILLED, LINE_8, 0)?;
+		put_text(img, mode.label(), Point { x: badge.x + 6, y: size.height + 4 }, FONT_HERSHEY_SIMPLEX,
+			style.font_scale, Scalar::new(0.0, 0.0, 0.0, 0.0), 1, LINE_AA, false)?;
+	}
+	Ok(())
+}
+
+// Smoothed frames per second, updated once per presented frame.
+pub struct FrameRate {
+	last: Option<Instant>,
+	fps: f64,
+}
+
+impl FrameRate {
+	pub fn new() -> FrameRate {
+		FrameRate { last: None, fps: 0.0 }
+	}
+
+	pub fn tick(&mut self) -> f64 {
+		let now = Instant::now();
+		if let Some(last) = self.last {
+			let elapsed = (now - last).as_secs_f64();
+			if elapsed > 0.0 {
+				let fps = 1.0 / elapsed;
+				self.fps = if self.fps == 0.0 { fps } else { 0.9 * self.fps + 0.1 * fps };
+			}
 		}
+		self.last = Some(now);
+		self.fps
 	}
 }
@@ -0,0 +1,644 @@
+use nix::fcntl::{open, OFlag};
+use nix::{ioctl_readwrite, ioctl_write_ptr};
+use nix::errno::Errno;
+use nix::sys::stat::Mode;
+use nix::sys::mman::{mmap, munmap, ProtFlags, MapFlags};
+use nix::unistd::{close, /*write*/};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::libc::size_t;
+use std::ffi::{c_int, c_ulong, c_void};
+use std::fmt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr::copy_nonoverlapping;
+use std::time::{Duration, Instant};
+
+const N_BUFFERS: usize = 2;
+const W: usize = 400;
+const H: usize = 712;
+
+// How long to wait for the driver to fill a buffer before reporting a timeout.
+pub const DEFAULT_TIMEOUT_MS: i32 = 1000;
+
+// fn u8_vec_of_u64_arr(a: &[u64]) -> Vec<u8> {
+//     let mut ans = vec![];
+// 
+//     for x in a {
+//         for i in 0..8 {
+//             ans.push(((x >> (i*8)) & 0xff) as u8);
+//         }
+//     }
+// 
+//     ans
+// }
+
+// FFI bindings to v4l2 api
+
+/*
+#[repr(C)]
+pub struct v4l2_capability {
+    pub driver: [u8; 16],
+    pub card: [u8; 32],
+    pub bus_info: [u8; 32],
+    pub version: u32,
+    pub capabilities: u32,
+    pub device_caps: u32,
+    pub reserved: [u32; 3],
+}
+
+ioctl_read!(vidioc_querycap, b'V', 0, v4l2_capability);
+*/
+
+pub const FORMAT_PADDING: usize = 208 - 10*4;
+#[repr(C)]
+pub struct v4l2_format {
+    pub type_: u32,
+    pub space: u32,
+    pub width: u32,
+    pub height: u32,
+    pub pixelformat: u32,
+    pub field: u32,
+    pub bytesperline: u32,
+    pub sizeimage: u32,
+    pub colorspace: u32,
+    pub priv_: u32,
+    pub others: [u8; FORMAT_PADDING]
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct v4l2_requestbuffers {
+    pub count: u32,
+    pub type_: u32,
+    pub memory: u32,
+    pub capabilities: u32,
+    pub flags: u8,
+    pub reserved: [u8; 3]
+}
+
+#[repr(C)]
+pub union anon_plane_m {
+    mem_offset: u32,
+    userptr: c_ulong,
+    fd: i32
+}
+
+#[repr(C)]
+pub struct v4l2_plane {
+    pub bytesused: u32,
+    pub length: u32,
+    pub m: anon_plane_m,
+    pub data_offset: u32,
+    pub reserved: [u32; 11]
+}
+
+#[repr(C)]
+pub union anon_buffer_m {
+    pub offset: u32,
+    pub userptr: c_ulong,
+    pub planes: *mut v4l2_plane,
+    pub fd: i32
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct v4l2_timecode {
+    type_: u32,
+    flags: u32,
+    frame: u8,
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    userbits: [u8; 4]
+}
+
+// https://docs.rs/libc/0.2.137/libc/struct.timeval.html
+// /usr/include/bits/types/struct_timeval.h
+// assumes __USE_TIME_BITS64
+#[repr(C)]
+pub struct timeval {
+    tv_sec: i64,
+    tv_usec: i64
+}
+
+#[repr(C)]
+pub struct v4l2_buffer {
+    pub index: u32,
+    pub type_: u32,
+    pub bytesused: u32,
+    pub flags: u32,
+    pub field: u32,
+    pub timestamp: timeval,
+    pub timecode: v4l2_timecode,
+    pub sequence: u32,
+
+    // memory location
+    pub memory: u32,
+    pub m: anon_buffer_m,
+    pub length: u32,
+    pub reserved2: u32,
+    pub anon_union: u32 // technically u32/s32
+}
+
+impl Default for v4l2_buffer {
+    fn default() -> Self {
+        v4l2_buffer {
+            index: 0,
+            type_: 0,
+            bytesused: 0,
+            flags: 0,
+            field: 0,
+            timestamp: timeval{tv_sec: 0, tv_usec: 0},
+            timecode: Default::default(),
+            sequence: 0,
+            memory: 0,
+            m: anon_buffer_m { offset: 0 },
+            length: 0,
+            reserved2: 0,
+            anon_union: 0
+        }
+    }
+}
+
+// #define v4l2_fourcc(a, b, c, d) \
+//      ((__u32)(a) | ((__u32)(b) << 8) | ((__u32)(c) << 16) | ((__u32)(d) << 24))
+pub const fn v4l2_fourcc(a: u8, b: u8, c: u8, d: u8) -> u32 {
+    (a as u32) | (b as u32) << 8 | (c as u32) << 16 | (d as u32) << 24
+}
+
+pub const V4L2_BUF_TYPE_VIDEO_CAPTURE: usize = 1;
+pub const V4L2_MEMORY_MMAP: usize = 1;
+pub const V4L2_PIX_FMT_MJPG: u32 = v4l2_fourcc(b'M', b'J', b'P', b'G');
+// const V4L2_PIX_FMT_RGB24: usize = 0x52474233;
+// const V4L2_PIX_FMT_YUV420: usize = 0x32315559;
+// const V4L2_PIX_FMT_VYUY: usize = 0x56595559;
+// const V4L2_FIELD_INTERLACED: usize = 4;
+// const V4L2_FIELD_ANY: usize = 4;
+
+ioctl_readwrite!(vidioc_reqbufs, b'V', 8, v4l2_requestbuffers);
+ioctl_readwrite!(vidioc_querybuf, b'V', 9, v4l2_buffer);
+ioctl_readwrite!(vidioc_qbuf, b'V', 15, v4l2_buffer);
+// ioctl_write_int!(vidioc_streamon, b'V', 18);
+// ioctl_write_int!(vidioc_streamoff, b'V', 19);
+ioctl_readwrite!(vidioc_dqbuf, b'V', 17, v4l2_buffer);
+ioctl_readwrite!(vidioc_g_fmt, b'V', 4, v4l2_format);
+ioctl_readwrite!(vidioc_s_fmt, b'V', 5, v4l2_format);
+ioctl_write_ptr!(vidioc_streamon, b'V', 18, c_int);
+ioctl_write_ptr!(vidioc_streamoff, b'V', 19, c_int);
+
+// The device is opened with O_NONBLOCK, so EAGAIN is handed back to the caller, who is expected
+// to wait for readiness with poll() instead of spinning on the ioctl.
+unsafe fn xioctl<T>(myfn: unsafe fn(c_int, *mut T) -> Result<i32, Errno>, fd: c_int,
+                    arg: *mut T) -> Result<c_int, Errno> {
+    loop {
+        let result = myfn(fd, arg);
+        match result {
+            Ok(retval) => {return Ok(retval);}
+            Err(Errno::EINTR) => {}
+            Err(other) => { return Err(other) }
+        }
+    }
+}
+
+unsafe fn xioctl_const<T>(myfn: unsafe fn(c_int, *const T) -> Result<i32, Errno>, fd: c_int,
+                    arg: *const T) -> Result<c_int, Errno> {
+    loop {
+        let result = myfn(fd, arg);
+        match result {
+            Ok(retval) => {return Ok(retval);}
+            Err(Errno::EINTR) => {}
+            Err(other) => { return Err(other) }
+        }
+    }
+}
+
+// Errors from waiting on / dequeueing frames.
+
+#[derive(Debug)]
+pub enum CaptureError {
+    // No frame was ready within the given number of milliseconds.
+    Timeout(i32),
+    // poll() reported POLLERR/POLLHUP/POLLNVAL on the device, e.g. the camera was unplugged.
+    Device(PollFlags),
+    Errno(Errno),
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CaptureError::Timeout(ms) => write!(f, "no frame within {} ms", ms),
+            CaptureError::Device(flags) => write!(f, "capture device error: {:?}", flags),
+            CaptureError::Errno(e) => write!(f, "capture ioctl failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
+impl From<Errno> for CaptureError {
+    fn from(e: Errno) -> Self {
+        CaptureError::Errno(e)
+    }
+}
+
+impl From<CaptureError> for std::io::Error {
+    fn from(e: CaptureError) -> Self {
+        match e {
+            CaptureError::Errno(errno) => errno.into(),
+            CaptureError::Timeout(_) => std::io::Error::new(std::io::ErrorKind::TimedOut, e),
+            CaptureError::Device(_) => std::io::Error::other(e),
+        }
+    }
+}
+
+// Everything VideoHandler needs from a capture device. Device is the real thing; tests plug in
+// v4l2_fake::FakeDevice (with the fake-v4l2 feature) to exercise the same code without a camera.
+//
+/// # Safety
+///
+/// VideoHandler reads frames straight out of the memory map() returns. It has to stay valid for
+/// reads of the buffer's length until it is passed to unmap(), and none of the safe methods may
+/// move or free it in the meantime.
+pub unsafe trait Backend {
+    fn g_fmt(&mut self, fmt: &mut v4l2_format) -> Result<(), Errno>;
+    fn s_fmt(&mut self, fmt: &mut v4l2_format) -> Result<(), Errno>;
+    fn reqbufs(&mut self, req: &mut v4l2_requestbuffers) -> Result<(), Errno>;
+    fn querybuf(&mut self, buf: &mut v4l2_buffer) -> Result<(), Errno>;
+    fn qbuf(&mut self, buf: &mut v4l2_buffer) -> Result<(), Errno>;
+    fn dqbuf(&mut self, buf: &mut v4l2_buffer) -> Result<(), Errno>;
+    fn streamon(&mut self) -> Result<(), Errno>;
+    fn streamoff(&mut self) -> Result<(), Errno>;
+
+    /// Maps the buffer described by a QUERYBUF result into our address space.
+    ///
+    /// # Safety
+    ///
+    /// `buf` has to be what QUERYBUF returned for one of this backend's buffers.
+    unsafe fn map(&mut self, buf: &v4l2_buffer) -> Result<*mut c_void, Errno>;
+
+    /// # Safety
+    ///
+    /// `start` and `length` have to be a mapping map() returned and that has not been unmapped
+    /// yet; nothing may touch that memory afterwards.
+    unsafe fn unmap(&mut self, start: *mut c_void, length: size_t) -> Result<(), Errno>;
+
+    // Descriptor that polls readable once a filled buffer can be dequeued.
+    fn poll_fd(&self) -> RawFd;
+}
+
+// A V4L2 device node, driven through real ioctls.
+pub struct Device {
+    fd: c_int
+}
+
+impl Device {
+    pub fn open(path: &str) -> Result<Device, Errno> {
+        let fd = open(path, OFlag::O_NONBLOCK.union(OFlag::O_RDWR), Mode::S_IRUSR.union(Mode::S_IWUSR))?;
+        Ok(Device{fd})
+    }
+}
+
+// SAFETY: The mappings are the kernel's; they stay valid until munmap(), whatever else is done
+// with the descriptor.
+unsafe impl Backend for Device {
+    fn g_fmt(&mut self, fmt: &mut v4l2_format) -> Result<(), Errno> {
+        unsafe { xioctl(vidioc_g_fmt, self.fd, fmt as *mut v4l2_format)?; }
+        Ok(())
+    }
+
+    fn s_fmt(&mut self, fmt: &mut v4l2_format) -> Result<(), Errno> {
+        unsafe { xioctl(vidioc_s_fmt, self.fd, fmt as *mut v4l2_format)?; }
+        Ok(())
+    }
+
+    fn reqbufs(&mut self, req: &mut v4l2_requestbuffers) -> Result<(), Errno> {
+        unsafe { xioctl(vidioc_reqbufs, self.fd, req as *mut v4l2_requestbuffers)?; }
+        Ok(())
+    }
+
+    fn querybuf(&mut self, buf: &mut v4l2_buffer) -> Result<(), Errno> {
+        unsafe { xioctl(vidioc_querybuf, self.fd, buf as *mut v4l2_buffer)?; }
+        Ok(())
+    }
+
+    fn qbuf(&mut self, buf: &mut v4l2_buffer) -> Result<(), Errno> {
+        unsafe { xioctl(vidioc_qbuf, self.fd, buf as *mut v4l2_buffer)?; }
+        Ok(())
+    }
+
+    fn dqbuf(&mut self, buf: &mut v4l2_buffer) -> Result<(), Errno> {
+        unsafe { xioctl(vidioc_dqbuf, self.fd, buf as *mut v4l2_buffer)?; }
+        Ok(())
+    }
+
+    fn streamon(&mut self) -> Result<(), Errno> {
+        let type_ = V4L2_BUF_TYPE_VIDEO_CAPTURE as i32;
+        unsafe { xioctl_const(vidioc_streamon, self.fd, &type_ as *const i32)?; }
+        Ok(())
+    }
+
+    fn streamoff(&mut self) -> Result<(), Errno> {
+        let type_ = V4L2_BUF_TYPE_VIDEO_CAPTURE as i32;
+        unsafe { xioctl_const(vidioc_streamoff, self.fd, &type_ as *const i32)?; }
+        Ok(())
+    }
+
+    unsafe fn map(&mut self, buf: &v4l2_buffer) -> Result<*mut c_void, Errno> {
+        // SAFETY: A fresh shared mapping of the device at the offset the driver gave us; it does
+        // not alias anything of ours.
+        unsafe {
+            mmap(std::ptr::null_mut(), buf.length as usize,
+                 ProtFlags::PROT_READ.union(ProtFlags::PROT_WRITE),
+                 MapFlags::MAP_SHARED,
+                 self.fd, buf.m.offset as i64)
+        }
+    }
+
+    unsafe fn unmap(&mut self, start: *mut c_void, length: size_t) -> Result<(), Errno> {
+        // SAFETY: The caller hands us a mapping of ours that nothing uses any more.
+        unsafe { munmap(start, length) }
+    }
+
+    fn poll_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        close(self.fd).unwrap();
+    }
+}
+
+// Definition of handler struct
+
+// A buffer as mapped by Backend::map; valid until the handler is dropped.
+#[derive(Copy, Clone)]
+struct FrameBuffer {
+    start: *mut c_void,
+    length: size_t
+}
+
+pub struct VideoHandler<B: Backend = Device> {
+    backend: B,
+    buffers: [FrameBuffer; N_BUFFERS],
+    timeout_ms: i32,
+    width: u32,
+    height: u32,
+    pixelformat: u32
+}
+
+// What to ask the driver for. Fields left as None keep whatever the device currently reports.
+#[derive(Clone, Debug)]
+pub struct VideoConfig {
+    pub path: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub pixelformat: Option<u32>,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        VideoConfig {
+            path: "/dev/video0".to_string(),
+            width: None,
+            height: None,
+            pixelformat: None,
+        }
+    }
+}
+
+impl VideoHandler {
+    pub fn new() -> Result<VideoHandler, Errno> {
+        VideoHandler::with_config(&Default::default())
+    }
+
+    pub fn with_config(config: &VideoConfig) -> Result<VideoHandler, Errno> {
+        VideoHandler::with_backend(Device::open(config.path.as_str())?, config)
+    }
+}
+
+impl<B: Backend> VideoHandler<B> {
+    // Negotiates the format, maps N_BUFFERS buffers, queues them and starts streaming.
+    // config.path is ignored; the backend is already open.
+    pub fn with_backend(mut backend: B, config: &VideoConfig) -> Result<VideoHandler<B>, Errno> {
+        let mut gfmt = v4l2_format {
+            type_: V4L2_BUF_TYPE_VIDEO_CAPTURE as u32,
+            space: 0,
+            width: W as u32,
+            height: H as u32,
+            pixelformat: 0,
+            field: 0,
+            bytesperline: 0,
+            sizeimage: 0,
+            colorspace: 0,
+            priv_: 0,
+            others: [0; FORMAT_PADDING]
+        };
+        backend.g_fmt(&mut gfmt)?;
+
+        if config.width.is_some() || config.height.is_some() || config.pixelformat.is_some() {
+            gfmt.width = config.width.unwrap_or(gfmt.width);
+            gfmt.height = config.height.unwrap_or(gfmt.height);
+            gfmt.pixelformat = config.pixelformat.unwrap_or(gfmt.pixelformat);
+            backend.s_fmt(&mut gfmt)?;
+        }
+        println!("gfmt: type {} space {} width {} height {} pixfmt 0x{:x} field {}
+            bytesperline {} sizeimage {} colorspace {} priv {}",
+                 gfmt.type_,
+                 gfmt.space,
+                 gfmt.width,
+                 gfmt.height,
+                 gfmt.pixelformat,
+                 gfmt.field,
+                 gfmt.bytesperline,
+                 gfmt.sizeimage,
+                 gfmt.colorspace,
+                 gfmt.priv_
+        );
+
+        let mut req = v4l2_requestbuffers {
+            count: N_BUFFERS as u32,
+            type_: V4L2_BUF_TYPE_VIDEO_CAPTURE as u32,
+            memory: V4L2_MEMORY_MMAP as u32,
+            ..Default::default()
+        };
+        backend.reqbufs(&mut req)?;
+        if (req.count as usize) < N_BUFFERS {
+            return Err(Errno::ENOMEM);
+        }
+
+        // Owns the buffers from here on, so Drop unmaps the ones mapped so far if a later step fails.
+        let mut handler = VideoHandler{
+            backend,
+            buffers: [FrameBuffer{start: std::ptr::null_mut(), length: 0}; N_BUFFERS],
+            timeout_ms: DEFAULT_TIMEOUT_MS,
+            width: gfmt.width,
+            height: gfmt.height,
+            pixelformat: gfmt.pixelformat
+        };
+        let backend = &mut handler.backend;
+
+        for (i, buffer) in handler.buffers.iter_mut().enumerate() {
+            let mut buf = v4l2_buffer {
+                index: i as u32,
+                type_: V4L2_BUF_TYPE_VIDEO_CAPTURE as u32,
+                memory: V4L2_MEMORY_MMAP as u32,
+                ..Default::default()
+            };
+
+            backend.querybuf(&mut buf)?;
+
+            buffer.length = buf.length as usize;
+            // SAFETY: buf is the QUERYBUF result for buffer i.
+            buffer.start = unsafe { backend.map(&buf)? };
+        }
+
+        for i in 0..N_BUFFERS {
+            let mut buf = v4l2_buffer {
+                type_: V4L2_BUF_TYPE_VIDEO_CAPTURE as u32,
+                memory: V4L2_MEMORY_MMAP as u32,
+                index: i as u32,
+                ..Default::default()
+            };
+
+            backend.qbuf(&mut buf)?;
+        }
+
+        backend.streamon()?;
+
+        Ok(handler)
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    // Only for VideoHandler<FakeDevice>::fake_mut, as other backends may not be safe to drive
+    // behind the handler's back.
+    #[cfg(feature = "fake-v4l2")]
+    pub(crate) fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    // Number of buffers cycled through the driver.
+    pub fn buffer_count(&self) -> usize {
+        self.buffers.len()
+    }
+
+    // Format the driver actually settled on, as (width, height, fourcc).
+    pub fn format(&self) -> (u32, u32, u32) {
+        (self.width, self.height, self.pixelformat)
+    }
+
+    // Timeout used by wait_frame()/frame(). A negative value waits forever.
+    pub fn set_timeout(&mut self, timeout_ms: i32) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    pub fn timeout(&self) -> i32 {
+        self.timeout_ms
+    }
+
+    // Sleeps in poll() until the camera or any of `others` (e.g. the server socket) is readable.
+    // ready[0] is the camera, ready[1..] follow the order of `others`. Fails with
+    // CaptureError::Timeout if nothing became ready within the configured timeout.
+    pub fn wait_any(&self, others: &[RawFd]) -> Result<Vec<bool>, CaptureError> {
+        let mut fds = vec![PollFd::new(self.backend.poll_fd(), PollFlags::POLLIN)];
+        for fd in others {
+            fds.push(PollFd::new(*fd, PollFlags::POLLIN));
+        }
+
+        // A signal restarts the wait with what is left of the timeout, so it still bounds the call.
+        let deadline = (self.timeout_ms >= 0)
+            .then(|| Instant::now() + Duration::from_millis(self.timeout_ms as u64));
+        let n = loop {
+            let timeout_ms = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()).as_millis() as i32,
+                None => -1,
+            };
+            match poll(&mut fds, timeout_ms) {
+                Ok(n) => break n,
+                Err(Errno::EINTR) => {}
+                Err(e) => return Err(e.into()),
+            }
+        };
+        if n == 0 {
+            return Err(CaptureError::Timeout(self.timeout_ms));
+        }
+
+        let bad = PollFlags::POLLERR | PollFlags::POLLHUP | PollFlags::POLLNVAL;
+        let revents = fds[0].revents().unwrap_or(PollFlags::empty());
+        if revents.intersects(bad) {
+            return Err(CaptureError::Device(revents));
+        }
+
+        Ok(fds.iter()
+            .map(|fd| fd.revents().is_some_and(|r| r.contains(PollFlags::POLLIN)))
+            .collect())
+    }
+
+    // Blocks (without spinning) until a filled buffer can be dequeued.
+    pub fn wait_frame(&self) -> Result<(), CaptureError> {
+        self.wait_any(&[])?;
+        Ok(())
+    }
+
+    // Dequeues one frame, copies it out and hands the buffer back to the driver.
+    pub fn frame(&mut self) -> Result<Vec<u8>, CaptureError> {
+        let mut buf = v4l2_buffer {
+            type_: V4L2_BUF_TYPE_VIDEO_CAPTURE as u32,
+            memory: V4L2_MEMORY_MMAP as u32,
+            ..Default::default()
+        };
+
+        // POLLIN can race with another reader; go back to waiting if the queue is empty again.
+        loop {
+            self.wait_frame()?;
+            match self.backend.dqbuf(&mut buf) {
+                Ok(_) => break,
+                Err(Errno::EAGAIN) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let slot = *self.buffers.get(buf.index as usize).ok_or(Errno::EINVAL)?;
+        if buf.bytesused as usize > slot.length {
+            return Err(Errno::EOVERFLOW.into());
+        }
+        let mut ans_vec = vec![0; buf.bytesused as usize];
+
+        // SAFETY: slot was mapped by with_backend and stays mapped until drop, and bytesused was
+        // checked against its length above.
+        unsafe {copy_nonoverlapping::<u8>(
+            slot.start as *const u8,
+            ans_vec.as_mut_ptr(),
+            buf.bytesused as usize
+        ); }
+
+        self.backend.qbuf(&mut buf)?;
+
+        Ok(ans_vec)
+    }
+}
+
+impl<B: Backend> AsRawFd for VideoHandler<B> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.backend.poll_fd()
+    }
+}
+
+// The device itself is closed when the backend is dropped, right after this.
+impl<B: Backend> Drop for VideoHandler<B> {
+    fn drop(&mut self) {
+        if let Err(e) = self.backend.streamoff() {
+            println!("streamoff failed: {}", e);
+        }
+        // Null past the last buffer mapped when with_backend failed half way.
+        for (i, buffer) in self.buffers.iter().enumerate().filter(|(_, b)| !b.start.is_null()) {
+            // SAFETY: Mapped by with_backend, and nothing reads it after this.
+            if let Err(e) = unsafe { self.backend.unmap(buffer.start, buffer.length) } {
+                println!("munmap of buffer {} failed: {}", i, e);
+            }
+        }
+    }
+}
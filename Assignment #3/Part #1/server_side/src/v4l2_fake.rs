@@ -0,0 +1,305 @@
+//! In-process stand-in for a V4L2 capture driver, so VideoHandler/V4l2Source can be driven without
+//! a camera. It keeps the parts of the driver contract the handler relies on: format negotiation,
+//! MMAP buffers, the queued/dequeued cycle, streaming state and the usual errnos. Frames are
+//! "captured" the moment a queued buffer is dequeued.
+//!
+//! Readiness is signalled through a pipe, so poll() on poll_fd() behaves like on a real device.
+//! Only built with the fake-v4l2 feature, which the tests turn on.
+
+use std::collections::VecDeque;
+use std::ffi::c_void;
+use std::os::unix::io::RawFd;
+
+use nix::errno::Errno;
+use nix::libc::size_t;
+use nix::unistd::{close, pipe, read, write};
+
+use crate::v4l2::{Backend, v4l2_buffer, v4l2_format, v4l2_requestbuffers, VideoHandler,
+                  V4L2_BUF_TYPE_VIDEO_CAPTURE, V4L2_MEMORY_MMAP};
+
+const MAX_BUFFERS: u32 = 8;
+
+// Operations that can be made to fail with fail_next().
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FakeOp {
+    GFmt,
+    SFmt,
+    ReqBufs,
+    QueryBuf,
+    QBuf,
+    DqBuf,
+    StreamOn,
+    StreamOff,
+}
+
+pub struct FakeDevice {
+    // Supported (width, height, fourcc), the first entry is the power-on format.
+    formats: Vec<(u32, u32, u32)>,
+    current: (u32, u32, u32),
+    buffers: Vec<Vec<u8>>,
+    queued: VecDeque<u32>,
+    mapped: usize,
+    streaming: bool,
+    stalled: bool,
+    sequence: u32,
+    frames: Box<dyn FnMut(u32) -> Vec<u8>>,
+    failures: Vec<(FakeOp, Errno)>,
+    ready_r: RawFd,
+    ready_w: Option<RawFd>,
+    signalled: bool,
+}
+
+impl FakeDevice {
+    // `frames` produces the payload of the n-th captured frame; it is cut to the buffer size.
+    pub fn new(formats: Vec<(u32, u32, u32)>, frames: Box<dyn FnMut(u32) -> Vec<u8>>) -> FakeDevice {
+        assert!(!formats.is_empty());
+        let (ready_r, ready_w) = pipe().expect("pipe [FAILED]");
+
+        FakeDevice {
+            current: formats[0],
+            formats,
+            buffers: vec![],
+            queued: VecDeque::new(),
+            mapped: 0,
+            streaming: false,
+            stalled: false,
+            sequence: 0,
+            frames,
+            failures: vec![],
+            ready_r,
+            ready_w: Some(ready_w),
+            signalled: false,
+        }
+    }
+
+    // The next call of `op` fails with `errno` instead of doing anything.
+    pub fn fail_next(&mut self, op: FakeOp, errno: Errno) {
+        self.failures.push((op, errno));
+    }
+
+    // While stalled, queued buffers never fill: poll() times out and DQBUF returns EAGAIN.
+    pub fn set_stalled(&mut self, stalled: bool) {
+        self.stalled = stalled;
+        self.sync_ready();
+    }
+
+    // Simulates the camera disappearing: poll() reports POLLHUP from now on.
+    pub fn hangup(&mut self) {
+        if let Some(fd) = self.ready_w.take() {
+            close(fd).expect("close [FAILED]");
+        }
+    }
+
+    pub fn streaming(&self) -> bool {
+        self.streaming
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.len()
+    }
+
+    pub fn mapped(&self) -> usize {
+        self.mapped
+    }
+
+    pub fn allocated(&self) -> usize {
+        self.buffers.len()
+    }
+
+    // Number of frames handed out so far.
+    pub fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    fn check(&mut self, op: FakeOp) -> Result<(), Errno> {
+        match self.failures.iter().position(|(o, _)| *o == op) {
+            Some(i) => Err(self.failures.remove(i).1),
+            None => Ok(()),
+        }
+    }
+
+    fn sizeimage(&self) -> u32 {
+        self.current.0 * self.current.1 * 2
+    }
+
+    // Keeps exactly one byte in the pipe while a buffer can be dequeued, none otherwise.
+    fn sync_ready(&mut self) {
+        let ready = self.streaming && !self.stalled && !self.queued.is_empty();
+        let ready_w = match self.ready_w {
+            Some(fd) => fd,
+            None => return,
+        };
+
+        if ready && !self.signalled {
+            write(ready_w, &[1]).expect("write [FAILED]");
+            self.signalled = true;
+        } else if !ready && self.signalled {
+            let mut byte = [0u8; 1];
+            read(self.ready_r, &mut byte).expect("read [FAILED]");
+            self.signalled = false;
+        }
+    }
+
+    fn fill_format(&self, fmt: &mut v4l2_format) {
+        let (width, height, pixelformat) = self.current;
+        fmt.width = width;
+        fmt.height = height;
+        fmt.pixelformat = pixelformat;
+        fmt.bytesperline = width * 2;
+        fmt.sizeimage = self.sizeimage();
+    }
+}
+
+// SAFETY: Mapped buffers are the Vecs in `buffers`, which only reqbufs replaces, and it refuses
+// (EBUSY) while any of them is mapped.
+unsafe impl Backend for FakeDevice {
+    fn g_fmt(&mut self, fmt: &mut v4l2_format) -> Result<(), Errno> {
+        self.check(FakeOp::GFmt)?;
+        if fmt.type_ != V4L2_BUF_TYPE_VIDEO_CAPTURE as u32 {
+            return Err(Errno::EINVAL);
+        }
+        self.fill_format(fmt);
+        Ok(())
+    }
+
+    // Like real drivers: unknown fourccs fall back to the default one and the size snaps to the
+    // closest supported resolution. Changing format with buffers allocated is EBUSY.
+    fn s_fmt(&mut self, fmt: &mut v4l2_format) -> Result<(), Errno> {
+        self.check(FakeOp::SFmt)?;
+        if fmt.type_ != V4L2_BUF_TYPE_VIDEO_CAPTURE as u32 {
+            return Err(Errno::EINVAL);
+        }
+        if !self.buffers.is_empty() {
+            return Err(Errno::EBUSY);
+        }
+
+        let pixelformat = if self.formats.iter().any(|f| f.2 == fmt.pixelformat) {
+            fmt.pixelformat
+        } else {
+            self.formats[0].2
+        };
+        let area = fmt.width as i64 * fmt.height as i64;
+        self.current = *self.formats.iter()
+            .filter(|f| f.2 == pixelformat)
+            .min_by_key(|f| ((f.0 as i64 * f.1 as i64) - area).abs())
+            .unwrap();
+
+        self.fill_format(fmt);
+        Ok(())
+    }
+
+    fn reqbufs(&mut self, req: &mut v4l2_requestbuffers) -> Result<(), Errno> {
+        self.check(FakeOp::ReqBufs)?;
+        if req.type_ != V4L2_BUF_TYPE_VIDEO_CAPTURE as u32 || req.memory != V4L2_MEMORY_MMAP as u32 {
+            return Err(Errno::EINVAL);
+        }
+        if self.streaming || self.mapped > 0 {
+            return Err(Errno::EBUSY);
+        }
+
+        req.count = req.count.min(MAX_BUFFERS);
+        self.queued.clear();
+        self.buffers = (0..req.count).map(|_| vec![0; self.sizeimage() as usize]).collect();
+        self.sync_ready();
+        Ok(())
+    }
+
+    fn querybuf(&mut self, buf: &mut v4l2_buffer) -> Result<(), Errno> {
+        self.check(FakeOp::QueryBuf)?;
+        let length = self.buffers.get(buf.index as usize).ok_or(Errno::EINVAL)?.len();
+        buf.length = length as u32;
+        buf.m.offset = buf.index * length as u32;
+        Ok(())
+    }
+
+    fn qbuf(&mut self, buf: &mut v4l2_buffer) -> Result<(), Errno> {
+        self.check(FakeOp::QBuf)?;
+        if buf.index as usize >= self.buffers.len() || self.queued.contains(&buf.index) {
+            return Err(Errno::EINVAL);
+        }
+        self.queued.push_back(buf.index);
+        self.sync_ready();
+        Ok(())
+    }
+
+    fn dqbuf(&mut self, buf: &mut v4l2_buffer) -> Result<(), Errno> {
+        self.check(FakeOp::DqBuf)?;
+        if !self.streaming {
+            return Err(Errno::EINVAL);
+        }
+        if self.stalled {
+            return Err(Errno::EAGAIN);
+        }
+        let index = self.queued.pop_front().ok_or(Errno::EAGAIN)?;
+
+        let slot = &mut self.buffers[index as usize];
+        let mut payload = (self.frames)(self.sequence);
+        payload.truncate(slot.len());
+        slot[..payload.len()].copy_from_slice(&payload);
+
+        buf.index = index;
+        buf.bytesused = payload.len() as u32;
+        buf.length = slot.len() as u32;
+        buf.sequence = self.sequence;
+        self.sequence += 1;
+
+        self.sync_ready();
+        Ok(())
+    }
+
+    fn streamon(&mut self) -> Result<(), Errno> {
+        self.check(FakeOp::StreamOn)?;
+        if self.buffers.is_empty() {
+            return Err(Errno::EINVAL);
+        }
+        self.streaming = true;
+        self.sync_ready();
+        Ok(())
+    }
+
+    // As in the V4L2 spec, stopping the stream returns every buffer to userspace.
+    fn streamoff(&mut self) -> Result<(), Errno> {
+        self.check(FakeOp::StreamOff)?;
+        self.streaming = false;
+        self.queued.clear();
+        self.sync_ready();
+        Ok(())
+    }
+
+    unsafe fn map(&mut self, buf: &v4l2_buffer) -> Result<*mut c_void, Errno> {
+        let slot = self.buffers.get_mut(buf.index as usize).ok_or(Errno::EINVAL)?;
+        self.mapped += 1;
+        Ok(slot.as_mut_ptr() as *mut c_void)
+    }
+
+    unsafe fn unmap(&mut self, start: *mut c_void, _length: size_t) -> Result<(), Errno> {
+        if !self.buffers.iter_mut().any(|b| b.as_mut_ptr() as *mut c_void == start) || self.mapped == 0 {
+            return Err(Errno::EINVAL);
+        }
+        self.mapped -= 1;
+        Ok(())
+    }
+
+    fn poll_fd(&self) -> RawFd {
+        self.ready_r
+    }
+}
+
+impl VideoHandler<FakeDevice> {
+    // The fake behind the handler, to stall it, make calls fail or drive it directly. Nothing the
+    // fake does safely can pull the mapped buffers out from under the handler.
+    pub fn fake_mut(&mut self) -> &mut FakeDevice {
+        self.backend_mut()
+    }
+}
+
+impl Drop for FakeDevice {
+    fn drop(&mut self) {
+        // A leaked mapping would outlive the device; catch it here rather than let tests pass.
+        if !std::thread::panicking() {
+            assert_eq!(self.mapped, 0, "FakeDevice dropped with buffers still mapped");
+        }
+        self.hangup();
+        close(self.ready_r).expect("close [FAILED]");
+    }
+}
@@ -0,0 +1,157 @@
+use std::io::{Cursor, ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::thread;
+
+use server_side::boundary::{accept, dequantize, handshake, read_frame, write_frame, BoundarySpec, DType, Quantization};
+
+// Handshake and framing of the partition-boundary tensor between the two model halves.
+
+fn float32() -> BoundarySpec {
+    BoundarySpec { dtype: DType::Float32, shape: vec![1, 96, 96, 16], quantization: None }
+}
+
+fn int8() -> BoundarySpec {
+    BoundarySpec {
+        dtype: DType::Int8,
+        shape: vec![1, 96, 96, 16],
+        quantization: Some(Quantization { scale: 0.0235, zero_point: -128 }),
+    }
+}
+
+// Runs `accept` against `expected` on one end of a socket pair while the client offers `offered`.
+fn connect(offered: &BoundarySpec, expected: &BoundarySpec)
+           -> (std::io::Result<()>, std::io::Result<BoundarySpec>, UnixStream, UnixStream) {
+    let (mut client, mut server) = UnixStream::pair().unwrap();
+    let expected = expected.clone();
+    let server_side = thread::spawn(move || {
+        let result = accept(&mut server, &expected);
+        (result, server)
+    });
+    let client_result = handshake(&mut client, offered);
+    let (server_result, server) = server_side.join().unwrap();
+    (client_result, server_result, client, server)
+}
+
+#[test]
+fn matching_specs_are_accepted() {
+    let (client, server, _, _) = connect(&int8(), &int8());
+    client.unwrap();
+    assert_eq!(server.unwrap(), int8());
+}
+
+#[test]
+fn mismatch_is_rejected_and_the_client_hears_why() {
+    let (client, server, _, _) = connect(&float32(), &int8());
+    let client = client.unwrap_err();
+    assert_eq!(client.kind(), ErrorKind::ConnectionRefused);
+    let message = client.to_string();
+    assert!(message.contains("dtype Float32 vs Int8"), "{}", message);
+    assert!(message.contains("quantization"), "{}", message);
+    assert_eq!(server.unwrap_err().kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn old_clients_are_rejected() {
+    // Raw f32s straight away, as the client sent before the handshake.
+    let mut stream = Cursor::new(vec![0u8; 64]);
+    let err = accept(&mut stream, &float32()).unwrap_err();
+    assert!(err.to_string().contains("old client"), "{}", err);
+    // Only the 5 byte hello head was read; the rejection was still written back after it.
+    assert_eq!(stream.position(), 5 + 5 + err.to_string().len() as u64);
+    assert_eq!(stream.get_ref()[5], 1);
+}
+
+#[test]
+fn check_lists_every_difference() {
+    let mut other = int8();
+    other.shape = vec![1, 48, 48, 16];
+    other.quantization = Some(Quantization { scale: 0.5, zero_point: 0 });
+    let err = int8().check(&other).unwrap_err().to_string();
+    assert!(err.contains("shape [1, 96, 96, 16] vs [1, 48, 48, 16]"), "{}", err);
+    assert!(err.contains("quantization"), "{}", err);
+    assert!(!err.contains("dtype"), "{}", err);
+
+    // Rounding in the splitter's JSON round trip is not a mismatch.
+    let mut rounded = int8();
+    rounded.quantization = Some(Quantization { scale: 0.0235 * (1.0 + 2e-6), zero_point: -128 });
+    int8().check(&rounded).unwrap();
+}
+
+#[test]
+fn frames_round_trip() {
+    for spec in [float32(), int8()] {
+        let (client, server, mut client_end, mut server_end) = connect(&spec, &spec);
+        client.unwrap();
+        server.unwrap();
+
+        // Larger than the socket buffer, so write while the server reads.
+        let data: Vec<u8> = (0..spec.byte_len()).map(|i| i as u8).collect();
+        let writer = {
+            let (spec, data) = (spec.clone(), data.clone());
+            thread::spawn(move || {
+                write_frame(&mut client_end, &spec, &data).unwrap();
+                client_end.flush().unwrap();
+            })
+        };
+
+        let mut received = vec![];
+        read_frame(&mut server_end, &spec, &mut received).unwrap();
+        writer.join().unwrap();
+        assert_eq!(received, data);
+    }
+    assert_eq!(int8().byte_len() * 4, float32().byte_len());
+}
+
+#[test]
+fn frame_headers_are_checked_against_the_handshake() {
+    let mut wire = vec![];
+    write_frame(&mut wire, &float32(), &vec![0; float32().byte_len()]).unwrap();
+    let err = read_frame(&mut Cursor::new(&wire), &int8(), &mut vec![]).unwrap_err();
+    assert!(err.to_string().contains("dtype Int8 vs Float32"), "{}", err);
+
+    // Same dtype, different scale.
+    let mut rescaled = int8();
+    rescaled.quantization = Some(Quantization { scale: 0.1, zero_point: -128 });
+    wire.clear();
+    write_frame(&mut wire, &rescaled, &vec![0; rescaled.byte_len()]).unwrap();
+    assert!(read_frame(&mut Cursor::new(&wire), &int8(), &mut vec![]).is_err());
+}
+
+#[test]
+fn wrong_lengths_are_refused() {
+    let spec = int8();
+    assert_eq!(write_frame(&mut vec![], &spec, &[0; 10]).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    // A header claiming the wrong length.
+    let mut wire = vec![];
+    write_frame(&mut wire, &spec, &vec![0; spec.byte_len()]).unwrap();
+    wire[9..17].copy_from_slice(&10u64.to_le_bytes());
+    let err = read_frame(&mut Cursor::new(&wire), &spec, &mut vec![]).unwrap_err();
+    assert!(err.to_string().contains("expected 147456"), "{}", err);
+
+    // Truncated payload.
+    let mut wire = vec![];
+    write_frame(&mut wire, &spec, &vec![0; spec.byte_len()]).unwrap();
+    wire.truncate(100);
+    assert_eq!(read_frame(&mut Cursor::new(&wire), &spec, &mut vec![]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn dequantize_uses_scale_and_zero_point() {
+    let spec = BoundarySpec {
+        dtype: DType::Int8,
+        shape: vec![4],
+        quantization: Some(Quantization { scale: 0.5, zero_point: -128 }),
+    };
+    assert_eq!(dequantize(&spec, &[0x80, 0x81, 0x00, 0x7f]), vec![0.0, 0.5, 64.0, 127.5]);
+
+    let spec = BoundarySpec { dtype: DType::Uint8, shape: vec![2], quantization: Some(Quantization { scale: 2.0, zero_point: 10 }) };
+    assert_eq!(dequantize(&spec, &[10, 12]), vec![0.0, 4.0]);
+
+    let mut floats = vec![];
+    for v in [1.5f32, -2.0] {
+        floats.extend_from_slice(&v.to_le_bytes());
+    }
+    let spec = BoundarySpec { dtype: DType::Float32, shape: vec![2], quantization: None };
+    assert_eq!(dequantize(&spec, &floats), vec![1.5, -2.0]);
+}
@@ -0,0 +1,264 @@
+use std::fs;
+use std::os::unix::io::AsRawFd;
+
+use nix::errno::Errno;
+use nix::poll::PollFlags;
+use nix::unistd::{close, pipe, write};
+
+use server_side::v4l2::{v4l2_buffer, v4l2_format, v4l2_fourcc, Backend, CaptureError, VideoConfig,
+                        VideoHandler, FORMAT_PADDING, V4L2_PIX_FMT_MJPG};
+use server_side::v4l2_fake::{FakeDevice, FakeOp};
+
+// Capture-path tests. Everything runs against the in-process fake driver; the ignored test at the
+// bottom runs against a vivid or v4l2loopback node instead.
+
+const YUYV: u32 = v4l2_fourcc(b'Y', b'U', b'Y', b'V');
+
+fn fake() -> FakeDevice {
+    FakeDevice::new(
+        vec![(640, 480, YUYV), (800, 448, V4L2_PIX_FMT_MJPG), (1280, 720, V4L2_PIX_FMT_MJPG)],
+        Box::new(|seq| vec![seq as u8; 16]),
+    )
+}
+
+fn config(width: u32, height: u32, pixelformat: u32) -> VideoConfig {
+    VideoConfig {
+        width: Some(width),
+        height: Some(height),
+        pixelformat: Some(pixelformat),
+        ..Default::default()
+    }
+}
+
+// FORMAT NEGOTIATION
+
+#[test]
+fn keeps_current_format_when_nothing_requested() {
+    let handler = VideoHandler::with_backend(fake(), &Default::default()).unwrap();
+    assert_eq!(handler.format(), (640, 480, YUYV));
+}
+
+#[test]
+fn negotiates_requested_format() {
+    let handler = VideoHandler::with_backend(fake(), &config(800, 448, V4L2_PIX_FMT_MJPG)).unwrap();
+    assert_eq!(handler.format(), (800, 448, V4L2_PIX_FMT_MJPG));
+}
+
+#[test]
+fn snaps_to_closest_supported_resolution() {
+    let handler = VideoHandler::with_backend(fake(), &config(1200, 700, V4L2_PIX_FMT_MJPG)).unwrap();
+    assert_eq!(handler.format(), (1280, 720, V4L2_PIX_FMT_MJPG));
+}
+
+#[test]
+fn unsupported_pixelformat_falls_back_to_default() {
+    let rgb3 = v4l2_fourcc(b'R', b'G', b'B', b'3');
+    let handler = VideoHandler::with_backend(fake(), &config(800, 448, rgb3)).unwrap();
+    assert_eq!(handler.format(), (640, 480, YUYV));
+}
+
+// BUFFER CYCLING AND STREAMING
+
+#[test]
+fn setup_maps_queues_and_starts_streaming() {
+    let handler = VideoHandler::with_backend(fake(), &Default::default()).unwrap();
+    assert!(handler.backend().streaming());
+    assert_eq!(handler.backend().allocated(), handler.buffer_count());
+    assert_eq!(handler.backend().mapped(), handler.buffer_count());
+    assert_eq!(handler.backend().queued(), handler.buffer_count());
+}
+
+#[test]
+fn buffers_cycle_through_the_driver() {
+    let mut handler = VideoHandler::with_backend(fake(), &Default::default()).unwrap();
+    let n = handler.buffer_count();
+
+    for seq in 0..(3 * n as u32) {
+        let frame = handler.frame().unwrap();
+        assert_eq!(frame, vec![seq as u8; 16]);
+        // Every dequeued buffer is handed straight back.
+        assert_eq!(handler.backend().queued(), n);
+    }
+    assert_eq!(handler.backend().sequence(), 3 * n as u32);
+}
+
+#[test]
+fn stream_off_returns_buffers_and_stops_frames() {
+    let mut handler = VideoHandler::with_backend(fake(), &Default::default()).unwrap();
+    handler.frame().unwrap();
+
+    handler.fake_mut().streamoff().unwrap();
+    assert!(!handler.backend().streaming());
+    assert_eq!(handler.backend().queued(), 0);
+
+    // Nothing is queued any more, so the device never becomes readable.
+    handler.set_timeout(20);
+    assert!(matches!(handler.frame(), Err(CaptureError::Timeout(20))));
+}
+
+#[test]
+fn stream_can_be_restarted() {
+    let mut handler = VideoHandler::with_backend(fake(), &Default::default()).unwrap();
+    handler.fake_mut().streamoff().unwrap();
+    for i in 0..handler.buffer_count() {
+        let mut buf = v4l2_buffer { index: i as u32, ..Default::default() };
+        handler.fake_mut().qbuf(&mut buf).unwrap();
+    }
+    handler.fake_mut().streamon().unwrap();
+
+    assert_eq!(handler.frame().unwrap(), vec![0; 16]);
+}
+
+#[test]
+fn format_cannot_change_while_buffers_are_allocated() {
+    let mut handler = VideoHandler::with_backend(fake(), &Default::default()).unwrap();
+    let mut fmt = v4l2_format {
+        type_: 1,
+        space: 0,
+        width: 800,
+        height: 448,
+        pixelformat: V4L2_PIX_FMT_MJPG,
+        field: 0,
+        bytesperline: 0,
+        sizeimage: 0,
+        colorspace: 0,
+        priv_: 0,
+        others: [0; FORMAT_PADDING],
+    };
+    assert_eq!(handler.fake_mut().s_fmt(&mut fmt), Err(Errno::EBUSY));
+}
+
+// ERROR PATHS
+
+#[test]
+fn setup_errors_are_returned() {
+    for (op, errno) in [
+        (FakeOp::GFmt, Errno::ENODEV),
+        (FakeOp::SFmt, Errno::EINVAL),
+        (FakeOp::ReqBufs, Errno::ENOMEM),
+        (FakeOp::QueryBuf, Errno::EINVAL),
+        (FakeOp::QBuf, Errno::EIO),
+        (FakeOp::StreamOn, Errno::ENOSPC),
+    ] {
+        let mut device = fake();
+        device.fail_next(op, errno);
+        // Dropping the fake with the result checks nothing mapped before the failure leaked.
+        let result = VideoHandler::with_backend(device, &config(800, 448, V4L2_PIX_FMT_MJPG));
+        assert_eq!(result.err(), Some(errno), "{:?}", op);
+    }
+}
+
+#[test]
+fn dequeue_error_is_returned() {
+    let mut handler = VideoHandler::with_backend(fake(), &Default::default()).unwrap();
+    handler.fake_mut().fail_next(FakeOp::DqBuf, Errno::EIO);
+    assert!(matches!(handler.frame(), Err(CaptureError::Errno(Errno::EIO))));
+
+    // The failure was one-off; capture carries on.
+    assert!(handler.frame().is_ok());
+}
+
+#[test]
+fn requeue_error_is_returned() {
+    let mut handler = VideoHandler::with_backend(fake(), &Default::default()).unwrap();
+    handler.fake_mut().fail_next(FakeOp::QBuf, Errno::EIO);
+    assert!(matches!(handler.frame(), Err(CaptureError::Errno(Errno::EIO))));
+}
+
+#[test]
+fn no_frame_within_timeout() {
+    let mut handler = VideoHandler::with_backend(fake(), &Default::default()).unwrap();
+    handler.fake_mut().set_stalled(true);
+    handler.set_timeout(30);
+
+    let err = handler.frame().unwrap_err();
+    assert!(matches!(err, CaptureError::Timeout(30)));
+    assert_eq!(err.to_string(), "no frame within 30 ms");
+
+    handler.fake_mut().set_stalled(false);
+    assert!(handler.frame().is_ok());
+}
+
+#[test]
+fn unplugged_device_is_reported() {
+    let mut handler = VideoHandler::with_backend(fake(), &Default::default()).unwrap();
+    handler.fake_mut().set_stalled(true);
+    handler.fake_mut().hangup();
+
+    match handler.frame() {
+        Err(CaptureError::Device(flags)) => assert!(flags.contains(PollFlags::POLLHUP)),
+        other => panic!("expected a device error, got {:?}", other.map(|f| f.len())),
+    }
+}
+
+#[test]
+fn drop_survives_failing_stream_off() {
+    let mut handler = VideoHandler::with_backend(fake(), &Default::default()).unwrap();
+    handler.fake_mut().fail_next(FakeOp::StreamOff, Errno::EIO);
+    drop(handler);
+}
+
+// EVENT LOOP
+
+#[test]
+fn waits_on_camera_and_socket_together() {
+    let mut handler = VideoHandler::with_backend(fake(), &Default::default()).unwrap();
+    let (r, w) = pipe().unwrap();
+
+    // Camera ready, "socket" quiet.
+    assert_eq!(handler.wait_any(&[r]).unwrap(), vec![true, false]);
+
+    // Camera stalled, "socket" has data.
+    handler.fake_mut().set_stalled(true);
+    write(w, b"x").unwrap();
+    assert_eq!(handler.wait_any(&[r]).unwrap(), vec![false, true]);
+    assert!(handler.as_raw_fd() >= 0);
+
+    close(r).unwrap();
+    close(w).unwrap();
+}
+
+// REAL VIRTUAL DEVICES (vivid / v4l2loopback)
+
+// Virtual capture candidates, if the vivid or v4l2loopback module is loaded.
+fn virtual_devices() -> Vec<(String, bool)> {
+    let mut nodes: Vec<_> = match fs::read_dir("/sys/class/video4linux") {
+        Ok(dir) => dir.filter_map(|e| e.ok()).collect(),
+        Err(_) => return vec![],
+    };
+    nodes.sort_by_key(|e| e.file_name());
+
+    nodes.into_iter().filter_map(|node| {
+        let name = fs::read_to_string(node.path().join("name")).unwrap_or_default();
+        let vivid = name.contains("vivid");
+        (vivid || name.contains("Dummy video device") || name.contains("loopback"))
+            .then(|| (format!("/dev/{}", node.file_name().to_string_lossy()), vivid))
+    }).collect()
+}
+
+#[test]
+#[ignore = "needs the vivid or v4l2loopback module (modprobe vivid), run with --ignored"]
+fn virtual_device_negotiates_and_streams() {
+    let devices = virtual_devices();
+    assert!(!devices.is_empty(), "no vivid/v4l2loopback device");
+
+    // vivid also registers output/metadata nodes; those cannot capture.
+    let (mut handler, vivid) = devices.iter().find_map(|(path, vivid)| {
+        let config = VideoConfig { path: path.clone(), ..config(640, 480, YUYV) };
+        match VideoHandler::with_config(&config) {
+            Ok(handler) => Some((handler, *vivid)),
+            Err(Errno::EINVAL) | Err(Errno::ENOTTY) => None,
+            Err(e) => panic!("setting up {} failed: {}", path, e),
+        }
+    }).expect("no virtual capture node");
+    let (width, height, _) = handler.format();
+    assert!(width > 0 && height > 0);
+
+    // v4l2loopback only produces frames while something writes into it.
+    if vivid {
+        handler.set_timeout(2000);
+        for _ in 0..(3 * handler.buffer_count()) {
+            assert!(!handler.frame().unwrap().is_empty());
+        }
+    }
+}
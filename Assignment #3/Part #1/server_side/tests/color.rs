@@ -0,0 +1,150 @@
+use server_side::color::{convert, convert_into, ColorSpace, Matrix, PixelFormat, Range};
+
+// Golden colour bars through every YUV layout, and the edge cases the old f64 conversion missed.
+
+const BT601_LIMITED: ColorSpace = ColorSpace { matrix: Matrix::Bt601, range: Range::Limited };
+const BT709_LIMITED: ColorSpace = ColorSpace { matrix: Matrix::Bt709, range: Range::Limited };
+const BT601_FULL: ColorSpace = ColorSpace { matrix: Matrix::Bt601, range: Range::Full };
+
+// 100% colour bars (white, yellow, cyan, green, magenta, red, blue, black) as (Y, U, V).
+const BARS_601: [[u8; 3]; 8] = [[235, 128, 128], [210, 16, 146], [170, 166, 16], [145, 54, 34],
+                                [106, 202, 222], [81, 90, 240], [41, 240, 110], [16, 128, 128]];
+const BARS_709: [[u8; 3]; 8] = [[235, 128, 128], [219, 16, 138], [188, 154, 16], [173, 42, 26],
+                                [78, 214, 230], [63, 102, 240], [32, 240, 118], [16, 128, 128]];
+const BARS_RGB: [[u8; 3]; 8] = [[255, 255, 255], [255, 255, 0], [0, 255, 255], [0, 255, 0],
+                                [255, 0, 255], [255, 0, 0], [0, 0, 255], [0, 0, 0]];
+
+// Each bar 2 pixels wide and 2 high, so every format carries exactly the same samples.
+const W: usize = 16;
+const H: usize = 2;
+
+fn bars(yuv: &[[u8; 3]; 8], format: PixelFormat) -> Vec<u8> {
+    let mut frame = vec![];
+    match format {
+        PixelFormat::Yuyv | PixelFormat::Uyvy => {
+            for _ in 0..H {
+                for [y, u, v] in yuv {
+                    let macropixel = if format == PixelFormat::Yuyv { [*y, *u, *y, *v] } else { [*u, *y, *v, *y] };
+                    frame.extend_from_slice(&macropixel);
+                }
+            }
+        }
+        PixelFormat::I420 | PixelFormat::Nv12 => {
+            for _ in 0..H {
+                frame.extend(yuv.iter().flat_map(|p| [p[0], p[0]]));
+            }
+            if format == PixelFormat::I420 {
+                frame.extend(yuv.iter().map(|p| p[1]));
+                frame.extend(yuv.iter().map(|p| p[2]));
+            } else {
+                frame.extend(yuv.iter().flat_map(|p| [p[1], p[2]]));
+            }
+        }
+        PixelFormat::Rgb24 | PixelFormat::Bgr24 => unreachable!(),
+    }
+    assert_eq!(frame.len(), format.frame_size(W, H));
+    frame
+}
+
+fn assert_bars(rgb: &[u8], what: &str) {
+    for (i, pixel) in rgb.chunks_exact(3).enumerate() {
+        let expected = BARS_RGB[(i % W) / 2];
+        for c in 0..3 {
+            assert!((pixel[c] as i32 - expected[c] as i32).abs() <= 2,
+                    "{}: pixel {} is {:?}, expected {:?}", what, i, pixel, expected);
+        }
+    }
+}
+
+const YUV_FORMATS: [PixelFormat; 4] = [PixelFormat::Yuyv, PixelFormat::Uyvy, PixelFormat::Nv12, PixelFormat::I420];
+
+#[test]
+fn bt601_limited_bars_in_every_layout() {
+    for format in YUV_FORMATS {
+        let rgb = convert(&bars(&BARS_601, format), format, W, H, BT601_LIMITED).unwrap();
+        assert_bars(&rgb, &format!("{:?}", format));
+    }
+}
+
+#[test]
+fn bt709_limited_bars_in_every_layout() {
+    for format in YUV_FORMATS {
+        let rgb = convert(&bars(&BARS_709, format), format, W, H, BT709_LIMITED).unwrap();
+        assert_bars(&rgb, &format!("{:?}", format));
+    }
+}
+
+#[test]
+fn matrix_and_range_change_the_result() {
+    let frame = bars(&BARS_601, PixelFormat::I420);
+    let bt601 = convert(&frame, PixelFormat::I420, W, H, BT601_LIMITED).unwrap();
+    let bt709 = convert(&frame, PixelFormat::I420, W, H, BT709_LIMITED).unwrap();
+    assert_ne!(bt601, bt709);
+
+    // JPEG red under full range, and limited black/white lifted to full swing.
+    let red = convert(&[76, 85, 76, 255], PixelFormat::Yuyv, 2, 1, BT601_FULL).unwrap();
+    assert!(red[0] >= 253 && red[1] <= 1 && red[2] <= 1, "{:?}", red);
+    let grey = convert(&[16, 128, 235, 128], PixelFormat::Yuyv, 2, 1, BT601_FULL).unwrap();
+    assert_eq!(grey, [16, 16, 16, 235, 235, 235]);
+}
+
+#[test]
+fn out_of_gamut_values_clamp_instead_of_wrapping() {
+    let rgb = convert(&[255, 255, 0, 255], PixelFormat::Yuyv, 2, 1, BT601_LIMITED).unwrap();
+    // Y=255 U=V=255: red and blue far above 255, green in range.
+    assert_eq!((rgb[0], rgb[2]), (255, 255));
+    assert!((rgb[1] as i32 - 125).abs() <= 1, "{:?}", rgb);
+
+    // Y=U=V=0: red and blue far below 0.
+    let rgb = convert(&[0, 0, 0, 0], PixelFormat::Yuyv, 2, 1, BT601_LIMITED).unwrap();
+    assert_eq!((rgb[0], rgb[2], rgb[3], rgb[5]), (0, 0, 0, 0));
+    assert!((rgb[1] as i32 - 136).abs() <= 1, "{:?}", rgb);
+}
+
+#[test]
+fn odd_sizes_round_chroma_up() {
+    // 3x3: 2x2 chroma samples, the last column/row sharing them with nobody.
+    assert_eq!(PixelFormat::I420.frame_size(3, 3), 9 + 2 * 4);
+    assert_eq!(PixelFormat::Nv12.frame_size(3, 3), 9 + 2 * 4);
+    assert_eq!(PixelFormat::Yuyv.frame_size(3, 3), 3 * 8);
+
+    let mut frame = vec![128; 9];
+    frame.extend_from_slice(&[128, 128, 128, 128, 128, 128, 128, 255]); // U, then V (one red sample)
+    let rgb = convert(&frame, PixelFormat::I420, 3, 3, BT601_FULL).unwrap();
+    assert_eq!(rgb.len(), 27);
+    assert_eq!(rgb[..3], [128, 128, 128]);
+    // Pixel (2, 0) uses the second chroma column.
+    assert_eq!(rgb[6..9], [128, 128, 128]);
+    // Pixel (2, 2) the second chroma row and column, where V is 255.
+    assert!(rgb[24] > 250 && rgb[25] < 128, "{:?}", &rgb[24..]);
+
+    let rgb = convert(&[16, 128, 16, 128, 235, 128, 0, 128].repeat(3), PixelFormat::Yuyv, 3, 3, BT601_LIMITED).unwrap();
+    assert_eq!(rgb[..9], [0, 0, 0, 0, 0, 0, 255, 255, 255]);
+}
+
+#[test]
+fn rgb_and_bgr_pass_through() {
+    let rgb = [1, 2, 3, 4, 5, 6];
+    assert_eq!(convert(&rgb, PixelFormat::Rgb24, 2, 1, ColorSpace::default()).unwrap(), rgb);
+    assert_eq!(convert(&rgb, PixelFormat::Bgr24, 2, 1, ColorSpace::default()).unwrap(), [3, 2, 1, 6, 5, 4]);
+}
+
+#[test]
+fn rejects_wrong_buffer_sizes() {
+    let frame = bars(&BARS_601, PixelFormat::I420);
+    assert!(convert(&frame[1..], PixelFormat::I420, W, H, BT601_LIMITED).is_err());
+    let mut small = vec![0; W * H * 3 - 1];
+    assert!(convert_into(&frame, PixelFormat::I420, W, H, BT601_LIMITED, &mut small).is_err());
+}
+
+#[test]
+fn formats_from_v4l2_codes() {
+    let fourcc = |c: &[u8; 4]| u32::from_le_bytes(*c);
+    assert_eq!(PixelFormat::from_fourcc(fourcc(b"YUYV")), Some(PixelFormat::Yuyv));
+    assert_eq!(PixelFormat::from_fourcc(fourcc(b"YU12")), Some(PixelFormat::I420));
+    assert_eq!(PixelFormat::from_fourcc(fourcc(b"NV12")), Some(PixelFormat::Nv12));
+    assert_eq!(PixelFormat::from_fourcc(fourcc(b"MJPG")), None);
+
+    // What the kernel module sends: 400x712 YUV420.
+    assert_eq!(PixelFormat::I420.frame_size(400, 712), 400 * 712 * 3 / 2);
+}
@@ -0,0 +1,96 @@
+use image::{DynamicImage, Rgb, RgbImage};
+
+use server_side::crop::{CropRegion, CropTracker};
+use server_side::preprocess::{ResizeMode, Transform, MODEL_INPUT};
+
+// Crop placement from the previous frame's keypoints, and mapping through the crop.
+
+fn close(a: f32, b: f32) -> bool {
+    (a - b).abs() < 0.01
+}
+
+// Standing person in frame pixels: shoulders at y=200, hips at y=300, around x=320.
+fn person(score: f32) -> Vec<f32> {
+    let mut keypoints = vec![0.0; 51];
+    let mut set = |i: usize, y: f32, x: f32| keypoints[i * 3..i * 3 + 3].copy_from_slice(&[y, x, score]);
+    set(0, 150.0, 320.0);
+    set(5, 200.0, 290.0);
+    set(6, 200.0, 350.0);
+    set(11, 300.0, 300.0);
+    set(12, 300.0, 340.0);
+    set(15, 400.0, 300.0);
+    set(16, 400.0, 340.0);
+    keypoints
+}
+
+#[test]
+fn full_region_is_the_padded_frame() {
+    let wide = CropRegion::full(640, 480);
+    assert!(close(wide.x_min, 0.0) && close(wide.x_max, 1.0));
+    assert!(close(wide.y_min, -1.0 / 6.0) && close(wide.y_max, 7.0 / 6.0));
+
+    // Same mapping as letterboxing the frame into a square.
+    let through_crop = wide.transform((640, 480), MODEL_INPUT);
+    let letterbox = Transform::new((640, 480), MODEL_INPUT, ResizeMode::Letterbox);
+    let (a, b) = (through_crop.to_source(0.3, 0.7), letterbox.to_source(0.3, 0.7));
+    assert!(close(a.0, b.0) && close(a.1, b.1), "{:?} {:?}", a, b);
+}
+
+#[test]
+fn crop_is_a_square_around_the_hips() {
+    let region = CropRegion::from_keypoints(&person(0.9), 640, 480);
+    let (x, y, w, h) = region.to_pixels(640, 480);
+    assert!(close(w, h));
+    // Centered on the hips.
+    assert!(close(x + w / 2.0, 320.0) && close(y + h / 2.0, 300.0));
+    // Shoulders are 100 px above the hips, so the torso sets the size: 2 * 1.9 * 100.
+    assert!(close(w, 380.0), "{}", w);
+}
+
+#[test]
+fn falls_back_to_full_frame_without_a_torso() {
+    let full = CropRegion::full(640, 480);
+    assert_eq!(CropRegion::from_keypoints(&person(0.1), 640, 480), full);
+
+    let mut no_hips = person(0.9);
+    no_hips[11 * 3 + 2] = 0.0;
+    no_hips[12 * 3 + 2] = 0.0;
+    assert_eq!(CropRegion::from_keypoints(&no_hips, 640, 480), full);
+    assert_eq!(CropRegion::from_keypoints(&[], 640, 480), full);
+}
+
+#[test]
+fn tracker_moves_the_crop_and_maps_keypoints_back() {
+    let frame = DynamicImage::ImageRgb8(RgbImage::from_pixel(640, 480, Rgb([255, 255, 255])));
+    let mut tracker = CropTracker::new();
+
+    // First frame: whole frame.
+    let (input, transform) = tracker.preprocess(&frame, MODEL_INPUT);
+    assert_eq!(input.dimensions(), MODEL_INPUT);
+    assert_eq!(input.get_pixel(96, 0)[0], 0); // padding above the frame
+
+    // The model found the person; express that in model coordinates of this crop.
+    let found: Vec<f32> = person(0.9).chunks_exact(3)
+        .flat_map(|k| {
+            let (y, x) = transform.to_model(k[1], k[0]);
+            [y, x, k[2]]
+        })
+        .collect();
+    tracker.update(&found, &transform);
+
+    // Second frame: cropped around the hips, starting inside the white frame.
+    let (x, y, w, _) = tracker.region(640, 480).to_pixels(640, 480);
+    assert!(close(x, 130.0) && close(y, 110.0) && close(w, 380.0), "{} {} {}", x, y, w);
+    let (input, transform) = tracker.preprocess(&frame, MODEL_INPUT);
+    assert_eq!(input.get_pixel(96, 0)[0], 255);
+
+    // The crop center maps back to the hips, and reprojects into full-frame coordinates.
+    let (x, y) = transform.to_source(0.5, 0.5);
+    assert!(close(x, 320.0) && close(y, 300.0));
+    let stretched = Transform::new((640, 480), MODEL_INPUT, ResizeMode::Stretch);
+    let steady = transform.reproject(&[0.5, 0.5, 0.9], &stretched);
+    assert!(close(steady[0], 300.0 / 480.0) && close(steady[1], 0.5));
+
+    // A different frame size starts over.
+    assert_eq!(tracker.region(320, 240), CropRegion::full(320, 240));
+}
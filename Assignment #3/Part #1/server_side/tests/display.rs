@@ -0,0 +1,68 @@
+use std::io::{self, Cursor};
+use std::sync::{Arc, Mutex};
+
+use image::{DynamicImage, ImageOutputFormat, RgbImage};
+use opencv::core::Mat;
+use opencv::prelude::*;
+use tflitec::interpreter::{Interpreter, Options};
+
+use server_side::frame_source::V4l2Source;
+use server_side::output::FrameSink;
+use server_side::v4l2::{VideoConfig, VideoHandler, V4L2_PIX_FMT_MJPG};
+use server_side::v4l2_fake::FakeDevice;
+use server_side::{display, frames};
+
+// display() end to end without annotation (which needs the remote server): fake camera in,
+// recording sink out. One test per binary run as display() is driven by global settings.
+
+const MODEL: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../client_side/resource/model_local.tflite");
+
+// Sizes of the frames presented.
+struct Recorder(Arc<Mutex<Vec<(i32, i32)>>>);
+
+impl FrameSink for Recorder {
+    fn present(&mut self, frame: &Mat) -> io::Result<bool> {
+        self.0.lock().unwrap().push((frame.cols(), frame.rows()));
+        Ok(false)
+    }
+}
+
+fn jpeg(width: u32, height: u32) -> Vec<u8> {
+    let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, image::Rgb([90, 90, 90])));
+    let mut bytes = Cursor::new(vec![]);
+    image.write_to(&mut bytes, ImageOutputFormat::Jpeg(90)).unwrap();
+    bytes.into_inner()
+}
+
+fn fake_camera() -> V4l2Source<FakeDevice> {
+    let device = FakeDevice::new(vec![(800, 448, V4L2_PIX_FMT_MJPG)], Box::new(|_| jpeg(800, 448)));
+    let config = VideoConfig {
+        width: Some(800),
+        height: Some(448),
+        pixelformat: Some(V4L2_PIX_FMT_MJPG),
+        ..Default::default()
+    };
+    V4l2Source::from_handler(VideoHandler::with_backend(device, &config).unwrap()).unwrap()
+}
+
+fn interpreter() -> Arc<Mutex<Interpreter>> {
+    let interpreter = Interpreter::with_model_path(MODEL, Some(Options::default())).unwrap();
+    interpreter.allocate_tensors().unwrap();
+    Arc::new(Mutex::new(interpreter))
+}
+
+#[test]
+fn display_presents_camera_frames_until_the_limit() {
+    let shown = Arc::new(Mutex::new(vec![]));
+    frames(3);
+    display(interpreter(), Box::new(fake_camera()), Box::new(Recorder(shown.clone())));
+    assert_eq!(*shown.lock().unwrap(), vec![(800, 448); 3]);
+
+    // A camera that stops delivering ends the loop instead of hanging it.
+    let shown = Arc::new(Mutex::new(vec![]));
+    let mut camera = fake_camera();
+    camera.handler().fake_mut().set_stalled(true);
+    camera.handler().set_timeout(20);
+    display(interpreter(), Box::new(camera), Box::new(Recorder(shown.clone())));
+    assert!(shown.lock().unwrap().is_empty());
+}
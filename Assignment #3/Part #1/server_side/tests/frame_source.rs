@@ -0,0 +1,120 @@
+use std::env;
+use std::fs;
+use std::io::Cursor;
+
+use image::{DynamicImage, ImageOutputFormat, RgbImage};
+use opencv::prelude::*;
+
+use server_side::frame_source::{self, FrameData, FrameSource, ImageDirSource, PatternSource, V4l2Source};
+use server_side::v4l2::{v4l2_fourcc, VideoConfig, VideoHandler, V4L2_PIX_FMT_MJPG};
+use server_side::v4l2_fake::FakeDevice;
+
+// The frame path display() runs every iteration (source -> Frame -> Mat/RGB image), exercised
+// through the fake V4L2 driver and the camera-less sources.
+
+fn jpeg(width: u32, height: u32, shade: u8) -> Vec<u8> {
+    let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, image::Rgb([shade, 0, 0])));
+    let mut bytes = Cursor::new(vec![]);
+    image.write_to(&mut bytes, ImageOutputFormat::Jpeg(90)).unwrap();
+    bytes.into_inner()
+}
+
+fn fake_camera() -> V4l2Source<FakeDevice> {
+    let device = FakeDevice::new(
+        vec![(800, 448, V4L2_PIX_FMT_MJPG)],
+        Box::new(|seq| jpeg(800, 448, (seq * 40) as u8)),
+    );
+    let config = VideoConfig {
+        width: Some(800),
+        height: Some(448),
+        pixelformat: Some(V4L2_PIX_FMT_MJPG),
+        ..Default::default()
+    };
+    V4l2Source::from_handler(VideoHandler::with_backend(device, &config).unwrap()).unwrap()
+}
+
+#[test]
+fn camera_frames_decode_for_display_and_model() {
+    let mut source = fake_camera();
+    assert_eq!(source.size(), (800, 448));
+
+    for seq in 0..4 {
+        let frame = source.next_frame().unwrap().unwrap();
+        assert_eq!(frame.seq, seq);
+        assert!(matches!(frame.data, FrameData::Mjpeg(_)));
+
+        let mat = frame.to_mat().unwrap();
+        assert_eq!((mat.cols(), mat.rows()), (800, 448));
+
+        let image = frame.to_image().unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (800, 448));
+        let red = image.get_pixel(400, 224)[0] as i32;
+        assert!((red - (seq * 40) as i32).abs() < 8);
+    }
+}
+
+#[test]
+fn camera_without_mjpg_is_rejected() {
+    let yuyv = v4l2_fourcc(b'Y', b'U', b'Y', b'V');
+    let device = FakeDevice::new(vec![(640, 480, yuyv)], Box::new(|_| vec![]));
+    let handler = VideoHandler::with_backend(device, &Default::default()).unwrap();
+    assert!(V4l2Source::from_handler(handler).is_err());
+}
+
+#[test]
+fn stalled_camera_times_out() {
+    let mut source = fake_camera();
+    source.handler().fake_mut().set_stalled(true);
+    source.handler().set_timeout(20);
+
+    let err = source.next_frame().err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn pattern_runs_until_limit() {
+    let mut source = PatternSource::new(320, 240).with_limit(3);
+    let mut previous: Option<Vec<u8>> = None;
+
+    for seq in 0..3 {
+        let frame = source.next_frame().unwrap().unwrap();
+        assert_eq!(frame.seq, seq);
+        let image = frame.to_image().unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (320, 240));
+
+        // The block moves, so consecutive frames differ.
+        let raw = image.into_raw();
+        assert_ne!(previous.as_ref(), Some(&raw));
+        previous = Some(raw);
+    }
+    assert!(source.next_frame().unwrap().is_none());
+}
+
+#[test]
+fn image_directory_plays_in_name_order() {
+    let dir = env::temp_dir().join(format!("frame_source_test_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for (name, shade) in [("b.png", 200u8), ("a.png", 100), ("c.png", 50)] {
+        RgbImage::from_pixel(64, 48, image::Rgb([shade, shade, shade])).save(dir.join(name)).unwrap();
+    }
+    fs::write(dir.join("notes.txt"), "not an image").unwrap();
+
+    let mut source = ImageDirSource::new(dir.to_str().unwrap()).unwrap();
+    assert_eq!(source.size(), (64, 48));
+
+    let mut shades = vec![];
+    while let Some(frame) = source.next_frame().unwrap() {
+        shades.push(frame.to_image().unwrap().to_rgb8().get_pixel(0, 0)[0]);
+    }
+    assert_eq!(shades, vec![100, 200, 50]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn specs_are_parsed() {
+    assert_eq!(frame_source::from_spec("pattern:64x32").unwrap().size(), (64, 32));
+    assert!(frame_source::from_spec("pattern:64by32").is_err());
+    assert!(frame_source::from_spec("dir:/nonexistent/frames").is_err());
+    assert!(frame_source::from_spec("webcam").is_err());
+}
@@ -0,0 +1,115 @@
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use opencv::core::{Mat, Scalar, CV_8UC3};
+use opencv::imgcodecs::{imread, IMREAD_COLOR};
+use opencv::prelude::*;
+use opencv::videoio::{VideoCapture, CAP_ANY};
+
+use server_side::output::{self, ImageSequenceSink, MjpegHttpSink, VideoFileSink};
+
+// The sinks that run without a display (the MJPEG one with local viewers), writing frames of a known size to a scratch directory.
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("output_test_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn frame() -> Mat {
+    Mat::new_rows_cols_with_default(120, 160, CV_8UC3, Scalar::new(0.0, 0.0, 200.0, 0.0)).unwrap()
+}
+
+#[test]
+fn unknown_specs_are_rejected() {
+    for spec in ["", "windows", "ftp:host", "file"] {
+        let err = output::from_spec(spec).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput, "{}", spec);
+    }
+}
+
+#[test]
+fn images_spec_writes_numbered_jpegs() {
+    let dir = scratch("images");
+    let mut sink = output::from_spec(&format!("images:{}", dir.display())).unwrap();
+    for _ in 0..3 {
+        assert!(!sink.present(&frame()).unwrap());
+    }
+
+    let mut names: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+    names.sort();
+    assert_eq!(names, ["frame_000000.jpg", "frame_000001.jpg", "frame_000002.jpg"]);
+
+    let image = imread(&dir.join("frame_000002.jpg").to_string_lossy(), IMREAD_COLOR).unwrap();
+    assert_eq!((image.cols(), image.rows()), (160, 120));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn image_sequence_reports_unwritable_directory() {
+    let dir = scratch("unwritable");
+    let mut sink = ImageSequenceSink::new(&dir.to_string_lossy()).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(sink.present(&frame()).is_err());
+}
+
+#[test]
+fn file_spec_records_a_readable_video() {
+    let dir = scratch("video");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("run.avi");
+    {
+        let mut sink = output::from_spec(&format!("file:{}", path.display())).unwrap();
+        for _ in 0..5 {
+            assert!(!sink.present(&frame()).unwrap());
+        }
+    } // dropping the sink finalizes the file
+
+    let mut capture = VideoCapture::from_file(&path.to_string_lossy(), CAP_ANY).unwrap();
+    assert!(capture.is_opened().unwrap());
+    let mut read = Mat::default();
+    let mut frames = 0;
+    while capture.read(&mut read).unwrap() {
+        assert_eq!((read.cols(), read.rows()), (160, 120));
+        frames += 1;
+    }
+    assert_eq!(frames, 5);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn video_file_reports_unwritable_path() {
+    let path = scratch("missing").join("run.avi");
+    let mut sink = VideoFileSink::new(&path.to_string_lossy());
+    assert!(sink.present(&frame()).is_err());
+}
+
+#[test]
+fn http_viewer_is_served_while_another_never_finishes_its_request() {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let sink = MjpegHttpSink::new(&addr.to_string()).unwrap();
+
+    let mut silent = TcpStream::connect(addr).unwrap();
+    silent.write_all(b"GET / HTTP/1.0\r\n").unwrap();
+
+    let mut viewer = TcpStream::connect(addr).unwrap();
+    viewer.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    viewer.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    let mut status = String::new();
+    BufReader::new(&viewer).read_line(&mut status).unwrap();
+    assert_eq!(status, "HTTP/1.0 200 OK\r\n");
+
+    // Only the viewer is streamed to; it is added right after its header went out.
+    for _ in 0..100 {
+        if sink.clients() == 1 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(sink.clients(), 1);
+}
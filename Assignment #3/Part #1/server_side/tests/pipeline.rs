@@ -0,0 +1,147 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::{ColorType, Rgb, RgbImage};
+
+use server_side::jpeg::{default_decoder, JpegDecoder, RustJpeg};
+use server_side::pipeline::{Pipeline, ResizeFilter, Source, Tensor};
+use server_side::preprocess::{ResizeMode, Transform};
+
+// Decode/resize/quantize into preallocated tensor memory.
+
+fn stretch(size: (u32, u32)) -> Transform {
+    Transform::new(size, (4, 4), ResizeMode::Stretch)
+}
+
+// Left half red, right half blue.
+fn halves(w: u32, h: u32) -> RgbImage {
+    RgbImage::from_fn(w, h, |x, _| if x < w / 2 { Rgb([255, 0, 0]) } else { Rgb([0, 0, 255]) })
+}
+
+fn jpeg(image: &RgbImage) -> Vec<u8> {
+    let mut bytes = vec![];
+    JpegEncoder::new_with_quality(&mut bytes, 95)
+        .encode(image.as_raw(), image.width(), image.height(), ColorType::Rgb8)
+        .unwrap();
+    bytes
+}
+
+fn pixel(tensor: &[u8], x: usize, y: usize) -> &[u8] {
+    &tensor[(y * 192 + x) * 3..(y * 192 + x) * 3 + 3]
+}
+
+fn near(a: u8, b: u8) -> bool {
+    (a as i32 - b as i32).abs() <= 8
+}
+
+#[test]
+fn every_filter_keeps_flat_regions() {
+    let frame = halves(16, 8);
+    for filter in [ResizeFilter::Nearest, ResizeFilter::Bilinear, ResizeFilter::Area] {
+        let mut pipeline = Pipeline::new(filter);
+        let mut tensor = [0u8; 4 * 4 * 3];
+        pipeline.run(Source::Rgb(frame.as_raw(), 16, 8), stretch, Tensor::U8(&mut tensor)).unwrap();
+        for row in tensor.chunks_exact(12) {
+            assert_eq!(row[..3], [255, 0, 0], "{:?}", filter);
+            assert_eq!(row[9..], [0, 0, 255], "{:?}", filter);
+        }
+    }
+}
+
+#[test]
+fn area_averages_what_nearest_aliases() {
+    // One pixel columns alternating black/white, shrunk 4x.
+    let frame = RgbImage::from_fn(16, 4, |x, _| if x % 2 == 0 { Rgb([0; 3]) } else { Rgb([255; 3]) });
+    let run = |filter| {
+        let mut tensor = [0u8; 4 * 4 * 3];
+        Pipeline::new(filter).run(Source::Rgb(frame.as_raw(), 16, 4), stretch, Tensor::U8(&mut tensor)).unwrap();
+        tensor
+    };
+    assert!(run(ResizeFilter::Area).iter().all(|v| near(*v, 128)));
+    assert!(run(ResizeFilter::Nearest).iter().all(|v| *v == 0 || *v == 255));
+}
+
+#[test]
+fn letterbox_padding_stays_black() {
+    let frame = RgbImage::from_pixel(8, 4, Rgb([200, 100, 50]));
+    let mut tensor = [7u8; 4 * 4 * 3];
+    let transform = Pipeline::new(ResizeFilter::Bilinear)
+        .run(Source::Rgb(frame.as_raw(), 8, 4), |size| Transform::new(size, (4, 4), ResizeMode::Letterbox),
+             Tensor::U8(&mut tensor))
+        .unwrap();
+    assert_eq!(transform.pad, (0.0, 1.0));
+    let rows: Vec<&[u8]> = tensor.chunks_exact(12).collect();
+    assert!(rows[0].iter().all(|v| *v == 0) && rows[3].iter().all(|v| *v == 0));
+    assert_eq!(rows[1][..3], [200, 100, 50]);
+    assert_eq!(rows[2][9..], [200, 100, 50]);
+}
+
+#[test]
+fn bgr_and_f32_tensors() {
+    let frame = halves(16, 8);
+    let bgr: Vec<u8> = frame.as_raw().chunks_exact(3).flat_map(|p| [p[2], p[1], p[0]]).collect();
+    let mut pipeline = Pipeline::new(ResizeFilter::Area);
+
+    let mut bytes = [0u8; 4 * 4 * 3];
+    pipeline.run(Source::Bgr(&bgr, 16, 8), stretch, Tensor::U8(&mut bytes)).unwrap();
+    assert_eq!(bytes[..3], [255, 0, 0]);
+
+    let mut floats = [0f32; 4 * 4 * 3];
+    pipeline.run(Source::Rgb(frame.as_raw(), 16, 8), stretch, Tensor::F32(&mut floats)).unwrap();
+    assert!(floats.iter().zip(&bytes).all(|(f, b)| *f == *b as f32));
+    assert_eq!(pipeline.frames, 2);
+}
+
+#[test]
+fn jpeg_is_decoded_at_reduced_scale() {
+    let frame = halves(800, 448);
+    let bytes = jpeg(&frame);
+    let mut pipeline = Pipeline::new(ResizeFilter::Area);
+    let mut tensor = vec![0u8; 192 * 192 * 3];
+
+    let transform = pipeline
+        .run(Source::Jpeg(&bytes), |size| Transform::new(size, (192, 192), ResizeMode::Stretch), Tensor::U8(&mut tensor))
+        .unwrap();
+    // 192 of 800 columns and 448 rows: 1/2 is the smallest scale keeping 192 rows.
+    assert_eq!(transform.source, (800, 448));
+    assert_eq!(pipeline.decoded_size(), (400, 224));
+
+    let (left, right) = (pixel(&tensor, 10, 96), pixel(&tensor, 180, 96));
+    assert!(near(left[0], 255) && near(left[2], 0), "{:?}", left);
+    assert!(near(right[0], 0) && near(right[2], 255), "{:?}", right);
+    assert!(pipeline.last.decode > std::time::Duration::ZERO);
+
+    // A small crop needs the full resolution.
+    let crop = Transform { source: (800, 448), input: (192, 192), scale: (1.0, 1.0), pad: (0.0, 0.0), crop: (304.0, 128.0) };
+    pipeline.run(Source::Jpeg(&bytes), |_| crop, Tensor::U8(&mut tensor)).unwrap();
+    assert_eq!(pipeline.decoded_size(), (800, 448));
+    assert!(near(pixel(&tensor, 10, 10)[0], 255) && near(pixel(&tensor, 180, 10)[2], 255));
+}
+
+#[test]
+fn decoders_scale_and_expand_grayscale() {
+    let mut rgb = vec![];
+    let colour = jpeg(&halves(800, 448));
+    for mut decoder in [Box::new(RustJpeg) as Box<dyn JpegDecoder>, default_decoder()] {
+        assert_eq!(decoder.size(&colour).unwrap(), (800, 448));
+        assert_eq!(decoder.decode(&colour, (100, 56), &mut rgb).unwrap(), (100, 56), "{}", decoder.name());
+        assert_eq!(rgb.len(), 100 * 56 * 3);
+        assert_eq!(decoder.decode(&colour, (800, 448), &mut rgb).unwrap(), (800, 448), "{}", decoder.name());
+    }
+
+    let mut grey = vec![];
+    JpegEncoder::new(&mut grey).encode(&[90; 16 * 16], 16, 16, ColorType::L8).unwrap();
+    assert_eq!(RustJpeg.decode(&grey, (16, 16), &mut rgb).unwrap(), (16, 16));
+    assert_eq!(rgb.len(), 16 * 16 * 3);
+    assert!(rgb.iter().all(|v| near(*v, 90)));
+}
+
+#[test]
+fn rejects_mismatched_buffers() {
+    let frame = halves(16, 8);
+    let mut pipeline = Pipeline::new(ResizeFilter::Nearest);
+    let mut small = [0u8; 10];
+    assert!(pipeline.run(Source::Rgb(frame.as_raw(), 16, 8), stretch, Tensor::U8(&mut small)).is_err());
+    let mut tensor = [0u8; 4 * 4 * 3];
+    assert!(pipeline.run(Source::Rgb(&frame.as_raw()[1..], 16, 8), stretch, Tensor::U8(&mut tensor)).is_err());
+    assert!(pipeline.run(Source::Jpeg(&[0xff, 0xd8, 0]), stretch, Tensor::U8(&mut tensor)).is_err());
+    assert!(ResizeFilter::from_name("cubic").is_err());
+}
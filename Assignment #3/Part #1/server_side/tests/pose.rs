@@ -0,0 +1,247 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use server_side::pose::{decode_output, iou, parse_output, parse_pose, validate_output, OutputError, OutputMonitor,
+                        Person, PersonTracker, PoseModel, MIN_PERSON_SCORE, MULTIPOSE_VALUES,
+                        MULTIPOSE_VALUES_PER_PERSON};
+use server_side::preprocess::{ResizeMode, Transform};
+use server_side::smoothing::{Filter, PersonSmoothers, SmoothingConfig};
+
+// SinglePose/MultiPose parsing and keeping person IDs across frames.
+
+fn close(a: f32, b: f32) -> bool {
+    (a - b).abs() < 0.001
+}
+
+fn at(frame: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(1_000_000 + frame * 1000 / 30)
+}
+
+// 17 keypoints at (y, x) with `score`.
+fn keypoints(y: f32, x: f32, score: f32) -> Vec<f32> {
+    (0..17).flat_map(|_| [y, x, score]).collect()
+}
+
+// One MultiPose row: keypoints, then box and score.
+fn row(bbox: [f32; 4], score: f32) -> Vec<f32> {
+    let mut row = keypoints((bbox[0] + bbox[2]) / 2.0, (bbox[1] + bbox[3]) / 2.0, 0.8);
+    row.extend_from_slice(&bbox);
+    row.push(score);
+    row
+}
+
+fn multipose(rows: &[Vec<f32>]) -> Vec<f32> {
+    let mut values: Vec<f32> = rows.concat();
+    values.resize(MULTIPOSE_VALUES, 0.0);
+    values
+}
+
+fn person(bbox: [f32; 4]) -> Person {
+    Person { id: None, keypoints: keypoints(0.5, 0.5, 0.8), bbox, score: 0.9 }
+}
+
+#[test]
+fn parses_singlepose_output() {
+    let mut values = keypoints(0.5, 0.5, 0.6);
+    values[0..3].copy_from_slice(&[0.2, 0.3, 0.6]);
+    values[48..51].copy_from_slice(&[0.9, 0.7, 0.0]);
+
+    let persons = parse_output(&values, MIN_PERSON_SCORE).unwrap();
+    assert_eq!(persons.len(), 1);
+    assert_eq!(persons[0].keypoints, values);
+    // Boxed around the confident keypoints only.
+    assert_eq!(persons[0].bbox, [0.2, 0.3, 0.5, 0.5]);
+    assert!(close(persons[0].score, 0.6 * 16.0 / 17.0));
+}
+
+#[test]
+fn parses_multipose_output_and_drops_low_scores() {
+    let values = multipose(&[row([0.1, 0.1, 0.5, 0.3], 0.9), row([0.2, 0.6, 0.9, 0.9], 0.1),
+                             row([0.3, 0.4, 0.8, 0.6], 0.5)]);
+    let persons = parse_output(&values, MIN_PERSON_SCORE).unwrap();
+
+    assert_eq!(persons.len(), 2);
+    assert_eq!(persons[0].bbox, [0.1, 0.1, 0.5, 0.3]);
+    assert_eq!(persons[1].bbox, [0.3, 0.4, 0.8, 0.6]);
+    assert!(close(persons[1].score, 0.5));
+    assert_eq!(persons[1].keypoints.len(), 51);
+    assert!(persons.iter().all(|p| p.id.is_none()));
+
+    // Fewer rows than six are fine too.
+    assert_eq!(parse_output(&values[..MULTIPOSE_VALUES_PER_PERSON], MIN_PERSON_SCORE).unwrap().len(), 1);
+}
+
+#[test]
+fn rejects_other_output_sizes() {
+    assert!(parse_output(&[], MIN_PERSON_SCORE).is_err());
+    assert!(parse_output(&[0.0; 50], MIN_PERSON_SCORE).is_err());
+    assert!(parse_output(&[0.0; 57], MIN_PERSON_SCORE).is_err());
+}
+
+#[test]
+fn validates_values_and_coordinates() {
+    let single = keypoints(0.5, 0.5, 0.6);
+    assert_eq!(validate_output(&single, MIN_PERSON_SCORE), Ok(PoseModel::SinglePose));
+    let multi = multipose(&[row([0.1, 0.1, 0.5, 0.3], 0.9)]);
+    assert_eq!(parse_pose(&multi, MIN_PERSON_SCORE).unwrap().model, PoseModel::MultiPose);
+
+    let mut nan = single.clone();
+    nan[4] = f32::NAN;
+    assert!(matches!(validate_output(&nan, MIN_PERSON_SCORE), Err(OutputError::NotFinite { index: 4, .. })));
+    let mut infinite_score = single.clone();
+    infinite_score[2] = f32::INFINITY;
+    assert!(matches!(validate_output(&infinite_score, MIN_PERSON_SCORE), Err(OutputError::NotFinite { index: 2, .. })));
+
+    let mut outside = single.clone();
+    outside[3] = 1.5;
+    assert_eq!(validate_output(&outside, MIN_PERSON_SCORE), Err(OutputError::OutOfRange { index: 3, value: 1.5 }));
+    // Scores are not coordinates.
+    let mut high_score = single;
+    high_score[5] = 3.0;
+    assert!(validate_output(&high_score, MIN_PERSON_SCORE).is_ok());
+
+    // MultiPose box corners are coordinates, the person score is not.
+    let mut bbox = multi.clone();
+    bbox[51 + 2] = -0.1;
+    assert_eq!(validate_output(&bbox, MIN_PERSON_SCORE), Err(OutputError::OutOfRange { index: 53, value: -0.1 }));
+    let mut person_score = multi;
+    person_score[51 + 4] = 2.0;
+    assert!(validate_output(&person_score, MIN_PERSON_SCORE).is_ok());
+
+    assert_eq!(validate_output(&[0.0; 50], MIN_PERSON_SCORE), Err(OutputError::Length(50)));
+}
+
+#[test]
+fn ignores_multipose_padding_rows() {
+    // Rows nobody scored in carry leftovers; they are dropped without being checked.
+    let mut values = multipose(&[row([0.1, 0.1, 0.5, 0.3], 0.9)]);
+    let padding = MULTIPOSE_VALUES_PER_PERSON;
+    values[padding] = f32::NAN;
+    values[padding + 51] = -3.0;
+    values[2 * padding + 4] = 7.5;
+    values[3 * padding + 51 + 4] = f32::NAN;
+
+    let persons = parse_output(&values, MIN_PERSON_SCORE).unwrap();
+    assert_eq!(persons.len(), 1);
+    assert_eq!(persons[0].bbox, [0.1, 0.1, 0.5, 0.3]);
+
+    // A row that clears the score is checked like any other.
+    values[padding + 51 + 4] = 0.9;
+    assert!(matches!(validate_output(&values, MIN_PERSON_SCORE), Err(OutputError::NotFinite { index, .. }) if index == padding));
+}
+
+#[test]
+fn decodes_whole_floats_only() {
+    let bytes: Vec<u8> = [0.25f32, 1.0].iter().flat_map(|v| v.to_le_bytes()).collect();
+    assert_eq!(decode_output(&bytes), Ok(vec![0.25, 1.0]));
+    assert_eq!(decode_output(&bytes[..7]), Err(OutputError::Bytes(7)));
+}
+
+#[test]
+fn monitor_counts_and_dumps_invalid_outputs() {
+    let dir = std::env::temp_dir().join(format!("pose-dump-{}", std::process::id()));
+    let mut monitor = OutputMonitor::new(Some(dir.clone())).unwrap();
+    let bytes = |values: &[f32]| -> Vec<u8> { values.iter().flat_map(|v| v.to_le_bytes()).collect() };
+
+    let good = bytes(&keypoints(0.5, 0.5, 0.6));
+    assert_eq!(monitor.check(&good, MIN_PERSON_SCORE).unwrap().persons.len(), 1);
+    assert!(monitor.check(&good[..10], MIN_PERSON_SCORE).is_err());
+    assert!(monitor.check(&bytes(&[f32::NAN; 51]), MIN_PERSON_SCORE).is_err());
+    assert!(monitor.check(&bytes(&[0.0; 52]), MIN_PERSON_SCORE).is_err());
+
+    assert_eq!(monitor.valid, 1);
+    assert_eq!(monitor.invalid_total(), 3);
+    assert_eq!(monitor.to_string(), "1 valid, 3 invalid (bytes 1, length 1, not finite 1)");
+
+    assert_eq!(std::fs::read(dir.join("invalid-000000.bin")).unwrap(), &good[..10]);
+    let reason = std::fs::read_to_string(dir.join("invalid-000002.txt")).unwrap();
+    assert!(reason.contains("52 values"), "{}", reason);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn iou_of_boxes() {
+    let a = [0.0, 0.0, 1.0, 1.0];
+    assert!(close(iou(&a, &a), 1.0));
+    assert!(close(iou(&a, &[0.0, 0.5, 1.0, 1.5]), 1.0 / 3.0));
+    assert!(close(iou(&a, &[2.0, 2.0, 3.0, 3.0]), 0.0));
+    assert!(close(iou(&[0.0; 4], &[0.0; 4]), 0.0));
+}
+
+#[test]
+fn tracker_keeps_ids_while_people_move() {
+    let mut tracker = PersonTracker::new();
+    let mut frame = vec![person([0.1, 0.1, 0.5, 0.3]), person([0.1, 0.6, 0.5, 0.8])];
+    tracker.assign(&mut frame);
+    assert_eq!((frame[0].id, frame[1].id), (Some(1), Some(2)));
+
+    // Both shift a little and come out of the model in the other order.
+    let mut frame = vec![person([0.1, 0.62, 0.5, 0.82]), person([0.12, 0.1, 0.52, 0.3])];
+    tracker.assign(&mut frame);
+    assert_eq!((frame[0].id, frame[1].id), (Some(2), Some(1)));
+
+    // Somebody new walks in.
+    let mut frame = vec![person([0.12, 0.1, 0.52, 0.3]), person([0.5, 0.4, 0.9, 0.6])];
+    tracker.assign(&mut frame);
+    assert_eq!((frame[0].id, frame[1].id), (Some(1), Some(3)));
+}
+
+#[test]
+fn tracker_forgets_people_gone_too_long() {
+    let mut tracker = PersonTracker::new();
+    let mut frame = vec![person([0.1, 0.1, 0.5, 0.3])];
+    tracker.assign(&mut frame);
+
+    // Gone for max_age frames: still remembered.
+    for _ in 0..tracker.max_age {
+        tracker.assign(&mut []);
+    }
+    let mut frame = vec![person([0.1, 0.1, 0.5, 0.3])];
+    tracker.assign(&mut frame);
+    assert_eq!(frame[0].id, Some(1));
+
+    // One frame longer: a new ID.
+    for _ in 0..=tracker.max_age {
+        tracker.assign(&mut []);
+    }
+    assert!(tracker.ids().is_empty());
+    let mut frame = vec![person([0.1, 0.1, 0.5, 0.3])];
+    tracker.assign(&mut frame);
+    assert_eq!(frame[0].id, Some(2));
+}
+
+#[test]
+fn reprojects_keypoints_and_box() {
+    let letterbox = Transform::new((384, 192), (192, 192), ResizeMode::Letterbox);
+    let stretch = Transform::new((384, 192), (192, 192), ResizeMode::Stretch);
+    let mut p = person([0.25, 0.25, 0.75, 0.75]);
+    p.reproject(&letterbox, &stretch);
+
+    // Letterboxed rows 0.25..0.75 are the whole frame height.
+    assert!(close(p.bbox[0], 0.0) && close(p.bbox[2], 1.0), "{:?}", p.bbox);
+    assert!(close(p.bbox[1], 0.25) && close(p.bbox[3], 0.75), "{:?}", p.bbox);
+    assert!(close(p.keypoints[0], 0.5) && close(p.keypoints[1], 0.5) && close(p.keypoints[2], 0.8));
+}
+
+#[test]
+fn smoothers_follow_ids_and_drop_people_who_left() {
+    let config = SmoothingConfig { filter: Filter::EMA, ..Default::default() };
+    let mut smoothers = PersonSmoothers::new(config);
+    let tracked = |id: u32, y: f32| Person { id: Some(id), keypoints: keypoints(y, 0.5, 0.8), ..person([0.0; 4]) };
+
+    let mut frame = vec![tracked(1, 0.5), tracked(2, 0.2)];
+    smoothers.apply(&mut frame, at(0));
+    let mut frame = vec![tracked(2, 0.3), tracked(1, 0.6)];
+    smoothers.apply(&mut frame, at(1));
+    assert!(close(frame[0].keypoints[0], 0.25) && close(frame[1].keypoints[0], 0.55));
+
+    // Person 1 leaves for a frame; when the ID comes back its filter starts over.
+    smoothers.apply(&mut [tracked(2, 0.3)], at(2));
+    let mut frame = vec![tracked(1, 0.7)];
+    smoothers.apply(&mut frame, at(3));
+    assert!(close(frame[0].keypoints[0], 0.7));
+
+    // Untracked people pass through.
+    let mut frame = vec![person([0.0; 4])];
+    smoothers.apply(&mut frame, at(4));
+    assert_eq!(frame[0].keypoints, keypoints(0.5, 0.5, 0.8));
+}
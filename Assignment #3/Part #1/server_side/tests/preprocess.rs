@@ -0,0 +1,109 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use image::{DynamicImage, Rgb, RgbImage};
+
+use server_side::preprocess::{preprocess, ResizeMode, Transform, MODEL_INPUT};
+use server_side::results::{PoseRecord, ResultFormat, ResultsWriter};
+
+// Model input <-> frame coordinate mapping for each resize mode.
+
+const MODES: [ResizeMode; 3] = [ResizeMode::Stretch, ResizeMode::Letterbox, ResizeMode::CenterCrop];
+
+fn close(a: (f32, f32), b: (f32, f32)) -> bool {
+    (a.0 - b.0).abs() < 0.01 && (a.1 - b.1).abs() < 0.01
+}
+
+#[test]
+fn frame_points_round_trip_in_every_mode() {
+    for source in [(800, 448), (400, 712), (192, 192), (641, 479)] {
+        for mode in MODES {
+            let transform = Transform::new(source, MODEL_INPUT, mode);
+            for point in [(0.0, 0.0), (source.0 as f32 / 2.0, source.1 as f32 / 3.0), (123.0, 45.0)] {
+                let (y, x) = transform.to_model(point.0, point.1);
+                assert!(close(transform.to_source(y, x), point), "{:?} {:?} {:?}", source, mode, point);
+            }
+        }
+    }
+}
+
+#[test]
+fn stretch_maps_corners_to_corners() {
+    let transform = Transform::new((800, 448), MODEL_INPUT, ResizeMode::Stretch);
+    assert!(close(transform.to_source(0.0, 0.0), (0.0, 0.0)));
+    assert!(close(transform.to_source(1.0, 1.0), (800.0, 448.0)));
+    assert!(close(transform.to_source(0.5, 0.25), (200.0, 224.0)));
+}
+
+#[test]
+fn letterbox_pads_the_short_side() {
+    // 800x448 -> 192x108 with 42 rows of padding above.
+    let transform = Transform::new((800, 448), MODEL_INPUT, ResizeMode::Letterbox);
+    assert_eq!(transform.pad, (0.0, 42.0));
+    assert!(close(transform.to_source(42.0 / 192.0, 0.0), (0.0, 0.0)));
+    assert!(close(transform.to_source(0.5, 0.5), (400.0, 224.0)));
+
+    // Padding the frame to a square is the old draw_keypoints mapping.
+    let square = Transform::new((800, 448), (800, 800), ResizeMode::Letterbox);
+    assert!(close(square.to_source(0.5, 0.5), (400.0, 224.0)));
+    assert!(close(square.to_source(0.25, 0.1), (80.0, 24.0)));
+}
+
+#[test]
+fn center_crop_cuts_the_long_side() {
+    // The middle 448x448 of 800x448.
+    let transform = Transform::new((800, 448), MODEL_INPUT, ResizeMode::CenterCrop);
+    assert_eq!(transform.crop, (176.0, 0.0));
+    assert!(close(transform.to_source(0.0, 0.0), (176.0, 0.0)));
+    assert!(close(transform.to_source(1.0, 1.0), (624.0, 448.0)));
+}
+
+#[test]
+fn keypoints_are_mapped_and_scores_kept() {
+    let transform = Transform::new((400, 712), MODEL_INPUT, ResizeMode::Stretch);
+    let mapped = transform.keypoints_to_source(&[0.5, 0.25, 0.9, 1.0, 1.0, 0.1]);
+    assert_eq!(mapped.len(), 6);
+    assert!(close((mapped[0], mapped[1]), (356.0, 100.0)));
+    assert_eq!(mapped[2], 0.9);
+    assert!(close((mapped[3], mapped[4]), (712.0, 400.0)));
+    assert_eq!(mapped[5], 0.1);
+}
+
+#[test]
+fn preprocess_produces_model_input_matching_its_transform() {
+    // White frame: letterbox padding stays black, everything else is white.
+    let frame = DynamicImage::ImageRgb8(RgbImage::from_pixel(800, 448, Rgb([255, 255, 255])));
+
+    for mode in MODES {
+        let (input, transform) = preprocess(&frame, MODEL_INPUT, mode);
+        assert_eq!(input.dimensions(), MODEL_INPUT);
+        assert_eq!(transform, Transform::new((800, 448), MODEL_INPUT, mode));
+
+        let top = input.get_pixel(96, 0)[0];
+        let middle = input.get_pixel(96, 96)[0];
+        assert_eq!(middle, 255, "{:?}", mode);
+        assert_eq!(top, if mode == ResizeMode::Letterbox { 0 } else { 255 }, "{:?}", mode);
+    }
+}
+
+#[test]
+fn exported_keypoints_are_in_frame_pixels() {
+    // 384x192 -> 192x96 with 48 rows of padding above, exact in floats.
+    let transform = Transform::new((384, 192), MODEL_INPUT, ResizeMode::Letterbox);
+    let mut keypoints = vec![0.0; 51];
+    keypoints[..3].copy_from_slice(&[0.5, 0.5, 0.75]);
+
+    let mut writer = ResultsWriter::new(vec![], ResultFormat::JsonLines);
+    writer.write(&PoseRecord {
+        seq: 0,
+        timestamp: UNIX_EPOCH,
+        latency: Duration::ZERO,
+        person: None,
+        keypoints: Some(&keypoints),
+        transform: &transform,
+        producer: "test",
+    }).unwrap();
+    let line = String::from_utf8(writer.into_inner()).unwrap();
+
+    assert!(line.contains("\"person\":null,\"width\":384,\"height\":192"), "{}", line);
+    assert!(line.contains("\"nose\":{\"y\":96,\"x\":192,\"score\":0.75}"), "{}", line);
+}
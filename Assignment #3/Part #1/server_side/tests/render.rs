@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use opencv::core::{Mat, Scalar, Vec3b, CV_8UC3};
+use opencv::prelude::*;
+
+use server_side::preprocess::{ResizeMode, Transform, MODEL_INPUT};
+use server_side::utils::{draw_overlay, draw_pose, edge_side, InferenceMode, Overlay, PoseStyle, Side, SKELETON};
+
+// The renderer drawn onto blank in-memory frames, checked pixel by pixel.
+
+const SIZE: i32 = 200;
+
+fn blank() -> Mat {
+    Mat::new_rows_cols_with_default(SIZE, SIZE, CV_8UC3, Scalar::all(0.0)).unwrap()
+}
+
+// The model saw the blank frame stretched to its input.
+fn stretched() -> Transform {
+    Transform::new((SIZE as u32, SIZE as u32), MODEL_INPUT, ResizeMode::Stretch)
+}
+
+fn pixel(img: &Mat, x: i32, y: i32) -> [u8; 3] {
+    img.at_2d::<Vec3b>(y, x).unwrap().0
+}
+
+fn bgr(color: Scalar) -> [u8; 3] {
+    [color.0[0] as u8, color.0[1] as u8, color.0[2] as u8]
+}
+
+fn lit(img: &Mat) -> usize {
+    let mut count = 0;
+    for y in 0..img.rows() {
+        for x in 0..img.cols() {
+            if pixel(img, x, y) != [0, 0, 0] {
+                count += 1;
+            }
+        }
+    }
+    count
+}
+
+// 17 keypoints, all at `score`, with a few placed far apart so edges are easy to probe.
+fn pose(score: f32) -> Vec<f32> {
+    let mut keypoints = vec![];
+    for i in 0..17 {
+        keypoints.extend([0.1 + 0.04 * i as f32, 0.5, score]);
+    }
+    // left shoulder -> left elbow: horizontal line at y = 50
+    keypoints[5 * 3..5 * 3 + 2].copy_from_slice(&[0.25, 0.1]);
+    keypoints[7 * 3..7 * 3 + 2].copy_from_slice(&[0.25, 0.4]);
+    // right shoulder -> right elbow: horizontal line at y = 150
+    keypoints[6 * 3..6 * 3 + 2].copy_from_slice(&[0.75, 0.1]);
+    keypoints[8 * 3..8 * 3 + 2].copy_from_slice(&[0.75, 0.4]);
+    keypoints
+}
+
+#[test]
+fn skeleton_edges_are_sided() {
+    assert_eq!(SKELETON.len(), 18);
+    assert_eq!(edge_side((5, 7)), Side::Left);
+    assert_eq!(edge_side((6, 8)), Side::Right);
+    assert_eq!(edge_side((5, 6)), Side::Center);
+    assert_eq!(edge_side((11, 12)), Side::Center);
+    assert_eq!(edge_side((0, 1)), Side::Left);
+    assert_eq!(edge_side((0, 2)), Side::Right);
+}
+
+#[test]
+fn limbs_are_coloured_by_side() {
+    let style = PoseStyle::default();
+    let mut img = blank();
+    draw_pose(&mut img, &pose(1.0), &stretched(), &style).unwrap();
+
+    // Midpoints of the two arms, away from the joints.
+    assert_eq!(pixel(&img, 50, 50), bgr(style.left));
+    assert_eq!(pixel(&img, 50, 150), bgr(style.right));
+}
+
+#[test]
+fn joints_are_shaded_by_confidence() {
+    let style = PoseStyle::default();
+    assert_eq!(bgr(style.confidence_color(1.0)), bgr(style.high_confidence));
+    assert_eq!(bgr(style.confidence_color(style.threshold)), bgr(style.low_confidence));
+
+    let mut img = blank();
+    draw_pose(&mut img, &pose(1.0), &stretched(), &style).unwrap();
+    assert_eq!(pixel(&img, 20, 50), bgr(style.high_confidence));
+
+    let unshaded = PoseStyle { shade_confidence: false, ..Default::default() };
+    assert_eq!(bgr(unshaded.confidence_color(0.3)), bgr(unshaded.high_confidence));
+}
+
+#[test]
+fn low_confidence_keypoints_are_skipped() {
+    let mut img = blank();
+    draw_pose(&mut img, &pose(0.1), &stretched(), &PoseStyle::default()).unwrap();
+    assert_eq!(lit(&img), 0);
+}
+
+#[test]
+fn short_output_draws_nothing() {
+    let mut img = blank();
+    draw_pose(&mut img, &[0.5; 12], &stretched(), &PoseStyle::default()).unwrap();
+    assert_eq!(lit(&img), 0);
+}
+
+#[test]
+fn skeleton_and_labels_are_optional() {
+    let keypoints = pose(1.0);
+    let count = |style: PoseStyle| {
+        let mut img = blank();
+        draw_pose(&mut img, &keypoints, &stretched(), &style).unwrap();
+        lit(&img)
+    };
+
+    let joints = count(PoseStyle { skeleton: false, ..Default::default() });
+    let skeleton = count(PoseStyle::default());
+    let labelled = count(PoseStyle { labels: true, ..Default::default() });
+    assert!(joints > 0);
+    assert!(skeleton > joints);
+    assert!(labelled > skeleton);
+}
+
+#[test]
+fn overlay_draws_stats_and_badge() {
+    let style = PoseStyle::default();
+    let mut img = blank();
+    draw_overlay(&mut img, &Overlay::default(), &style).unwrap();
+    assert_eq!(lit(&img), 0);
+
+    let overlay = Overlay {
+        fps: Some(29.97),
+        latency: Some(Duration::from_millis(42)),
+        mode: Some(InferenceMode::Server),
+    };
+    draw_overlay(&mut img, &overlay, &style).unwrap();
+    // Badge in the top right corner, text in the top left one.
+    assert_eq!(pixel(&img, SIZE - 2, 1), bgr(style.high_confidence));
+    assert!(lit(&img) > 0);
+    assert_eq!(pixel(&img, 100, 150), [0, 0, 0]);
+}
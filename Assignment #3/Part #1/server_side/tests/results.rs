@@ -0,0 +1,129 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use server_side::preprocess::{ResizeMode, Transform, MODEL_INPUT};
+use server_side::results::{csv_header, PoseRecord, ResultFormat, ResultsWriter};
+
+// The results writers against golden JSON Lines/CSV output, so a format change is a visible diff.
+
+// The model saw a 200x100 frame stretched to its input: y scales by 100, x by 200.
+fn stretched() -> Transform {
+    Transform::new((200, 100), MODEL_INPUT, ResizeMode::Stretch)
+}
+
+// Every keypoint at y 0.5, x 0.25 (50, 50 in the frame), scores 0, 0.05, ..., 0.8.
+fn keypoints() -> Vec<f32> {
+    (0..17).flat_map(|i| [0.5, 0.25, i as f32 / 20.0]).collect()
+}
+
+fn record<'a>(keypoints: Option<&'a [f32]>, transform: &'a Transform, producer: &'a str) -> PoseRecord<'a> {
+    PoseRecord {
+        seq: 7,
+        timestamp: UNIX_EPOCH + Duration::from_millis(1500),
+        latency: Duration::from_micros(12500),
+        person: keypoints.map(|_| 2),
+        keypoints,
+        transform,
+        producer,
+    }
+}
+
+fn written(format: ResultFormat, records: &[PoseRecord]) -> String {
+    let mut writer = ResultsWriter::new(vec![], format);
+    for record in records {
+        writer.write(record).unwrap();
+    }
+    String::from_utf8(writer.into_inner()).unwrap()
+}
+
+const JSON_PERSON: &str = concat!(
+    r#"{"seq":7,"timestamp":1.500000,"latency_ms":12.500,"producer":"split:127.0.0.1:8000","person":2,"#,
+    r#""width":200,"height":100,"keypoints":{"#,
+    r#""nose":{"y":50,"x":50,"score":0},"#,
+    r#""left_eye":{"y":50,"x":50,"score":0.05},"right_eye":{"y":50,"x":50,"score":0.1},"#,
+    r#""left_ear":{"y":50,"x":50,"score":0.15},"right_ear":{"y":50,"x":50,"score":0.2},"#,
+    r#""left_shoulder":{"y":50,"x":50,"score":0.25},"right_shoulder":{"y":50,"x":50,"score":0.3},"#,
+    r#""left_elbow":{"y":50,"x":50,"score":0.35},"right_elbow":{"y":50,"x":50,"score":0.4},"#,
+    r#""left_wrist":{"y":50,"x":50,"score":0.45},"right_wrist":{"y":50,"x":50,"score":0.5},"#,
+    r#""left_hip":{"y":50,"x":50,"score":0.55},"right_hip":{"y":50,"x":50,"score":0.6},"#,
+    r#""left_knee":{"y":50,"x":50,"score":0.65},"right_knee":{"y":50,"x":50,"score":0.7},"#,
+    r#""left_ankle":{"y":50,"x":50,"score":0.75},"right_ankle":{"y":50,"x":50,"score":0.8}}}"#,
+);
+
+const CSV_HEADER: &str = concat!(
+    "seq,timestamp,latency_ms,producer,person,width,height,",
+    "nose_y,nose_x,nose_score,",
+    "left_eye_y,left_eye_x,left_eye_score,right_eye_y,right_eye_x,right_eye_score,",
+    "left_ear_y,left_ear_x,left_ear_score,right_ear_y,right_ear_x,right_ear_score,",
+    "left_shoulder_y,left_shoulder_x,left_shoulder_score,right_shoulder_y,right_shoulder_x,right_shoulder_score,",
+    "left_elbow_y,left_elbow_x,left_elbow_score,right_elbow_y,right_elbow_x,right_elbow_score,",
+    "left_wrist_y,left_wrist_x,left_wrist_score,right_wrist_y,right_wrist_x,right_wrist_score,",
+    "left_hip_y,left_hip_x,left_hip_score,right_hip_y,right_hip_x,right_hip_score,",
+    "left_knee_y,left_knee_x,left_knee_score,right_knee_y,right_knee_x,right_knee_score,",
+    "left_ankle_y,left_ankle_x,left_ankle_score,right_ankle_y,right_ankle_x,right_ankle_score",
+);
+
+const CSV_PERSON: &str = concat!(
+    "7,1.500000,12.500,split:127.0.0.1:8000,2,200,100,",
+    "50,50,0,50,50,0.05,50,50,0.1,50,50,0.15,50,50,0.2,50,50,0.25,50,50,0.3,50,50,0.35,50,50,0.4,",
+    "50,50,0.45,50,50,0.5,50,50,0.55,50,50,0.6,50,50,0.65,50,50,0.7,50,50,0.75,50,50,0.8",
+);
+
+#[test]
+fn person_as_json_line() {
+    let (keypoints, transform) = (keypoints(), stretched());
+    let out = written(ResultFormat::JsonLines, &[record(Some(&keypoints), &transform, "split:127.0.0.1:8000")]);
+    assert_eq!(out, format!("{}\n", JSON_PERSON));
+}
+
+#[test]
+fn csv_starts_with_one_header() {
+    assert_eq!(csv_header(), CSV_HEADER);
+
+    let (keypoints, transform) = (keypoints(), stretched());
+    let person = record(Some(&keypoints), &transform, "split:127.0.0.1:8000");
+    let out = written(ResultFormat::Csv, &[person, record(Some(&keypoints), &transform, "split:127.0.0.1:8000")]);
+    assert_eq!(out, format!("{}\n{}\n{}\n", CSV_HEADER, CSV_PERSON, CSV_PERSON));
+}
+
+#[test]
+fn empty_frame_is_a_null_record() {
+    let transform = stretched();
+    let empty = record(None, &transform, "kernel");
+
+    assert_eq!(written(ResultFormat::JsonLines, &[empty]),
+               "{\"seq\":7,\"timestamp\":1.500000,\"latency_ms\":12.500,\"producer\":\"kernel\",\
+                \"person\":null,\"width\":200,\"height\":100,\"keypoints\":null}\n");
+
+    let empty = record(None, &transform, "kernel");
+    let out = written(ResultFormat::Csv, &[empty]);
+    let line = out.lines().nth(1).unwrap();
+    assert_eq!(line, format!("7,1.500000,12.500,kernel,,200,100{}", ",".repeat(51)));
+}
+
+#[test]
+fn short_output_leaves_missing_values_empty() {
+    let transform = stretched();
+    let keypoints = [0.5, 0.25, 0.9, f32::NAN];
+
+    let out = written(ResultFormat::JsonLines, &[record(Some(&keypoints), &transform, "kernel")]);
+    assert!(out.contains(r#""nose":{"y":50,"x":50,"score":0.9},"left_eye":{"y":null,"x":null,"score":null}"#), "{}", out);
+
+    let out = written(ResultFormat::Csv, &[record(Some(&keypoints), &transform, "kernel")]);
+    let line = out.lines().nth(1).unwrap();
+    assert_eq!(line, format!("7,1.500000,12.500,kernel,2,200,100,50,50,0.9{}", ",".repeat(48)));
+}
+
+#[test]
+fn producer_is_escaped() {
+    let transform = stretched();
+    let producer = "a \"b\",\nc\\\u{1}";
+
+    let out = written(ResultFormat::JsonLines, &[record(None, &transform, producer)]);
+    assert!(out.contains(r#""producer":"a \"b\",\u000ac\\\u0001","#), "{}", out);
+
+    // Quoted (doubling the quotes) when it holds a comma, quote or newline; as is otherwise.
+    let out = written(ResultFormat::Csv, &[record(None, &transform, producer),
+                                           record(None, &transform, "split:host")]);
+    assert!(out.contains(",12.500,\"a \"\"b\"\",\nc\\\u{1}\",,200,"), "{}", out);
+    assert!(out.contains(",12.500,split:host,,200,"), "{}", out);
+}
@@ -0,0 +1,114 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use server_side::smoothing::{AxisFilter, Filter, Kalman, OneEuro, PoseSmoother, SmoothingConfig};
+
+// Keypoint filters fed synthetic tracks at 30 fps.
+
+const DT: f32 = 1.0 / 30.0;
+
+fn at(frame: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(1_000_000 + frame * 1000 / 30)
+}
+
+// 17 keypoints at (y, x) with `score`.
+fn pose(y: f32, x: f32, score: f32) -> Vec<f32> {
+    (0..17).flat_map(|_| [y, x, score]).collect()
+}
+
+// Deterministic +-amplitude noise.
+fn noise(i: usize, amplitude: f32) -> f32 {
+    if (i * 7919).is_multiple_of(3) { amplitude } else { -amplitude / 2.0 }
+}
+
+fn jitter(values: &[f32]) -> f32 {
+    values.windows(2).map(|w| (w[1] - w[0]).abs()).sum::<f32>() / (values.len() - 1) as f32
+}
+
+#[test]
+fn filters_reduce_jitter_on_a_still_point() {
+    for filter in [Filter::EMA, Filter::ONE_EURO, Filter::KALMAN] {
+        let mut axis = filter.make();
+        let raw: Vec<f32> = (0..60).map(|i| 0.5 + noise(i, 0.02)).collect();
+        let smoothed: Vec<f32> = raw.iter().map(|v| axis.update(*v, DT)).collect();
+
+        assert!(jitter(&smoothed[10..]) < jitter(&raw[10..]) / 2.0, "{:?}", filter);
+        assert!((smoothed[59] - 0.5).abs() < 0.02, "{:?}", filter);
+    }
+}
+
+#[test]
+fn one_euro_follows_fast_movement() {
+    let mut slow = OneEuro::new(1.0, 0.0, 1.0);
+    let mut adaptive = OneEuro::new(1.0, 5.0, 1.0);
+    let (mut a, mut b) = (0.0, 0.0);
+    for i in 0..15 {
+        let value = i as f32 * 0.05;
+        a = slow.update(value, DT);
+        b = adaptive.update(value, DT);
+    }
+    // The speed-dependent cutoff lags the true 0.7 far less.
+    assert!((0.7 - b) < (0.7 - a) / 2.0, "{} {}", a, b);
+}
+
+#[test]
+fn kalman_tracks_constant_velocity() {
+    let mut kalman = Kalman::new(0.5, 1e-4);
+    let mut last = 0.0;
+    for i in 0..30 {
+        last = kalman.update(0.1 + i as f32 * 0.01, DT);
+    }
+    assert!((last - 0.39).abs() < 0.005, "{}", last);
+    assert!((kalman.velocity() - 0.3).abs() < 0.05, "{}", kalman.velocity());
+}
+
+#[test]
+fn low_confidence_keypoints_hold_then_release() {
+    let mut smoother = PoseSmoother::new(SmoothingConfig { filter: Filter::None, max_hold: 2, ..Default::default() });
+    smoother.apply(&pose(0.4, 0.6, 0.9), at(0));
+
+    // Held twice at the last good position and score...
+    for frame in 1..3 {
+        let out = smoother.apply(&pose(0.0, 0.0, 0.05), at(frame));
+        assert_eq!(&out[..3], &[0.4, 0.6, 0.9]);
+    }
+    // ...then the raw output goes through.
+    let out = smoother.apply(&pose(0.0, 0.0, 0.05), at(3));
+    assert_eq!(&out[..3], &[0.0, 0.0, 0.05]);
+}
+
+#[test]
+fn scene_change_resets_the_filters() {
+    let mut smoother = PoseSmoother::new(SmoothingConfig { filter: Filter::Ema { alpha: 0.1 }, ..Default::default() });
+    for frame in 0..10 {
+        smoother.apply(&pose(0.2, 0.2, 0.9), at(frame));
+    }
+
+    // A small step is smoothed...
+    let out = smoother.apply(&pose(0.25, 0.2, 0.9), at(10));
+    assert!(out[0] < 0.21);
+    assert_eq!(smoother.resets(), 0);
+
+    // ...a jump across the frame is taken as is.
+    let out = smoother.apply(&pose(0.8, 0.7, 0.9), at(11));
+    assert_eq!(&out[..2], &[0.8, 0.7]);
+    assert_eq!(smoother.resets(), 1);
+
+    // So is the first frame after a pause.
+    let out = smoother.apply(&pose(0.7, 0.7, 0.9), at(100));
+    assert_eq!(&out[..2], &[0.7, 0.7]);
+    assert_eq!(smoother.resets(), 2);
+}
+
+#[test]
+fn specs_are_parsed() {
+    assert_eq!(Filter::from_spec("none").unwrap(), Filter::None);
+    assert_eq!(Filter::from_spec("ema:0.3").unwrap(), Filter::Ema { alpha: 0.3 });
+    assert_eq!(Filter::from_spec("one-euro").unwrap(), Filter::ONE_EURO);
+    assert_eq!(Filter::from_spec("one-euro:0.5,2").unwrap(),
+               Filter::OneEuro { min_cutoff: 0.5, beta: 2.0, d_cutoff: 1.0 });
+    assert_eq!(Filter::from_spec("kalman:1,0.001").unwrap(),
+               Filter::Kalman { process_noise: 1.0, measurement_noise: 0.001 });
+    assert!(Filter::from_spec("ema:2").is_err());
+    assert!(Filter::from_spec("kalman:1").is_err());
+    assert!(Filter::from_spec("median").is_err());
+}
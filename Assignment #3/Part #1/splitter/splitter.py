@@ -1,9 +1,21 @@
+import argparse
 import shutil
 import copy
 import json
 import os
 
+# tensor 181 : the int8 activation the model is cut at
+BOUNDARY = 181
+
 if __name__ == "__main__":
+    # float32 : DEQUANTIZE at the end of model_local / QUANTIZE at the start of model_remote
+    #           (4 bytes per value on the wire)
+    # int8    : cut right at tensor 181 and send it as it is, with its scale/zero point
+    #           (1 byte per value, no extra operators)
+    parser = argparse.ArgumentParser()
+    parser.add_argument("--boundary", choices=["float32", "int8"], default="float32")
+    args = parser.parse_args()
+
     # create json file from original model
     os.system("./flatc -t --strict-json --raw-binary --defaults-json schema.fbs -- model_original.tflite")
 
@@ -12,48 +24,58 @@ if __name__ == "__main__":
     with open("model_original.json", "r") as file: # create and open file
         model_original = json.load(file)
 
+    # what the boundary tensor looks like in the original model
+    boundary = model_original["subgraphs"][0]["tensors"][BOUNDARY]
+    print("boundary tensor", BOUNDARY, ":", boundary["type"], boundary["shape"], boundary.get("quantization"))
+    print("splitting with a", args.boundary, "boundary")
+
     # model_local : client/server side version
     model_local = copy.deepcopy(model_original)
     with open("model_local.json", "w") as file: # edit the local model
-        # change the overall outputs
-        model_local["subgraphs"][0]["outputs"] = [333]
-
-        # create new buffer
-        buffers = model_local["buffers"]
-        buffers += [{}]
-
-        model_local["buffers"] = buffers # list of buffers
-
-        # create new tensor
-        tensors = model_local["subgraphs"][0]["tensors"]
-        tensors += [{
-            'shape': [1, 96, 96, 16],
-            'type': 'FLOAT32',
-            'buffer': 335,
-            'name': 'StatefulPartitionedCall:0',
-            'quantization': {
-                'details_type': 'NONE',
-                'quantized_dimension': 0
-            },
-            'is_variable': False,
-            'has_rank': False
-        }]
-
-        model_local["subgraphs"][0]["tensors"] = tensors # list of tensors
-
-        # change the operators vector
-        operators = model_local["subgraphs"][0]["operators"][:8]
-        operators += [{
-            'opcode_index': 13,
-            'inputs': [181],
-            'outputs': [333],
-            'builtin_options_type': 'NONE',
-            'custom_options_format': 'FLEXBUFFERS'
-        }]
-
-        model_local["subgraphs"][0]["operators"] = operators # list of operators
+        if args.boundary == "int8":
+            # output the quantized tensor directly, nothing to add
+            model_local["subgraphs"][0]["outputs"] = [BOUNDARY]
+            model_local["subgraphs"][0]["operators"] = model_local["subgraphs"][0]["operators"][:8]
+        else:
+            # change the overall outputs
+            model_local["subgraphs"][0]["outputs"] = [333]
+
+            # create new buffer
+            buffers = model_local["buffers"]
+            buffers += [{}]
+
+            model_local["buffers"] = buffers # list of buffers
+
+            # create new tensor
+            tensors = model_local["subgraphs"][0]["tensors"]
+            tensors += [{
+                'shape': [1, 96, 96, 16],
+                'type': 'FLOAT32',
+                'buffer': 335,
+                'name': 'StatefulPartitionedCall:0',
+                'quantization': {
+                    'details_type': 'NONE',
+                    'quantized_dimension': 0
+                },
+                'is_variable': False,
+                'has_rank': False
+            }]
+
+            model_local["subgraphs"][0]["tensors"] = tensors # list of tensors
+
+            # change the operators vector
+            operators = model_local["subgraphs"][0]["operators"][:8]
+            operators += [{
+                'opcode_index': 13,
+                'inputs': [181],
+                'outputs': [333],
+                'builtin_options_type': 'NONE',
+                'custom_options_format': 'FLEXBUFFERS'
+            }]
+
+            model_local["subgraphs"][0]["operators"] = operators # list of operators
         
-        # breakpoint() # breakpoint
+            # breakpoint() # breakpoint
 
         json.dump(model_local, file) # put changes to new json
     
@@ -67,45 +89,50 @@ if __name__ == "__main__":
     # model_remote : remote server version
     model_remote = copy.deepcopy(model_original)
     with open("model_remote.json", "w") as file: # edit the remote model
-        # change the overall inputs
-        model_remote["subgraphs"][0]["inputs"] = [333]
-
-        # create new buffer
-        buffers = model_remote["buffers"]
-        buffers += [{}]
-
-        model_remote["buffers"] = buffers # list of buffers
-
-        # create new tensor
-        tensors = model_remote["subgraphs"][0]["tensors"]
-        tensors += [{
-            'shape': [1, 96, 96, 16],
-            'type': 'FLOAT32',
-            'buffer': 335,
-            'name': 'StatefulPartitionedCall:0',
-            'quantization': {
-                'details_type': 'NONE',
-                'quantized_dimension': 0
-            },
-            'is_variable': False,
-            'has_rank': False
-        }]
-
-        model_remote["subgraphs"][0]["tensors"] = tensors # list of tensors
-
-        # change operators vector
-        operators = model_remote["subgraphs"][0]["operators"][8:]
-        operators[:0] = [{
-            'opcode_index': 1,
-            'inputs': [333],
-            'outputs': [181],
-            'builtin_options_type': 'NONE',
-            'custom_options_format': 'FLEXBUFFERS'
-        }]
-
-        model_remote["subgraphs"][0]["operators"] = operators # list of operators
-
-        # breakpoint() # breakpoint
+        if args.boundary == "int8":
+            # take the quantized tensor directly, nothing to add
+            model_remote["subgraphs"][0]["inputs"] = [BOUNDARY]
+            model_remote["subgraphs"][0]["operators"] = model_remote["subgraphs"][0]["operators"][8:]
+        else:
+            # change the overall inputs
+            model_remote["subgraphs"][0]["inputs"] = [333]
+
+            # create new buffer
+            buffers = model_remote["buffers"]
+            buffers += [{}]
+
+            model_remote["buffers"] = buffers # list of buffers
+
+            # create new tensor
+            tensors = model_remote["subgraphs"][0]["tensors"]
+            tensors += [{
+                'shape': [1, 96, 96, 16],
+                'type': 'FLOAT32',
+                'buffer': 335,
+                'name': 'StatefulPartitionedCall:0',
+                'quantization': {
+                    'details_type': 'NONE',
+                    'quantized_dimension': 0
+                },
+                'is_variable': False,
+                'has_rank': False
+            }]
+
+            model_remote["subgraphs"][0]["tensors"] = tensors # list of tensors
+
+            # change operators vector
+            operators = model_remote["subgraphs"][0]["operators"][8:]
+            operators[:0] = [{
+                'opcode_index': 1,
+                'inputs': [333],
+                'outputs': [181],
+                'builtin_options_type': 'NONE',
+                'custom_options_format': 'FLEXBUFFERS'
+            }]
+
+            model_remote["subgraphs"][0]["operators"] = operators # list of operators
+
+            # breakpoint() # breakpoint
 
         json.dump(model_remote, file) # put changes to new json
 
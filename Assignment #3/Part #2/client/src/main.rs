@@ -1,139 +1,181 @@
-use opencv::core::{CV_8UC3, Mat, /*Size, Mat_AUTO_STEP,*/ VecN};
-use opencv::highgui::*;
+use opencv::core::{copy_make_border, BORDER_CONSTANT, CV_8UC3, Mat, Rect, Scalar, Size, /*Mat_AUTO_STEP,*/ VecN};
+use opencv::imgproc::{cvt_color, resize, COLOR_BGR2YUV_I420, INTER_LINEAR};
+use opencv::prelude::*;
 
-use nix::libc::{c_int};
-// use std::env;
-use std::time::Instant;
+use std::env;
+use std::fs::File;
+use std::io::BufWriter;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
 
-use nix::fcntl::{open, OFlag};
-use nix::sys::stat::Mode;
-use nix::unistd::{close, write, read};
 use nix::errno::Errno;
-use nix::sys::uio::pread;
 
-mod utils;
-use utils::*;
+use kerncamera::{DeviceConfig, Kerncamera};
 
-mod v4l2;
-use v4l2::{VideoHandler, V4L2_MEMORY_MMAP, v4l2_buffer};
+use server_side::crop::{CropRegion, CropTracker};
+use server_side::frame_source;
+use server_side::output::{self, FrameSink, stop_requested};
+use server_side::pose::{OutputMonitor, Person, PersonTracker, MIN_PERSON_SCORE, MULTIPOSE_VALUES};
+use server_side::preprocess::{ResizeMode, Transform, MODEL_INPUT};
+use server_side::results::{PoseRecord, ResultsWriter};
+use server_side::smoothing::{Filter, PersonSmoothers, SmoothingConfig};
+use server_side::utils::*;
+
+mod server_facing;
+use server_facing::Handler;
 
 // const RCV_VIDEO: bool = false;
 const W: usize = 400;
 const H: usize = 712;
-const OUTPUT_SIZE: usize = 17*4*3;
-const V4L2_BUF_TYPE_VIDEO_CAPTURE: usize = 1;
-const PAGE_SIZE: usize = 4096;
-
-// Deserialize u64 array to bytes.
-fn u8_vec_of_u64_arr(a: &[u64]) -> Vec<u8> {
-    let mut ans = vec![];
-
-    for x in a {
-        for i in 0..8 {
-            ans.push(((x >> (i*8)) & 0xff) as u8);
-        }
-    }
+// Largest result the kernel module hands back: MultiPose, 6 people x 56 floats.
+const OUTPUT_SIZE: usize = MULTIPOSE_VALUES*4;
+// How long to wait for the kernel module's next pose before handling window events.
+const IDLE_WAIT: Duration = Duration::from_millis(30);
 
-    ans
+// Where each processed frame ends up.
+struct Outputs {
+    sink: Box<dyn FrameSink>,
+    style: PoseStyle,
+    rate: FrameRate,
+    // How the server fits our W x H frames to the model input.
+    transform: Transform,
+    tracker: PersonTracker,
+    smoothers: Option<PersonSmoothers>,
+    // Validates what comes back, counting (and optionally dumping) bad outputs.
+    monitor: OutputMonitor,
+    results: Option<ResultsWriter<BufWriter<File>>>,
+    // Which partition/server produced the poses, recorded with every result.
+    producer: String,
+    // Stop after this many frames, 0 for no limit.
+    limit: u64,
 }
 
-// Expects length is divisible by 4, may panic otherwise.
-fn f32_array_of_u8_array(arr: &[u8]) -> Vec<f32> {
-    if arr.len() % 4 != 0 {
-        println!("length not divisible by 4: {}", arr.len());
-        return vec![];
+impl Outputs {
+    // SinglePose or MultiPose output bytes -> people; invalid output is reported, counted and
+    // skipped.
+    fn people(&mut self, output: &[u8]) -> Vec<Person> {
+        match self.monitor.check(output, MIN_PERSON_SCORE) {
+            Ok(pose) => pose.persons,
+            Err(e) => {
+                println!("output [ERROR]: {}", e);
+                vec![]
+            }
+        }
     }
 
-    let final_len = arr.len() / 4;
-    let mut ans = vec![];
+    // Tracks, smooths, records and draws the people, hands the frame to the output and prints
+    // timings. Returns true once the run should stop.
+    fn present(&mut self, seq: u64, captured: SystemTime, mat_video: &mut Mat, mut persons: Vec<Person>,
+               now: Instant, after_interpreter: f64) -> bool {
+        self.tracker.assign(&mut persons);
+        if let Some(smoothers) = self.smoothers.as_mut() {
+            smoothers.apply(&mut persons, captured);
+        }
 
-    for i in 0..final_len {
-        let f = f32::from_le_bytes(<&[u8] as TryInto<[u8; 4]>>::try_into(&arr[i*4..(i*4)+4]).expect("slice conversion failed"));
-        ans.push(f);
+        // One record per person, or a null one when nobody was detected.
+        if let Some(results) = self.results.as_mut() {
+            for person in persons.iter().map(Some).chain(persons.is_empty().then_some(None)) {
+                results.write(&PoseRecord {
+                    seq,
+                    timestamp: captured,
+                    latency: captured.elapsed().unwrap_or_default(),
+                    person: person.and_then(|person| person.id),
+                    keypoints: person.map(|person| &person.keypoints[..]),
+                    transform: &self.transform,
+                    producer: &self.producer,
+                }).expect("results [ERROR]");
+            }
+        }
 
-    }
+        // Draw & present annotated frame.
+        for person in &persons {
+            draw_person(mat_video, person, &self.transform, &self.style).expect("draw_person [ERROR]");
+        }
+        let overlay = Overlay {
+            fps: Some(self.rate.tick()),
+            latency: Some(captured.elapsed().unwrap_or_default()),
+            mode: Some(InferenceMode::Server),
+        };
+        draw_overlay(mat_video, &overlay, &self.style).expect("draw_overlay [ERROR]");
+        let quit = self.sink.present(mat_video).expect("present [ERROR]");
+        let total = now.elapsed().as_secs_f64();
+        let after_present = total - after_interpreter;
 
-    ans
-}
+        // Print benchmarking code.
+        let interp_frac = after_interpreter / total * 100.0;
+        let present_frac = after_present / total * 100.0;
+        println!("total {:.5} | interp {:.5} {:.3}% | present {:.5} {:.3}%",
+                 total, after_interpreter, interp_frac, after_present, present_frac);
 
-fn u64_of_u8_arr(a: &[u8]) -> u64 {
-    let mut ans: u64 = 0;
-    for i in 0..8 {
-        ans += (a[i] as u64) << (8*(i as u64));
+        // Exit if key pressed, SIGINT/SIGTERM received or enough frames were processed.
+        quit || stop_requested() || (self.limit > 0 && seq + 1 >= self.limit)
     }
-    ans
 }
 
-// https://stackoverflow.com/questions/5748492/is-there-any-api-for-determining-the-physical-address-from-virtual-address-in-li/45128487#45128487
-pub fn read_pfn(fd: c_int, vaddr: u64) -> Result<u64, Errno> {
-    let mut nread = 0;
-    let mut data: [u8; 8] = [0; 8];
-
-    let vpn = vaddr / (PAGE_SIZE as u64);
-
-    while nread < 8 {
-        let ret = pread(fd, &mut data[nread..], (vpn*8 + (nread as u64)) as i64)?;
-        nread += ret;
-        if ret <= 0 {
-            println!("pread error in read_pfn: returned {}", ret);
-            return Ok(0)
+impl Drop for Outputs {
+    fn drop(&mut self) {
+        println!("outputs: {}", self.monitor);
+        if let Some(results) = self.results.as_mut() {
+            results.flush().expect("results [ERROR]");
         }
     }
+}
 
-    let entry = u64_of_u8_arr(&data);
-
-    Ok(entry & ((1u64 << 55) - 1))
+// Value following `--name` on the command line.
+fn arg(name: &str) -> Option<String> {
+    let args: Vec<String> = env::args().collect();
+    args.iter().position(|a| a == name).and_then(|i| args.get(i + 1).cloned())
 }
 
+// Frames are captured and shipped to the server by the kernel module; we only read back poses.
+// The module (or kerncamera-mock, listening on `path`) captures from `device` and sends to its
+// server_addr/server_port parameters unless `server` overrides them.
+fn run_kernel(path: &str, device: &str, server: Option<&str>, outputs: &mut Outputs) {
+    let mut camera = Kerncamera::open_path(path).expect("open kerncamera [ERROR]");
+    if let Some(server) = server {
+        let server: SocketAddr = server.parse().expect("--server expects <ipv4>:<port> or [<ipv6>]:<port>");
+        camera.set_server(server).expect("set kernel server [ERROR]");
+    }
+    camera.set_device(&DeviceConfig::yuv420(device, W as u32, H as u32)).expect("set kernel device [ERROR]");
+    camera.start().expect("start kernel capture [ERROR]");
+    // The server only takes W x H frames, and we draw on a W x H canvas.
+    let config = camera.device().expect("get kernel device [ERROR]");
+    assert_eq!((config.width, config.height), (W as u32, H as u32), "{} does not capture {}x{}", device, W, H);
 
-fn main() {
-    // IP address is baked into the kernel module now.
-    // let addr = env::args().nth(1).expect("Usage: ./cmd <address>");
-
-    let video_handler = VideoHandler::new().unwrap();
-
-    // Acquire address & pfn pairs to pass to kernel.
-    // TODO: buf2 is redundant. Make buf1 more stable, ie static or Pinned.
-    let fd = open("/proc/self/pagemap", OFlag::O_RDONLY, Mode::S_IRUSR.union(Mode::S_IWUSR)).unwrap();
-    let mut buf1 = v4l2_buffer {
-           type_: V4L2_BUF_TYPE_VIDEO_CAPTURE as u32,
-           memory: V4L2_MEMORY_MMAP as u32,
-           ..Default::default()
-    };
-    let mut buf2 = v4l2_buffer {
-           type_: V4L2_BUF_TYPE_VIDEO_CAPTURE as u32,
-           memory: V4L2_MEMORY_MMAP as u32,
-           ..Default::default()
-    };
+    // The module's worker keeps producing poses; wait for them in short steps so the window
+    // stays responsive and ^C is noticed.
+    camera.set_nonblocking(true).expect("set kernel nonblocking [ERROR]");
 
-    let buf1_vaddr = (&buf1 as *const v4l2_buffer) as u64;
-    let buf1_pfn = read_pfn(fd, buf1_vaddr).unwrap();
-    let buf2_vaddr = (&buf2 as *const v4l2_buffer) as u64;
-    let buf2_pfn = read_pfn(fd, buf2_vaddr).unwrap();
-    let mmap1_vaddr = video_handler.buffers[0].start as u64;
-    let mmap1_pfn = read_pfn(fd, mmap1_vaddr).unwrap();
-    let mmap2_vaddr = video_handler.buffers[1].start as u64;
-    let mmap2_pfn = read_pfn(fd, mmap2_vaddr).unwrap();
-    close(fd).unwrap();
-
-    // Send addresses to kernel via write()
-    let fd2 = open("/dev/kerncamera", OFlag::O_RDWR, Mode::S_IRUSR.union(Mode::S_IWUSR)).unwrap();
-    let arr = [buf1_vaddr, buf1_pfn, buf2_vaddr, buf2_pfn, mmap1_vaddr, mmap1_pfn, mmap2_vaddr, mmap2_pfn];
-    let v = u8_vec_of_u64_arr(&arr);
-    let _nbytes = write(fd2, v.as_slice()).unwrap();
-
-    // One iteration per frame, sequentially.
+    // One iteration per pose.
+    let mut seq = 0;
     loop {
+        if !camera.wait(Some(IDLE_WAIT)).expect("poll /dev/kerncamera [ERROR]") {
+            if stop_requested() || outputs.sink.idle().expect("idle [ERROR]") {
+                break;
+            }
+            continue;
+        }
         let now = Instant::now();
+        let captured = SystemTime::now();
 
-        // Obtain a frame via read()
+        // Obtain a pose via read()
         let mut buf: [u8; OUTPUT_SIZE] = [0; OUTPUT_SIZE];
-        read(fd2, &mut buf).unwrap();
-        let out_points = f32_array_of_u8_array(buf.as_slice());
+        let n = match camera.read_pose(&mut buf).map_err(|e| Errno::from_i32(e.raw_os_error().unwrap_or(0))) {
+            Ok(n) => n,
+            Err(Errno::EAGAIN) => continue,
+            // The module could not reach the server, lost it, timed out waiting for it or got a
+            // reply it could not make sense of; it reconnects (with backoff) for the next frame.
+            Err(e @ (Errno::ECONNREFUSED | Errno::ECONNRESET | Errno::EPIPE | Errno::ETIMEDOUT
+                     | Errno::EHOSTUNREACH | Errno::ENETUNREACH | Errno::EPROTO | Errno::EINTR)) => {
+                println!("read /dev/kerncamera [ERROR]: {}", e);
+                continue;
+            }
+            Err(e) => panic!("read /dev/kerncamera: {}", e),
+        };
+        let persons = outputs.people(&buf[..n]);
         let after_interpreter = now.elapsed().as_secs_f64();
-        println!("points: {:?}", out_points);
 
-        let output_data = out_points.as_slice();
         let mut mat_video =
             Mat::new_rows_cols_with_default(
                     H as i32,
@@ -142,23 +184,134 @@ fn main() {
                     VecN::new(1.0, 1.0, 1.0, 1.0)
                     ).unwrap();
 
-        // Draw & present annotated frame.
-        draw_keypoints(&mut mat_video, output_data, 0.25);
-        imshow("MoveNet", &mat_video).expect("imshow [ERROR]");
-        let total = now.elapsed().as_secs_f64();
-        let after_present = total - after_interpreter;
+        if outputs.present(seq, captured, &mut mat_video, persons, now, after_interpreter) {
+            break;
+        }
+        seq += 1;
+    }
+    let stats = camera.stats().expect("kernel stats [ERROR]");
+    println!("kerncamera: {} frames, {} capture errors, {} server errors, {} connects",
+             stats.frames, stats.capture_errors, stats.server_errors, stats.connects);
+    match kerncamera::debug_stats() {
+        Ok(text) => print!("{}", text),
+        Err(e) => println!("{} [UNAVAILABLE]: {}", kerncamera::DEBUGFS_STATS, e),
+    }
+    camera.stop().expect("stop kernel capture [ERROR]");
+}
 
-        // Print benchmarking code.
-        let interp_frac = after_interpreter / total * 100.0;
-        let present_frac = after_present / total * 100.0;
-        println!("total {:.5} | interp {:.5} {:.3}% | present {:.5} {:.3}%",
-                 total, after_interpreter, interp_frac, after_present, present_frac);
+// Cuts `region` out of the W x H frame, padding with black, and stretches it back to W x H.
+fn crop_frame(mat_video: &Mat, region: &CropRegion) -> Mat {
+    let (x, y, w, h) = region.to_pixels(W as u32, H as u32);
+    let (x, y, w, h) = (x.round() as i32, y.round() as i32, (w.round() as i32).max(1), (h.round() as i32).max(1));
+    let (left, top) = ((-x).max(0), (-y).max(0));
+    let (right, bottom) = ((x + w - W as i32).max(0), (y + h - H as i32).max(0));
+
+    let mut padded = Mat::default();
+    copy_make_border(mat_video, &mut padded, top, bottom, left, right, BORDER_CONSTANT,
+                     Scalar::all(0.0)).unwrap();
+    let roi = Mat::roi(&padded, Rect::new(x + left, y + top, w, h)).unwrap();
+
+    let mut cropped = Mat::default();
+    resize(&roi, &mut cropped, Size::new(W as i32, H as i32), 0.0, 0.0, INTER_LINEAR).unwrap();
+    cropped
+}
 
-        // Exit if key pressed.
-        let key = wait_key(1).unwrap();
-        if key > 0 && key != 255 {
+// Frames come from a file, image directory or pattern and are sent to the server from userspace,
+// using the same length-prefixed YUV420 protocol as the kernel module. With `crop`, the server gets
+// the region around the body found in the previous frame instead of the whole frame.
+fn run_userspace(spec: &str, addr: String, crop: bool, outputs: &mut Outputs) {
+    let mut source = frame_source::from_spec(spec).expect("Open frame source [FAILED]");
+    let mut handler = Handler::new(addr).expect("Connection [FAILED]");
+    let mut cropper = crop.then(CropTracker::new);
+
+    while let Some(frame) = source.next_frame().expect("Reading frame [FAILED]") {
+        let now = Instant::now();
+
+        // Match the frame the kernel module would send: W x H, planar YUV420.
+        let mut mat_video = Mat::default();
+        resize(&frame.to_mat().unwrap(), &mut mat_video, Size::new(W as i32, H as i32),
+               0.0, 0.0, INTER_LINEAR).unwrap();
+        let region = cropper.as_mut().map(|c| c.region(W as u32, H as u32));
+        let sent = match &region {
+            Some(region) => crop_frame(&mat_video, region),
+            None => mat_video.clone(),
+        };
+        let mut yuv = Mat::default();
+        cvt_color(&sent, &mut yuv, COLOR_BGR2YUV_I420, 0).unwrap();
+
+        let (_, out_points) = handler.analyze(yuv.data_bytes().unwrap()).unwrap();
+        let mut persons = outputs.people(&out_points);
+        let after_interpreter = now.elapsed().as_secs_f64();
+
+        // Place the next crop around the most confident person, and hand on the keypoints as if
+        // the whole frame had been sent.
+        if let (Some(cropper), Some(region)) = (cropper.as_mut(), region) {
+            let crop_transform = region.transform((W as u32, H as u32), (W as u32, H as u32));
+            match persons.iter().max_by(|a, b| a.score.total_cmp(&b.score)) {
+                Some(person) => cropper.update(&person.keypoints, &crop_transform),
+                None => cropper.reset(),
+            }
+            for person in persons.iter_mut() {
+                person.reproject(&crop_transform, &outputs.transform);
+            }
+        }
+
+        if outputs.present(frame.seq, frame.timestamp, &mut mat_video, persons, now, after_interpreter) {
             break;
         }
     }
-    close(fd2).unwrap();
+}
+
+fn main() {
+    // Usage: ./rust_movenet [--source <spec>] [--server <address>] [--device <path>]
+    //                       [--kerncamera <path>]
+    //                       [--output <spec>] [--frames <n>]
+    //                       [--results <path>] [--labels] [--smooth <filter>]
+    //                       [--crop] [--dump-invalid <dir>]
+    //   --source defaults to v4l2, which goes through /dev/kerncamera. Any other frame source
+    //   (file:<path>, dir:<path>, pattern[:<W>x<H>]) is sent to --server from userspace.
+    //   With v4l2, --server (<ipv4>:<port> or [<ipv6>]:<port>) repoints the kernel module and
+    //   --device picks the camera it captures from (default /dev/video0).
+    //   --kerncamera is the module's device (default /dev/kerncamera) or the socket of a
+    //   kerncamera-mock, which stands in for the module and the camera.
+    //   --output is window (default), file:<path>, images:<dir> or http:<addr:port>.
+    //   --frames stops after that many frames; ^C also stops cleanly.
+    //   --results writes every pose to a .jsonl or .csv file.
+    //   --labels names the joints on the skeleton.
+    //   --smooth filters keypoints over time: ema, one-euro or kalman, optionally with parameters
+    //   (e.g. ema:0.3, see Filter::from_spec).
+    //   --crop sends the server the region around the body in the previous frame (not with
+    //   the kernel path, where the module sends whole frames).
+    //   --dump-invalid keeps every output that fails validation (raw bytes and the reason).
+    let spec = arg("--source").unwrap_or("v4l2".to_string());
+    let addr = arg("--server");
+    let mut outputs = Outputs {
+        sink: output::from_spec(&arg("--output").unwrap_or("window".to_string())).unwrap(),
+        style: PoseStyle { labels: env::args().any(|a| a == "--labels"), ..Default::default() },
+        rate: FrameRate::new(),
+        transform: Transform::new((W as u32, H as u32), MODEL_INPUT, ResizeMode::Stretch),
+        tracker: PersonTracker::new(),
+        smoothers: arg("--smooth").map(|spec| PersonSmoothers::new(SmoothingConfig {
+            filter: Filter::from_spec(&spec).unwrap(),
+            ..Default::default()
+        })),
+        monitor: OutputMonitor::new(arg("--dump-invalid").map(PathBuf::from)).unwrap(),
+        results: arg("--results").map(|path| ResultsWriter::create(&path).unwrap()),
+        producer: match &addr {
+            Some(addr) if spec != "v4l2" => format!("server:{}", addr),
+            Some(addr) => format!("kernel:{}", addr),
+            None => "kernel".to_string(),
+        },
+        limit: arg("--frames").map_or(0, |n| n.parse().expect("--frames expects a number")),
+    };
+    output::install_stop_handler().unwrap();
+
+    if spec == "v4l2" {
+        let device = arg("--device").unwrap_or("/dev/video0".to_string());
+        let path = arg("--kerncamera").unwrap_or(kerncamera::DEVICE_PATH.to_string());
+        run_kernel(&path, &device, addr.as_deref(), &mut outputs);
+    } else {
+        let addr = addr.expect("--server <address> is required for non-v4l2 sources");
+        run_userspace(&spec, addr, env::args().any(|a| a == "--crop"), &mut outputs);
+    }
 }
@@ -1,13 +1,14 @@
 use std::io::{Error, ErrorKind};
 use std::io::prelude::*;
-use std::convert::TryInto;
 use std::net::TcpStream;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 const RCV_VIDEO: bool = false;
 
 /**
- * Collection of diy serialization/deserialization functions between u64, arrays/vecs of floats to
- * arrays/vecs of u8s for sending over the network. Simpler than learning the serde crate.
+ * Collection of diy serialization/deserialization functions between u64 and arrays/vecs of u8s for
+ * sending over the network. Simpler than learning the serde crate. The keypoints coming back are
+ * handed on as bytes and decoded/validated by server_side::pose::OutputMonitor.
  *
  * multi-byte types use little endian byte order.
  */
@@ -32,25 +33,6 @@ fn u64_of_array(arr: &[u8]) -> u64 {
     ans
 }
 
-// Expects length is divisible by 4, may panic otherwise.
-fn f32_array_of_u8_array(arr: &[u8]) -> Vec<f32> {
-    if arr.len() % 4 != 0 {
-        println!("length not divisible by 4: {}", arr.len());
-        return vec![];
-    }
-
-    let final_len = arr.len() / 4;
-    let mut ans = vec![];
-
-    for i in 0..final_len {
-        let f = f32::from_le_bytes(<&[u8] as TryInto<[u8; 4]>>::try_into(&arr[i*4..(i*4)+4]).expect("slice conversion failed"));
-        ans.push(f);
-
-    }
-
-    ans
-}
-
 pub struct Handler {
     stream: TcpStream
 }
@@ -70,7 +52,8 @@ impl Handler {
         Ok(Handler { stream: stream })
     }
 
-    pub fn analyze(&mut self, data:&[u8]) -> std::io::Result<(Vec<u8>, Vec<f32>)> {
+    // Returns the video data (empty unless RCV_VIDEO) and the raw point data.
+    pub fn analyze(&mut self, data:&[u8]) -> std::io::Result<(Vec<u8>, Vec<u8>)> {
         // Send length of data as u64, then send data.
         let mut len_array = u8_array_of_u64(data.len() as u64);
         self.stream.write_all(len_array.as_mut_slice())?;
@@ -97,14 +80,16 @@ impl Handler {
             }
             let pt_data = rcv_vec_u8.split_off(rcv_video_len);
 
-            // Convert return data to array of f32s.
-            let rcv_vec = f32_array_of_u8_array(pt_data.as_slice());
-
-            Ok((rcv_vec_u8, rcv_vec))
+            Ok((rcv_vec_u8, pt_data))
         } else {
-            let rcv_vec = f32_array_of_u8_array(rcv_vec_u8.as_slice());
-
-            Ok((vec![], rcv_vec))
+            Ok((vec![], rcv_vec_u8))
         }
     }
 }
+
+// Lets the connection be polled next to the camera, see VideoHandler::wait_any.
+impl AsRawFd for Handler {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
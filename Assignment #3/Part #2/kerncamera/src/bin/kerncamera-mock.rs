@@ -0,0 +1,37 @@
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use kerncamera::mock::{MockCamera, MockConfig};
+
+// Value following `--name` on the command line.
+fn arg(name: &str) -> Option<String> {
+    let args: Vec<String> = env::args().collect();
+    args.iter().position(|a| a == name).and_then(|i| args.get(i + 1).cloned())
+}
+
+fn main() {
+    // Usage: ./kerncamera-mock [--socket <path>] [--server <address>] [--timeout-ms <n>]
+    //                          [--frames <path>]
+    //   Stands in for /dev/kerncamera; point the client at it with --kerncamera <socket>.
+    //   --socket defaults to /tmp/kerncamera.sock.
+    //   --server (<ipv4>:<port> or [<ipv6>]:<port>) is where frames go until the client sets
+    //   another, by default remote_server on 127.0.0.1:8000.
+    //   --timeout-ms bounds the wait for a reply (default 5000, 0 for no limit).
+    //   --frames loops over a file of raw frames in the client's format instead of sending a
+    //   generated pattern.
+    let socket = arg("--socket").unwrap_or("/tmp/kerncamera.sock".to_string());
+    let mut config = MockConfig::default();
+    if let Some(server) = arg("--server") {
+        config.server = server.parse().expect("--server expects <ipv4>:<port> or [<ipv6>]:<port>");
+    }
+    if let Some(ms) = arg("--timeout-ms") {
+        let ms: u64 = ms.parse().expect("--timeout-ms expects a number");
+        config.timeout = (ms > 0).then(|| Duration::from_millis(ms));
+    }
+    config.frames = arg("--frames").map(PathBuf::from);
+
+    let mock = MockCamera::bind(&socket, config.clone()).expect("bind mock socket [FAILED]");
+    println!("kerncamera mock on {}, sending to {}", socket, config.server);
+    mock.run().expect("accept [FAILED]");
+}
@@ -0,0 +1,392 @@
+/*!
+ * Userspace side of /dev/kerncamera, the kernel module in ../kernel_module.
+ *
+ * The structs and request numbers mirror kerncamera.h there, which documents each request and
+ * the errors it returns; `Kerncamera` wraps them in safe calls. It also talks to the stand-in in
+ * `mock`, which needs neither the module nor a camera. `protocol` is what the module (and the mock)
+ * and remote_server say to each other.
+ */
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read};
+use std::mem::size_of;
+use std::net::{IpAddr, SocketAddr};
+use std::os::raw::c_int;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::{ioctl_none, ioctl_read, ioctl_write_ptr};
+
+pub mod mock;
+pub mod protocol;
+
+pub const DEVICE_PATH: &str = "/dev/kerncamera";
+// The module's counters and latency histograms as text; debugfs is usually only readable by root.
+pub const DEBUGFS_STATS: &str = "/sys/kernel/debug/kerncamera/stats";
+pub const KERNCAMERA_VERSION: u32 = 1;
+// Largest reply read() returns: MultiPose, 6 people x 56 floats.
+pub const POSE_SIZE: usize = 6*56*4;
+pub const V4L2_PIX_FMT_YUV420: u32 = fourcc(b"YU12");
+
+pub const fn fourcc(code: &[u8; 4]) -> u32 {
+    u32::from_le_bytes(*code)
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct KerncameraServer {
+    pub version: u32,
+    pub port: u16,
+    pub reserved: u16,
+    pub addr: [u8; 48],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct KerncameraDevice {
+    pub version: u32,
+    pub width: u32,
+    pub height: u32,
+    pub pixelformat: u32,
+    pub buffers: u32,
+    pub reserved: u32,
+    pub path: [u8; 64],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct KerncameraTimeout {
+    pub version: u32,
+    pub timeout_ms: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KerncameraStats {
+    pub version: u32,
+    pub streaming: u32,
+    pub frames: u64,
+    pub capture_errors: u64,
+    pub server_errors: u64,
+    pub connects: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub last_exchange_ns: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct KerncameraPose {
+    pub version: u32,
+    pub length: u32,
+    pub frame: u64,
+    pub timestamp_ns: u64,
+    pub data: [u8; POSE_SIZE],
+}
+
+// The same sizes as in kerncamera.h; they are part of the request numbers.
+const _: () = assert!(size_of::<KerncameraServer>() == 56);
+const _: () = assert!(size_of::<KerncameraDevice>() == 88);
+const _: () = assert!(size_of::<KerncameraTimeout>() == 8);
+const _: () = assert!(size_of::<KerncameraStats>() == 64);
+const _: () = assert!(size_of::<KerncameraPose>() == 1368);
+
+// The structs above: no padding, and any bytes make a valid value, so the mock can pass them
+// around as bytes. Only for such types.
+pub(crate) trait Plain: Copy {
+    fn zeroed() -> Self {
+        unsafe { std::mem::zeroed() }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self as *const Self as *const u8, size_of::<Self>()) }
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self as *mut Self as *mut u8, size_of::<Self>()) }
+    }
+}
+
+impl Plain for KerncameraServer {}
+impl Plain for KerncameraDevice {}
+impl Plain for KerncameraTimeout {}
+impl Plain for KerncameraStats {}
+impl Plain for KerncameraPose {}
+
+// Request numbers, which the mock uses as well.
+const KERNCAMERA_MAGIC: u8 = b'K';
+pub(crate) const NR_SET_SERVER: u32 = 1;
+pub(crate) const NR_GET_SERVER: u32 = 2;
+pub(crate) const NR_SET_DEVICE: u32 = 3;
+pub(crate) const NR_GET_DEVICE: u32 = 4;
+pub(crate) const NR_START: u32 = 5;
+pub(crate) const NR_STOP: u32 = 6;
+pub(crate) const NR_GET_STATS: u32 = 7;
+pub(crate) const NR_GET_LAST_POSE: u32 = 8;
+pub(crate) const NR_SET_TIMEOUT: u32 = 9;
+ioctl_write_ptr!(kerncamera_set_server, KERNCAMERA_MAGIC, NR_SET_SERVER, KerncameraServer);
+ioctl_read!(kerncamera_get_server, KERNCAMERA_MAGIC, NR_GET_SERVER, KerncameraServer);
+ioctl_write_ptr!(kerncamera_set_device, KERNCAMERA_MAGIC, NR_SET_DEVICE, KerncameraDevice);
+ioctl_read!(kerncamera_get_device, KERNCAMERA_MAGIC, NR_GET_DEVICE, KerncameraDevice);
+ioctl_none!(kerncamera_start, KERNCAMERA_MAGIC, NR_START);
+ioctl_none!(kerncamera_stop, KERNCAMERA_MAGIC, NR_STOP);
+ioctl_read!(kerncamera_get_stats, KERNCAMERA_MAGIC, NR_GET_STATS, KerncameraStats);
+ioctl_read!(kerncamera_get_last_pose, KERNCAMERA_MAGIC, NR_GET_LAST_POSE, KerncameraPose);
+ioctl_write_ptr!(kerncamera_set_timeout, KERNCAMERA_MAGIC, NR_SET_TIMEOUT, KerncameraTimeout);
+
+// Copies `s` into a NUL-terminated C array.
+fn c_array<const N: usize>(s: &str) -> io::Result<[u8; N]> {
+    if s.len() >= N || s.contains('\0') {
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("{:?} does not fit {} bytes", s, N)));
+    }
+    let mut array = [0; N];
+    array[..s.len()].copy_from_slice(s.as_bytes());
+    Ok(array)
+}
+
+pub(crate) fn c_str(array: &[u8]) -> io::Result<&str> {
+    array.iter().position(|b| *b == 0)
+        .and_then(|end| std::str::from_utf8(&array[..end]).ok())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "string from kerncamera is not NUL-terminated UTF-8"))
+}
+
+// What to capture; see kerncamera_device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceConfig {
+    pub path: String,
+    pub width: u32,
+    pub height: u32,
+    pub pixelformat: u32,
+    pub buffers: u32,
+}
+
+impl DeviceConfig {
+    // `width` x `height` planar YUV420 from `path`, double buffered.
+    pub fn yuv420(path: &str, width: u32, height: u32) -> DeviceConfig {
+        DeviceConfig { path: path.to_string(), width, height, pixelformat: V4L2_PIX_FMT_YUV420, buffers: 2 }
+    }
+}
+
+// The server's reply to the most recent frame.
+#[derive(Clone, Debug)]
+pub struct LastPose {
+    // Number of the frame (counting answered frames) it belongs to.
+    pub frame: u64,
+    // CLOCK_MONOTONIC.
+    pub timestamp: Duration,
+    pub data: Vec<u8>,
+}
+
+pub fn debug_stats() -> io::Result<String> {
+    std::fs::read_to_string(DEBUGFS_STATS)
+}
+
+enum Backend {
+    Device(File),
+    // A connection to mock::MockCamera.
+    Mock(mock::Connection),
+}
+
+// One open file. The first one to set something or start owns the device until it stops or is
+// dropped; the others can only query meanwhile (EBUSY).
+pub struct Kerncamera {
+    backend: Backend,
+}
+
+impl Kerncamera {
+    pub fn open() -> io::Result<Kerncamera> {
+        Kerncamera::open_path(DEVICE_PATH)
+    }
+
+    // The device, or the Unix socket of a mock::MockCamera.
+    pub fn open_path(path: &str) -> io::Result<Kerncamera> {
+        let backend = if std::fs::metadata(path)?.file_type().is_socket() {
+            Backend::Mock(mock::Connection::connect(path)?)
+        } else {
+            Backend::Device(OpenOptions::new().read(true).write(true).open(path)?)
+        };
+        Ok(Kerncamera { backend })
+    }
+
+    // Request `nr` passing `arg` in; `ioctl` does it on the device.
+    fn set<T: Plain>(&self, nr: u32, arg: &T, ioctl: unsafe fn(c_int, *const T) -> nix::Result<c_int>) -> io::Result<()> {
+        match &self.backend {
+            Backend::Device(file) => {
+                unsafe { ioctl(file.as_raw_fd(), arg) }?;
+            }
+            Backend::Mock(mock) => {
+                mock.call(nr, arg.as_bytes(), &mut [])?;
+            }
+        }
+        Ok(())
+    }
+
+    // Request `nr` filling in `arg`.
+    fn get<T: Plain>(&self, nr: u32, arg: &mut T, ioctl: unsafe fn(c_int, *mut T) -> nix::Result<c_int>) -> io::Result<()> {
+        match &self.backend {
+            Backend::Device(file) => {
+                unsafe { ioctl(file.as_raw_fd(), arg) }?;
+            }
+            Backend::Mock(mock) => {
+                let len = mock.call(nr, &[], arg.as_bytes_mut())?;
+                if len != size_of::<T>() {
+                    return Err(io::Error::new(ErrorKind::InvalidData, format!("mock replied to request {} with {} bytes", nr, len)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Request `nr` without an argument.
+    fn command(&self, nr: u32, ioctl: unsafe fn(c_int) -> nix::Result<c_int>) -> io::Result<()> {
+        match &self.backend {
+            Backend::Device(file) => {
+                unsafe { ioctl(file.as_raw_fd()) }?;
+            }
+            Backend::Mock(mock) => {
+                mock.call(nr, &[], &mut [])?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_server(&self, server: SocketAddr) -> io::Result<()> {
+        let arg = KerncameraServer {
+            version: KERNCAMERA_VERSION,
+            port: server.port(),
+            reserved: 0,
+            addr: c_array(&server.ip().to_string())?,
+        };
+        self.set(NR_SET_SERVER, &arg, kerncamera_set_server)
+    }
+
+    pub fn server(&self) -> io::Result<SocketAddr> {
+        let mut arg = KerncameraServer::zeroed();
+        self.get(NR_GET_SERVER, &mut arg, kerncamera_get_server)?;
+        let ip: IpAddr = c_str(&arg.addr)?.parse()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("server address: {}", e)))?;
+        Ok(SocketAddr::new(ip, arg.port))
+    }
+
+    // Only while stopped (EBUSY otherwise).
+    pub fn set_device(&self, config: &DeviceConfig) -> io::Result<()> {
+        let arg = KerncameraDevice {
+            version: KERNCAMERA_VERSION,
+            width: config.width,
+            height: config.height,
+            pixelformat: config.pixelformat,
+            buffers: config.buffers,
+            reserved: 0,
+            path: c_array(&config.path)?,
+        };
+        self.set(NR_SET_DEVICE, &arg, kerncamera_set_device)
+    }
+
+    // While streaming, what the driver settled on.
+    pub fn device(&self) -> io::Result<DeviceConfig> {
+        let mut arg = KerncameraDevice::zeroed();
+        self.get(NR_GET_DEVICE, &mut arg, kerncamera_get_device)?;
+        Ok(DeviceConfig {
+            path: c_str(&arg.path)?.to_string(),
+            width: arg.width,
+            height: arg.height,
+            pixelformat: arg.pixelformat,
+            buffers: arg.buffers,
+        })
+    }
+
+    pub fn start(&self) -> io::Result<()> {
+        self.command(NR_START, kerncamera_start)
+    }
+
+    pub fn stop(&self) -> io::Result<()> {
+        self.command(NR_STOP, kerncamera_stop)
+    }
+
+    pub fn stats(&self) -> io::Result<KerncameraStats> {
+        let mut arg = KerncameraStats::default();
+        self.get(NR_GET_STATS, &mut arg, kerncamera_get_stats)?;
+        Ok(arg)
+    }
+
+    // None until a frame has been answered.
+    pub fn last_pose(&self) -> io::Result<Option<LastPose>> {
+        let mut arg = KerncameraPose::zeroed();
+        match self.get(NR_GET_LAST_POSE, &mut arg, kerncamera_get_last_pose) {
+            Ok(()) => {}
+            Err(e) if e.raw_os_error() == Some(Errno::ENODATA as i32) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let len = (arg.length as usize).min(POSE_SIZE);
+        Ok(Some(LastPose {
+            frame: arg.frame,
+            timestamp: Duration::from_nanos(arg.timestamp_ns),
+            data: arg.data[..len].to_vec(),
+        }))
+    }
+
+    // None for no limit; whole milliseconds, at most u32::MAX of them.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let timeout_ms = match timeout {
+            Some(timeout) => u32::try_from(timeout.as_millis()).ok().filter(|ms| *ms > 0)
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("timeout {:?} out of range", timeout)))?,
+            None => 0,
+        };
+        let arg = KerncameraTimeout { version: KERNCAMERA_VERSION, timeout_ms };
+        self.set(NR_SET_TIMEOUT, &arg, kerncamera_set_timeout)
+    }
+
+    // The next pose the module's worker queued, its length in `buf` (which should hold POSE_SIZE
+    // bytes). Blocks for it unless non-blocking, where an empty queue is ErrorKind::WouldBlock.
+    pub fn read_pose(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.backend {
+            Backend::Device(file) => file.read(buf),
+            Backend::Mock(mock) => mock.read(buf),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        let file = match &self.backend {
+            Backend::Device(file) => file,
+            Backend::Mock(mock) => {
+                mock.nonblocking.set(nonblocking);
+                return Ok(());
+            }
+        };
+        let mut flags = OFlag::from_bits_truncate(fcntl(file.as_raw_fd(), FcntlArg::F_GETFL)?);
+        flags.set(OFlag::O_NONBLOCK, nonblocking);
+        fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(flags))?;
+        Ok(())
+    }
+
+    // Waits up to `timeout` (None: for ever) for read_pose to have something, which includes the
+    // ENODEV it fails with once stopped. Returns false on timeout.
+    pub fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let file = match &self.backend {
+            Backend::Device(file) => file,
+            Backend::Mock(mock) => return mock.wait(timeout),
+        };
+        let timeout_ms = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+        let mut fds = [PollFd::new(file.as_raw_fd(), PollFlags::POLLIN)];
+        // A signal counts as a timeout, so the caller gets to check for ^C.
+        match poll(&mut fds, timeout_ms) {
+            Ok(n) => Ok(n > 0),
+            Err(Errno::EINTR) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// With the mock this is its socket, which cannot be polled for poses.
+impl AsRawFd for Kerncamera {
+    fn as_raw_fd(&self) -> i32 {
+        match &self.backend {
+            Backend::Device(file) => file.as_raw_fd(),
+            Backend::Mock(mock) => mock.as_raw_fd(),
+        }
+    }
+}
@@ -0,0 +1,684 @@
+/*!
+ * A userspace stand-in for /dev/kerncamera, for running the client without the kernel module or
+ * a camera (in CI, say).
+ *
+ * `MockCamera` listens on a Unix socket; every connection is an open file and `Kerncamera`
+ * connects to it when given the socket's path. It follows kerncamera.h: the same structs,
+ * checks, errnos, single owner and pose ring. Instead of capturing, its worker generates YUV420
+ * frames (or loops over the raw frames in a file) at about 30 per second, and sends them to the
+ * server with `protocol::exchange`. remote_server (by default on this machine) answers them with
+ * `protocol::serve` if they are `protocol::FRAME_WIDTH` x `FRAME_HEIGHT`; it hangs up on other
+ * sizes, which reads come back with as ECONNRESET.
+ *
+ * On the socket, a request is the request number (u32), the argument's length (u32) and the
+ * argument, which for the ioctls is the struct. The reply is an errno (i32, 0 for success), the
+ * data's length (u32) and the data. Both ends are on one machine, so all of it is in host byte
+ * order. read() and poll() are two more requests, OP_READ and OP_WAIT.
+ */
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::time::{clock_gettime, ClockId};
+
+use crate::protocol;
+use crate::{
+    c_str, KerncameraDevice, KerncameraPose, KerncameraServer, KerncameraStats, KerncameraTimeout, Plain,
+    KERNCAMERA_VERSION, NR_GET_DEVICE, NR_GET_LAST_POSE, NR_GET_SERVER, NR_GET_STATS, NR_SET_DEVICE,
+    NR_SET_SERVER, NR_SET_TIMEOUT, NR_START, NR_STOP, POSE_SIZE, V4L2_PIX_FMT_YUV420,
+};
+
+// read(); argument: u32, 1 if the file is non-blocking. Data: the pose.
+pub(crate) const OP_READ: u32 = 64;
+// poll(); argument: u32 timeout in ms, u32::MAX for none. Data: u32, 1 if read() would not block.
+pub(crate) const OP_WAIT: u32 = 65;
+
+// Largest argument a request can have.
+const MAX_ARG: usize = 256;
+const RING_SIZE: usize = 4;
+const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+// Same limits as the module.
+const MAX_DIMENSION: u32 = 8192;
+const MAX_BUFFERS: u32 = 8;
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_millis(5000);
+
+fn errno(e: &io::Error) -> Errno {
+    match e.kind() {
+        ErrorKind::UnexpectedEof => Errno::ECONNRESET,
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => Errno::ETIMEDOUT,
+        // A reply too long for a pose.
+        ErrorKind::InvalidData => Errno::EPROTO,
+        _ => e.raw_os_error().map_or(Errno::EIO, Errno::from_i32),
+    }
+}
+
+fn read_u32(stream: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    stream.read_exact(&mut bytes)?;
+    Ok(u32::from_ne_bytes(bytes))
+}
+
+/*******************
+ * The client side *
+ ******************/
+
+// An open file, for Kerncamera.
+pub(crate) struct Connection {
+    stream: UnixStream,
+    pub(crate) nonblocking: Cell<bool>,
+}
+
+impl Connection {
+    pub(crate) fn connect(path: &str) -> io::Result<Connection> {
+        Ok(Connection { stream: UnixStream::connect(path)?, nonblocking: Cell::new(false) })
+    }
+
+    // Sends request `op` with `arg` and puts the reply's data into `out`; returns its length.
+    // Errors come back as the errno.
+    pub(crate) fn call(&self, op: u32, arg: &[u8], out: &mut [u8]) -> io::Result<usize> {
+        let mut stream = &self.stream;
+        let mut request = Vec::with_capacity(8 + arg.len());
+        request.extend_from_slice(&op.to_ne_bytes());
+        request.extend_from_slice(&(arg.len() as u32).to_ne_bytes());
+        request.extend_from_slice(arg);
+        stream.write_all(&request)?;
+
+        let errno = read_u32(&mut stream)? as i32;
+        let len = read_u32(&mut stream)? as usize;
+        let mut data = vec![0; len];
+        stream.read_exact(&mut data)?;
+        if errno != 0 {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        // Like read() into a buffer too small for the pose.
+        if len > out.len() {
+            return Err(io::Error::from_raw_os_error(Errno::EFAULT as i32));
+        }
+        out[..len].copy_from_slice(&data);
+        Ok(len)
+    }
+
+    pub(crate) fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.call(OP_READ, &(self.nonblocking.get() as u32).to_ne_bytes(), buf)
+    }
+
+    pub(crate) fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let timeout_ms = timeout.map_or(u32::MAX, |t| t.as_millis().min(u32::MAX as u128 - 1) as u32);
+        let mut ready = [0; 4];
+        self.call(OP_WAIT, &timeout_ms.to_ne_bytes(), &mut ready)?;
+        Ok(u32::from_ne_bytes(ready) != 0)
+    }
+}
+
+impl AsRawFd for Connection {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+/*******************
+ * The mock itself *
+ ******************/
+
+// What the module gets from its parameters, and where frames come from.
+#[derive(Clone, Debug)]
+pub struct MockConfig {
+    pub server: SocketAddr,
+    // None for no limit.
+    pub timeout: Option<Duration>,
+    // Raw frames in the configured format, sent in turn and from the start again after the last;
+    // None for a generated pattern.
+    pub frames: Option<PathBuf>,
+}
+
+impl Default for MockConfig {
+    // remote_server on this machine, where its main binds.
+    fn default() -> MockConfig {
+        MockConfig {
+            server: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8000),
+            timeout: Some(Duration::from_millis(5000)),
+            frames: None,
+        }
+    }
+}
+
+struct Worker {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+    config: KerncameraDevice,
+}
+
+// SharedStateInner in the module.
+struct State {
+    server: SocketAddr,
+    server_changes: u64,
+    timeout_ms: u32,
+    frames: Option<PathBuf>,
+    config: Option<KerncameraDevice>,
+    worker: Option<Worker>,
+    stats: KerncameraStats,
+    last_pose: Option<KerncameraPose>,
+    ring: VecDeque<Result<Vec<u8>, Errno>>,
+    // The connection controlling the camera.
+    owner: Option<u64>,
+}
+
+impl State {
+    fn claim(&mut self, session: u64) -> Result<(), Errno> {
+        self.check_owner(session)?;
+        self.owner = Some(session);
+        Ok(())
+    }
+
+    fn check_owner(&self, session: u64) -> Result<(), Errno> {
+        match self.owner {
+            Some(owner) if owner != session => Err(Errno::EBUSY),
+            _ => Ok(()),
+        }
+    }
+
+    // When full, drops the oldest entry.
+    fn push(&mut self, entry: Result<Vec<u8>, Errno>) {
+        if self.ring.len() == RING_SIZE {
+            self.ring.pop_front();
+        }
+        self.ring.push_back(entry);
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+pub struct MockCamera {
+    listener: UnixListener,
+    shared: Arc<Shared>,
+}
+
+impl MockCamera {
+    // Listens on `path`, replacing a socket left there by an earlier run.
+    pub fn bind(path: impl AsRef<Path>, config: MockConfig) -> io::Result<MockCamera> {
+        let path = path.as_ref();
+        if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        let timeout_ms = match config.timeout {
+            Some(timeout) => timeout.as_millis().clamp(1, u32::MAX as u128) as u32,
+            None => 0,
+        };
+        let state = State {
+            server: config.server,
+            server_changes: 0,
+            timeout_ms,
+            frames: config.frames,
+            config: None,
+            worker: None,
+            stats: Default::default(),
+            last_pose: None,
+            ring: VecDeque::new(),
+            owner: None,
+        };
+        Ok(MockCamera { listener, shared: Arc::new(Shared { state: Mutex::new(state), changed: Condvar::new() }) })
+    }
+
+    // Serves every connection on a thread of its own, until accepting fails.
+    pub fn run(&self) -> io::Result<()> {
+        for (session, stream) in (0..).zip(self.listener.incoming()) {
+            let stream = stream?;
+            let shared = self.shared.clone();
+            thread::spawn(move || shared.serve(stream, session));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MockCamera {
+    fn drop(&mut self) {
+        self.shared.stop();
+    }
+}
+
+fn decode<T: Plain>(arg: &[u8]) -> Result<T, Errno> {
+    let mut value = T::zeroed();
+    if arg.len() != size_of::<T>() {
+        return Err(Errno::EINVAL);
+    }
+    value.as_bytes_mut().copy_from_slice(arg);
+    Ok(value)
+}
+
+fn decode_u32(arg: &[u8]) -> Result<u32, Errno> {
+    arg.try_into().map(u32::from_ne_bytes).map_err(|_| Errno::EINVAL)
+}
+
+fn check_version(version: u32) -> Result<(), Errno> {
+    if version != KERNCAMERA_VERSION {
+        return Err(Errno::EINVAL);
+    }
+    Ok(())
+}
+
+fn frame_size(config: &KerncameraDevice) -> usize {
+    let (w, h) = (config.width as usize, config.height as usize);
+    w * h + 2 * w.div_ceil(2) * h.div_ceil(2)
+}
+
+fn now_ns() -> u64 {
+    clock_gettime(ClockId::CLOCK_MONOTONIC).map_or(0, |t| t.tv_sec() as u64 * 1_000_000_000 + t.tv_nsec() as u64)
+}
+
+// Sleeps for `duration` unless `stop` is set first; returns false then.
+fn sleep_unless(stop: &AtomicBool, duration: Duration) -> bool {
+    let until = Instant::now() + duration;
+    while !stop.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        if now >= until {
+            return true;
+        }
+        thread::sleep((until - now).min(Duration::from_millis(10)));
+    }
+    false
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    // One open file, until the client closes it.
+    fn serve(self: Arc<Shared>, stream: UnixStream, session: u64) {
+        let mut stream = &stream;
+        loop {
+            let request = (|| {
+                let op = read_u32(&mut stream)?;
+                let len = read_u32(&mut stream)? as usize;
+                if len > MAX_ARG {
+                    return Err(io::Error::new(ErrorKind::InvalidData, "argument too long"));
+                }
+                let mut arg = vec![0; len];
+                stream.read_exact(&mut arg)?;
+                Ok((op, arg))
+            })();
+            let (op, arg) = match request {
+                Ok(request) => request,
+                Err(_) => break,
+            };
+            let (errno, data) = match self.handle(session, op, &arg) {
+                Ok(data) => (0, data),
+                Err(e) => (e as i32, vec![]),
+            };
+            let mut reply = Vec::with_capacity(8 + data.len());
+            reply.extend_from_slice(&errno.to_ne_bytes());
+            reply.extend_from_slice(&(data.len() as u32).to_ne_bytes());
+            reply.extend_from_slice(&data);
+            if stream.write_all(&reply).is_err() {
+                break;
+            }
+        }
+        self.release(session);
+    }
+
+    // The owner closing stops streaming.
+    fn release(&self, session: u64) {
+        let owned = {
+            let mut state = self.lock();
+            let owned = state.owner == Some(session);
+            if owned {
+                state.owner = None;
+            }
+            owned
+        };
+        if owned {
+            self.stop();
+        }
+    }
+
+    fn handle(self: &Arc<Shared>, session: u64, op: u32, arg: &[u8]) -> Result<Vec<u8>, Errno> {
+        let mut state = self.lock();
+        match op {
+            NR_SET_SERVER => {
+                let arg: KerncameraServer = decode(arg)?;
+                check_version(arg.version)?;
+                if arg.reserved != 0 || arg.port == 0 {
+                    return Err(Errno::EINVAL);
+                }
+                let ip: IpAddr = c_str(&arg.addr).ok().and_then(|a| a.parse().ok()).ok_or(Errno::EINVAL)?;
+                state.claim(session)?;
+                state.server = SocketAddr::new(ip, arg.port);
+                state.server_changes += 1;
+                Ok(vec![])
+            }
+            NR_GET_SERVER => {
+                let mut arg = KerncameraServer {
+                    version: KERNCAMERA_VERSION,
+                    port: state.server.port(),
+                    reserved: 0,
+                    addr: [0; 48],
+                };
+                let ip = state.server.ip().to_string();
+                arg.addr[..ip.len()].copy_from_slice(ip.as_bytes());
+                Ok(arg.as_bytes().to_vec())
+            }
+            // The path is checked like the module does but not opened, and only YUV420 is made.
+            NR_SET_DEVICE => {
+                let arg: KerncameraDevice = decode(arg)?;
+                check_version(arg.version)?;
+                let path = c_str(&arg.path).map_err(|_| Errno::EINVAL)?;
+                if arg.reserved != 0 || !path.starts_with("/dev/")
+                    || arg.width == 0 || arg.height == 0 || arg.width > MAX_DIMENSION || arg.height > MAX_DIMENSION
+                    || arg.buffers == 0 || arg.buffers > MAX_BUFFERS || arg.pixelformat != V4L2_PIX_FMT_YUV420 {
+                    return Err(Errno::EINVAL);
+                }
+                state.claim(session)?;
+                if state.worker.is_some() {
+                    return Err(Errno::EBUSY);
+                }
+                state.config = Some(arg);
+                Ok(vec![])
+            }
+            NR_GET_DEVICE => {
+                let config = match (&state.worker, &state.config) {
+                    (Some(worker), _) => worker.config,
+                    (None, Some(config)) => *config,
+                    (None, None) => return Err(Errno::ENODEV),
+                };
+                Ok(config.as_bytes().to_vec())
+            }
+            NR_START => {
+                state.claim(session)?;
+                if state.worker.is_some() {
+                    return Err(Errno::EBUSY);
+                }
+                let config = state.config.ok_or(Errno::ENODEV)?;
+                let frames = match &state.frames {
+                    Some(path) => Some(Frames::open(path, frame_size(&config)).map_err(|e| errno(&e))?),
+                    None => None,
+                };
+                let stop = Arc::new(AtomicBool::new(false));
+                let thread = {
+                    let (shared, stop) = (self.clone(), stop.clone());
+                    thread::spawn(move || shared.worker(stop, config, frames))
+                };
+                state.ring.clear();
+                state.worker = Some(Worker { stop, thread, config });
+                Ok(vec![])
+            }
+            NR_STOP => {
+                state.check_owner(session)?;
+                state.owner = None;
+                drop(state);
+                self.stop();
+                Ok(vec![])
+            }
+            NR_GET_STATS => {
+                let mut stats = state.stats;
+                stats.version = KERNCAMERA_VERSION;
+                stats.streaming = state.worker.is_some() as u32;
+                Ok(stats.as_bytes().to_vec())
+            }
+            NR_GET_LAST_POSE => Ok(state.last_pose.ok_or(Errno::ENODATA)?.as_bytes().to_vec()),
+            NR_SET_TIMEOUT => {
+                let arg: KerncameraTimeout = decode(arg)?;
+                check_version(arg.version)?;
+                state.claim(session)?;
+                state.timeout_ms = arg.timeout_ms;
+                Ok(vec![])
+            }
+            OP_READ => {
+                let nonblocking = decode_u32(arg)? != 0;
+                loop {
+                    state.check_owner(session)?;
+                    if let Some(entry) = state.ring.pop_front() {
+                        return entry;
+                    }
+                    if state.worker.is_none() {
+                        return Err(Errno::ENODEV);
+                    }
+                    if nonblocking {
+                        return Err(Errno::EAGAIN);
+                    }
+                    state = self.changed.wait(state).unwrap();
+                }
+            }
+            OP_WAIT => {
+                let timeout_ms = decode_u32(arg)?;
+                let until = (timeout_ms != u32::MAX).then(|| Instant::now() + Duration::from_millis(timeout_ms as u64));
+                loop {
+                    // Like POLLIN, POLLHUP or POLLERR.
+                    if state.check_owner(session).is_err() || !state.ring.is_empty() || state.worker.is_none() {
+                        return Ok(1u32.to_ne_bytes().to_vec());
+                    }
+                    state = match until {
+                        Some(until) => {
+                            let now = Instant::now();
+                            if now >= until {
+                                return Ok(0u32.to_ne_bytes().to_vec());
+                            }
+                            self.changed.wait_timeout(state, until - now).unwrap().0
+                        }
+                        None => self.changed.wait(state).unwrap(),
+                    };
+                }
+            }
+            _ => Err(Errno::ENOTTY),
+        }
+    }
+
+    // Stops the worker if it runs, after the frame in flight.
+    fn stop(&self) {
+        let worker = self.lock().worker.take();
+        if let Some(worker) = worker {
+            worker.stop.store(true, Ordering::Relaxed);
+            let _ = worker.thread.join();
+            // Blocked readers get ENODEV now.
+            self.changed.notify_all();
+        }
+    }
+
+    // worker_main in the module.
+    fn worker(&self, stop: Arc<AtomicBool>, config: KerncameraDevice, mut frames: Option<Frames>) {
+        let (mut server, mut server_changes, timeout_ms) = {
+            let state = self.lock();
+            (state.server, state.server_changes, state.timeout_ms)
+        };
+        let mut upstream = Upstream::new(timeout_ms);
+        let mut frame = vec![0; frame_size(&config)];
+        // Like a camera, the first frame takes a frame interval.
+        let mut next_frame = Instant::now() + FRAME_INTERVAL;
+
+        for seq in 0.. {
+            if !sleep_unless(&stop, next_frame.saturating_duration_since(Instant::now())) {
+                break;
+            }
+            next_frame += FRAME_INTERVAL;
+            {
+                let state = self.lock();
+                if state.server_changes != server_changes {
+                    server = state.server;
+                    server_changes = state.server_changes;
+                    upstream.disconnect();
+                }
+                if state.timeout_ms != upstream.timeout_ms {
+                    upstream.set_timeout(state.timeout_ms);
+                }
+            }
+
+            let captured = match &mut frames {
+                Some(frames) => frames.next(&mut frame),
+                None => {
+                    generate(&mut frame, &config, seq);
+                    Ok(())
+                }
+            };
+            if let Err(e) = captured {
+                let mut state = self.lock();
+                state.stats.capture_errors += 1;
+                state.push(Err(errno(&e)));
+                drop(state);
+                self.changed.notify_all();
+                continue;
+            }
+
+            let sent = now_ns();
+            let result = upstream.analyze(&server, &frame, &stop);
+            let answered = now_ns();
+
+            let mut state = self.lock();
+            state.stats.connects += std::mem::take(&mut upstream.connects);
+            match result {
+                Ok(reply) => {
+                    state.stats.frames += 1;
+                    state.stats.bytes_sent += 8 + frame.len() as u64;
+                    state.stats.bytes_received += 8 + reply.len() as u64;
+                    state.stats.last_exchange_ns = answered - sent;
+                    let mut pose = KerncameraPose {
+                        version: KERNCAMERA_VERSION,
+                        length: reply.len() as u32,
+                        frame: state.stats.frames,
+                        timestamp_ns: answered,
+                        data: [0; POSE_SIZE],
+                    };
+                    pose.data[..reply.len()].copy_from_slice(&reply);
+                    state.last_pose = Some(pose);
+                    state.push(Ok(reply));
+                }
+                // Interrupted by stop().
+                Err(_) if stop.load(Ordering::Relaxed) => {}
+                Err(e) => {
+                    state.stats.server_errors += 1;
+                    state.push(Err(e));
+                }
+            }
+            drop(state);
+            self.changed.notify_all();
+        }
+    }
+}
+
+// Frames from a file of raw frames.
+struct Frames {
+    file: File,
+}
+
+impl Frames {
+    // EINVAL unless the file holds at least one frame.
+    fn open(path: &Path, frame_size: usize) -> io::Result<Frames> {
+        let file = File::open(path)?;
+        if file.metadata()?.len() < frame_size as u64 {
+            return Err(io::Error::from_raw_os_error(Errno::EINVAL as i32));
+        }
+        Ok(Frames { file })
+    }
+
+    // A partial frame at the end is skipped.
+    fn next(&mut self, frame: &mut [u8]) -> io::Result<()> {
+        match self.file.read_exact(frame) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                self.file.seek(SeekFrom::Start(0))?;
+                self.file.read_exact(frame)
+            }
+            result => result,
+        }
+    }
+}
+
+// A diagonal luma gradient moving with `seq`, without colour.
+fn generate(frame: &mut [u8], config: &KerncameraDevice, seq: u64) {
+    let (w, h) = (config.width as usize, config.height as usize);
+    let (luma, chroma) = frame.split_at_mut(w * h);
+    for (i, y) in luma.iter_mut().enumerate() {
+        *y = ((i % w + i / w) as u64 + seq * 4) as u8;
+    }
+    chroma.fill(128);
+}
+
+// Socket in the module: the connection to the server.
+struct Upstream {
+    stream: Option<TcpStream>,
+    // No connect attempt before this.
+    retry_at: Instant,
+    backoff: Duration,
+    // 0 for none.
+    timeout_ms: u32,
+    connects: u64,
+}
+
+impl Upstream {
+    fn new(timeout_ms: u32) -> Upstream {
+        Upstream { stream: None, retry_at: Instant::now(), backoff: MIN_BACKOFF, timeout_ms, connects: 0 }
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        (self.timeout_ms > 0).then(|| Duration::from_millis(self.timeout_ms as u64))
+    }
+
+    fn set_timeout(&mut self, timeout_ms: u32) {
+        self.timeout_ms = timeout_ms;
+        if let Some(stream) = &self.stream {
+            let _ = stream.set_read_timeout(self.timeout());
+            let _ = stream.set_write_timeout(self.timeout());
+        }
+    }
+
+    fn disconnect(&mut self) {
+        self.stream = None;
+        self.retry_at = Instant::now();
+        self.backoff = MIN_BACKOFF;
+    }
+
+    fn back_off(&mut self) {
+        self.retry_at = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+
+    // The connected stream, connecting first (after the backoff) if needed.
+    fn connect(&mut self, server: &SocketAddr, stop: &AtomicBool) -> Result<&mut TcpStream, Errno> {
+        if self.stream.is_none() {
+            if !sleep_unless(stop, self.retry_at.saturating_duration_since(Instant::now())) {
+                return Err(Errno::EINTR);
+            }
+            let connected = match self.timeout() {
+                Some(timeout) => TcpStream::connect_timeout(server, timeout),
+                None => TcpStream::connect(server),
+            };
+            let stream = connected.map_err(|e| {
+                self.back_off();
+                errno(&e)
+            })?;
+            stream.set_read_timeout(self.timeout()).map_err(|e| errno(&e))?;
+            stream.set_write_timeout(self.timeout()).map_err(|e| errno(&e))?;
+            self.backoff = MIN_BACKOFF;
+            self.connects += 1;
+            self.stream = Some(stream);
+        }
+        Ok(self.stream.as_mut().unwrap())
+    }
+
+    // One frame out, its reply back; a broken connection is dropped and reconnected after a
+    // backoff.
+    fn analyze(&mut self, server: &SocketAddr, frame: &[u8], stop: &AtomicBool) -> Result<Vec<u8>, Errno> {
+        let stream = self.connect(server, stop)?;
+        let result = protocol::exchange(stream, frame, POSE_SIZE).map_err(|e| errno(&e));
+        if result.is_err() {
+            self.disconnect();
+            self.back_off();
+        }
+        result
+    }
+}
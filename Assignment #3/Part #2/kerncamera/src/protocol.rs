@@ -0,0 +1,74 @@
+/*!
+ * What the kernel module sends remote_server, and what comes back.
+ *
+ * A connection carries any number of frames, each one
+ *     client -> server: u64 length in bytes, the frame
+ *     server -> client: u64 length in bytes, the model output as f32s
+ * with all of it little endian. remote_server answers with `serve`; the mock's worker sends its
+ * frames with `exchange`, as the module does.
+ */
+
+use std::io::{self, ErrorKind, Read, Write};
+
+// The only frames remote_server takes: FRAME_WIDTH x FRAME_HEIGHT YUV420.
+pub const FRAME_WIDTH: u32 = 400;
+pub const FRAME_HEIGHT: u32 = 712;
+pub const FRAME_SIZE: usize = (FRAME_WIDTH * FRAME_HEIGHT * 3 / 2) as usize;
+
+fn read_length(stream: &mut impl Read) -> io::Result<u64> {
+    let mut length = [0; 8];
+    stream.read_exact(&mut length)?;
+    Ok(u64::from_le_bytes(length))
+}
+
+// Sends `frame` and returns the reply; InvalidData if it is longer than `max_reply`.
+pub fn exchange(stream: &mut (impl Read + Write), frame: &[u8], max_reply: usize) -> io::Result<Vec<u8>> {
+    stream.write_all(&(frame.len() as u64).to_le_bytes())?;
+    stream.write_all(frame)?;
+    let length = read_length(stream)?;
+    if length > max_reply as u64 {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("reply of {} bytes", length)));
+    }
+    let mut reply = vec![0; length as usize];
+    stream.read_exact(&mut reply)?;
+    Ok(reply)
+}
+
+// Reads the next frame into `frame`, which it resizes to `frame_size`. Ok(false) once the client
+// closed the connection between frames; InvalidData for a frame of another size.
+pub fn read_frame(stream: &mut impl Read, frame: &mut Vec<u8>, frame_size: usize) -> io::Result<bool> {
+    let length = match read_length(stream) {
+        Ok(length) => length,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    if length != frame_size as u64 {
+        return Err(io::Error::new(ErrorKind::InvalidData,
+            format!("frame of {} bytes, expected {}", length, frame_size)));
+    }
+    frame.resize(frame_size, 0);
+    stream.read_exact(frame)?;
+    Ok(true)
+}
+
+pub fn write_reply(stream: &mut impl Write, reply: &[u8]) -> io::Result<()> {
+    stream.write_all(&(reply.len() as u64).to_le_bytes())?;
+    stream.write_all(reply)?;
+    stream.flush()
+}
+
+// Answers frames of `frame_size` bytes with what `analyze` puts into its second argument, until
+// the client hangs up (Ok) or something goes wrong (the error; the connection is of no more use).
+pub fn serve<S, F>(stream: &mut S, frame_size: usize, mut analyze: F) -> io::Result<()>
+where
+    S: Read + Write,
+    F: FnMut(&[u8], &mut Vec<u8>) -> io::Result<()>,
+{
+    let (mut frame, mut reply) = (vec![], vec![]);
+    while read_frame(stream, &mut frame, frame_size)? {
+        reply.clear();
+        analyze(&frame, &mut reply)?;
+        write_reply(stream, &reply)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use nix::errno::Errno;
+
+use kerncamera::mock::{MockCamera, MockConfig};
+use kerncamera::protocol::{self, FRAME_HEIGHT, FRAME_SIZE, FRAME_WIDTH};
+use kerncamera::{DeviceConfig, Kerncamera, POSE_SIZE};
+
+// The whole contract against the mock, with remote_server's connection handling (protocol::serve)
+// in front of a stand-in for its model.
+
+const W: u32 = FRAME_WIDTH;
+const H: u32 = FRAME_HEIGHT;
+const REPLY: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+// remote_server on a free port, answering every frame with REPLY. Frames must be the mock's
+// pattern, without colour.
+fn server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let _ = protocol::serve(&mut stream, FRAME_SIZE, |frame, reply| {
+                    assert!(frame[(W * H) as usize..].iter().all(|&c| c == 128));
+                    reply.extend_from_slice(&REPLY);
+                    Ok(())
+                });
+            });
+        }
+    });
+    addr
+}
+
+fn start_mock(name: &str, server: SocketAddr) -> String {
+    let socket: PathBuf = std::env::temp_dir().join(format!("kerncamera-{}-{}.sock", name, std::process::id()));
+    let mock = MockCamera::bind(&socket, MockConfig { server, ..Default::default() }).unwrap();
+    thread::spawn(move || mock.run());
+    socket.to_str().unwrap().to_string()
+}
+
+fn errno(e: &std::io::Error) -> Option<Errno> {
+    e.raw_os_error().map(Errno::from_i32)
+}
+
+#[test]
+fn streams_poses_from_server() {
+    let server = server();
+    let socket = start_mock("stream", server);
+    let mut camera = Kerncamera::open_path(&socket).unwrap();
+    assert_eq!(camera.server().unwrap(), server);
+
+    let mut buf = [0; POSE_SIZE];
+    assert_eq!(errno(&camera.start().unwrap_err()), Some(Errno::ENODEV));
+    assert_eq!(errno(&camera.read_pose(&mut buf).unwrap_err()), Some(Errno::ENODEV));
+    assert!(camera.last_pose().unwrap().is_none());
+
+    let config = DeviceConfig::yuv420("/dev/video0", W, H);
+    camera.set_device(&config).unwrap();
+    camera.start().unwrap();
+    assert_eq!(camera.device().unwrap(), config);
+    assert_eq!(errno(&camera.set_device(&config).unwrap_err()), Some(Errno::EBUSY));
+
+    assert!(camera.wait(Some(Duration::from_secs(5))).unwrap());
+    let n = camera.read_pose(&mut buf).unwrap();
+    assert_eq!(&buf[..n], &REPLY);
+    let pose = camera.last_pose().unwrap().unwrap();
+    assert_eq!(pose.data, REPLY);
+    assert!(pose.frame >= 1);
+
+    let stats = camera.stats().unwrap();
+    assert_eq!(stats.streaming, 1);
+    assert_eq!(stats.connects, 1);
+    assert!(stats.frames >= 1);
+
+    camera.stop().unwrap();
+    assert_eq!(camera.stats().unwrap().streaming, 0);
+    camera.set_nonblocking(true).unwrap();
+    // Poses queued before the stop, then nothing more.
+    loop {
+        match camera.read_pose(&mut buf) {
+            Ok(_) => continue,
+            Err(e) => {
+                assert_eq!(errno(&e), Some(Errno::ENODEV));
+                break;
+            }
+        }
+    }
+}
+
+#[test]
+fn nonblocking_read_and_server_errors() {
+    // Nothing listens there once the listener is gone.
+    let unused = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let socket = start_mock("refused", unused);
+    let mut camera = Kerncamera::open_path(&socket).unwrap();
+    camera.set_device(&DeviceConfig::yuv420("/dev/video0", W, H)).unwrap();
+    camera.set_nonblocking(true).unwrap();
+    camera.start().unwrap();
+
+    let mut buf = [0; POSE_SIZE];
+    match camera.read_pose(&mut buf) {
+        Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+        other => panic!("expected EAGAIN, got {:?}", other.map(|_| ())),
+    }
+    assert!(camera.wait(Some(Duration::from_secs(5))).unwrap());
+    assert_eq!(errno(&camera.read_pose(&mut buf).unwrap_err()), Some(Errno::ECONNREFUSED));
+    assert!(camera.stats().unwrap().server_errors >= 1);
+}
+
+#[test]
+fn one_owner_at_a_time() {
+    let server = server();
+    let socket = start_mock("owner", server);
+    let owner = Kerncamera::open_path(&socket).unwrap();
+    let other = Kerncamera::open_path(&socket).unwrap();
+    let config = DeviceConfig::yuv420("/dev/video0", W, H);
+    owner.set_device(&config).unwrap();
+    owner.start().unwrap();
+
+    assert_eq!(errno(&other.set_device(&config).unwrap_err()), Some(Errno::EBUSY));
+    assert_eq!(errno(&other.stop().unwrap_err()), Some(Errno::EBUSY));
+    assert_eq!(errno(&other.set_timeout(None).unwrap_err()), Some(Errno::EBUSY));
+    // Queries are fine.
+    assert_eq!(other.device().unwrap(), config);
+    assert_eq!(other.stats().unwrap().streaming, 1);
+
+    // Closing the owner stops streaming and frees the device.
+    drop(owner);
+    let mut stopped = false;
+    for _ in 0..100 {
+        if other.stats().unwrap().streaming == 0 {
+            stopped = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(stopped);
+    other.start().unwrap();
+    other.stop().unwrap();
+}
+
+#[test]
+fn rejects_bad_arguments() {
+    let socket = start_mock("invalid", server());
+    let camera = Kerncamera::open_path(&socket).unwrap();
+    let mut config = DeviceConfig::yuv420("/tmp/video0", W, H);
+    assert_eq!(errno(&camera.set_device(&config).unwrap_err()), Some(Errno::EINVAL));
+    config.path = "/dev/video0".to_string();
+    config.buffers = 0;
+    assert_eq!(errno(&camera.set_device(&config).unwrap_err()), Some(Errno::EINVAL));
+    config.buffers = 2;
+    config.width = 0;
+    assert_eq!(errno(&camera.set_device(&config).unwrap_err()), Some(Errno::EINVAL));
+    assert_eq!(errno(&camera.device().unwrap_err()), Some(Errno::ENODEV));
+}
+
+#[test]
+fn server_hangs_up_on_other_frame_sizes() {
+    let socket = start_mock("size", server());
+    let mut camera = Kerncamera::open_path(&socket).unwrap();
+    camera.set_device(&DeviceConfig::yuv420("/dev/video0", 64, 48)).unwrap();
+    camera.start().unwrap();
+
+    let mut buf = [0; POSE_SIZE];
+    assert_eq!(errno(&camera.read_pose(&mut buf).unwrap_err()), Some(Errno::ECONNRESET));
+    assert!(camera.stats().unwrap().server_errors >= 1);
+}
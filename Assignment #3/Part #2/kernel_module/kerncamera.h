@@ -0,0 +1,116 @@
+/* SPDX-License-Identifier: GPL-2.0 WITH Linux-syscall-note */
+/*
+ * Userspace API of /dev/kerncamera (rust_camera.rs).
+ *
+ * Between START and STOP a kernel thread captures frames from a V4L2 device,
+ * sends each one to a remote server and queues the server's reply (the pose)
+ * in a small ring; when the ring is full the oldest pose is dropped. Typical
+ * use:
+ *
+ *	KERNCAMERA_SET_SERVER	(optional, defaults to the module parameters)
+ *	KERNCAMERA_SET_DEVICE
+ *	KERNCAMERA_START
+ *	read() ...		one pose per call, oldest first
+ *	KERNCAMERA_STOP		(or close())
+ *
+ * read() blocks until a pose is queued, or fails with EAGAIN if the file is
+ * O_NONBLOCK. poll() reports POLLIN while poses are queued and POLLHUP while
+ * not streaming. STOP (and closing the owner) shuts the server connection
+ * down, so the frame in flight is dropped rather than waited for; it returns
+ * once the thread is gone, also with no server timeout set.
+ *
+ * The device can be open more than once. Any open file may use the GET
+ * ioctls, but the first one to SET something or START owns the device until
+ * it calls STOP or is closed; closing the owner stops streaming. Meanwhile
+ * the other files get EBUSY from SET, START, STOP and read(), and POLLERR
+ * from poll().
+ *
+ * More counters and latency histograms are in debugfs, as text:
+ * /sys/kernel/debug/kerncamera/stats.
+ *
+ * Every struct starts with a version, which has to be KERNCAMERA_VERSION;
+ * reserved fields have to be 0. Both are checked (EINVAL) so the structs can
+ * grow later. All integers are in host byte order.
+ *
+ * Errors besides the usual EFAULT and ENOTTY:
+ *	EINVAL	bad version, reserved field, address, path, resolution or count
+ *	EBUSY	SET_DEVICE or START while streaming, or another open file owns
+ *		the device
+ *	ENODEV	START before SET_DEVICE, GET_DEVICE before SET_DEVICE, read()
+ *		while not streaming
+ *	ENODATA	GET_LAST_POSE before any frame was answered
+ *	EAGAIN	read() on an O_NONBLOCK file with no pose queued
+ * Frames that got no answer are queued as errors instead: read() fails with
+ * the errno of the capture or server exchange (ECONNREFUSED, ETIMEDOUT,
+ * EPROTO, ...) and the worker carries on with the next frame.
+ */
+#ifndef _UAPI_LINUX_KERNCAMERA_H
+#define _UAPI_LINUX_KERNCAMERA_H
+
+#include <linux/ioctl.h>
+#include <linux/types.h>
+
+#define KERNCAMERA_VERSION	1
+
+/* Where frames are sent. Takes effect from the next frame on. */
+struct kerncamera_server {
+	__u32 version;
+	__u16 port;
+	__u16 reserved;
+	char addr[48];		/* NUL-terminated IPv4 or IPv6 address */
+};
+
+/*
+ * What to capture. GET_DEVICE returns what the driver settled on while
+ * streaming (resolution, format and buffer count may be adjusted).
+ */
+struct kerncamera_device {
+	__u32 version;
+	__u32 width;		/* 1 to 8192 */
+	__u32 height;		/* 1 to 8192 */
+	__u32 pixelformat;	/* V4L2 fourcc, e.g. V4L2_PIX_FMT_YUV420 */
+	__u32 buffers;		/* 1 to 8 */
+	__u32 reserved;
+	char path[64];		/* NUL-terminated, under /dev */
+};
+
+/* How long the worker waits on the server, also for the current connection. */
+struct kerncamera_timeout {
+	__u32 version;
+	__u32 timeout_ms;	/* 0 for no limit */
+};
+
+struct kerncamera_stats {
+	__u32 version;
+	__u32 streaming;	/* 1 between START and STOP */
+	__u64 frames;		/* frames the server answered */
+	__u64 capture_errors;	/* frames that could not be dequeued */
+	__u64 server_errors;	/* frames without a (valid) answer */
+	__u64 connects;
+	__u64 bytes_sent;
+	__u64 bytes_received;
+	__u64 last_exchange_ns;	/* dequeue to answer, last answered frame */
+};
+
+/* The server's reply to the most recent frame, as read() returned it. */
+struct kerncamera_pose {
+	__u32 version;
+	__u32 length;		/* valid bytes in data */
+	__u64 frame;		/* stats.frames when it came back */
+	__u64 timestamp_ns;	/* CLOCK_MONOTONIC */
+	__u8 data[1344];	/* up to 6 people x 56 floats */
+};
+
+#define KERNCAMERA_MAGIC	'K'
+
+#define KERNCAMERA_SET_SERVER	_IOW(KERNCAMERA_MAGIC, 1, struct kerncamera_server)
+#define KERNCAMERA_GET_SERVER	_IOR(KERNCAMERA_MAGIC, 2, struct kerncamera_server)
+#define KERNCAMERA_SET_DEVICE	_IOW(KERNCAMERA_MAGIC, 3, struct kerncamera_device)
+#define KERNCAMERA_GET_DEVICE	_IOR(KERNCAMERA_MAGIC, 4, struct kerncamera_device)
+#define KERNCAMERA_START	_IO(KERNCAMERA_MAGIC, 5)
+#define KERNCAMERA_STOP		_IO(KERNCAMERA_MAGIC, 6)
+#define KERNCAMERA_GET_STATS	_IOR(KERNCAMERA_MAGIC, 7, struct kerncamera_stats)
+#define KERNCAMERA_GET_LAST_POSE _IOR(KERNCAMERA_MAGIC, 8, struct kerncamera_pose)
+#define KERNCAMERA_SET_TIMEOUT	_IOW(KERNCAMERA_MAGIC, 9, struct kerncamera_timeout)
+
+#endif /* _UAPI_LINUX_KERNCAMERA_H */
@@ -5,14 +5,17 @@
 use kernel::prelude::*;
 use kernel::str::CString;
 use kernel::{
-    file::{self, File},
-    io_buffer::{IoBufferReader, IoBufferWriter},
+    file::{self, File, IoctlCommand, IoctlHandler, PollTable},
+    io_buffer::{IoBufferReader, IoBufferWriter, ReadableFromBytes, WritableToBytes},
+    ioctl::{_IO, _IOR, _IOW},
     miscdev,
-    sync::{Ref, RefBorrow, UniqueRef},
-    sync::smutex::Mutex,
+    sync::{CondVar, Mutex, Ref, UniqueRef},
+    task::Task,
+    user_ptr::{UserSlicePtrReader, UserSlicePtrWriter},
+    condvar_init, mutex_init,
 };
 
-use kernel::bindings::{socket, sock_create, vfs_ioctl};
+use kernel::bindings::{socket, sock_create};
 use core::ffi::c_int;
 
 module! {
@@ -21,150 +24,578 @@ module! {
     author: "Daniel Luick",
     description: "A simple module that reads camera input.",
     license: "GPL",
+    params: {
+        server_addr: str {
+            default: b"172.28.229.170",
+            permissions: 0o444,
+            description: "IPv4 or IPv6 address of the remote server (change at runtime with KERNCAMERA_SET_SERVER)",
+        },
+        server_port: u16 {
+            default: 8008,
+            permissions: 0o444,
+            description: "TCP port of the remote server",
+        },
+        recv_timeout_ms: u32 {
+            default: 5000,
+            permissions: 0o444,
+            description: "How long the worker waits for the server's reply before giving up on a frame with ETIMEDOUT, 0 for no limit (change at runtime with KERNCAMERA_SET_TIMEOUT)",
+        },
+    },
 }
 
-const W: usize = 400;
-const H: usize = 712;
+// SinglePose replies with 17*3 f32s, MultiPose with up to 6*56; take the larger.
+const OUTPUT_SIZE: usize = 6*56*4;
+
+/**************************
+ * In-kernel V4L2 capture *
+ *************************/
+
+// The module opens the capture device itself and drives it through the driver's
+// v4l2_ioctl_ops on kernel structs (vfs_ioctl would copy_from_user its argument). Each MMAP
+// buffer is exported as a dma-buf and vmap'd, so frames are read straight from kernel memory and
+// userspace needs no v4l2 code at all.
+// Needs media/v4l2-dev.h, media/v4l2-ioctl.h and linux/dma-buf.h in rust/bindings/bindings_helper.h.
+
+const V4L2_BUF_TYPE_VIDEO_CAPTURE: u32 = 1;
+const V4L2_MEMORY_MMAP: u32 = 1;
+const V4L2_FIELD_NONE: u32 = 1;
+// Character devices the V4L2 core hands out (media/v4l2-dev.h, linux/kdev_t.h, linux/stat.h).
+const VIDEO_MAJOR: u32 = 81;
+const VIDEO_NUM_DEVICES: u32 = 256;
+const MINORBITS: u32 = 20;
+const S_IFMT: u16 = 0o170000;
+const S_IFCHR: u16 = 0o020000;
+
+// Longest device path, including the NUL.
+const DEVICE_LEN: usize = 64;
+const MAX_DIMENSION: u32 = 8192;
+const MAX_BUFFERS: u32 = 8;
+
+// What to capture; see KERNCAMERA_SET_DEVICE.
+#[derive(Clone, Copy)]
+struct CaptureConfig {
+    // NUL-terminated path of the capture device, e.g. /dev/video0.
+    device: [u8; DEVICE_LEN],
+    width: u32,
+    height: u32,
+    // fourcc, e.g. YU12 for the YUV420 the server expects.
+    pixelformat: u32,
+    buffers: u32,
+}
 
-const PAGE_SIZE: usize = 4096;
+impl CaptureConfig {
+    fn from_arg(arg: &KerncameraDevice) -> Result<CaptureConfig> {
+        check_version(arg.version)?;
+        if arg.reserved != 0 {
+            pr_err!("kerncamera_device.reserved must be 0\n");
+            return Err(EINVAL);
+        }
+        let config = CaptureConfig {
+            device: arg.path,
+            width: arg.width,
+            height: arg.height,
+            pixelformat: arg.pixelformat,
+            buffers: arg.buffers,
+        };
 
-// 4 addresses (vaddr+pfn pairs) are passed from userspace:
-// - 2 v4l2_buffer structs (2nd is unused)
-// - 2 mmap'd addresses since we use 2 buffers
-const N_ADDRS: usize = 4;
-const INPUT_SIZE: usize = N_ADDRS*2*8;
+        let device = match config.device.iter().position(|b| *b == 0) {
+            Some(end) => &config.device[..end],
+            None => {
+                pr_err!("capture device path is not NUL-terminated within {} bytes\n", DEVICE_LEN);
+                return Err(EINVAL);
+            }
+        };
+        if !device.starts_with(b"/dev/") {
+            pr_err!("capture device '{}' is not under /dev\n", config.device());
+            return Err(EINVAL);
+        }
+        if config.width == 0 || config.height == 0 || config.width > MAX_DIMENSION || config.height > MAX_DIMENSION {
+            pr_err!("invalid capture resolution {}x{}\n", config.width, config.height);
+            return Err(EINVAL);
+        }
+        if config.buffers == 0 || config.buffers > MAX_BUFFERS {
+            pr_err!("invalid capture buffer count {}, expected 1 to {}\n", config.buffers, MAX_BUFFERS);
+            return Err(EINVAL);
+        }
+        Ok(config)
+    }
 
-const BUF_SIZE: usize = (W*H)+((W*H)>>1);
-const OUTPUT_SIZE: usize = 17*4*3;
+    fn to_arg(&self) -> KerncameraDevice {
+        KerncameraDevice {
+            version: KERNCAMERA_VERSION,
+            width: self.width,
+            height: self.height,
+            pixelformat: self.pixelformat,
+            buffers: self.buffers,
+            reserved: 0,
+            path: self.device,
+        }
+    }
 
-/*******************************
- * v4l2 declarations & helpers *
- *******************************/
+    fn device(&self) -> &str {
+        let end = self.device.iter().position(|b| *b == 0).unwrap_or(DEVICE_LEN);
+        core::str::from_utf8(&self.device[..end]).unwrap_or("<not UTF-8>")
+    }
+}
 
-// const V4L2_BUF_TYPE_VIDEO_CAPTURE: usize = 1;
-// const V4L2_MEMORY_MMAP: usize = 1;
+impl core::fmt::Display for CaptureConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let fourcc = self.pixelformat.to_le_bytes();
+        write!(f, "{} {}x{} ", self.device(), self.width, self.height)?;
+        for c in fourcc {
+            write!(f, "{}", c as char)?;
+        }
+        write!(f, ", {} buffers", self.buffers)
+    }
+}
 
-fn xioctl(filp: *mut bindings::file, code: u32, vaddr: u64) -> Option<()> {
-    loop {
-        let result = unsafe { vfs_ioctl(filp, code, vaddr) };
-        match result {
-            0 => { return Some(()); }
-            4 => {} // EINTR
-            11 => {} // EAGAIN
-            _ => {
-                pr_info!("xioctl error, returned code {}\n", result);
-                return None;
+fn check(r: c_int) -> Result {
+    if r < 0 {
+        Err(Error::from_kernel_errno(r))
+    } else {
+        Ok(())
+    }
+}
+
+// Pointers returned by filp_open, dma_buf_get etc. carry an errno instead of failing with NULL.
+fn check_ptr<T>(p: *mut T) -> Result<*mut T> {
+    let value = p as isize;
+    if value < 0 && value >= -(bindings::MAX_ERRNO as isize) {
+        Err(Error::from_kernel_errno(value as c_int))
+    } else {
+        Ok(p)
+    }
+}
+
+// Whether `filp` is a character device of the V4L2 core, i.e. one video_devdata() knows.
+fn is_video_node(filp: *mut bindings::file) -> bool {
+    let inode = unsafe { (*filp).f_inode };
+    let (mode, rdev) = unsafe { ((*inode).i_mode, (*inode).i_rdev) };
+    mode & S_IFMT == S_IFCHR && rdev >> MINORBITS == VIDEO_MAJOR
+        && rdev & ((1 << MINORBITS) - 1) < VIDEO_NUM_DEVICES
+}
+
+// Which lock an ioctl runs under, chosen like v4l2_ioctl_get_lock() does: the vb2 queue's for
+// buffer ioctls when the driver gave the queue one (many leave vdev->lock NULL then), the
+// device's otherwise. mem2mem contexts are not looked at, as those devices are not cameras.
+#[derive(Clone, Copy)]
+enum Ioctl {
+    Device,
+    // REQBUFS, EXPBUF, QBUF, DQBUF, STREAMON, STREAMOFF.
+    Queue,
+}
+
+impl Ioctl {
+    // NULL for none.
+    fn lock(self, vdev: *mut bindings::video_device) -> *mut bindings::mutex {
+        if let Ioctl::Queue = self {
+            let queue = unsafe { (*vdev).queue };
+            if !queue.is_null() && !unsafe { (*queue).lock }.is_null() {
+                return unsafe { (*queue).lock };
             }
         }
+        unsafe { (*vdev).lock }
     }
 }
 
-// const FORMAT_PADDING: usize = 208 - 10*4;
-// #[repr(C)]
-// struct v4l2_format {
-//     type_: u32,
-//     space: u32,
-//     width: u32,
-//     height: u32,
-//     pixelformat: u32,
-//     field: u32,
-//     bytesperline: u32,
-//     sizeimage: u32,
-//     colorspace: u32,
-//     priv_: u32,
-//     others: [u8; FORMAT_PADDING]
-// }
+// One capture buffer, mapped into the kernel's address space.
+struct MappedBuffer {
+    dmabuf: *mut bindings::dma_buf,
+    map: bindings::iosys_map,
+    length: usize,
+}
 
-#[repr(C)]
-union anon_plane_m {
-    mem_offset: u32,
-    userptr: u64,
-    fd: i32
+// An open, streaming capture device. Dropping it stops streaming and releases everything.
+struct Capture {
+    filp: *mut bindings::file,
+    // As the driver agreed to it, which can differ from what was asked for.
+    config: CaptureConfig,
+    buffers: Vec<MappedBuffer>,
+    streaming: bool,
 }
 
-#[repr(C)]
-struct v4l2_plane {
-    bytesused: u32,
-    length: u32,
-    m: anon_plane_m,
-    data_offset: u32,
-    reserved: [u32; 11]
+// Only used under the SharedState lock.
+unsafe impl Send for Capture {}
+
+impl Capture {
+    // Opens the device, sets the format, maps and queues the buffers and starts streaming. What
+    // was set up before a failing step is undone by Drop.
+    fn start(config: &CaptureConfig) -> Result<Capture> {
+        let path = CString::try_from_fmt(fmt!("{}", config.device()))?;
+        // Non-blocking, so DQBUF cannot hold up stopping the worker when no frame comes.
+        let flags = (bindings::O_RDWR | bindings::O_NONBLOCK) as c_int;
+        let filp = check_ptr(unsafe { bindings::filp_open(path.as_char_ptr(), flags, 0) })
+            .map_err(|e| {
+                pr_err!("opening {} failed: {:?}\n", config.device(), e);
+                e
+            })?;
+        let mut capture = Capture { filp, config: *config, buffers: Vec::new(), streaming: false };
+        // video_devdata() only indexes the V4L2 core's device table by minor, so it would hand
+        // back someone else's video_device for any other node with a matching minor.
+        if !is_video_node(filp) || unsafe { bindings::video_devdata(filp) }.is_null() {
+            pr_err!("{} is not a V4L2 device\n", config.device());
+            return Err(ENOTTY);
+        }
+
+        let mut format: bindings::v4l2_format = unsafe { core::mem::zeroed() };
+        format.type_ = V4L2_BUF_TYPE_VIDEO_CAPTURE;
+        unsafe {
+            format.fmt.pix.width = config.width;
+            format.fmt.pix.height = config.height;
+            format.fmt.pix.pixelformat = config.pixelformat;
+            format.fmt.pix.field = V4L2_FIELD_NONE;
+        }
+        capture.call("VIDIOC_S_FMT", Ioctl::Device, |ops, filp, fh| {
+            ops.vidioc_s_fmt_vid_cap.map(|op| unsafe { op(filp, fh, &mut format) })
+        })?;
+        let pix = unsafe { format.fmt.pix };
+        capture.config.width = pix.width;
+        capture.config.height = pix.height;
+        capture.config.pixelformat = pix.pixelformat;
+
+        let mut request: bindings::v4l2_requestbuffers = unsafe { core::mem::zeroed() };
+        request.count = config.buffers;
+        request.type_ = V4L2_BUF_TYPE_VIDEO_CAPTURE;
+        request.memory = V4L2_MEMORY_MMAP;
+        capture.call("VIDIOC_REQBUFS", Ioctl::Queue, |ops, filp, fh| {
+            ops.vidioc_reqbufs.map(|op| unsafe { op(filp, fh, &mut request) })
+        })?;
+        if request.count == 0 {
+            pr_err!("{} allocated no buffers\n", config.device());
+            return Err(ENOMEM);
+        }
+        capture.config.buffers = request.count;
+
+        for index in 0..request.count {
+            capture.map(index)?;
+            capture.queue(index)?;
+        }
+
+        capture.call("VIDIOC_STREAMON", Ioctl::Queue, |ops, filp, fh| {
+            ops.vidioc_streamon.map(|op| unsafe { op(filp, fh, V4L2_BUF_TYPE_VIDEO_CAPTURE) })
+        })?;
+        capture.streaming = true;
+        pr_info!("capturing from {}\n", capture.config);
+        Ok(capture)
+    }
+
+    // Calls one of the driver's v4l2_ioctl_ops, holding the lock video_ioctl2 would take for it
+    // (see Ioctl). `op` returns None if the driver does not implement it.
+    fn call(
+        &self,
+        name: &str,
+        ioctl: Ioctl,
+        op: impl FnOnce(&bindings::v4l2_ioctl_ops, *mut bindings::file, *mut core::ffi::c_void) -> Option<c_int>,
+    ) -> Result {
+        let vdev = unsafe { bindings::video_devdata(self.filp) };
+        let lock = ioctl.lock(vdev);
+        if !lock.is_null() {
+            check(unsafe { bindings::mutex_lock_interruptible(lock) })?;
+        }
+        let r = unsafe { op(&*(*vdev).ioctl_ops, self.filp, (*self.filp).private_data) };
+        if !lock.is_null() {
+            unsafe { bindings::mutex_unlock(lock) };
+        }
+        match r {
+            None => {
+                pr_err!("{} not supported by {}\n", name, self.config.device());
+                Err(ENOTTY)
+            }
+            Some(r) if r < 0 && r != -(bindings::EINTR as c_int) && r != -(bindings::ERESTARTSYS as c_int)
+                && r != -(bindings::EAGAIN as c_int) => {
+                pr_err!("{} on {} failed: {}\n", name, self.config.device(), r);
+                check(r)
+            }
+            Some(r) => check(r),
+        }
+    }
+
+    // Exports buffer `index` as a dma-buf and maps it.
+    fn map(&mut self, index: u32) -> Result {
+        let mut export: bindings::v4l2_exportbuffer = unsafe { core::mem::zeroed() };
+        export.type_ = V4L2_BUF_TYPE_VIDEO_CAPTURE;
+        export.index = index;
+        export.flags = (bindings::O_RDONLY | bindings::O_CLOEXEC) as u32;
+        self.call("VIDIOC_EXPBUF", Ioctl::Queue, |ops, filp, fh| {
+            ops.vidioc_expbuf.map(|op| unsafe { op(filp, fh, &mut export) })
+        })?;
+
+        // EXPBUF installed an fd in the calling process; keep the dma-buf and close the fd again.
+        let dmabuf = check_ptr(unsafe { bindings::dma_buf_get(export.fd) });
+        unsafe { bindings::close_fd(export.fd as _) };
+        let dmabuf = dmabuf?;
+
+        let mut map: bindings::iosys_map = unsafe { core::mem::zeroed() };
+        let r = unsafe { bindings::dma_buf_vmap(dmabuf, &mut map) };
+        if r < 0 || map.is_iomem {
+            pr_err!("mapping buffer {} of {} failed: {}\n", index, self.config.device(), r);
+            if r >= 0 {
+                unsafe { bindings::dma_buf_vunmap(dmabuf, &mut map) };
+            }
+            unsafe { bindings::dma_buf_put(dmabuf) };
+            return Err(if r < 0 { Error::from_kernel_errno(r) } else { EIO });
+        }
+        let length = unsafe { (*dmabuf).size };
+        if let Err(e) = self.buffers.try_push(MappedBuffer { dmabuf, map, length }) {
+            unsafe {
+                bindings::dma_buf_vunmap(dmabuf, &mut map);
+                bindings::dma_buf_put(dmabuf);
+            }
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    fn queue(&self, index: u32) -> Result {
+        let mut buffer: bindings::v4l2_buffer = unsafe { core::mem::zeroed() };
+        buffer.type_ = V4L2_BUF_TYPE_VIDEO_CAPTURE;
+        buffer.memory = V4L2_MEMORY_MMAP;
+        buffer.index = index;
+        self.call("VIDIOC_QBUF", Ioctl::Queue, |ops, filp, fh| {
+            ops.vidioc_qbuf.map(|op| unsafe { op(filp, fh, &mut buffer) })
+        })
+    }
+
+    // The next frame, None if there is none yet. Returns the buffer it is in and its length; the
+    // buffer has to be queued again once the frame has been used.
+    fn dequeue(&self) -> Result<Option<(u32, usize)>> {
+        let mut buffer: bindings::v4l2_buffer = unsafe { core::mem::zeroed() };
+        buffer.type_ = V4L2_BUF_TYPE_VIDEO_CAPTURE;
+        buffer.memory = V4L2_MEMORY_MMAP;
+        match self.call("VIDIOC_DQBUF", Ioctl::Queue, |ops, filp, fh| {
+            ops.vidioc_dqbuf.map(|op| unsafe { op(filp, fh, &mut buffer) })
+        }) {
+            Ok(()) => {}
+            Err(e) if e == EAGAIN => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mapped = match self.buffers.get(buffer.index as usize) {
+            Some(mapped) => mapped,
+            None => {
+                pr_err!("dequeued buffer index {} >= {}\n", buffer.index, self.buffers.len());
+                return Err(EIO);
+            }
+        };
+        Ok(Some((buffer.index, core::cmp::min(buffer.bytesused as usize, mapped.length))))
+    }
+
+    // The first `len` bytes of buffer `index`, while it is dequeued.
+    fn frame(&self, index: u32, len: usize) -> &[u8] {
+        let mapped = &self.buffers[index as usize];
+        unsafe { core::slice::from_raw_parts(mapped.map.__bindgen_anon_1.vaddr as *const u8, len) }
+    }
+
+    // Sleeps on the wait queues the driver's poll() registers until a frame can be dequeued (or
+    // the device reports an error), for at most `timeout_ms`, like poll(2) on the device would.
+    // kthread_stop() cuts it short.
+    fn wait_frame(&self, timeout_ms: u32) {
+        let poll = match unsafe { (*(*self.filp).f_op).poll } {
+            Some(poll) => poll,
+            // Always readable, as far as the VFS is concerned.
+            None => return,
+        };
+        let ready = bindings::POLLIN | bindings::POLLERR;
+        let mut waiter = PollWaiter::new();
+        let waiter = &mut waiter;
+        if unsafe { poll(self.filp, &mut waiter.table) } & ready != 0 {
+            waiter.remove();
+            return;
+        }
+        if waiter.count == 0 {
+            // Nothing to wake us; poll again after the timeout.
+            unsafe { bindings::msleep_interruptible(timeout_ms) };
+            return;
+        }
+        let mut timeout = unsafe { bindings::__msecs_to_jiffies(timeout_ms) as core::ffi::c_long };
+        loop {
+            timeout = unsafe {
+                bindings::wait_woken(&mut waiter.entries[0].wait, bindings::TASK_INTERRUPTIBLE, timeout)
+            };
+            let mask = unsafe { poll(self.filp, core::ptr::null_mut()) };
+            if mask & ready != 0 || timeout == 0 || should_stop() {
+                break;
+            }
+        }
+        waiter.remove();
+    }
 }
 
+// Wait queues one poll() call registers at most; vb2 drivers use one for buffers and one for
+// events.
+const POLL_QUEUES: usize = 4;
+
 #[repr(C)]
-#[derive(Default)]
-struct v4l2_requestbuffers {
-    count: u32,
-    type_: u32,
-    memory: u32,
-    capabilities: u32,
-    flags: u8,
-    reserved: [u8; 3]
+struct PollEntry {
+    // First, so poll_wake can get from it to `woken`.
+    wait: bindings::wait_queue_entry,
+    // The flags of the first entry, the one wait_woken() sleeps on.
+    woken: *mut core::ffi::c_uint,
 }
 
+// A poll_table that registers the worker on the queues a driver's poll() hands it, like
+// poll(2)'s does. Every wakeup is recorded on the first entry, so one wait_woken() on it notices
+// any of them, including those that came before it started sleeping.
 #[repr(C)]
-#[derive(Clone, Copy)]
-union anon_buffer_m {
-    offset: u32,
-    userptr: u64,
-    planes: *mut v4l2_plane,
-    fd: i32
+struct PollWaiter {
+    // First, so poll_register can get from it to the rest.
+    table: bindings::poll_table,
+    entries: [PollEntry; POLL_QUEUES],
+    heads: [*mut bindings::wait_queue_head; POLL_QUEUES],
+    count: usize,
 }
 
-#[repr(C)]
-#[derive(Default, Clone, Copy)]
-struct v4l2_timecode {
-    type_: u32,
-    flags: u32,
-    frame: u8,
-    seconds: u8,
-    minutes: u8,
-    hours: u8,
-    userbits: [u8; 4]
-}
-
-// https://docs.rs/libc/0.2.137/libc/struct.timeval.html
-// /usr/include/bits/types/struct_timeval.h
-// assumes __USE_TIME_BITS64
-#[repr(C)]
-#[derive(Clone, Copy)]
-struct timeval {
-    tv_sec: i64,
-    tv_usec: i64
+impl PollWaiter {
+    fn new() -> PollWaiter {
+        let mut waiter: PollWaiter = unsafe { core::mem::zeroed() };
+        waiter.table._qproc = Some(poll_register);
+        waiter.table._key = bindings::POLLIN | bindings::POLLERR;
+        waiter
+    }
+
+    // Takes the worker off the queues again; has to happen before the waiter moves or goes away.
+    fn remove(&mut self) {
+        for i in 0..self.count {
+            unsafe { bindings::remove_wait_queue(self.heads[i], &mut self.entries[i].wait) };
+        }
+        self.count = 0;
+    }
 }
 
-#[repr(C)]
-#[derive(Clone, Copy)]
-struct v4l2_buffer {
-    index: u32,
-    type_: u32,
-    bytesused: u32,
-    flags: u32,
-    field: u32,
-    timestamp: timeval,
-    timecode: v4l2_timecode,
-    sequence: u32,
-
-    // memory location
-    memory: u32,
-    m: anon_buffer_m,
-    length: u32,
-    reserved2: u32,
-    anon_union: u32 // technically u32/s32
+unsafe extern "C" fn poll_register(
+    _filp: *mut bindings::file,
+    head: *mut bindings::wait_queue_head,
+    table: *mut bindings::poll_table,
+) {
+    // SAFETY: `table` is the first field of the PollWaiter wait_frame() passed to poll().
+    let waiter = unsafe { &mut *(table as *mut PollWaiter) };
+    if waiter.count == POLL_QUEUES {
+        pr_err!("capture device polls more than {} wait queues\n", POLL_QUEUES);
+        return;
+    }
+    let woken = &mut waiter.entries[0].wait.flags as *mut core::ffi::c_uint;
+    let entry = &mut waiter.entries[waiter.count];
+    entry.wait.private = unsafe { bindings::get_current() } as *mut core::ffi::c_void;
+    entry.wait.func = Some(poll_wake);
+    entry.woken = woken;
+    unsafe { bindings::add_wait_queue(head, &mut entry.wait) };
+    waiter.heads[waiter.count] = head;
+    waiter.count += 1;
+}
+
+// woken_wake_function(), but marking the first entry whichever queue woke us.
+unsafe extern "C" fn poll_wake(
+    wait: *mut bindings::wait_queue_entry,
+    mode: core::ffi::c_uint,
+    flags: c_int,
+    key: *mut core::ffi::c_void,
+) -> c_int {
+    // SAFETY: Only set on the `wait` of a PollEntry, by poll_register.
+    let entry = unsafe { &*(wait as *mut PollEntry) };
+    // Pairs with the barrier in wait_woken(), as in woken_wake_function().
+    core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+    unsafe { *entry.woken |= bindings::WQ_FLAG_WOKEN };
+    unsafe { bindings::default_wake_function(wait, mode, flags, key) }
 }
 
-// https://www.kernel.org/doc/html/latest/userspace-api/ioctl/ioctl-decoding.html
-const IORW: u32 = 0b11;
-// const IOW: u32 = 0b01;
-// const IOR: u32 = 0b10;
-fn ioctl_num(ioty: u32, num: u32, size: u32) -> u32 {
-    (ioty << 30) + (size << 16) + (('V' as u32) << 8) + num
+impl Drop for Capture {
+    fn drop(&mut self) {
+        if self.streaming {
+            // Also hands every buffer back from the driver.
+            let _ = self.call("VIDIOC_STREAMOFF", Ioctl::Queue, |ops, filp, fh| {
+                ops.vidioc_streamoff.map(|op| unsafe { op(filp, fh, V4L2_BUF_TYPE_VIDEO_CAPTURE) })
+            });
+        }
+        for mut mapped in self.buffers.drain(..) {
+            unsafe {
+                bindings::dma_buf_vunmap(mapped.dmabuf, &mut mapped.map);
+                bindings::dma_buf_put(mapped.dmabuf);
+            }
+        }
+        // Frees the buffers, now that nothing maps them.
+        unsafe { bindings::filp_close(self.filp, core::ptr::null_mut()) };
+        pr_info!("stopped capturing from {}\n", self.config.device());
+    }
 }
 
 /*******************
  * Networking code *
  *******************/
 
+// INET6_ADDRSTRLEN, including the NUL.
+const ADDR_LEN: usize = 46;
+
+// Where frames are sent. Parsed once (module load or ioctl) so a bad address is reported there
+// rather than as a failed connect on every frame.
+#[derive(Clone, Copy)]
+struct Endpoint {
+    // AF_INET or AF_INET6 address with the port, as connect() takes it.
+    addr: bindings::sockaddr_storage,
+    // The address as given, for messages.
+    text: [u8; ADDR_LEN],
+    port: u16,
+}
+
+impl Endpoint {
+    fn parse(text: &[u8], port: u16) -> Result<Endpoint> {
+        let text = match text.iter().position(|b| *b == 0) {
+            Some(end) => &text[..end],
+            None => text,
+        };
+        let shown = core::str::from_utf8(text).unwrap_or("<not UTF-8>");
+        if text.is_empty() || text.len() >= ADDR_LEN {
+            pr_err!("invalid server address '{}': expected an IPv4 or IPv6 address\n", shown);
+            return Err(EINVAL);
+        }
+        if port == 0 {
+            pr_err!("invalid server port 0 for {}\n", shown);
+            return Err(EINVAL);
+        }
+
+        let mut endpoint = Endpoint { addr: Default::default(), text: [0; ADDR_LEN], port };
+        endpoint.text[..text.len()].copy_from_slice(text);
+        let port_text = CString::try_from_fmt(fmt!("{}", port))?;
+        // Accepts dotted IPv4 and any IPv6 notation (AF_UNSPEC tries both), fills in the port.
+        let r = unsafe {
+            bindings::inet_pton_with_scope(
+                &mut bindings::init_net,
+                bindings::AF_UNSPEC as _,
+                endpoint.text.as_ptr() as _,
+                port_text.as_char_ptr(),
+                &mut endpoint.addr,
+                )
+        };
+        if r < 0 {
+            pr_err!("invalid server address '{}': expected an IPv4 or IPv6 address\n", shown);
+            return Err(EINVAL);
+        }
+        Ok(endpoint)
+    }
+
+    fn family(&self) -> c_int {
+        self.addr.ss_family as c_int
+    }
+
+    fn addr_len(&self) -> c_int {
+        if self.family() == bindings::AF_INET6 as c_int {
+            core::mem::size_of::<bindings::sockaddr_in6>() as c_int
+        } else {
+            core::mem::size_of::<bindings::sockaddr_in>() as c_int
+        }
+    }
+}
+
+impl core::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let end = self.text.iter().position(|b| *b == 0).unwrap_or(ADDR_LEN);
+        let text = core::str::from_utf8(&self.text[..end]).unwrap_or("?");
+        if self.family() == bindings::AF_INET6 as c_int {
+            write!(f, "[{}]:{}", text, self.port)
+        } else {
+            write!(f, "{}:{}", text, self.port)
+        }
+    }
+}
+
 fn u8_array_of_u64(x: u64) -> [u8; 8] {
     let mut ans = [0; 8];
 
@@ -186,7 +617,8 @@ fn u64_of_array(arr: &[u8]) -> u64 {
 }
 
 // https://rust-for-linux.github.io/docs/src/kernel/net.rs.html#335-358
-fn sock_read(sock: *mut bindings::socket, buf: &mut [u8]) -> i64 {
+// One receive; Ok(0) means the server closed the connection.
+fn sock_read(sock: *mut bindings::socket, buf: &mut [u8]) -> Result<usize> {
     let mut msg = bindings::msghdr::default();
     let mut vec = bindings::kvec {
         iov_base: buf.as_mut_ptr().cast(),
@@ -205,18 +637,16 @@ fn sock_read(sock: *mut bindings::socket, buf: &mut [u8]) -> i64 {
             /*if block { 0 } else { bindings::MSG_DONTWAIT } as _, */
             )
     };
-    r as _
-    /*
     if r < 0 {
-        /* Err(Error::from_kernel_errno(r)) */
-        Err(r)
+        Err(Error::from_kernel_errno(r))
     } else {
         Ok(r as _)
-    }*/
+    }
 }
 
 // https://rust-for-linux.github.io/docs/src/kernel/net.rs.html#367-384
-fn sock_write(sock: *mut bindings::socket, buf: &[u8]) -> i64 {
+// One send; may take only part of `buf`.
+fn sock_write(sock: *mut bindings::socket, buf: &[u8]) -> Result<usize> {
     let mut msg = bindings::msghdr {
         msg_flags: /*if block { 0 } else { bindings::MSG_DONTWAIT }*/ 0,
         ..bindings::msghdr::default()
@@ -228,258 +658,1128 @@ fn sock_write(sock: *mut bindings::socket, buf: &[u8]) -> i64 {
     // SAFETY: The type invariant guarantees that the socket is valid, and `vec` was
     // initialised with the input  buffer.
     let r = unsafe { bindings::kernel_sendmsg(sock, &mut msg, &mut vec, 1, vec.iov_len) };
-    r as _
-    /* if r < 0 {
+    if r < 0 {
         Err(Error::from_kernel_errno(r))
     } else {
         Ok(r as _)
-    }*/
+    }
+}
+
+// Between partial transfers: give up if the reading process got a signal (^C).
+fn check_signal() -> Result {
+    if Task::current().signal_pending() {
+        return Err(EINTR);
+    }
+    Ok(())
+}
+
+// Fills all of `buf`, counting every receive that came back with less than was left in `short`.
+// A receive timeout (EAGAIN from SO_RCVTIMEO) becomes ETIMEDOUT, the server closing the
+// connection ECONNRESET.
+fn sock_read_exact(sock: *mut bindings::socket, mut buf: &mut [u8], short: &mut u64) -> Result {
+    while !buf.is_empty() {
+        let n = match sock_read(sock, buf) {
+            Ok(0) => return Err(ECONNRESET),
+            Ok(n) => n,
+            Err(e) if e == EAGAIN => return Err(ETIMEDOUT),
+            Err(e) if e == ERESTARTSYS => return Err(EINTR),
+            Err(e) => return Err(e),
+        };
+        buf = &mut buf[n..];
+        if !buf.is_empty() {
+            *short += 1;
+            check_signal()?;
+        }
+    }
+    Ok(())
+}
+
+// Sends all of `buf`, counting partial sends in `short`.
+fn sock_write_all(sock: *mut bindings::socket, mut buf: &[u8], short: &mut u64) -> Result {
+    while !buf.is_empty() {
+        let n = match sock_write(sock, buf) {
+            Ok(0) => return Err(EPIPE),
+            Ok(n) => n,
+            Err(e) if e == EAGAIN => return Err(ETIMEDOUT),
+            Err(e) if e == ERESTARTSYS => return Err(EINTR),
+            Err(e) => return Err(e),
+        };
+        buf = &buf[n..];
+        if !buf.is_empty() {
+            *short += 1;
+            check_signal()?;
+        }
+    }
+    Ok(())
+}
+
+// One frame: length and data out, length and reply back. Returns the reply and how many of its
+// bytes are valid.
+fn exchange(sock: *mut bindings::socket, data: &[u8], counters: &mut SocketCounters) -> Result<([u8; OUTPUT_SIZE], usize)> {
+    // Send length of data as u64, then send data.
+    let len_array = u8_array_of_u64(data.len() as u64);
+    sock_write_all(sock, &len_array, &mut counters.short_writes)?;
+    sock_write_all(sock, data, &mut counters.short_writes)?;
+
+    // Receive length of data as u64;
+    let mut rcv_len_u8_arr: [u8; 8] = [0; 8];
+    sock_read_exact(sock, &mut rcv_len_u8_arr, &mut counters.short_reads)?;
+    let rcv_len = u64_of_array(&mut rcv_len_u8_arr);
+
+    // More than any model returns; reading only part of it would leave the rest in the stream.
+    if rcv_len > OUTPUT_SIZE as u64 {
+        pr_err!("server reply of {} bytes, expected at most {}\n", rcv_len, OUTPUT_SIZE as u64);
+        return Err(EPROTO);
+    }
+    let rcv_len = rcv_len as usize;
+
+    // Receive return data as array of u8s.
+    let mut rcv_vec_u8: [u8; OUTPUT_SIZE] = [0; OUTPUT_SIZE];
+    sock_read_exact(sock, &mut rcv_vec_u8[..rcv_len], &mut counters.short_reads)?;
+
+    Ok((rcv_vec_u8, rcv_len))
 }
 
-// TODO: this would contain info on the connection between frames, but I didn't get it to compile
-// with rust so we create a new connection every frame for now & this is an empty struct.
+// Needs to be wrapped in a struct to allow for unsafe Send implementation.
+struct Sockbox(*mut bindings::socket);
+unsafe impl Send for Sockbox {}
+
+// Wait before reconnecting after a failed connect or a broken connection; doubles on every
+// failure in a row, up to the max.
+const MIN_BACKOFF_MS: u64 = 100;
+const MAX_BACKOFF_MS: u64 = 5000;
+
+fn now_ns() -> u64 {
+    unsafe { bindings::ktime_get() as u64 }
+}
+
+fn now_ms() -> u64 {
+    now_ns() / 1_000_000
+}
+
+// What happened on the connection, for the debugfs statistics. The worker moves them there after
+// every frame.
+#[derive(Default)]
+struct SocketCounters {
+    connects: u64,
+    connect_failures: u64,
+    short_reads: u64,
+    short_writes: u64,
+}
+
+// The connection to the server, kept open across frames and reconnected when it breaks. The
+// socket is also published in the shared state while it exists, so stop() can shut it down.
 struct Socket {
-    // stream: TcpStream,
-    // sock: socket,
+    state: Ref<SharedState>,
+    // None while disconnected.
+    sock: Option<Sockbox>,
+    // No connect attempt before this (ktime in ms).
+    retry_at: u64,
+    backoff_ms: u64,
+    // Connect, send and receive timeout, 0 for none.
+    timeout_ms: u32,
+    counters: SocketCounters,
+}
+
+// Bounds how long a hung server can block read(); connecting and sending get the same limit.
+fn set_sock_timeout(sock: *mut socket, timeout_ms: u32) {
+    let timeout = if timeout_ms > 0 {
+        unsafe { bindings::__msecs_to_jiffies(timeout_ms) as core::ffi::c_long }
+    } else {
+        // MAX_SCHEDULE_TIMEOUT
+        core::ffi::c_long::MAX
+    };
+    unsafe {
+        (*(*sock).sk).sk_rcvtimeo = timeout;
+        (*(*sock).sk).sk_sndtimeo = timeout;
+    }
 }
 
 impl Socket {
-    fn new() -> Socket {
-        Socket{}
+    fn new(state: Ref<SharedState>, timeout_ms: u32) -> Socket {
+        Socket { state, sock: None, retry_at: 0, backoff_ms: MIN_BACKOFF_MS, timeout_ms, counters: Default::default() }
     }
 
-    // TODO: need to handle case where 1 read/write is not enough for all data.
-    fn analyze(&mut self, data:&[u8]) -> [u8; OUTPUT_SIZE] {
+    // The connected socket, connecting first if needed. While backing off, sleeps until the next
+    // attempt is due (a signal or kthread_stop() cuts that short with EINTR). EINTR as well once
+    // stop() is under way.
+    fn connect(&mut self, endpoint: &Endpoint) -> Result<*mut socket> {
+        if let Some(sock) = &self.sock {
+            return Ok(sock.0);
+        }
+        let now = now_ms();
+        if now < self.retry_at {
+            let wait = unsafe { bindings::__msecs_to_jiffies((self.retry_at - now) as u32) };
+            if unsafe { bindings::schedule_timeout_interruptible(wait as core::ffi::c_long) } != 0 {
+                return Err(EINTR);
+            }
+        }
+
         // let mut sock: socket = Default::default();
         let mut sock: *mut socket = core::ptr::null_mut();
         // https://elixir.bootlin.com/linux/latest/source/include/uapi/linux/in.h#L38
         // IPROTO_TCP = 6;
-        // TODO: check return value
-        unsafe { sock_create(bindings::PF_INET as c_int, bindings::sock_type_SOCK_STREAM as c_int, 6, &mut sock); }
+        let r = unsafe { sock_create(endpoint.family(), bindings::sock_type_SOCK_STREAM as c_int, 6, &mut sock) };
+        if r < 0 {
+            pr_err!("creating socket for {} failed: {}\n", endpoint, r);
+            self.counters.connect_failures += 1;
+            self.back_off();
+            return Err(Error::from_kernel_errno(r));
+        }
 
-        let mut sockaddr: bindings::sockaddr_in = Default::default();
-        sockaddr.sin_family = bindings::AF_INET as _;
-        sockaddr.sin_port = (8008 as u16).to_be();
-        // let a: Ipv4Addr = Ipv4Addr::new(172, 28, 229, 170);
-        sockaddr.sin_addr = bindings::in_addr { s_addr: u32::from_be_bytes([172, 28, 229, 170]).to_be() };
+        // Before connecting, so a server that never answers cannot block connect() for the
+        // minutes TCP keeps retrying.
+        set_sock_timeout(sock, self.timeout_ms);
+
+        // Published before connecting, so stop() can cut the connect short as well.
+        {
+            let mut inner = self.state.inner.lock();
+            if inner.stopping {
+                drop(inner);
+                unsafe { bindings::sock_release(sock) };
+                return Err(EINTR);
+            }
+            inner.sock = Some(Sockbox(sock));
+        }
 
         // r = sock->ops->connect(sock, &mut sockaddr, sizeof(servaddr), bindings::O_RDWR);
 
         // Connect
+        let mut sockaddr = endpoint.addr;
         let a = unsafe { (*((*sock).ops)).connect.unwrap() };
-        let x: *mut bindings::sockaddr_in = (&mut sockaddr) as *mut bindings::sockaddr_in;
-        let y: *mut bindings::sockaddr = x as _;
+        let y: *mut bindings::sockaddr = (&mut sockaddr) as *mut bindings::sockaddr_storage as _;
         let r = unsafe {
-            a(sock, y /*unsafe { (&mut sockaddr) as (&mut bindings::sockaddr) }*/ , core::mem::size_of::<bindings::sockaddr_in>() as i32 /*sizeof(servaddr)*/, bindings::O_RDWR as c_int)
+            a(sock, y, endpoint.addr_len(), bindings::O_RDWR as c_int)
         };
-        pr_info!("connect return: {}\n", r);
+        if r < 0 {
+            if self.release(sock) {
+                return Err(EINTR);
+            }
+            // Timing out leaves the connect in progress.
+            let r = if r == -(bindings::EINPROGRESS as c_int) { -(bindings::ETIMEDOUT as c_int) } else { r };
+            // Only the first of a series; the rest are counted in debugfs.
+            if self.backoff_ms == MIN_BACKOFF_MS {
+                pr_err!("connecting to server {} failed: {}, retrying with backoff\n", endpoint, r);
+            }
+            self.counters.connect_failures += 1;
+            self.back_off();
+            return Err(Error::from_kernel_errno(r));
+        }
+
+        pr_info!("connected to server {}\n", endpoint);
+        self.backoff_ms = MIN_BACKOFF_MS;
+        self.counters.connects += 1;
+        self.sock = Some(Sockbox(sock));
+        Ok(sock)
+    }
 
-        // Send length of data as u64, then send data.
-        let len_array = u8_array_of_u64(data.len() as u64);
-        sock_write(sock, &len_array); // TODO: might not write everything
-        sock_write(sock, &data);
+    // Also applies to the current connection.
+    fn set_timeout(&mut self, timeout_ms: u32) {
+        self.timeout_ms = timeout_ms;
+        if let Some(sock) = &self.sock {
+            set_sock_timeout(sock.0, timeout_ms);
+        }
+    }
 
-        // Receive length of data as u64;
-        let mut rcv_len_u8_arr: [u8; 8] = [0; 8];
-        sock_read(sock, &mut rcv_len_u8_arr);
-        let rcv_len = u64_of_array(&mut rcv_len_u8_arr);
+    fn back_off(&mut self) {
+        self.retry_at = now_ms() + self.backoff_ms;
+        self.backoff_ms = core::cmp::min(self.backoff_ms * 2, MAX_BACKOFF_MS);
+    }
 
-        // TODO: assert(rcv_len == OUTPUT_SIZE);
-        if rcv_len != OUTPUT_SIZE as u64 {
-            pr_warn!("rcv_len({}) != OUTPUT_SIZE({})\n", rcv_len, OUTPUT_SIZE as u64);
+    // Unpublishes and releases `sock`, under the lock so stop() never shuts down a released
+    // socket. Returns whether stop() is under way.
+    fn release(&self, sock: *mut socket) -> bool {
+        let stopping = {
+            let mut inner = self.state.inner.lock();
+            inner.sock = None;
+            inner.stopping
+        };
+        unsafe { bindings::sock_release(sock) };
+        stopping
+    }
+
+    // Closes the connection; the next frame reconnects right away.
+    fn disconnect(&mut self) {
+        if let Some(sock) = self.sock.take() {
+            self.release(sock.0);
         }
+        self.retry_at = 0;
+        self.backoff_ms = MIN_BACKOFF_MS;
+    }
+
+    // The connection broke mid-frame (or got out of step with the server): drop it and reconnect
+    // after a backoff.
+    fn broken(&mut self, endpoint: &Endpoint, e: Error) {
+        pr_err!("connection to server {} lost: {:?}\n", endpoint, e);
+        self.disconnect();
+        self.back_off();
+    }
 
-        // Receive return data as array of u8s.
-        let mut rcv_vec_u8: [u8; OUTPUT_SIZE] = [0; OUTPUT_SIZE];
-        sock_read(sock, &mut rcv_vec_u8);
+    // Errors come back as the errno read() fails with; EINTR when stop() cut the frame short.
+    fn analyze(&mut self, endpoint: &Endpoint, data:&[u8]) -> Result<([u8; OUTPUT_SIZE], usize)> {
+        let sock = self.connect(endpoint)?;
+        let result = exchange(sock, data, &mut self.counters);
+        if let Err(e) = result {
+            if self.state.inner.lock().stopping {
+                self.disconnect();
+                return Err(EINTR);
+            }
+            self.broken(endpoint, e);
+        }
+        result
+    }
+}
 
-        rcv_vec_u8
+impl Drop for Socket {
+    fn drop(&mut self) {
+        self.disconnect();
     }
 }
 
-// Needs to be wrapped in a struct to allow for unsafe Send implementation.
+// A reply from the server and how many of its bytes are valid.
 #[derive(Clone, Copy)]
-struct Filebox(*mut bindings::file);
-unsafe impl Send for Filebox {}
+struct Reply {
+    data: [u8; OUTPUT_SIZE],
+    len: usize,
+}
+
+// The reply to the most recent frame, for KERNCAMERA_GET_LAST_POSE.
+struct LastPose {
+    frame: u64,
+    timestamp_ns: u64,
+    reply: Reply,
+}
+
+// What read() hands out next: replies, or the errno of frames that got none.
+const RING_SIZE: usize = 4;
+
+struct PoseRing {
+    entries: [Option<Result<Reply>>; RING_SIZE],
+    // Oldest entry.
+    head: usize,
+    len: usize,
+    // Entries dropped unread because the ring was full.
+    overwritten: u64,
+}
+
+impl PoseRing {
+    const EMPTY: Option<Result<Reply>> = None;
+
+    fn new() -> PoseRing {
+        PoseRing { entries: [Self::EMPTY; RING_SIZE], head: 0, len: 0, overwritten: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // When full, makes room by dropping the oldest entry; a slow reader gets the latest poses.
+    fn push(&mut self, entry: Result<Reply>) {
+        if self.len == RING_SIZE {
+            self.pop();
+            self.overwritten += 1;
+        }
+        self.entries[(self.head + self.len) % RING_SIZE] = Some(entry);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<Result<Reply>> {
+        if self.len == 0 {
+            return None;
+        }
+        let entry = self.entries[self.head].take();
+        self.head = (self.head + 1) % RING_SIZE;
+        self.len -= 1;
+        entry
+    }
+
+    fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+// The worker thread, between KERNCAMERA_START and KERNCAMERA_STOP.
+struct Worker {
+    task: *mut bindings::task_struct,
+    // Handed to worker_main, which takes them over once it runs; see SharedState::stop.
+    args: *mut WorkerArgs,
+    // As the driver agreed to it, for KERNCAMERA_GET_DEVICE.
+    config: CaptureConfig,
+}
+
+// Only used under the SharedState lock.
+unsafe impl Send for Worker {}
 
 struct SharedStateInner {
-    // See N_ADDRS for how to index into this
-    write_input: Option<[u64; N_ADDRS*2]>,
-    filp: Option<Filebox>,
-    socket: Socket,
+    // Set with KERNCAMERA_SET_DEVICE.
+    config: Option<CaptureConfig>,
+    worker: Option<Worker>,
+    // Set by stop() until the worker is gone.
+    stopping: bool,
+    // The worker's socket while it has one, for stop() to shut down.
+    sock: Option<Sockbox>,
+    endpoint: Endpoint,
+    // Bumped by KERNCAMERA_SET_SERVER, so the worker knows to reconnect.
+    endpoint_changes: u64,
+    timeout_ms: u32,
+    // Counters for KERNCAMERA_GET_STATS; the version and streaming fields are filled in there.
+    stats: KerncameraStats,
+    // More of them, only in debugfs.
+    debug: DebugStats,
+    last_pose: Option<LastPose>,
+    ring: PoseRing,
+    // The session controlling the camera, see Session.
+    owner: Option<u64>,
+    next_session: u64,
+    // Open files.
+    sessions: u64,
+}
+
+impl SharedStateInner {
+    // Makes `session` the owner unless another session is (EBUSY).
+    fn claim(&mut self, session: u64) -> Result {
+        match self.owner {
+            Some(owner) if owner != session => Err(EBUSY),
+            _ => {
+                self.owner = Some(session);
+                Ok(())
+            }
+        }
+    }
+
+    // EBUSY if another session owns the camera.
+    fn check_owner(&self, session: u64) -> Result {
+        match self.owner {
+            Some(owner) if owner != session => Err(EBUSY),
+            _ => Ok(()),
+        }
+    }
 }
 
 struct SharedState {
+    // Signalled when the worker queues a pose or stops.
+    changed: CondVar,
     inner: Mutex<SharedStateInner>,
 }
 
 impl SharedState {
-    fn try_new() -> Result<Ref<Self>> {
-
-        let state = Pin::from(UniqueRef::try_new(Self {
-            inner: Mutex::new(SharedStateInner {
-                write_input: Some([0; N_ADDRS*2]),
-                filp: None,
-                socket: Socket::new() }),
+    fn try_new(endpoint: Endpoint, timeout_ms: u32) -> Result<Ref<Self>> {
+
+        let mut state = Pin::from(UniqueRef::try_new(Self {
+            // SAFETY: `condvar_init!` is called below.
+            changed: unsafe { CondVar::new() },
+            // SAFETY: `mutex_init!` is called below.
+            inner: unsafe { Mutex::new(SharedStateInner {
+                config: None,
+                worker: None,
+                stopping: false,
+                sock: None,
+                endpoint,
+                endpoint_changes: 0,
+                timeout_ms,
+                stats: Default::default(),
+                debug: Default::default(),
+                last_pose: None,
+                ring: PoseRing::new(),
+                owner: None,
+                next_session: 0,
+                sessions: 0 }) },
         })?);
 
+        // SAFETY: `changed` is pinned when `state` is.
+        let pinned = unsafe { state.as_mut().map_unchecked_mut(|s| &mut s.changed) };
+        condvar_init!(pinned, "SharedState::changed");
+
+        // SAFETY: `inner` is pinned when `state` is.
+        let pinned = unsafe { state.as_mut().map_unchecked_mut(|s| &mut s.inner) };
+        mutex_init!(pinned, "SharedState::inner");
+
         Ok(state.into())
     }
-}
 
-struct Token;
-#[vtable]
-impl file::Operations for Token {
-    type Data = Ref<SharedState>;
-    type OpenData = Ref<SharedState>;
+    // Starts streaming the configured device and a worker thread that sends its frames, owned by
+    // `session`. Runs in the ioctl's process, which the buffer export needs.
+    fn start(this: &Ref<SharedState>, session: u64) -> Result {
+        let mut inner = this.inner.lock();
+        inner.claim(session)?;
+        if inner.worker.is_some() {
+            return Err(EBUSY);
+        }
+        let config = inner.config.ok_or_else(|| {
+            pr_err!("KERNCAMERA_START before KERNCAMERA_SET_DEVICE\n");
+            ENODEV
+        })?;
+        let capture = Capture::start(&config)?;
+        let config = capture.config;
+
+        let args = Box::try_new(WorkerArgs { state: this.clone(), capture })?;
+        let data = Box::into_raw(args);
+        let task = unsafe {
+            bindings::kthread_create_on_node(
+                Some(worker_main),
+                data as _,
+                -1, // NUMA_NO_NODE
+                c_str!("kerncamera").as_char_ptr(),
+                )
+        };
+        let task = match check_ptr(task) {
+            Ok(task) => task,
+            Err(e) => {
+                pr_err!("starting the worker failed: {:?}\n", e);
+                // SAFETY: The thread never ran, so the arguments are still ours.
+                drop(unsafe { Box::from_raw(data) });
+                return Err(e);
+            }
+        };
+        inner.ring.clear();
+        inner.worker = Some(Worker { task, args: data, config });
+        unsafe { bindings::wake_up_process(task) };
+        Ok(())
+    }
 
-    fn open(shared: &Ref<SharedState>, _file: &File) -> Result<Self::Data> {
-        Ok(shared.clone())
+    // Stops the worker if it runs. Shuts the connection down first, so the frame in flight (or
+    // the connect) fails right away instead of waiting for the server, even with no timeout; the
+    // worker closes the device on its way out. Returns right away if another stop() is under way.
+    fn stop(&self) {
+        let (task, args) = {
+            let mut inner = self.inner.lock();
+            let (task, args) = match &inner.worker {
+                Some(worker) if !inner.stopping => (worker.task, worker.args),
+                _ => return,
+            };
+            inner.stopping = true;
+            if let Some(sock) = &inner.sock {
+                unsafe { bindings::kernel_sock_shutdown(sock.0, bindings::sock_shutdown_cmd_SHUT_RDWR) };
+            }
+            (task, args)
+        };
+        // Not under the lock, which the worker takes on its way out. EINTR if the thread was
+        // stopped before it ever ran, which leaves the capture and everything else to us.
+        if unsafe { bindings::kthread_stop(task) } == -(bindings::EINTR as c_int) {
+            // SAFETY: worker_main never ran (it returns 0), so the arguments are still ours.
+            drop(unsafe { Box::from_raw(args) });
+        }
+
+        let mut inner = self.inner.lock();
+        inner.worker = None;
+        inner.stopping = false;
+        drop(inner);
+        // Blocked readers get ENODEV now.
+        self.changed.notify_all();
     }
+}
 
-    // One read call results in dqbuf+qbuf & communicating w/ server for one frame.
-    fn read(
-        shared: RefBorrow<'_, SharedState>,
-        _: &File,
-        data: &mut impl IoBufferWriter,
-        _offset: u64,
-    ) -> Result<usize> {
+// Longest wait for the device to have a frame before looking at the settings again, and the wait
+// after a failed DQBUF.
+const FRAME_WAIT_MS: u32 = 1000;
+const CAPTURE_ERROR_MS: u32 = 100;
 
-        let inner = shared.inner.lock();
-        let filp = inner.filp.unwrap().0;
+struct WorkerArgs {
+    state: Ref<SharedState>,
+    capture: Capture,
+}
 
-        let vidioc_dqbuf: u32 = ioctl_num(IORW, 17, core::mem::size_of::<v4l2_buffer>() as u32);
-        let vidioc_qbuf: u32 = ioctl_num(IORW, 15, core::mem::size_of::<v4l2_buffer>() as u32);
+fn should_stop() -> bool {
+    unsafe { bindings::kthread_should_stop() }
+}
 
-        // dqbuf
-        xioctl(filp, vidioc_dqbuf, inner.write_input.unwrap()[0]); // buf1 vaddr
-        // debug: could print buf.bytesused.
-        let index = unsafe {*(inner.write_input.unwrap()[1] as *const v4l2_buffer)}.index;
-        let paddr = inner.write_input.unwrap()[4+(index as usize*2)+1];
+// The worker thread: captures a frame, has the server analyze it and queues the reply for read(),
+// until kthread_stop(). Never returns before that, so the task stays valid for SharedState::stop;
+// stopped before it first runs, it is not called at all and stop() drops `data` instead.
+unsafe extern "C" fn worker_main(data: *mut core::ffi::c_void) -> c_int {
+    // SAFETY: Leaked for us by SharedState::start.
+    let args = unsafe { Box::from_raw(data as *mut WorkerArgs) };
+    let WorkerArgs { state, capture } = *args;
+
+    let (mut endpoint, mut endpoint_changes, timeout_ms) = {
+        let inner = state.inner.lock();
+        (inner.endpoint, inner.endpoint_changes, inner.timeout_ms)
+    };
+    let mut socket = Socket::new(state.clone(), timeout_ms);
+    // Since when the worker has been waiting for the next frame.
+    let mut waiting_since = now_ns();
+
+    while !should_stop() {
+        // Settings changed by ioctls since the last frame.
+        {
+            let inner = state.inner.lock();
+            if inner.endpoint_changes != endpoint_changes {
+                endpoint = inner.endpoint;
+                endpoint_changes = inner.endpoint_changes;
+                socket.disconnect();
+            }
+            if inner.timeout_ms != socket.timeout_ms {
+                socket.set_timeout(inner.timeout_ms);
+            }
+        }
 
-        // socket write
-        let mut image_data = alloc::vec::Vec::try_with_capacity(BUF_SIZE).unwrap();
-        for i in 0..BUF_SIZE {
-            let b = unsafe{*((paddr + i as u64) as *const u8)};
-            image_data.try_push(b).unwrap();
+        let (index, len) = match capture.dequeue() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => {
+                capture.wait_frame(FRAME_WAIT_MS);
+                continue;
+            }
+            Err(e) => {
+                let mut inner = state.inner.lock();
+                inner.stats.capture_errors += 1;
+                inner.ring.push(Err(e));
+                drop(inner);
+                state.changed.notify_all();
+                unsafe { bindings::msleep_interruptible(CAPTURE_ERROR_MS) };
+                waiting_since = now_ns();
+                continue;
+            }
+        };
+        let dequeued = now_ns();
+        let result = socket.analyze(&endpoint, capture.frame(index, len));
+        let answered = now_ns();
+
+        // qbuf (also when the server could not be reached, so the next frame has a buffer)
+        let queued = capture.queue(index);
+
+        let mut inner = state.inner.lock();
+        let counters = core::mem::take(&mut socket.counters);
+        inner.stats.connects += counters.connects;
+        inner.debug.add(&counters);
+        inner.debug.dequeue.record(dequeued - waiting_since);
+        match result {
+            Ok((data, out_len)) => {
+                let reply = Reply { data, len: out_len };
+                // Both ways: the u64 length, then the data.
+                inner.stats.frames += 1;
+                inner.stats.bytes_sent += 8 + len as u64;
+                inner.stats.bytes_received += 8 + out_len as u64;
+                inner.stats.last_exchange_ns = answered - dequeued;
+                inner.debug.exchange.record(answered - dequeued);
+                inner.last_pose = Some(LastPose { frame: inner.stats.frames, timestamp_ns: answered, reply });
+                inner.ring.push(Ok(reply));
+            }
+            // Cut short by stop() shutting the connection down, not the server's fault.
+            Err(_) if inner.stopping => {}
+            Err(e) => {
+                inner.stats.server_errors += 1;
+                inner.ring.push(Err(e));
+            }
         }
-        let mut s = Socket{};
-        let out_data = s.analyze(image_data.as_mut_slice());
-        data.write_slice(&out_data)?;
+        if let Err(e) = queued {
+            inner.stats.capture_errors += 1;
+            inner.ring.push(Err(e));
+        }
+        drop(inner);
+        state.changed.notify_all();
+        waiting_since = now_ns();
+    }
+
+    // Streams off and closes the device, and closes the connection.
+    drop(capture);
+    drop(socket);
+    0
+}
+
+/**********************
+ * debugfs statistics *
+ *********************/
 
-        // qbuf
-        xioctl(filp, vidioc_qbuf, inner.write_input.unwrap()[0]);
+// /sys/kernel/debug/kerncamera/stats: the KERNCAMERA_GET_STATS counters and the ones below, as
+// text. Nothing to do if debugfs is not there. Needs linux/debugfs.h in bindings_helper.h.
 
-        Ok(OUTPUT_SIZE)
+// Log2 histogram of durations: bucket i counts [2^i, 2^(i+1)) microseconds, bucket 0 also
+// everything shorter and the last one everything longer.
+const HISTOGRAM_BUCKETS: usize = 24;
 
-        /*
-        // Old version- uses baked in gray image
-        let mut gray_data = alloc::vec::Vec::try_with_capacity(BUF_SIZE).unwrap();
-        for _ in 0..BUF_SIZE {
-            gray_data.try_push(128).unwrap();
+#[derive(Clone, Copy, Default)]
+struct Histogram {
+    buckets: [u64; HISTOGRAM_BUCKETS],
+    count: u64,
+    total_ns: u64,
+    max_ns: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, ns: u64) {
+        let us = ns / 1000;
+        let bucket = if us == 0 { 0 } else { 63 - us.leading_zeros() as usize };
+        self.buckets[core::cmp::min(bucket, HISTOGRAM_BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.total_ns += ns;
+        self.max_ns = core::cmp::max(self.max_ns, ns);
+    }
+
+    // A summary line, then one line per non-empty bucket.
+    fn write(&self, name: &str, f: &mut impl core::fmt::Write) -> core::fmt::Result {
+        let avg_us = if self.count > 0 { self.total_ns / self.count / 1000 } else { 0 };
+        writeln!(f, "{}_us: count {} avg {} max {}", name, self.count, avg_us, self.max_ns / 1000)?;
+        for (i, n) in self.buckets.iter().enumerate().filter(|(_, n)| **n > 0) {
+            if i == HISTOGRAM_BUCKETS - 1 {
+                writeln!(f, "  >= {}: {}", 1u64 << i, n)?;
+            } else {
+                writeln!(f, "  < {}: {}", 1u64 << (i + 1), n)?;
+            }
         }
-        let mut s = Socket{};
-        let out_data = s.analyze(gray_data.as_mut_slice());
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct DebugStats {
+    connect_failures: u64,
+    // Receives/sends that moved less than asked for and had to be repeated.
+    short_reads: u64,
+    short_writes: u64,
+    // Waiting for a frame (DQBUF) versus sending it and waiting for the answer.
+    dequeue: Histogram,
+    exchange: Histogram,
+}
+
+impl DebugStats {
+    fn add(&mut self, counters: &SocketCounters) {
+        self.connect_failures += counters.connect_failures;
+        self.short_reads += counters.short_reads;
+        self.short_writes += counters.short_writes;
+    }
+}
 
-        data.write_slice(&out_data)?;
-        Ok(OUTPUT_SIZE) */
+// Collects text for a debugfs read.
+struct TextBuffer(Vec<u8>);
 
-            /* // Works w/ no networking
-        let gray_data = [128; OUTPUT_SIZE];
-        data.write_slice(&gray_data)?;
+impl core::fmt::Write for TextBuffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.0.try_extend_from_slice(s.as_bytes()).map_err(|_| core::fmt::Error)
+    }
+}
 
-        Ok(OUTPUT_SIZE)
-        */
+impl SharedState {
+    fn stats_text(&self) -> Result<Vec<u8>> {
+        use core::fmt::Write;
+        let inner = self.inner.lock();
+        let (stats, debug) = (&inner.stats, &inner.debug);
+        let mut text = TextBuffer(Vec::try_with_capacity(4096)?);
+        (|| -> core::fmt::Result {
+            writeln!(text, "streaming {}", inner.worker.is_some() as u32)?;
+            writeln!(text, "open_files {}", inner.sessions)?;
+            writeln!(text, "frames {}", stats.frames)?;
+            writeln!(text, "capture_errors {}", stats.capture_errors)?;
+            writeln!(text, "server_errors {}", stats.server_errors)?;
+            writeln!(text, "connects {}", stats.connects)?;
+            writeln!(text, "connect_failures {}", debug.connect_failures)?;
+            writeln!(text, "short_reads {}", debug.short_reads)?;
+            writeln!(text, "short_writes {}", debug.short_writes)?;
+            writeln!(text, "bytes_sent {}", stats.bytes_sent)?;
+            writeln!(text, "bytes_received {}", stats.bytes_received)?;
+            writeln!(text, "poses_dropped {}", inner.ring.overwritten)?;
+            debug.dequeue.write("dequeue", &mut text)?;
+            debug.exchange.write("exchange", &mut text)
+        })().map_err(|_| ENOMEM)?;
+        Ok(text.0)
     }
+}
 
-    // Called once. Expects to be passed in 4 relevant vaddr/pfn pairs (see N_ADDRS declaration).
-    // Opens video file in userspace.
-    fn write(
-        shared: RefBorrow<'_, SharedState>,
-        _: &File,
-        data: &mut impl IoBufferReader,
-        _offset: u64,
-    ) -> Result<usize> {
+unsafe extern "C" fn stats_read(
+    file: *mut bindings::file,
+    buf: *mut core::ffi::c_char,
+    count: usize,
+    pos: *mut bindings::loff_t,
+) -> isize {
+    // SAFETY: simple_open set private_data to the SharedState given to debugfs_create_file, which
+    // outlives the file: RustCamera removes it before dropping its reference.
+    let state = unsafe { &*((*file).private_data as *const SharedState) };
+    match state.stats_text() {
+        Ok(text) => unsafe {
+            bindings::simple_read_from_buffer(buf as _, count, pos, text.as_ptr() as _, text.len())
+        },
+        Err(e) => e.to_kernel_errno() as isize,
+    }
+}
+
+// The debugfs directory; removing it waits for reads in progress.
+struct DebugDir {
+    dir: *mut bindings::dentry,
+    // Referenced by the stats file, so it has to stay put until the directory is gone.
+    _fops: Box<bindings::file_operations>,
+}
+
+// Only touched in init and Drop.
+unsafe impl Send for DebugDir {}
+unsafe impl Sync for DebugDir {}
+
+impl DebugDir {
+    fn create(state: &Ref<SharedState>, module: &'static ThisModule) -> Result<DebugDir> {
+        let mut fops = Box::try_new(unsafe { core::mem::zeroed::<bindings::file_operations>() })?;
+        fops.owner = module.as_ptr();
+        fops.open = Some(bindings::simple_open);
+        fops.read = Some(stats_read);
+        fops.llseek = Some(bindings::default_llseek);
+
+        // Error pointers (no debugfs) are fine to pass on and to remove.
+        let dir = unsafe { bindings::debugfs_create_dir(c_str!("kerncamera").as_char_ptr(), core::ptr::null_mut()) };
+        unsafe {
+            bindings::debugfs_create_file(
+                c_str!("stats").as_char_ptr(),
+                0o444,
+                dir,
+                &**state as *const SharedState as *mut core::ffi::c_void,
+                &*fops,
+                )
+        };
+        Ok(DebugDir { dir, _fops: fops })
+    }
+}
+
+impl Drop for DebugDir {
+    fn drop(&mut self) {
+        unsafe { bindings::debugfs_remove(self.dir) };
+    }
+}
+
+/*********************
+ * ioctl definitions *
+ *********************/
+
+// Mirrors kerncamera.h next to this file, which documents every request; keep the two in step.
+// Each struct starts with the version it was written for and has no implicit padding, and the
+// command numbers encode the struct size, so a caller built against a different layout gets
+// ENOTTY or EINVAL rather than a misread struct.
+
+const KERNCAMERA_VERSION: u32 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct KerncameraServer {
+    version: u32,
+    // Host byte order.
+    port: u16,
+    reserved: u16,
+    // NUL-terminated IPv4 or IPv6 address.
+    addr: [u8; 48],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct KerncameraDevice {
+    version: u32,
+    width: u32,
+    height: u32,
+    // fourcc
+    pixelformat: u32,
+    buffers: u32,
+    reserved: u32,
+    // NUL-terminated path under /dev.
+    path: [u8; DEVICE_LEN],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct KerncameraTimeout {
+    version: u32,
+    // 0 for no limit.
+    timeout_ms: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct KerncameraStats {
+    version: u32,
+    // 1 between KERNCAMERA_START and KERNCAMERA_STOP.
+    streaming: u32,
+    // Frames the server answered.
+    frames: u64,
+    // Frames that could not be dequeued.
+    capture_errors: u64,
+    // Frames that got no (valid) answer from the server.
+    server_errors: u64,
+    connects: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    // From dequeuing the last answered frame to its answer.
+    last_exchange_ns: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct KerncameraPose {
+    version: u32,
+    // Valid bytes in data.
+    length: u32,
+    // stats.frames when this answer came back.
+    frame: u64,
+    // CLOCK_MONOTONIC.
+    timestamp_ns: u64,
+    // The server's reply, as read() returned it.
+    data: [u8; OUTPUT_SIZE],
+}
+
+// SAFETY: Plain integers and byte arrays without padding; every bit pattern is valid and no byte
+// is uninitialised.
+unsafe impl ReadableFromBytes for KerncameraServer {}
+unsafe impl WritableToBytes for KerncameraServer {}
+unsafe impl ReadableFromBytes for KerncameraDevice {}
+unsafe impl WritableToBytes for KerncameraDevice {}
+unsafe impl ReadableFromBytes for KerncameraTimeout {}
+unsafe impl WritableToBytes for KerncameraStats {}
+unsafe impl WritableToBytes for KerncameraPose {}
+
+const KERNCAMERA_MAGIC: u32 = b'K' as u32;
+const KERNCAMERA_SET_SERVER: u32 = _IOW::<KerncameraServer>(KERNCAMERA_MAGIC, 1);
+const KERNCAMERA_GET_SERVER: u32 = _IOR::<KerncameraServer>(KERNCAMERA_MAGIC, 2);
+const KERNCAMERA_SET_DEVICE: u32 = _IOW::<KerncameraDevice>(KERNCAMERA_MAGIC, 3);
+const KERNCAMERA_GET_DEVICE: u32 = _IOR::<KerncameraDevice>(KERNCAMERA_MAGIC, 4);
+const KERNCAMERA_START: u32 = _IO(KERNCAMERA_MAGIC, 5);
+const KERNCAMERA_STOP: u32 = _IO(KERNCAMERA_MAGIC, 6);
+const KERNCAMERA_GET_STATS: u32 = _IOR::<KerncameraStats>(KERNCAMERA_MAGIC, 7);
+const KERNCAMERA_GET_LAST_POSE: u32 = _IOR::<KerncameraPose>(KERNCAMERA_MAGIC, 8);
+const KERNCAMERA_SET_TIMEOUT: u32 = _IOW::<KerncameraTimeout>(KERNCAMERA_MAGIC, 9);
+
+fn check_version(version: u32) -> Result {
+    if version != KERNCAMERA_VERSION {
+        pr_err!("kerncamera struct version {}, this module speaks {}\n", version, KERNCAMERA_VERSION);
+        return Err(EINVAL);
+    }
+    Ok(())
+}
+
+/**************
+ * Open files *
+ **************/
+
+// Every open of /dev/kerncamera is a session. Any session may query (the GET ioctls); the first one
+// to change a setting or start streaming owns the camera until it stops streaming or closes the
+// file, and meanwhile the others get EBUSY for those and for read().
+struct Session {
+    shared: Ref<SharedState>,
+    id: u64,
+}
 
-        // Read data from userspace
+impl Session {
+    fn new(shared: &Ref<SharedState>) -> Session {
         let mut inner = shared.inner.lock();
-        let to_read = data.len();
-        if to_read != INPUT_SIZE {
-            pr_info!("camera write len({}) != expected input size({})\n", to_read, INPUT_SIZE);
-            return Err(EINVAL);
-        }
-        let mut inbuf: [u8; INPUT_SIZE] = [0; INPUT_SIZE];
-        data.read_slice(&mut inbuf)?;
+        let id = inner.next_session;
+        inner.next_session += 1;
+        inner.sessions += 1;
+        Session { shared: shared.clone(), id }
+    }
+}
+
+struct Token;
 
-        // Parse and write to shared state
-        let mut addr_array: [u64; N_ADDRS*2] = [0; N_ADDRS*2];
-        for i in 0..INPUT_SIZE {
-            let n = i / 8;
-            let b = i % 8;
-            addr_array[n] |= (inbuf[i] as u64) << ((b*8) as u64);
+impl IoctlHandler for Token {
+    type Target<'a> = &'a Session;
+
+    fn pure(session: &Session, _: &File, cmd: u32, _arg: usize) -> Result<i32> {
+        let shared = &session.shared;
+        match cmd {
+            // Opens the configured device and starts streaming.
+            KERNCAMERA_START => {
+                SharedState::start(shared, session.id)?;
+                Ok(0)
+            }
+            // Stops streaming and closes the device; nothing to do if it was not started. Gives
+            // up ownership, so another session can take over.
+            KERNCAMERA_STOP => {
+                {
+                    let mut inner = shared.inner.lock();
+                    inner.check_owner(session.id)?;
+                    inner.owner = None;
+                }
+                shared.stop();
+                Ok(0)
+            }
+            _ => Err(ENOTTY),
         }
-        pr_info!("camera getting buffer info: vaddr-pfn pairs\n");
-        for i in 0..N_ADDRS {
-            pr_info!("frame {} vaddr {:x} pfn {:x}\n", i, addr_array[i*2], addr_array[i*2+1]);
+    }
+
+    fn read(session: &Session, _: &File, cmd: u32, writer: &mut UserSlicePtrWriter) -> Result<i32> {
+        let inner = session.shared.inner.lock();
+        match cmd {
+            KERNCAMERA_GET_SERVER => {
+                let mut arg = KerncameraServer {
+                    version: KERNCAMERA_VERSION,
+                    port: inner.endpoint.port,
+                    ..Default::default()
+                };
+                arg.addr[..ADDR_LEN].copy_from_slice(&inner.endpoint.text);
+                writer.write(&arg)?;
+            }
+            // While streaming, what the driver settled on; otherwise what was set.
+            KERNCAMERA_GET_DEVICE => {
+                let config = match (&inner.worker, &inner.config) {
+                    (Some(worker), _) => worker.config,
+                    (None, Some(config)) => *config,
+                    (None, None) => return Err(ENODEV),
+                };
+                writer.write(&config.to_arg())?;
+            }
+            KERNCAMERA_GET_STATS => {
+                let mut stats = inner.stats;
+                stats.version = KERNCAMERA_VERSION;
+                stats.streaming = inner.worker.is_some() as u32;
+                writer.write(&stats)?;
+            }
+            KERNCAMERA_GET_LAST_POSE => {
+                let pose = inner.last_pose.as_ref().ok_or(ENODATA)?;
+                writer.write(&KerncameraPose {
+                    version: KERNCAMERA_VERSION,
+                    length: pose.reply.len as u32,
+                    frame: pose.frame,
+                    timestamp_ns: pose.timestamp_ns,
+                    data: pose.reply.data,
+                })?;
+            }
+            _ => return Err(ENOTTY),
         }
+        Ok(0)
+    }
 
-        // Modify pfn's to paddr's
-        for i in 0..N_ADDRS {
-            let vaddr = addr_array[i*2];
-            let pfn = addr_array[i*2+1];
-            let paddr = (pfn * PAGE_SIZE as u64) + (vaddr % PAGE_SIZE as u64) + (unsafe{bindings::page_offset_base as u64});
-            addr_array[i*2+1] = paddr;
+    // Invalid arguments leave the current settings in place.
+    fn write(session: &Session, _: &File, cmd: u32, reader: &mut UserSlicePtrReader) -> Result<i32> {
+        let shared = &session.shared;
+        match cmd {
+            // Takes effect from the next frame on.
+            KERNCAMERA_SET_SERVER => {
+                let arg: KerncameraServer = reader.read()?;
+                check_version(arg.version)?;
+                if arg.reserved != 0 {
+                    pr_err!("kerncamera_server.reserved must be 0\n");
+                    return Err(EINVAL);
+                }
+                let endpoint = Endpoint::parse(&arg.addr, arg.port)?;
+                let mut inner = shared.inner.lock();
+                inner.claim(session.id)?;
+                pr_info!("server set to {}\n", endpoint);
+                // The worker reconnects to the new server before its next frame.
+                inner.endpoint = endpoint;
+                inner.endpoint_changes += 1;
+            }
+            // Only while stopped; the device only streams to one owner.
+            KERNCAMERA_SET_DEVICE => {
+                let arg: KerncameraDevice = reader.read()?;
+                let config = CaptureConfig::from_arg(&arg)?;
+                let mut inner = shared.inner.lock();
+                inner.claim(session.id)?;
+                if inner.worker.is_some() {
+                    return Err(EBUSY);
+                }
+                inner.config = Some(config);
+            }
+            // Also applies to the current connection, from the next frame on.
+            KERNCAMERA_SET_TIMEOUT => {
+                let arg: KerncameraTimeout = reader.read()?;
+                check_version(arg.version)?;
+                let mut inner = shared.inner.lock();
+                inner.claim(session.id)?;
+                inner.timeout_ms = arg.timeout_ms;
+            }
+            _ => return Err(ENOTTY),
         }
+        Ok(0)
+    }
+}
 
-        inner.write_input = Some(addr_array);
+#[vtable]
+impl file::Operations for Token {
+    type Data = Box<Session>;
+    type OpenData = Ref<SharedState>;
 
-        // open filp
-        let s = CString::try_from_fmt(fmt!("{}", "/dev/video0")).unwrap();
-        let filp =
-            unsafe {
-            bindings::filp_open(
-                s.as_char_ptr(),
-                (bindings::O_RDWR | bindings::O_NONBLOCK) as i32,
-                (bindings::S_IRUSR | bindings::S_IWUSR) as u16
-                )
-            };
-        pr_info!("opened filp: {:x}\n", filp as u64);
-        inner.filp = Some(Filebox(filp));
+    fn open(shared: &Ref<SharedState>, _file: &File) -> Result<Self::Data> {
+        Ok(Box::try_new(Session::new(shared))?)
+    }
+
+    fn ioctl(session: &Session, file: &File, cmd: &mut IoctlCommand) -> Result<i32> {
+        cmd.dispatch::<Self>(session, file)
+    }
 
-        Ok(to_read)
+    // Hands out the worker's next pose, oldest first, or the errno of a frame that got none.
+    // Blocks until there is one unless the file is O_NONBLOCK (EAGAIN); ENODEV once stopped.
+    fn read(
+        session: &Session,
+        file: &File,
+        data: &mut impl IoBufferWriter,
+        _offset: u64,
+    ) -> Result<usize> {
 
+        let shared = &session.shared;
+        let mut inner = shared.inner.lock();
+        let entry = loop {
+            inner.check_owner(session.id)?;
+            if let Some(entry) = inner.ring.pop() {
+                break entry;
+            }
+            if inner.worker.is_none() {
+                return Err(ENODEV);
+            }
+            if file.flags() & file::flags::O_NONBLOCK != 0 {
+                return Err(EAGAIN);
+            }
+            if shared.changed.wait(&mut inner) {
+                return Err(EINTR);
+            }
+        };
+        drop(inner);
+
+        let reply = entry?;
+        data.write_slice(&reply.data[..reply.len])?;
+        Ok(reply.len)
     }
 
-    // Close filp on release.
-    fn release(shared: Ref<SharedState>, _file: &File) {
+    // Readable while poses are queued; hung up while not streaming and an error while another
+    // session owns the camera, as read() would not block then.
+    fn poll(session: &Session, _: &File, table: &PollTable) -> Result<u32> {
+        let shared = &session.shared;
+        table.register_wait(&shared.changed);
         let inner = shared.inner.lock();
-        match &inner.filp {
-            Some(f) => {
-                pr_info!("closing filp\n");
-                unsafe { bindings::filp_close(f.0, core::ptr::null_mut()) };
+        if inner.check_owner(session.id).is_err() {
+            Ok(bindings::POLLERR)
+        } else if !inner.ring.is_empty() {
+            Ok(bindings::POLLIN | bindings::POLLRDNORM)
+        } else if inner.worker.is_none() {
+            Ok(bindings::POLLHUP)
+        } else {
+            Ok(0)
+        }
+    }
+
+    // The owner closing stops capturing (which closes the server connection); other sessions
+    // leave it alone.
+    fn release(session: Box<Session>, _file: &File) {
+        let shared = &session.shared;
+        let owned = {
+            let mut inner = shared.inner.lock();
+            inner.sessions -= 1;
+            let owned = inner.owner == Some(session.id);
+            if owned {
+                inner.owner = None;
             }
-            None => {}
+            owned
+        };
+        if owned {
+            shared.stop();
         }
     }
 }
 
 struct RustCamera {
     _dev: Pin<Box<miscdev::Registration<Token>>>,
+    // Before `state`, so the stats file is gone by the time it is dropped.
+    _debug: DebugDir,
+    state: Ref<SharedState>,
 }
 
 impl kernel::Module for RustCamera {
-    fn init(_name: &'static CStr, _module: &'static ThisModule) -> Result<Self> {
+    fn init(_name: &'static CStr, module: &'static ThisModule) -> Result<Self> {
         pr_info!("Starting camera memory module.\n");
 
-        pr_info!("page_offset_base: {:x}\n", bindings::page_offset_base);
-        // pr_info!("max_pfn: {}\n", bindings::max_pfn);
-        pr_info!("vmalloc_base: {:x}\n", bindings::vmalloc_base);
-        pr_info!("vmemmap_base: {:x}\n", bindings::vmemmap_base);
+        // Refuse to load with an endpoint we could never connect to.
+        let (endpoint, timeout_ms) = {
+            let lock = module.kernel_param_lock();
+            (Endpoint::parse(server_addr.read(&lock), *server_port.read(&lock))?, *recv_timeout_ms.read(&lock))
+        };
+        pr_info!("server: {}\n", endpoint);
 
-        let state = SharedState::try_new()?;
+        let state = SharedState::try_new(endpoint, timeout_ms)?;
 
         Ok(RustCamera {
-            _dev: miscdev::Registration::new_pinned(fmt!("kerncamera"), state)?,
+            _dev: miscdev::Registration::new_pinned(fmt!("kerncamera"), state.clone())?,
+            _debug: DebugDir::create(&state, module)?,
+            state,
         })
     }
 }
@@ -487,5 +1787,6 @@ impl kernel::Module for RustCamera {
 impl Drop for RustCamera {
     fn drop(&mut self) {
         pr_info!("Ending rust camera module.\n");
+        self.state.stop();
     }
 }
@@ -1,19 +1,49 @@
 /// Setting up the server to receive connections/stream data from user (using ThreadPool)
 
+use std::cell::RefCell;
 use std::net::{TcpListener, TcpStream};
-use std::io::{prelude::*};
+use std::io;
 use std::sync::{Arc, Mutex};
 
 use byteorder::{ByteOrder, LittleEndian};
 
 use tflitec::interpreter::{Interpreter, Options};
+use tflitec::tensor::DataType;
 
+use kerncamera::protocol::{self, FRAME_HEIGHT, FRAME_SIZE, FRAME_WIDTH}; // WHAT THE CLIENTS SEND
 use remote_server::ThreadPool; // IMPORT THREADPOOL CAPABILITY
+use server_side::boundary::DType;
+use server_side::boundary_spec;
+use server_side::color::{self, ColorSpace, PixelFormat}; // CAMERA FRAME -> RGB24
+use server_side::pipeline::{Pipeline, ResizeFilter, Source, Tensor};
+use server_side::preprocess::{ResizeMode, Transform};
+
+// FRAMES COME FROM THE KERNEL MODULE (OR kerncamera-mock), OR FROM THE
+// CLIENT'S USERSPACE PATH, ALL AS W x H PLANAR YUV420 (I420)
+//      a connection carries any number of frames, each one
+//          client -> server : u64 length in bytes, the frame
+//          server -> client : u64 length in bytes, the model output as f32s
+//      (multi-byte values little endian, SEE kerncamera::protocol)
+
+const W: usize = FRAME_WIDTH as usize;
+const H: usize = FRAME_HEIGHT as usize;
+
+// BOTH HALVES OF THE SPLIT MODEL RUN HERE (NOTHING UPSTREAM RUNS A MODEL),
+// LOCKED TOGETHER SO THE BOUNDARY TENSOR GOES STRAIGHT FROM ONE TO THE OTHER
+
+struct Model {
+    local: Interpreter,
+    remote: Interpreter,
+}
+
+// RGB HOLDS THE CONVERSION OF ONE FRAME, KEPT PER WORKER THREAD AND REUSED
+// ACROSS FRAMES AND CONNECTIONS (THE FRAME AND THE REPLY ARE protocol::serve'S)
+//      SinglePose reply :   51 floats (17 keypoints)
+//      MultiPose  reply :  336 floats (6 people x 56)
 
-const BUFFER1_SIZE: usize = 589824; // BUFFER1 : READING IN
-const BUFFER2_SIZE: usize = 147456; // BUFFER2 : BYTES TO FLOAT CONVERSION
-const BUFFER3_SIZE: usize = 4; // BUFFER3 : STORE THE BYTES OF A FLOAT
-const BUFFER4_SIZE: usize = 204; // BUFFER4 : OUTPUT TENSOR
+thread_local! {
+    static RGB: RefCell<Vec<u8>> = RefCell::new(vec![0; W * H * 3]);
+}
 
 fn main() {
     // POSSIBLE CODES
@@ -23,95 +53,82 @@ fn main() {
     let listener: TcpListener = TcpListener::bind("127.0.0.1:8000").expect("Set up server [FAILED]"); // SET UP SERVER
     let pool = ThreadPool::new(4); // CREATE THREADPOOL
 
-    // LOADING THE MODEL/INTERPRETER
-    let path = format!("resource/model_remote.tflite");
-	
-    let interpreter = Interpreter::with_model_path(&path, Some(Options::default())).expect("Load model [FAILED]");
-	interpreter.allocate_tensors().expect("Allocate tensors [FAILED]");
-    let interpreter = Arc::new(Mutex::new(interpreter)); // CREATE A MUTEXED ATOMIC REFERENCE TO THE INTERPRETER
+    // LOADING THE MODELS/INTERPRETERS
+    //      (THE BOUNDARY THE LOCAL HALF PRODUCES MUST BE THE ONE THE
+    //      REMOTE HALF TAKES, SEE splitter.py)
+    let load = |path: &str| {
+        let interpreter = Interpreter::with_model_path(path, Some(Options::default())).expect("Load model [FAILED]");
+        interpreter.allocate_tensors().expect("Allocate tensors [FAILED]");
+        interpreter
+    };
+    let model = Model { local: load("resource/model_local.tflite"), remote: load("resource/model_remote.tflite") };
+    let boundary = boundary_spec(&model.local.output(0).expect("Output tensor [FAILED]"))
+        .expect("Boundary tensor [FAILED]");
+    boundary_spec(&model.remote.input(0).expect("Input tensor [FAILED]"))
+        .and_then(|remote| boundary.check(&remote))
+        .expect("Matching model halves [FAILED]");
+    println!("PARTITION BOUNDARY: {}", boundary);
+    let model = Arc::new(Mutex::new(model)); // CREATE A MUTEXED ATOMIC REFERENCE TO THE INTERPRETERS
 
     // ACCEPT CONNECTIONS USING TCPSTREAM
     for stream in listener.incoming() {
         let stream: TcpStream = stream.expect("Finding connection [FAILED]");
-        let interpreter = Arc::clone(&interpreter);
+        let model = Arc::clone(&model);
 
         pool.execute(move || {
-            handle_connection(stream, interpreter);
+            handle_connection(stream, model);
         });
     }
 }
 
 // HELPER FUNCTIONS
 
-fn handle_connection(mut stream: TcpStream, interpreter: Arc<Mutex<Interpreter>>) {
-    // READ INFORMATION IN FROM THE STREAM
-    let mut buffer1: [u8; BUFFER1_SIZE] = [0; BUFFER1_SIZE]; // CREATE BUFFER
-    stream.read(&mut buffer1).expect("Reading stream [FAILED]"); // READ IN THE DATA
-
-    // CONVERT BACK TO FLOATING POINT
-    let mut buffer2: [f32; BUFFER2_SIZE] = [0.0; BUFFER2_SIZE];
-    let mut buffer3: [u8; BUFFER3_SIZE] = [0; BUFFER3_SIZE];
-    for i in 0..BUFFER2_SIZE {
-        buffer3 = buffer1[i * 4..i * 4 + 4].try_into().expect("Taking slice [FAILED]");
-        buffer2[i] = LittleEndian::read_f32(&mut buffer3)
-    }
-
-    // SET THE INPUT TO THE INTERPRETER
-    let interpreter = interpreter.lock().expect("Unlocking interpreter [FAILED]");
-    interpreter.copy(&buffer2, 0).expect("Copying data into interpreter [FAILED]");
-    
-    interpreter.invoke().expect("Invoke [FAILED]"); // RUN THE INTERPRETER
-
-    // GET THE OUTPUT FROM THE INTERPRETER
-    let output_tensor = interpreter.output(0).expect(" [FAILED]");
-    let output_tensor = output_tensor.data::<f32>();
-
-    // CONVERT OUTPUT DATA TO BYTES
-    let mut buffer4: [u8; BUFFER4_SIZE] = [0; BUFFER4_SIZE];
-    
-    for i in 0..51 {
-        LittleEndian::write_f32(&mut buffer3, output_tensor[i]);
-        for j in 0..4 {
-            buffer4[i * 4 + j] = buffer3[j];
-        }
+// SERVES FRAMES UNTIL THE CLIENT HANGS UP (OR SENDS SOMETHING ELSE)
+fn handle_connection(mut stream: TcpStream, model: Arc<Mutex<Model>>) {
+    let mut pipeline = Pipeline::new(ResizeFilter::Area);
+    let served = protocol::serve(&mut stream, FRAME_SIZE,
+                                 |frame, reply| handle_frame(frame, reply, &model, &mut pipeline));
+    if let Err(e) = served {
+        println!("Handling frame [FAILED]: {}", e);
     }
-
-    // WRITE BACK TO THE CALLER
-    stream.write_all(&buffer4[..]).expect("Writing back to caller [FAILED]");
-    stream.flush().expect("Flushing the stream [FAILED]");
 }
 
-/// Implemented for when dealing with YUV422
-fn _buff_yuv422to_rgb888(yuv422: &[u8]) -> Vec<u8> {
-    let mut rgb888 = Vec::new(); // CREATE RESULTING VECTOR
-    
-    for i in 0..yuv422.len() / 4 {
-        // DECOMPOSING THE YUV422 ENTRIES
-        let (y1, u, y2, v) = (
-            yuv422[i * 4] as f64,
-            yuv422[i * 4 + 1] as f64,
-            yuv422[i * 4 + 2] as f64,
-            yuv422[i * 4 + 3] as f64,
-        );
-
-        // CALCULATING THE RGB VALUES
-        let (rgb1, rgb2) = (
-            _yuv422to_rgb888(y1, u, v), 
-            _yuv422to_rgb888(y2, u, v),
-        );
-
-        // PUSHING THE RESULTS
-        rgb888.extend(rgb1);
-        rgb888.extend(rgb2);
-    }
+// ONE W x H YUV420 FRAME IN, THE MODEL OUTPUT (AS BYTES) INTO reply
+fn handle_frame(frame: &[u8], reply: &mut Vec<u8>, model: &Mutex<Model>, pipeline: &mut Pipeline) -> io::Result<()> {
+    RGB.with(|rgb| {
+        let mut rgb = rgb.borrow_mut();
+
+        // YUV420 -> RGB, THEN FIT TO THE MODEL INPUT AND WRITE STRAIGHT INTO
+        // THE LOCAL HALF'S INPUT TENSOR (STRETCHED, AS THE CLIENT EXPECTS)
+        color::convert_into(frame, PixelFormat::I420, W, H, ColorSpace::default(), &mut rgb)?;
+        let model = model.lock().expect("Unlocking interpreters [FAILED]");
+        let mut input = model.local.input(0).expect("Input tensor [FAILED]");
+        let dimensions = input.shape().dimensions(); // [1, HEIGHT, WIDTH, 3]
+        let model_input = (dimensions[2] as u32, dimensions[1] as u32);
+        let tensor = match input.data_type() {
+            DataType::Float32 => Tensor::F32(input.data_mut::<f32>()),
+            _ => Tensor::U8(input.data_mut::<u8>()),
+        };
+        pipeline.run(Source::Rgb(&rgb, W as u32, H as u32),
+                     |size| Transform::new(size, model_input, ResizeMode::Stretch), tensor)?;
+
+        // RUN BOTH HALVES, HANDING THE BOUNDARY TENSOR OVER AS IT IS
+        model.local.invoke().expect("Invoke [FAILED]");
+        let boundary = model.local.output(0).expect("Output tensor [FAILED]");
+        let mut remote_input = model.remote.input(0).expect("Input tensor [FAILED]");
+        match boundary_spec(&boundary)?.dtype {
+            DType::Float32 => remote_input.data_mut::<f32>().copy_from_slice(boundary.data::<f32>()),
+            DType::Int8 | DType::Uint8 => remote_input.data_mut::<u8>().copy_from_slice(boundary.data::<u8>()),
+        }
+        model.remote.invoke().expect("Invoke [FAILED]");
 
-    return rgb888; // RETURN RESULTING VECTOR
-}
+        // GET THE OUTPUT FROM THE INTERPRETER
+        let output_tensor = model.remote.output(0).expect(" [FAILED]");
+        let output_tensor = output_tensor.data::<f32>();
 
-fn _yuv422to_rgb888(y: f64, u: f64, v: f64) -> [u8; 3] {
-    return [
-        (y + 1.4075 * (v - 128.0)) as u8,
-        (y - 0.3455 * (u - 128.0) - (0.7169 * (v - 128.0))) as u8,
-        (y + 1.7790 * (u - 128.0)) as u8,
-    ]; // CONVERT AND RETURN AN ARRAY OF [R, G, B] VALUES
+        // CONVERT OUTPUT DATA TO BYTES (protocol::serve SENDS THE LENGTH FIRST)
+        reply.resize(output_tensor.len() * 4, 0);
+        LittleEndian::write_f32_into(output_tensor, reply);
+        Ok(())
+    })
 }